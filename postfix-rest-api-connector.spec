@@ -35,9 +35,11 @@ Description=Postfix REST API Connector
 After=network.target
 
 [Service]
-Type=simple
+Type=notify
+WatchdogSec=30s
 Environment="RUST_LOG=warn"
 ExecStart=%{_bindir}/postfix-rest-api-connector /etc/postfix-rest-api-connector/config.json
+ExecReload=/bin/kill -HUP $MAINPID
 Restart=on-failure
 RestartSec=5s
 User=nobody
@@ -48,12 +50,12 @@ NoNewPrivileges=true
 PrivateTmp=true
 ProtectSystem=strict
 ProtectHome=true
-ReadWritePaths=/var/log
+ReadWritePaths=/var/log /etc/postfix-rest-api-connector
 ProtectKernelTunables=true
 ProtectKernelModules=true
 ProtectControlGroups=true
 RestrictRealtime=true
-RestrictAddressFamilies=AF_INET AF_INET6
+RestrictAddressFamilies=AF_INET AF_INET6 AF_UNIX
 LockPersonality=true
 
 [Install]
@@ -0,0 +1,211 @@
+//! One line per lookup, with a configurable set of fields
+//!
+//! Written to its own file, or to the process log with target "access".
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::logging::{self, LogFormat, LookupRecord};
+use crate::privacy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessField {
+    Timestamp,
+    RequestId,
+    Endpoint,
+    Mode,
+    Client,
+    Map,
+    Key,
+    /// OK, NOTFOUND, TEMP or PERM, as answered to the client
+    Result,
+    UpstreamStatus,
+    /// hit, stale, miss, or none without a cache
+    Cache,
+    /// Milliseconds
+    Duration,
+}
+
+impl AccessField {
+    fn name(&self) -> &'static str {
+        match self {
+            AccessField::Timestamp => "timestamp",
+            AccessField::RequestId => "request_id",
+            AccessField::Endpoint => "endpoint",
+            AccessField::Mode => "mode",
+            AccessField::Client => "client",
+            AccessField::Map => "map",
+            AccessField::Key => "key",
+            AccessField::Result => "result",
+            AccessField::UpstreamStatus => "upstream_status",
+            AccessField::Cache => "cache",
+            AccessField::Duration => "duration_ms",
+        }
+    }
+}
+
+fn default_fields() -> Vec<AccessField> {
+    vec![
+        AccessField::Timestamp,
+        AccessField::Endpoint,
+        AccessField::Mode,
+        AccessField::Map,
+        AccessField::Key,
+        AccessField::Result,
+        AccessField::UpstreamStatus,
+        AccessField::Cache,
+        AccessField::Duration,
+    ]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessLogConfig {
+    /// Appended to; without it, lines go to the log with target "access"
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// text: space-separated values, json: one object per line
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Fields in the order they are written
+    #[serde(default = "default_fields")]
+    pub fields: Vec<AccessField>,
+}
+
+impl AccessLogConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.fields.is_empty() {
+            anyhow::bail!("access-log fields must not be empty");
+        }
+        Ok(())
+    }
+}
+
+struct AccessLog {
+    file: Option<Mutex<File>>,
+    format: LogFormat,
+    fields: Vec<AccessField>,
+}
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+/// Open the access log (first call wins, changes require a restart)
+pub fn init(config: Option<&AccessLogConfig>) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    let file = match &config.file {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open access log {}", path.display()))?,
+        )),
+        None => None,
+    };
+    let _ = ACCESS_LOG.set(AccessLog {
+        file,
+        format: config.format,
+        fields: config.fields.clone(),
+    });
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ACCESS_LOG.get().is_some()
+}
+
+/// RFC 3339 UTC with milliseconds, e.g. 2026-10-16T09:12:44.318Z
+fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, rest) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn value(field: AccessField, record: &LookupRecord<'_>) -> Value {
+    match field {
+        AccessField::Timestamp => json!(timestamp(SystemTime::now())),
+        AccessField::RequestId => json!(logging::request_id()),
+        AccessField::Endpoint => json!(record.endpoint),
+        AccessField::Mode => json!(record.mode),
+        AccessField::Client => json!(logging::client()),
+        AccessField::Map => json!(record.map),
+        AccessField::Key => json!(privacy::redact(record.key)),
+        AccessField::Result => json!(record.class),
+        AccessField::UpstreamStatus => json!(record.upstream_status),
+        AccessField::Cache => json!(record.cache),
+        AccessField::Duration => json!(record.duration_ms),
+    }
+}
+
+/// Text form of a value: "-" when missing, whitespace escaped so every
+/// line splits into the same number of columns
+fn column(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(text) if text.is_empty() => "-".to_string(),
+        Value::String(text) => text.replace('%', "%25").replace(' ', "%20").replace('\n', "%0A"),
+        other => other.to_string(),
+    }
+}
+
+/// Write the access log line of a finished lookup
+pub fn write(record: &LookupRecord<'_>) {
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return;
+    };
+
+    let values = access_log.fields.iter().map(|field| (*field, value(*field, record)));
+    let line = match access_log.format {
+        LogFormat::Text => values
+            .map(|(_, value)| column(&value))
+            .collect::<Vec<_>>()
+            .join(" "),
+        LogFormat::Json => {
+            let object: Map<String, Value> = values
+                .map(|(field, value)| (field.name().to_string(), value))
+                .collect();
+            Value::Object(object).to_string()
+        }
+    };
+
+    match &access_log.file {
+        Some(file) => {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writeln!(file, "{}", line) {
+                log::warn!("Failed to write access log: {}", e);
+            }
+        }
+        None => log::info!(target: "access", "{}", line),
+    }
+}
@@ -0,0 +1,52 @@
+//! Postfix access(5) actions as returned by policy services
+
+/// Verbs that take no text
+const BARE: &[&str] = &["OK", "DUNNO"];
+/// Verbs with optional text
+const OPTIONAL_TEXT: &[&str] = &[
+    "REJECT",
+    "DEFER",
+    "DEFER_IF_REJECT",
+    "DEFER_IF_PERMIT",
+    "DISCARD",
+    "HOLD",
+    "INFO",
+    "WARN",
+];
+/// Verbs that need an argument
+const REQUIRED_ARGUMENT: &[&str] = &["BCC", "FILTER", "PREPEND", "REDIRECT"];
+
+/// Whether `action` (the part after "action=") follows the access(5) grammar
+///
+/// Verbs are case-insensitive like in Postfix. Besides the verbs above, an
+/// SMTP reply code (4NN or 5NN) followed by text is accepted.
+pub fn is_valid(action: &str) -> bool {
+    if action.chars().any(char::is_control) {
+        return false;
+    }
+    let (verb, argument) = match action.split_once(' ') {
+        Some((verb, argument)) => (verb, argument.trim()),
+        None => (action, ""),
+    };
+    let verb_is = |verbs: &[&str]| verbs.iter().any(|known| known.eq_ignore_ascii_case(verb));
+
+    if verb_is(BARE) {
+        argument.is_empty()
+    } else if verb_is(OPTIONAL_TEXT) {
+        true
+    } else if verb_is(REQUIRED_ARGUMENT) {
+        match verb.to_ascii_uppercase().as_str() {
+            // transport:destination
+            "FILTER" => argument.contains(':') && !argument.contains(char::is_whitespace),
+            // header: text
+            "PREPEND" => argument.split_once(':').is_some_and(|(name, _)| {
+                !name.is_empty() && !name.contains(char::is_whitespace)
+            }),
+            // a single address
+            _ => !argument.is_empty() && !argument.contains(char::is_whitespace),
+        }
+    } else {
+        let code = verb.as_bytes();
+        code.len() == 3 && matches!(code[0], b'4' | b'5') && code.iter().all(u8::is_ascii_digit)
+    }
+}
@@ -0,0 +1,647 @@
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::Notify;
+
+use crate::cli;
+use crate::config::{parse_file, AdminConfig, Config, Endpoint};
+use crate::metrics;
+use crate::privacy::sensitive;
+use crate::server;
+
+const BUFFER_SIZE: usize = 8192;
+
+// Larger requests (endpoint definitions included) are refused
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+// Cache entries listed per request
+const CACHE_ENTRY_LIMIT: usize = 100;
+
+const UI_HTML: &str = include_str!("admin_ui.html");
+
+/// Outcome of the most recent configuration load
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigStatus {
+    /// Hash of the running configuration
+    pub hash: String,
+    /// The running configuration is the last good copy, not the config file
+    pub fallback: bool,
+    /// Why the most recent load failed, cleared by a successful load
+    pub last_error: Option<String>,
+}
+
+/// Shared state exposed through the admin API
+pub struct AdminState {
+    endpoints: RwLock<Vec<Arc<Endpoint>>>,
+    config_status: RwLock<ConfigStatus>,
+    user_agent: RwLock<String>,
+    reload: Notify,
+    /// Main config file, for endpoints added and removed at runtime
+    config_path: String,
+}
+
+impl AdminState {
+    pub fn new(
+        endpoints: Vec<Arc<Endpoint>>,
+        config_status: ConfigStatus,
+        user_agent: String,
+        config_path: String,
+    ) -> Self {
+        Self {
+            endpoints: RwLock::new(endpoints),
+            config_status: RwLock::new(config_status),
+            user_agent: RwLock::new(user_agent),
+            reload: Notify::new(),
+            config_path,
+        }
+    }
+
+    /// User-Agent for test lookups, follows configuration reloads
+    pub fn set_user_agent(&self, user_agent: String) {
+        *self.user_agent.write().unwrap() = user_agent;
+    }
+
+    fn user_agent(&self) -> String {
+        self.user_agent.read().unwrap().clone()
+    }
+
+    pub fn config_status(&self) -> ConfigStatus {
+        self.config_status.read().unwrap().clone()
+    }
+
+    pub fn set_config_status(&self, status: ConfigStatus) {
+        *self.config_status.write().unwrap() = status;
+    }
+
+    /// Wait until a configuration reload is requested through the API
+    pub async fn reload_requested(&self) {
+        self.reload.notified().await;
+    }
+
+    /// Replace the endpoint list after a configuration reload
+    pub fn set_endpoints(&self, endpoints: Vec<Arc<Endpoint>>) {
+        *self.endpoints.write().unwrap() = endpoints;
+    }
+
+    fn endpoints(&self) -> Vec<Arc<Endpoint>> {
+        self.endpoints.read().unwrap().clone()
+    }
+
+    fn endpoint(&self, name: &str) -> Option<Arc<Endpoint>> {
+        self.endpoints()
+            .into_iter()
+            .find(|endpoint| endpoint.name == name)
+    }
+}
+
+/// The admin API's bound listener
+pub enum AdminListener {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener, String),
+}
+
+/// Bind the admin listener, before privileges are dropped
+pub async fn bind_admin(config: &AdminConfig) -> Result<AdminListener> {
+    if let Some(path) = &config.bind_socket {
+        if path.exists() {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind socket {}", path.display()))?;
+        if let Some(mode) = &config.socket_mode {
+            let mode = u32::from_str_radix(mode, 8).context("Admin: invalid socket-mode")?;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+        }
+        return Ok(AdminListener::Unix(listener, path.clone()));
+    }
+
+    let addr = format!("{}:{}", config.bind_address, config.bind_port);
+    let listener = TcpListener::bind(&addr).await?;
+    Ok(AdminListener::Tcp(listener, addr))
+}
+
+/// Minimal HTTP/1.1 admin API
+///
+/// - `GET  /`                                     web UI
+/// - `GET  /metrics`                              Prometheus metrics
+/// - `GET  /config`                               hash and load status of the configuration
+/// - `POST /reload`                               reload the configuration, like SIGHUP
+/// - `GET  /endpoints`                            endpoints with their listener and state
+/// - `POST /endpoints`                            add an endpoint (JSON definition)
+/// - `DELETE /endpoints/{endpoint}`               remove an endpoint added that way
+/// - `GET  /endpoints/{endpoint}`                 state and counters of an endpoint
+/// - `GET  /endpoints/{endpoint}/cache`           most recently used cache entries
+/// - `POST /endpoints/{endpoint}/cache/flush`     drop all cached lookups and verdicts
+/// - `POST /endpoints/{endpoint}/lookup?key=&map=` test lookup through the protocol handler
+/// - `POST /endpoints/{endpoint}/maintenance/{on|off}` toggle maintenance mode
+/// - `POST /endpoints/{endpoint}/{enable|disable}` accept or refuse connections
+/// - `GET  /targets`                              weights and drain state of all targets
+/// - `POST /targets/{endpoint}/{index}/weight/{n}` set the weight of a target
+/// - `POST /targets/{endpoint}/{index}/drain`      stop sending new requests to a target
+/// - `POST /targets/{endpoint}/{index}/ready`      put a drained target back in rotation
+///
+/// With a `token` configured, everything but the UI page and `/metrics` requires
+/// it in the `X-Admin-Token` header.
+pub async fn start_admin(
+    config: AdminConfig,
+    listener: AdminListener,
+    state: Arc<AdminState>,
+) -> Result<()> {
+    let token: Arc<Option<String>> = Arc::new(config.token.clone());
+
+    let listener = match listener {
+        AdminListener::Unix(listener, path) => {
+            info!("Admin API listening on {}", path.display());
+
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _)) => {
+                        let state = Arc::clone(&state);
+                        let token = Arc::clone(&token);
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_admin_connection(socket, &state, &token).await {
+                                error!("Admin connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("Admin accept error: {}", e);
+                    }
+                }
+            }
+        }
+        AdminListener::Tcp(listener, addr) => {
+            info!("Admin API listening on {}", addr);
+            listener
+        }
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                debug!("New admin connection from {}", sensitive(&addr));
+
+                let state = Arc::clone(&state);
+                let token = Arc::clone(&token);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_admin_connection(socket, &state, &token).await {
+                        error!("Admin connection error from {}: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Admin accept error: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_admin_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    state: &AdminState,
+    token: &Option<String>,
+) -> Result<()> {
+    let socket = &mut socket;
+    let Some((request, content)) = read_request(socket).await? else {
+        return Ok(());
+    };
+
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let target = request_line.next().unwrap_or("");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    debug!("Admin request: {} {}", method, sensitive(path));
+
+    if method == "GET" && path == "/" {
+        return write_response(socket, 200, "text/html; charset=utf-8", UI_HTML).await;
+    }
+    if method == "GET" && path == "/metrics" {
+        return write_response(socket, 200, "text/plain; version=0.0.4", &metrics::render()).await;
+    }
+
+    if let Some(token) = token {
+        let provided = request
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("x-admin-token"))
+            .map(|(_, value)| value.trim());
+        if provided != Some(token.as_str()) {
+            let body = json!({ "error": "invalid admin token" });
+            return write_response(socket, 401, "application/json", &body.to_string()).await;
+        }
+    }
+
+    // Requests that are async or need the body; everything else is answered by route()
+    let (status, body) = match (method, path_segments(path).as_slice()) {
+        ("POST", ["endpoints", name, "lookup"]) => {
+            let query: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect();
+            test_lookup(state, name, &query).await
+        }
+        ("POST", ["endpoints", name, "cache", "flush"]) => flush_cache(state, name).await,
+        ("POST", ["endpoints"]) => add_endpoint(state, &content),
+        ("DELETE", ["endpoints", name]) => remove_endpoint(state, name),
+        _ => route(state, method, path),
+    };
+    write_response(socket, status, "application/json", &body.to_string()).await
+}
+
+/// Read the request head and, per Content-Length, its body; None if the
+/// client closed the connection without sending anything
+async fn read_request<S: AsyncRead + Unpin>(socket: &mut S) -> Result<Option<(String, Vec<u8>)>> {
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0u8; BUFFER_SIZE];
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Admin request too large");
+        }
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            break buffer.len();
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..end]).into_owned();
+    let length: usize = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+    if length > MAX_REQUEST_SIZE {
+        anyhow::bail!("Admin request too large");
+    }
+
+    let mut body = buffer.get(end + 4..).unwrap_or(&[]).to_vec();
+    while body.len() < length {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed in the middle of a request");
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(length);
+    Ok(Some((head, body)))
+}
+
+/// Where an endpoint added at runtime is kept: <include-dir>/<name>.json
+fn endpoint_file(state: &AdminState, name: &str) -> Result<PathBuf> {
+    let usable = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !usable {
+        anyhow::bail!("endpoint name '{}' cannot be used as a file name", name);
+    }
+    let config: Config = parse_file(Path::new(&state.config_path))?;
+    let dir = config
+        .include_dir_path(&state.config_path)
+        .context("runtime endpoints need include-dir in the configuration")?;
+    Ok(dir.join(format!("{}.json", name)))
+}
+
+fn write_endpoint_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    // Owner-only, endpoint definitions can contain auth tokens
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    file.write_all(data)?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Load and check the configuration as it is on disk now, like `check`
+fn validate_config(path: &str) -> Result<()> {
+    let config = Config::from_file(path)?;
+    for endpoint in &config.endpoints {
+        cli::check_build(endpoint)?;
+    }
+    Ok(())
+}
+
+/// Validate and persist a new endpoint, then reload to start it
+fn add_endpoint(state: &AdminState, body: &[u8]) -> (u16, Value) {
+    let definition: Value = match serde_json::from_slice(body) {
+        Ok(definition) => definition,
+        Err(e) => return (400, json!({ "error": format!("invalid JSON: {}", e) })),
+    };
+    let endpoint: Endpoint = match serde_json::from_value(definition.clone()) {
+        Ok(endpoint) => endpoint,
+        Err(e) => return (400, json!({ "error": format!("invalid endpoint: {}", e) })),
+    };
+    if state.endpoint(&endpoint.name).is_some() {
+        return (409, json!({ "error": "endpoint already exists" }));
+    }
+    let file = match endpoint_file(state, &endpoint.name) {
+        Ok(file) => file,
+        Err(e) => return (400, json!({ "error": format!("{:#}", e) })),
+    };
+    if file.exists() {
+        return (409, json!({ "error": format!("{} already exists", file.display()) }));
+    }
+
+    let data = match serde_json::to_vec_pretty(&definition) {
+        Ok(data) => data,
+        Err(e) => return (500, json!({ "error": e.to_string() })),
+    };
+    if let Err(e) = write_endpoint_file(&file, &data) {
+        return (500, json!({ "error": format!("{:#}", e) }));
+    }
+    if let Err(e) = validate_config(&state.config_path) {
+        let _ = fs::remove_file(&file);
+        return (400, json!({ "error": format!("{:#}", e) }));
+    }
+
+    info!("Admin: endpoint '{}' added as {}", endpoint.name, file.display());
+    state.reload.notify_one();
+    (
+        201,
+        json!({ "endpoint": endpoint.name, "file": file.display().to_string() }),
+    )
+}
+
+/// Delete the file of an endpoint added at runtime, then reload to stop it
+fn remove_endpoint(state: &AdminState, name: &str) -> (u16, Value) {
+    if state.endpoint(name).is_none() {
+        return (404, json!({ "error": "unknown endpoint" }));
+    }
+    let file = match endpoint_file(state, name) {
+        Ok(file) => file,
+        Err(e) => return (400, json!({ "error": format!("{:#}", e) })),
+    };
+    // Endpoints of the main config file or other include files stay untouched
+    let definition = match fs::read(&file) {
+        Ok(definition) => definition,
+        Err(_) => {
+            let error = format!("only endpoints defined in {} can be removed", file.display());
+            return (409, json!({ "error": error }));
+        }
+    };
+
+    if let Err(e) = fs::remove_file(&file) {
+        return (500, json!({ "error": e.to_string() }));
+    }
+    if let Err(e) = validate_config(&state.config_path) {
+        let _ = write_endpoint_file(&file, &definition);
+        return (400, json!({ "error": format!("{:#}", e) }));
+    }
+
+    info!("Admin: endpoint '{}' removed, deleted {}", name, file.display());
+    state.reload.notify_one();
+    (200, json!({ "removed": name }))
+}
+
+fn path_segments(path: &str) -> Vec<&str> {
+    path.trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+async fn test_lookup(
+    state: &AdminState,
+    name: &str,
+    query: &HashMap<String, String>,
+) -> (u16, Value) {
+    let endpoint = match state.endpoint(name) {
+        Some(endpoint) => endpoint,
+        None => return (404, json!({ "error": "unknown endpoint" })),
+    };
+    let key = match query.get("key") {
+        Some(key) if !key.is_empty() => key,
+        _ => return (400, json!({ "error": "missing key" })),
+    };
+    let mapname = query
+        .get("map")
+        .map(String::as_str)
+        .filter(|mapname| !mapname.is_empty());
+
+    let (mode, request) = match cli::lookup_request(&endpoint, key, mapname) {
+        Ok(request) => request,
+        Err(e) => return (400, json!({ "error": e.to_string() })),
+    };
+    let (response, upstream) =
+        cli::run_lookup(&endpoint, &state.user_agent(), mode, &request).await;
+
+    match response {
+        Ok(response) => (
+            200,
+            json!({ "request": request, "response": response, "upstream": upstream }),
+        ),
+        Err(e) => (
+            500,
+            json!({ "error": format!("{:#}", e), "upstream": upstream }),
+        ),
+    }
+}
+
+async fn flush_cache(state: &AdminState, name: &str) -> (u16, Value) {
+    let endpoint = match state.endpoint(name) {
+        Some(endpoint) => endpoint,
+        None => return (404, json!({ "error": "unknown endpoint" })),
+    };
+    if endpoint.cache().is_none() && endpoint.policy_cache().is_none() {
+        return (404, json!({ "error": "endpoint has no cache" }));
+    }
+
+    let mut flushed = endpoint.policy_cache().map_or(0, |cache| cache.clear());
+    if let Some(cache) = endpoint.cache() {
+        match cache.clear().await {
+            Ok(count) => flushed += count,
+            Err(e) => return (500, json!({ "error": format!("{:#}", e) })),
+        }
+    }
+    info!("Admin: flushed {} cache entries of endpoint '{}'", flushed, name);
+    (200, json!({ "flushed": flushed }))
+}
+
+fn endpoint_status(endpoint: &Endpoint) -> Value {
+    let listener = match &endpoint.bind_socket {
+        Some(path) => path.display().to_string(),
+        None => format!("{}:{}", endpoint.bind_address, endpoint.bind_port),
+    };
+    json!({
+        "name": endpoint.name,
+        "mode": endpoint.mode,
+        "listener": listener,
+        "listening": server::is_listening(&endpoint.name),
+        "disabled": endpoint.is_disabled(),
+        "maintenance": endpoint.in_maintenance(),
+        "cache": endpoint.cache().is_some(),
+        "targets": endpoint.target_pool().status(),
+    })
+}
+
+fn route(state: &AdminState, method: &str, path: &str) -> (u16, Value) {
+    match (method, path_segments(path).as_slice()) {
+        ("GET", ["config"]) => (200, json!(state.config_status())),
+        ("POST", ["reload"]) => {
+            info!("Admin: configuration reload requested");
+            state.reload.notify_one();
+            // The outcome shows in GET /config
+            (202, json!({ "reload": "requested" }))
+        }
+        ("GET", ["endpoints"]) => {
+            let endpoints: Vec<Value> = state
+                .endpoints()
+                .iter()
+                .map(|endpoint| endpoint_status(endpoint))
+                .collect();
+            (200, json!(endpoints))
+        }
+        ("GET", ["endpoints", name]) => {
+            let endpoint = match state.endpoint(name) {
+                Some(endpoint) => endpoint,
+                None => return (404, json!({ "error": "unknown endpoint" })),
+            };
+            let mut status = endpoint_status(&endpoint);
+            status["counters"] = json!(metrics::counters_with("endpoint", name));
+            status["latency"] = json!(metrics::quantiles_with("endpoint", name));
+            (200, status)
+        }
+        ("POST", ["endpoints", name, action @ ("enable" | "disable")]) => {
+            let endpoint = match state.endpoint(name) {
+                Some(endpoint) => endpoint,
+                None => return (404, json!({ "error": "unknown endpoint" })),
+            };
+            let disabled = *action == "disable";
+            endpoint.set_disabled(disabled);
+            info!("Admin: endpoint '{}' {}d", name, action);
+            (200, json!({ "disabled": disabled }))
+        }
+        ("GET", ["endpoints", name, "cache"]) => {
+            let endpoint = match state.endpoint(name) {
+                Some(endpoint) => endpoint,
+                None => return (404, json!({ "error": "unknown endpoint" })),
+            };
+            match endpoint.cache().map(|cache| cache.entries(CACHE_ENTRY_LIMIT)) {
+                Some(Some(entries)) => (200, json!(entries)),
+                Some(None) => (400, json!({ "error": "cache backend cannot list entries" })),
+                None => (404, json!({ "error": "endpoint has no cache" })),
+            }
+        }
+        ("POST", ["endpoints", name, "maintenance", action]) => {
+            let endpoint = match state.endpoint(name) {
+                Some(endpoint) => endpoint,
+                None => return (404, json!({ "error": "unknown endpoint" })),
+            };
+            let maintenance = match *action {
+                "on" => true,
+                "off" => false,
+                _ => return (404, json!({ "error": "unknown action" })),
+            };
+            endpoint.set_maintenance(maintenance);
+            info!(
+                "Admin: maintenance mode of endpoint '{}' {}",
+                name,
+                if maintenance { "enabled" } else { "disabled" }
+            );
+            (200, json!({ "maintenance": maintenance }))
+        }
+        ("GET", ["targets"]) => {
+            let targets: serde_json::Map<String, Value> = state
+                .endpoints()
+                .iter()
+                .map(|endpoint| {
+                    (
+                        endpoint.name.clone(),
+                        json!(endpoint.target_pool().status()),
+                    )
+                })
+                .collect();
+            (200, Value::Object(targets))
+        }
+        ("POST", ["targets", name, index, action @ ..]) => {
+            let endpoint = match state.endpoint(name) {
+                Some(endpoint) => endpoint,
+                None => return (404, json!({ "error": "unknown endpoint" })),
+            };
+            let index: usize = match index.parse() {
+                Ok(index) => index,
+                Err(_) => return (400, json!({ "error": "invalid target index" })),
+            };
+
+            let pool = endpoint.target_pool();
+            let updated = match action {
+                ["weight", weight] => match weight.parse() {
+                    Ok(weight) => pool.set_weight(index, weight),
+                    Err(_) => return (400, json!({ "error": "invalid weight" })),
+                },
+                ["drain"] => pool.set_draining(index, true),
+                ["ready"] => pool.set_draining(index, false),
+                _ => return (404, json!({ "error": "unknown action" })),
+            };
+
+            if updated {
+                info!("Admin: updated target {} of endpoint '{}'", index, name);
+                (200, json!(pool.status()))
+            } else {
+                (404, json!({ "error": "unknown target" }))
+            }
+        }
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        warn!("Admin write error: {}", e);
+        return Err(e.into());
+    }
+    socket.flush().await?;
+    Ok(())
+}
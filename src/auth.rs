@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use reqwest::header::HeaderName;
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::secret::SecretFile;
+
+/// How upstream requests authenticate; without it the endpoint sends
+/// auth-token as X-Auth-Token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AuthConfig {
+    /// Authorization: Bearer <token>
+    #[serde(rename_all = "kebab-case")]
+    Bearer {
+        #[serde(default)]
+        token: String,
+        #[serde(default)]
+        token_file: Option<PathBuf>,
+    },
+    /// Authorization: Basic with username and password
+    #[serde(rename_all = "kebab-case")]
+    Basic {
+        username: String,
+        #[serde(default)]
+        password: String,
+        #[serde(default)]
+        password_file: Option<PathBuf>,
+    },
+    /// Secret in a custom header, e.g. X-Api-Key
+    #[serde(rename_all = "kebab-case")]
+    Header {
+        name: String,
+        #[serde(default)]
+        value: String,
+        #[serde(default)]
+        value_file: Option<PathBuf>,
+    },
+    /// No credentials at all
+    None,
+}
+
+impl AuthConfig {
+    /// The secret given inline in the config, if any
+    pub fn inline_secret(&self) -> Option<&str> {
+        let secret = match self {
+            AuthConfig::Bearer { token, .. } => token,
+            AuthConfig::Basic { password, .. } => password,
+            AuthConfig::Header { value, .. } => value,
+            AuthConfig::None => return None,
+        };
+        (!secret.is_empty()).then_some(secret.as_str())
+    }
+
+    fn secret_file(&self) -> Option<&Path> {
+        match self {
+            AuthConfig::Bearer { token_file, .. } => token_file.as_deref(),
+            AuthConfig::Basic { password_file, .. } => password_file.as_deref(),
+            AuthConfig::Header { value_file, .. } => value_file.as_deref(),
+            AuthConfig::None => None,
+        }
+    }
+
+    /// Whether the scheme sets the Authorization header
+    pub fn uses_authorization(&self) -> bool {
+        matches!(self, AuthConfig::Bearer { .. } | AuthConfig::Basic { .. })
+    }
+}
+
+/// Initialized auth scheme of an endpoint
+#[derive(Debug)]
+pub struct Credentials {
+    config: AuthConfig,
+    secret_source: Option<SecretFile>,
+    header_name: Option<HeaderName>,
+}
+
+impl Credentials {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        if config.inline_secret().is_some() && config.secret_file().is_some() {
+            anyhow::bail!("auth secret and secret file are mutually exclusive");
+        }
+        let secret_source = match config.secret_file() {
+            Some(path) => Some(SecretFile::open(path)?),
+            None => None,
+        };
+        let header_name = match config {
+            AuthConfig::Header { name, .. } => Some(
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid auth header name '{}'", name))?,
+            ),
+            _ => None,
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            secret_source,
+            header_name,
+        })
+    }
+
+    fn secret(&self) -> String {
+        match &self.secret_source {
+            Some(source) => source.get(),
+            None => self.config.inline_secret().unwrap_or("").to_string(),
+        }
+    }
+
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match (&self.config, &self.header_name) {
+            (AuthConfig::Bearer { .. }, _) => request.bearer_auth(self.secret()),
+            (AuthConfig::Basic { username, .. }, _) => {
+                request.basic_auth(username, Some(self.secret()))
+            }
+            (AuthConfig::Header { .. }, Some(name)) => request.header(name.clone(), self.secret()),
+            _ => request,
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::Endpoint;
+use crate::logging;
+use crate::privacy::sensitive;
+use crate::protocol::post_json;
+
+// Dovecot's requests are a few hundred bytes of JSON
+const MAX_REQUEST_LENGTH: usize = 65536;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthPolicyConfig {
+    /// Header Dovecot must send, as in auth_policy_server_api_header,
+    /// e.g. "Authorization: Basic ..."
+    #[serde(default)]
+    pub api_header: Option<String>,
+    /// Status answered when the REST API fails: 0 lets the login proceed
+    /// (Dovecot's own behaviour), -1 rejects it, n delays it n seconds
+    #[serde(default)]
+    pub on_error: i64,
+}
+
+impl AuthPolicyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(header) = &self.api_header {
+            if !header.contains(':') {
+                anyhow::bail!("auth-policy api-header must be \"Name: value\"");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the request headers carry the configured api-header
+    fn is_authorized(&self, headers: &[(&str, &str)]) -> bool {
+        let Some((name, value)) = self.api_header.as_deref().and_then(|h| h.split_once(':')) else {
+            return true;
+        };
+        headers
+            .iter()
+            .any(|(n, v)| n.eq_ignore_ascii_case(name.trim()) && *v == value.trim())
+    }
+}
+
+/// Answer in Dovecot's format: status < 0 rejects, 0 allows, > 0 delays
+/// the login by that many seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Decision {
+    status: i64,
+    #[serde(default)]
+    msg: String,
+}
+
+/// Request attributes as policy-cache lines, so cache keys can use
+/// {remote}, {login}, {protocol}, ...
+fn cache_request(request: &Map<String, Value>) -> String {
+    request
+        .iter()
+        .map(|(name, value)| match value.as_str() {
+            Some(value) => format!("{}={}\n", name, value),
+            None => format!("{}={}\n", name, value),
+        })
+        .collect()
+}
+
+/// Decide on one request from Dovecot
+async fn decide(
+    endpoint: &Endpoint,
+    command: &str,
+    mut request: Map<String, Value>,
+    user_agent: &str,
+) -> Decision {
+    debug!(
+        "Auth policy {} for login {} from {}",
+        command,
+        sensitive(request.get("login").and_then(Value::as_str).unwrap_or("")),
+        sensitive(request.get("remote").and_then(Value::as_str).unwrap_or(""))
+    );
+
+    // Reports are notifications of finished logins and never cached
+    let cache = endpoint.policy_cache().filter(|_| command == "allow");
+    let cache_key = cache.map(|cache| cache.key(&cache_request(&request)));
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        if let Some(cached) = cache.get(key) {
+            if let Ok(decision) = serde_json::from_str(&cached) {
+                debug!("Auth policy cache hit");
+                return decision;
+            }
+        }
+    }
+
+    request.insert("command".to_string(), json!(command));
+    match post_json::<Decision>(endpoint, &Value::Object(request), user_agent).await {
+        Ok(decision) => {
+            debug!("Auth policy status: {}", decision.status);
+            if let (Some(cache), Some(key)) = (cache, cache_key) {
+                cache.insert(key, json!(decision).to_string());
+            }
+            decision
+        }
+        Err(e) => {
+            warn!("Endpoint '{}': auth policy request failed: {:#}", endpoint.name, e);
+            Decision {
+                status: endpoint.auth_policy.on_error,
+                msg: String::new(),
+            }
+        }
+    }
+}
+
+fn header_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    status: u16,
+    body: &str,
+) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        _ => "Not Found",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+/// Serve one Dovecot connection speaking the auth_policy HTTP exchange
+///
+/// Dovecot POSTs the login attributes as JSON to `?command=allow` before
+/// authentication and to `?command=report` after it. Both are forwarded to
+/// the REST target with the command added, and its `{"status", "msg"}`
+/// answer is passed back. Connections are kept alive.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    endpoint: &Endpoint,
+    user_agent: &str,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+
+    loop {
+        let end = loop {
+            if let Some(end) = header_end(&buffer) {
+                break end;
+            }
+            if buffer.len() > MAX_REQUEST_LENGTH {
+                anyhow::bail!("Auth policy request headers too long");
+            }
+            let mut chunk = [0u8; 4096];
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 {
+                debug!("Client closed connection");
+                return Ok(());
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        };
+
+        let head = String::from_utf8_lossy(&buffer[..end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split_whitespace();
+        let method = request_line.next().unwrap_or("").to_string();
+        let target = request_line.next().unwrap_or("").to_string();
+        let headers: Vec<(&str, &str)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+
+        let length: usize = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(0);
+        if length > MAX_REQUEST_LENGTH {
+            anyhow::bail!("Auth policy request body too long");
+        }
+        let body_start = end + 4;
+        while buffer.len() < body_start + length {
+            let mut chunk = [0u8; 4096];
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 {
+                anyhow::bail!("Connection closed in the middle of a request");
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+        let body: Vec<u8> = buffer.drain(..body_start + length).skip(body_start).collect();
+
+        let query = target.split_once('?').map_or("", |(_, query)| query);
+        let command = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "command")
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default();
+
+        if !endpoint.auth_policy.is_authorized(&headers) {
+            warn!("Endpoint '{}': auth policy request without api-header", endpoint.name);
+            write_response(socket, 401, r#"{"error":"unauthorized"}"#).await?;
+            continue;
+        }
+        if method != "POST" || !matches!(command.as_str(), "allow" | "report") {
+            write_response(socket, 404, r#"{"error":"not found"}"#).await?;
+            continue;
+        }
+        let request = match serde_json::from_slice::<Map<String, Value>>(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Invalid auth policy request: {}", e);
+                write_response(socket, 400, r#"{"error":"invalid JSON"}"#).await?;
+                continue;
+            }
+        };
+
+        let decision = logging::request(decide(endpoint, &command, request, user_agent)).await;
+        write_response(socket, 200, &json!(decision).to_string()).await?;
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::LookupResult;
+
+#[cfg(feature = "ldap")]
+mod ldap;
+#[cfg(feature = "sql")]
+mod sql;
+
+/// Where lookups are answered from; REST is the default
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum BackendConfig {
+    #[default]
+    Rest,
+    Sql(SqlConfig),
+    Ldap(LdapConfig),
+}
+
+/// Lookup parameters that can be bound to a backend query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueryParam {
+    Key,
+    Map,
+}
+
+fn default_query_params() -> Vec<QueryParam> {
+    vec![QueryParam::Key]
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SqlConfig {
+    /// postgres://... or mysql://...
+    pub url: String,
+    /// Parameterized query in the database's placeholder syntax ($1 or ?);
+    /// the first column of every returned row is a result value
+    pub query: String,
+    /// Values bound to the query placeholders, in order
+    #[serde(default = "default_query_params")]
+    pub params: Vec<QueryParam>,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+}
+
+fn default_scope() -> String {
+    "sub".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LdapConfig {
+    /// ldap://... or ldaps://...
+    pub url: String,
+    #[serde(default)]
+    pub bind_dn: Option<String>,
+    #[serde(default)]
+    pub bind_password: Option<String>,
+    pub search_base: String,
+    /// "sub", "one" or "base"
+    #[serde(default = "default_scope")]
+    pub scope: String,
+    /// Filter template: %s key, %u local part, %d domain (values are escaped)
+    pub filter: String,
+    /// Attributes whose values are returned as results
+    pub attributes: Vec<String>,
+}
+
+/// Initialized non-REST backend of an endpoint
+#[derive(Debug)]
+pub enum Backend {
+    #[cfg(feature = "sql")]
+    Sql(sql::SqlBackend),
+    #[cfg(feature = "ldap")]
+    Ldap(ldap::LdapBackend),
+}
+
+impl Backend {
+    /// Returns None for the REST backend, which is handled by the protocol module
+    pub fn new(config: &BackendConfig, endpoint: &str) -> Result<Option<Self>> {
+        match config {
+            BackendConfig::Rest => Ok(None),
+            #[cfg(feature = "sql")]
+            BackendConfig::Sql(sql) => Ok(Some(Backend::Sql(sql::SqlBackend::new(sql)?))),
+            #[cfg(feature = "ldap")]
+            BackendConfig::Ldap(ldap) => Ok(Some(Backend::Ldap(ldap::LdapBackend::new(ldap)?))),
+            #[allow(unreachable_patterns)]
+            _ => anyhow::bail!("Endpoint '{}': built without support for this backend", endpoint),
+        }
+    }
+
+    pub async fn lookup(&self, map: Option<&str>, key: &str) -> LookupResult {
+        match self {
+            #[cfg(feature = "sql")]
+            Backend::Sql(backend) => backend.lookup(map, key).await,
+            #[cfg(feature = "ldap")]
+            Backend::Ldap(backend) => backend.lookup(key).await,
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (map, key);
+                LookupResult::UnknownError
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, Scope, SearchEntry};
+use log::{debug, error, warn};
+use tokio::sync::Mutex;
+
+use super::LdapConfig;
+use crate::privacy::sensitive;
+use crate::protocol::LookupResult;
+
+/// LDAP backend with a shared, lazily (re)established connection
+pub struct LdapBackend {
+    config: LdapConfig,
+    connection: Mutex<Option<Ldap>>,
+}
+
+impl std::fmt::Debug for LdapBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LdapBackend")
+            .field("url", &self.config.url)
+            .field("search_base", &self.config.search_base)
+            .finish()
+    }
+}
+
+/// Expand a Postfix ldap_table style filter: %s key, %u local part, %d domain, %% literal
+fn expand_filter(template: &str, key: &str) -> String {
+    let (local, domain) = match key.rsplit_once('@') {
+        Some((local, domain)) => (local, domain),
+        None => (key, ""),
+    };
+
+    let mut filter = String::with_capacity(template.len() + key.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            filter.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => filter.push_str(&ldap_escape(key)),
+            Some('u') => filter.push_str(&ldap_escape(local)),
+            Some('d') => filter.push_str(&ldap_escape(domain)),
+            Some('%') => filter.push('%'),
+            Some(other) => {
+                filter.push('%');
+                filter.push(other);
+            }
+            None => filter.push('%'),
+        }
+    }
+    filter
+}
+
+impl LdapBackend {
+    pub fn new(config: &LdapConfig) -> Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> Result<Ldap> {
+        let mut connection = self.connection.lock().await;
+        if let Some(ldap) = connection.as_ref() {
+            return Ok(ldap.clone());
+        }
+
+        let (conn, mut ldap) = LdapConnAsync::new(&self.config.url).await?;
+        ldap3::drive!(conn);
+
+        if let Some(bind_dn) = &self.config.bind_dn {
+            let password = self.config.bind_password.as_deref().unwrap_or("");
+            ldap.simple_bind(bind_dn, password).await?.success()?;
+        }
+
+        debug!("Connected to LDAP server {}", self.config.url);
+        *connection = Some(ldap.clone());
+        Ok(ldap)
+    }
+
+    async fn reset(&self) {
+        *self.connection.lock().await = None;
+    }
+
+    pub async fn lookup(&self, key: &str) -> LookupResult {
+        let mut ldap = match self.connect().await {
+            Ok(ldap) => ldap,
+            Err(e) => {
+                error!("LDAP connection failed: {}", e);
+                return LookupResult::ConnectionFailed;
+            }
+        };
+
+        let filter = expand_filter(&self.config.filter, key);
+        debug!("LDAP search base: {}, filter: {}", self.config.search_base, sensitive(&filter));
+
+        let scope = match self.config.scope.as_str() {
+            "base" => Scope::Base,
+            "one" => Scope::OneLevel,
+            _ => Scope::Subtree,
+        };
+
+        let search = ldap
+            .search(&self.config.search_base, scope, &filter, self.config.attributes.clone())
+            .await;
+        let entries = match search.and_then(|result| result.success()) {
+            Ok((entries, _)) => entries,
+            Err(e) => {
+                warn!("LDAP search failed: {}", e);
+                self.reset().await;
+                return LookupResult::ConnectionFailed;
+            }
+        };
+
+        let values: Vec<String> = entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .flat_map(|entry| {
+                self.config
+                    .attributes
+                    .iter()
+                    .filter_map(|attr| entry.attrs.get(attr).cloned())
+                    .flatten()
+                    .collect::<Vec<String>>()
+            })
+            .collect();
+
+        if values.is_empty() {
+            LookupResult::NotFound
+        } else {
+            LookupResult::Found(values)
+        }
+    }
+}
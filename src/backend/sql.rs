@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use log::{debug, error};
+use sqlx::any::{AnyPoolOptions, AnyRow};
+use sqlx::{AnyPool, Row};
+
+use super::{QueryParam, SqlConfig};
+use crate::protocol::LookupResult;
+
+/// Pooled SQL backend running one parameterized query per lookup
+#[derive(Debug)]
+pub struct SqlBackend {
+    pool: AnyPool,
+    query: String,
+    params: Vec<QueryParam>,
+}
+
+impl SqlBackend {
+    pub fn new(config: &SqlConfig) -> Result<Self> {
+        sqlx::any::install_default_drivers();
+
+        // Connect lazily so that startup does not depend on database availability
+        let pool = AnyPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_lazy(&config.url)
+            .context("Invalid SQL backend url")?;
+
+        Ok(Self {
+            pool,
+            query: config.query.clone(),
+            params: config.params.clone(),
+        })
+    }
+
+    pub async fn lookup(&self, map: Option<&str>, key: &str) -> LookupResult {
+        let mut query = sqlx::query(&self.query);
+        for param in &self.params {
+            query = match param {
+                QueryParam::Key => query.bind(key.to_string()),
+                QueryParam::Map => query.bind(map.unwrap_or("").to_string()),
+            };
+        }
+
+        let rows: Vec<AnyRow> = match query.fetch_all(&self.pool).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("SQL query failed: {}", e);
+                return LookupResult::ConnectionFailed;
+            }
+        };
+
+        debug!("SQL query returned {} rows", rows.len());
+
+        let mut values = Vec::with_capacity(rows.len());
+        for row in &rows {
+            match row.try_get::<String, _>(0) {
+                Ok(value) => values.push(value),
+                Err(e) => {
+                    error!("SQL result column is not a string: {}", e);
+                    return LookupResult::ServerError;
+                }
+            }
+        }
+
+        if values.is_empty() {
+            LookupResult::NotFound
+        } else {
+            LookupResult::Found(values)
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock;
+use crate::protocol::LookupResult;
+
+#[cfg(feature = "redis")]
+mod redis_cache;
+mod record;
+
+use record::CacheRecord;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheBackend {
+    #[default]
+    Memory,
+    Redis,
+}
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+fn default_key_prefix() -> String {
+    "postfix-rest-api-connector:".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub backend: CacheBackend,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    pub ttl: u64, // milliseconds
+    /// Redis connection URL, e.g. redis://127.0.0.1:6379/0
+    #[serde(default)]
+    pub redis_url: Option<String>,
+    /// Prefix for shared cache keys (redis backend)
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// Serve entries up to this long past their TTL when the upstream fails
+    #[serde(default)]
+    pub max_stale: Option<u64>, // milliseconds
+}
+
+/// Lookup cache, either in-process or shared between connector instances
+#[derive(Debug)]
+pub enum LookupCache {
+    Memory(MemoryCache),
+    #[cfg(feature = "redis")]
+    Redis(redis_cache::RedisCache),
+}
+
+impl LookupCache {
+    pub fn new(config: &CacheConfig, endpoint: &str) -> Result<Self> {
+        match config.backend {
+            CacheBackend::Memory => Ok(LookupCache::Memory(MemoryCache::new(config))),
+            #[cfg(feature = "redis")]
+            CacheBackend::Redis => Ok(LookupCache::Redis(redis_cache::RedisCache::new(config, endpoint)?)),
+            #[cfg(not(feature = "redis"))]
+            CacheBackend::Redis => {
+                anyhow::bail!("Endpoint '{}': built without redis cache support", endpoint)
+            }
+        }
+    }
+
+    pub async fn get(&self, map: &str, key: &str) -> Option<LookupResult> {
+        match self {
+            LookupCache::Memory(cache) => cache.get(map, key),
+            #[cfg(feature = "redis")]
+            LookupCache::Redis(cache) => cache.get(map, key).await,
+        }
+    }
+
+    /// Fetch an entry even if expired, as long as it is within max-stale
+    pub async fn get_stale(&self, map: &str, key: &str) -> Option<LookupResult> {
+        match self {
+            LookupCache::Memory(cache) => cache.get_stale(map, key),
+            #[cfg(feature = "redis")]
+            LookupCache::Redis(cache) => cache.get_stale(map, key).await,
+        }
+    }
+
+    /// Most recently used entries, None if the backend cannot list its entries
+    pub fn entries(&self, limit: usize) -> Option<Vec<CacheEntryInfo>> {
+        match self {
+            LookupCache::Memory(cache) => Some(cache.entries(limit)),
+            #[cfg(feature = "redis")]
+            LookupCache::Redis(_) => None,
+        }
+    }
+
+    pub async fn insert(&self, map: &str, key: &str, result: LookupResult) {
+        match self {
+            LookupCache::Memory(cache) => cache.insert(map, key, result),
+            #[cfg(feature = "redis")]
+            LookupCache::Redis(cache) => cache.insert(map, key, result).await,
+        }
+    }
+
+    /// Forget an entry, e.g. after the value was changed upstream
+    pub async fn remove(&self, map: &str, key: &str) {
+        match self {
+            LookupCache::Memory(cache) => cache.remove(map, key),
+            #[cfg(feature = "redis")]
+            LookupCache::Redis(cache) => cache.remove(map, key).await,
+        }
+    }
+
+    /// Forget all entries of the endpoint, returns how many there were
+    pub async fn clear(&self) -> Result<usize> {
+        match self {
+            LookupCache::Memory(cache) => Ok(cache.clear()),
+            #[cfg(feature = "redis")]
+            LookupCache::Redis(cache) => cache.clear().await,
+        }
+    }
+}
+
+type CacheKey = (String, String);
+
+/// A cache entry as shown by the admin API
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CacheEntryInfo {
+    pub map: String,
+    pub key: String,
+    pub result: LookupResult,
+    pub age_ms: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    /// Encoded CacheRecord, in the format the shared backends store
+    record: Vec<u8>,
+    last_used: u64,
+}
+
+impl CacheEntry {
+    fn decode(&self) -> Option<CacheRecord> {
+        match CacheRecord::decode(&self.record) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Dropping undecodable cache entry: {:#}", e);
+                None
+            }
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    clock::unix_time().as_millis() as u64
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Recency index: access tick -> key, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+/// In-process LRU cache of lookup results keyed on map+key
+#[derive(Debug)]
+pub struct MemoryCache {
+    max_entries: usize,
+    ttl: Duration,
+    max_stale: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl MemoryCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            max_entries: config.max_entries,
+            ttl: Duration::from_millis(config.ttl),
+            max_stale: Duration::from_millis(config.max_stale.unwrap_or(0)),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn get(&self, map: &str, key: &str) -> Option<LookupResult> {
+        self.lookup(map, key, self.ttl)
+    }
+
+    pub fn get_stale(&self, map: &str, key: &str) -> Option<LookupResult> {
+        self.lookup(map, key, self.ttl + self.max_stale)
+    }
+
+    fn lookup(&self, map: &str, key: &str, max_age: Duration) -> Option<LookupResult> {
+        let key = (map.to_string(), key.to_string());
+        let mut state = self.state.lock().unwrap();
+
+        let record = match state.entries.get(&key) {
+            Some(entry) => entry.decode(),
+            None => return None,
+        };
+        let Some(record) = record else {
+            state.remove(&key);
+            return None;
+        };
+        let age = Duration::from_millis(now_millis().saturating_sub(record.stored));
+
+        // Expired entries are kept around for the stale window
+        if age >= self.ttl + self.max_stale {
+            state.remove(&key);
+            return None;
+        }
+        if age >= max_age {
+            return None;
+        }
+
+        state.touch(&key);
+        Some(record.result)
+    }
+
+    pub fn entries(&self, limit: usize) -> Vec<CacheEntryInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .recency
+            .values()
+            .rev()
+            .filter_map(|key| {
+                let record = state.entries.get(key)?.decode()?;
+                Some(CacheEntryInfo {
+                    age_ms: now_millis().saturating_sub(record.stored),
+                    map: record.map,
+                    key: record.key,
+                    result: record.result,
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+
+    pub fn remove(&self, map: &str, key: &str) {
+        let key = (map.to_string(), key.to_string());
+        self.state.lock().unwrap().remove(&key);
+    }
+
+    pub fn clear(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = state.entries.len();
+        *state = CacheState::default();
+        count
+    }
+
+    pub fn insert(&self, map: &str, key: &str, result: LookupResult) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let stored = now_millis();
+        let record = CacheRecord {
+            map: map.to_string(),
+            key: key.to_string(),
+            result,
+            stored,
+            expires: stored + self.ttl.as_millis() as u64,
+            etag: None,
+        }
+        .encode();
+
+        let key = (map.to_string(), key.to_string());
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+
+        // Evict least recently used entries until there is room
+        while state.entries.len() >= self.max_entries {
+            match state.recency.pop_first() {
+                Some((_, oldest)) => {
+                    state.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.recency.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                record,
+                last_used: tick,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl: u64, max_stale: Option<u64>) -> MemoryCache {
+        MemoryCache::new(&CacheConfig {
+            backend: CacheBackend::Memory,
+            max_entries: 10,
+            ttl,
+            redis_url: None,
+            key_prefix: default_key_prefix(),
+            max_stale,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire_after_ttl() {
+        let cache = cache(1000, None);
+        cache.insert("virtual", "user@example.com", LookupResult::NotFound);
+
+        tokio::time::advance(Duration::from_millis(999)).await;
+        assert!(cache.get("virtual", "user@example.com").is_some());
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(cache.get("virtual", "user@example.com").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_entries_are_served_until_max_stale() {
+        let cache = cache(1000, Some(500));
+        cache.insert("virtual", "user@example.com", LookupResult::NotFound);
+
+        tokio::time::advance(Duration::from_millis(1200)).await;
+        assert!(cache.get("virtual", "user@example.com").is_none());
+        assert!(cache.get_stale("virtual", "user@example.com").is_some());
+
+        tokio::time::advance(Duration::from_millis(300)).await;
+        assert!(cache.get_stale("virtual", "user@example.com").is_none());
+        // Dropped once past the stale window
+        assert!(cache.entries(10).is_empty());
+    }
+}
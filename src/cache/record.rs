@@ -0,0 +1,292 @@
+//! Versioned binary format for cache entries, used by the in-process and
+//! Redis backends alike
+//!
+//! Layout (integers are LEB128 varints, strings are length-prefixed UTF-8):
+//!
+//! ```text
+//! u8      format version (major)
+//! u8      result class
+//! varint  stored, milliseconds since the Unix epoch
+//! varint  expires, milliseconds since the Unix epoch
+//! string  map
+//! string  key
+//! varint  number of values, followed by the values (status, tcp code,
+//!         socketmap reply and reason for status-mapped results)
+//! u8      1 if an etag follows, 0 otherwise
+//! string  etag
+//! ...     fields added later
+//! ```
+//!
+//! Fields are only ever appended and decoders ignore trailing bytes, so older
+//! versions can still read entries written by newer ones. Incompatible changes
+//! bump the format version; entries with an unknown version are cache misses.
+
+use anyhow::{Context, Result};
+
+use crate::protocol::LookupResult;
+use crate::response::SocketmapReply;
+
+const FORMAT_VERSION: u8 = 1;
+
+/// A cached lookup result with its metadata
+#[derive(Debug, Clone)]
+pub struct CacheRecord {
+    pub map: String,
+    pub key: String,
+    pub result: LookupResult,
+    pub stored: u64,  // milliseconds since the Unix epoch
+    pub expires: u64, // milliseconds since the Unix epoch
+    pub etag: Option<String>,
+}
+
+// Stable on-disk codes, never reuse a retired one
+fn result_class(result: &LookupResult) -> u8 {
+    match result {
+        LookupResult::Found(_) => 1,
+        LookupResult::Empty => 2,
+        LookupResult::NotFound => 3,
+        LookupResult::InvalidJson => 4,
+        LookupResult::ClientError => 5,
+        LookupResult::ServerError => 6,
+        LookupResult::UnknownError => 7,
+        LookupResult::ConnectionFailed => 8,
+        LookupResult::Timeout => 9,
+        LookupResult::Overloaded => 10,
+        LookupResult::CircuitOpen => 11,
+        LookupResult::Maintenance => 12,
+        LookupResult::Mapped { .. } => 13,
+    }
+}
+
+fn result_from_class(class: u8, values: Vec<String>) -> Result<LookupResult> {
+    Ok(match class {
+        1 => LookupResult::Found(values),
+        2 => LookupResult::Empty,
+        3 => LookupResult::NotFound,
+        4 => LookupResult::InvalidJson,
+        5 => LookupResult::ClientError,
+        6 => LookupResult::ServerError,
+        7 => LookupResult::UnknownError,
+        8 => LookupResult::ConnectionFailed,
+        9 => LookupResult::Timeout,
+        10 => LookupResult::Overloaded,
+        11 => LookupResult::CircuitOpen,
+        12 => LookupResult::Maintenance,
+        13 => match <[String; 4]>::try_from(values) {
+            Ok([status, tcp, socketmap, reason]) => LookupResult::Mapped {
+                status: status.parse()?,
+                tcp: tcp.parse()?,
+                socketmap: SocketmapReply::from_code(&socketmap)
+                    .with_context(|| format!("unknown socketmap reply {}", socketmap))?,
+                reason,
+            },
+            Err(_) => anyhow::bail!("malformed status-mapped entry"),
+        },
+        _ => anyhow::bail!("unknown result class {}", class),
+    })
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let (&byte, rest) = self.input.split_first().context("truncated cache entry")?;
+        self.input = rest;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("varint too long")
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = usize::try_from(self.varint()?)?;
+        if len > self.input.len() {
+            anyhow::bail!("truncated cache entry");
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+}
+
+impl CacheRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.push(FORMAT_VERSION);
+        out.push(result_class(&self.result));
+        put_varint(&mut out, self.stored);
+        put_varint(&mut out, self.expires);
+        put_str(&mut out, &self.map);
+        put_str(&mut out, &self.key);
+
+        // Status-mapped results keep their reply in the value list
+        let mapped;
+        let values: &[String] = match &self.result {
+            LookupResult::Found(values) => values,
+            LookupResult::Mapped {
+                status,
+                tcp,
+                socketmap,
+                reason,
+            } => {
+                mapped = [
+                    status.to_string(),
+                    tcp.to_string(),
+                    socketmap.code().to_string(),
+                    reason.clone(),
+                ];
+                &mapped
+            }
+            _ => &[],
+        };
+        put_varint(&mut out, values.len() as u64);
+        for value in values {
+            put_str(&mut out, value);
+        }
+
+        match &self.etag {
+            Some(etag) => {
+                out.push(1);
+                put_str(&mut out, etag);
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self> {
+        let mut reader = Reader { input };
+
+        let version = reader.byte()?;
+        if version != FORMAT_VERSION {
+            anyhow::bail!("unsupported cache entry format version {}", version);
+        }
+        let class = reader.byte()?;
+        let stored = reader.varint()?;
+        let expires = reader.varint()?;
+        let map = reader.string()?;
+        let key = reader.string()?;
+
+        let count = reader.varint()?;
+        let mut values = Vec::new();
+        for _ in 0..count {
+            values.push(reader.string()?);
+        }
+
+        let etag = match reader.byte()? {
+            0 => None,
+            _ => Some(reader.string()?),
+        };
+
+        // Anything after this was appended by a newer version
+        Ok(Self {
+            map,
+            key,
+            result: result_from_class(class, values)?,
+            stored,
+            expires,
+            etag,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(result: LookupResult, etag: Option<&str>) -> CacheRecord {
+        CacheRecord {
+            map: "virtual".to_string(),
+            key: "user@example.com".to_string(),
+            result,
+            stored: 1_700_000_000_000,
+            expires: 1_700_000_060_000,
+            etag: etag.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let found = LookupResult::Found(vec!["a@example.org".to_string(), "".to_string()]);
+        let decoded = CacheRecord::decode(&record(found, Some("\"v1\"")).encode()).unwrap();
+        assert_eq!(decoded.map, "virtual");
+        assert_eq!(decoded.key, "user@example.com");
+        assert_eq!(decoded.stored, 1_700_000_000_000);
+        assert_eq!(decoded.expires, 1_700_000_060_000);
+        assert_eq!(decoded.etag.as_deref(), Some("\"v1\""));
+        assert!(matches!(
+            decoded.result,
+            LookupResult::Found(values) if values == ["a@example.org", ""]
+        ));
+
+        let decoded = CacheRecord::decode(&record(LookupResult::NotFound, None).encode()).unwrap();
+        assert!(matches!(decoded.result, LookupResult::NotFound));
+        assert_eq!(decoded.etag, None);
+    }
+
+    #[test]
+    fn round_trip_status_mapped() {
+        let mapped = LookupResult::Mapped {
+            status: 429,
+            tcp: 400,
+            socketmap: SocketmapReply::Temp,
+            reason: "rate limited".to_string(),
+        };
+        let decoded = CacheRecord::decode(&record(mapped, None).encode()).unwrap();
+        assert!(matches!(
+            decoded.result,
+            LookupResult::Mapped { status: 429, tcp: 400, socketmap: SocketmapReply::Temp, reason }
+                if reason == "rate limited"
+        ));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut encoded = record(LookupResult::Empty, None).encode();
+        encoded[0] = FORMAT_VERSION + 1;
+        let error = CacheRecord::decode(&encoded).unwrap_err();
+        assert!(error.to_string().contains("format version"));
+    }
+
+    #[test]
+    fn fields_appended_by_newer_versions_are_ignored() {
+        let mut encoded = record(LookupResult::Empty, Some("etag")).encode();
+        put_str(&mut encoded, "a field added later");
+        put_varint(&mut encoded, 42);
+        let decoded = CacheRecord::decode(&encoded).unwrap();
+        assert!(matches!(decoded.result, LookupResult::Empty));
+        assert_eq!(decoded.etag.as_deref(), Some("etag"));
+    }
+
+    #[test]
+    fn truncated_entries_are_rejected() {
+        let encoded = record(LookupResult::Found(vec!["value".to_string()]), None).encode();
+        for len in 0..encoded.len() {
+            assert!(CacheRecord::decode(&encoded[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use tokio::sync::OnceCell;
+
+use super::record::CacheRecord;
+use super::CacheConfig;
+use crate::clock;
+use crate::protocol::LookupResult;
+
+fn now_millis() -> u64 {
+    clock::unix_time().as_millis() as u64
+}
+
+/// Redis-backed lookup cache shared by all connector instances
+pub struct RedisCache {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    prefix: String,
+    ttl: u64,       // milliseconds
+    max_stale: u64, // milliseconds
+}
+
+impl std::fmt::Debug for RedisCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCache")
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl RedisCache {
+    pub fn new(config: &CacheConfig, endpoint: &str) -> Result<Self> {
+        let url = config
+            .redis_url
+            .as_deref()
+            .with_context(|| format!("Endpoint '{}': redis cache requires redis-url", endpoint))?;
+        let client = redis::Client::open(url)
+            .with_context(|| format!("Endpoint '{}': invalid redis-url", endpoint))?;
+
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            prefix: format!("{}{}:", config.key_prefix, endpoint),
+            ttl: config.ttl,
+            max_stale: config.max_stale.unwrap_or(0),
+        })
+    }
+
+    /// Connect lazily so that startup does not depend on Redis availability
+    async fn connection(&self) -> Option<ConnectionManager> {
+        let result = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await;
+
+        match result {
+            Ok(connection) => Some(connection.clone()),
+            Err(e) => {
+                warn!("Redis connection failed: {}", e);
+                None
+            }
+        }
+    }
+
+    fn cache_key(&self, map: &str, key: &str) -> String {
+        format!("{}{}:{}", self.prefix, map, key)
+    }
+
+    pub async fn get(&self, map: &str, key: &str) -> Option<LookupResult> {
+        self.lookup(map, key, self.ttl).await
+    }
+
+    pub async fn get_stale(&self, map: &str, key: &str) -> Option<LookupResult> {
+        self.lookup(map, key, self.ttl + self.max_stale).await
+    }
+
+    async fn lookup(&self, map: &str, key: &str, max_age: u64) -> Option<LookupResult> {
+        let mut connection = self.connection().await?;
+        let value: Option<Vec<u8>> = match connection.get(self.cache_key(map, key)).await {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Redis GET failed: {}", e);
+                return None;
+            }
+        };
+
+        // The stored timestamp lets expired entries be served while stale
+        match CacheRecord::decode(&value?) {
+            Ok(record) if now_millis().saturating_sub(record.stored) < max_age => {
+                Some(record.result)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                // Expected for entries written by other format versions
+                debug!("Ignoring undecodable redis cache entry: {:#}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, map: &str, key: &str, result: LookupResult) {
+        let stored = now_millis();
+        let value = CacheRecord {
+            map: map.to_string(),
+            key: key.to_string(),
+            result,
+            stored,
+            expires: stored + self.ttl,
+            etag: None,
+        }
+        .encode();
+
+        if let Some(mut connection) = self.connection().await {
+            let stored: redis::RedisResult<()> = connection
+                .pset_ex(self.cache_key(map, key), value, self.ttl + self.max_stale)
+                .await;
+            if let Err(e) = stored {
+                warn!("Redis PSETEX failed: {}", e);
+            }
+        }
+    }
+
+    pub async fn remove(&self, map: &str, key: &str) {
+        if let Some(mut connection) = self.connection().await {
+            let removed: redis::RedisResult<()> = connection.del(self.cache_key(map, key)).await;
+            if let Err(e) = removed {
+                warn!("Redis DEL failed: {}", e);
+            }
+        }
+    }
+
+    /// Delete every entry under this endpoint's prefix, shared by all instances
+    pub async fn clear(&self) -> Result<usize> {
+        let mut connection = self.connection().await.context("Redis unavailable")?;
+        let pattern = format!("{}*", self.prefix);
+        let mut cursor: u64 = 0;
+        let mut deleted = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(500)
+                .query_async(&mut connection)
+                .await?;
+            if !keys.is_empty() {
+                let _: () = connection.del(&keys).await?;
+                deleted += keys.len();
+            }
+            if next == 0 {
+                return Ok(deleted);
+            }
+            cursor = next;
+        }
+    }
+}
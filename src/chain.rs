@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::localmap::LocalMap;
+
+/// One source of a map chain, consulted in order until one has the key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ChainStep {
+    /// REST target, queried with the endpoint's client and credentials
+    Rest { target: String },
+    /// Local "key value" file, like static-map
+    Map { path: PathBuf },
+}
+
+/// Initialized chain step
+#[derive(Debug, Clone)]
+pub enum ChainSource {
+    Rest(String),
+    Map(Arc<LocalMap>),
+}
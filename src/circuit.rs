@@ -0,0 +1,200 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::Instant;
+
+fn default_half_open_probes() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CircuitBreakerConfig {
+    /// Consecutive upstream failures that open the circuit
+    pub failure_threshold: u32,
+    pub open_duration: u64, // milliseconds
+    /// Trial requests let through after open-duration; all must succeed to close
+    #[serde(default = "default_half_open_probes")]
+    pub half_open_probes: u32,
+}
+
+#[derive(Debug)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant, in_flight: u32, successes: u32 },
+}
+
+/// Fails fast while the upstream is known to be down
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    half_open_probes: u32,
+    state: Mutex<CircuitState>,
+    // First opening since the circuit was last closed, kept across half-open probes
+    unhealthy_since: Mutex<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            failure_threshold: config.failure_threshold.max(1),
+            open_duration: Duration::from_millis(config.open_duration),
+            half_open_probes: config.half_open_probes.max(1),
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+            unhealthy_since: Mutex::new(None),
+        }
+    }
+
+    /// How long the circuit has been open or half-open without closing, None while closed
+    pub fn unhealthy_for(&self) -> Option<Duration> {
+        self.unhealthy_since.lock().unwrap().map(|since| since.elapsed())
+    }
+
+    /// Whether a request may be sent upstream now
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        if let CircuitState::Open { until } = *state {
+            if now < until {
+                return false;
+            }
+            info!("Endpoint '{}': circuit half-open, probing upstream", self.name);
+            *state = CircuitState::HalfOpen { since: now, in_flight: 0, successes: 0 };
+        }
+
+        match &mut *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { .. } => false,
+            CircuitState::HalfOpen { since, in_flight, successes } => {
+                // Probes that never reported back must not wedge the circuit
+                if now.duration_since(*since) >= self.open_duration {
+                    *since = now;
+                    *in_flight = *successes;
+                }
+                if *in_flight < self.half_open_probes {
+                    *in_flight += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Report the outcome of a request that was allowed through
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        let next = match &mut *state {
+            CircuitState::Closed { failures } => {
+                if success {
+                    *failures = 0;
+                    None
+                } else {
+                    *failures += 1;
+                    (*failures >= self.failure_threshold).then_some(false)
+                }
+            }
+            CircuitState::Open { .. } => None,
+            CircuitState::HalfOpen { successes, .. } => {
+                if success {
+                    *successes += 1;
+                    (*successes >= self.half_open_probes).then_some(true)
+                } else {
+                    Some(false)
+                }
+            }
+        };
+
+        match next {
+            Some(true) => {
+                info!("Endpoint '{}': circuit closed, upstream recovered", self.name);
+                *state = CircuitState::Closed { failures: 0 };
+                *self.unhealthy_since.lock().unwrap() = None;
+            }
+            Some(false) => {
+                warn!(
+                    "Endpoint '{}': circuit open for {:?} after upstream failures",
+                    self.name, self.open_duration
+                );
+                *state = CircuitState::Open { until: Instant::now() + self.open_duration };
+                self.unhealthy_since.lock().unwrap().get_or_insert_with(Instant::now);
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(half_open_probes: u32) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            &CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_duration: 1000,
+                half_open_probes,
+            },
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_threshold_and_probes_after_open_duration() {
+        let circuit = breaker(1);
+        circuit.record(false);
+        assert!(circuit.allow());
+        circuit.record(false);
+        assert!(!circuit.allow());
+
+        tokio::time::advance(Duration::from_millis(999)).await;
+        assert!(!circuit.allow());
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(circuit.allow());
+        // One probe at a time
+        assert!(!circuit.allow());
+
+        circuit.record(true);
+        assert!(circuit.allow());
+        assert!(circuit.unhealthy_for().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_reopens() {
+        let circuit = breaker(1);
+        circuit.record(false);
+        circuit.record(false);
+
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        assert!(circuit.allow());
+        circuit.record(false);
+        assert!(!circuit.allow());
+        assert_eq!(circuit.unhealthy_for(), Some(Duration::from_millis(1000)));
+
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        assert!(circuit.allow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_probes_are_replaced_after_open_duration() {
+        let circuit = breaker(1);
+        circuit.record(false);
+        circuit.record(false);
+
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        assert!(circuit.allow());
+        assert!(!circuit.allow());
+
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        assert!(circuit.allow());
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::{Context, Result};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+
+use crate::config::{Config, Endpoint, EndpointMode};
+use crate::lint;
+use crate::protocol::{self, encode_netstring, UpstreamTrace};
+use crate::response::SocketmapDialect;
+
+const BUFFER_SIZE: usize = 65536;
+
+pub const LOOKUP_USAGE: &str = "lookup [--daemon] <config-file> <endpoint> <key> [map-name]";
+
+pub const CHECK_USAGE: &str = "check [--strict] <config-file>";
+
+pub const OPENSMTPD_TABLE_USAGE: &str = "opensmtpd-table <config-file> <endpoint>";
+
+/// `check` subcommand: validate a configuration and warn about unwise settings
+///
+/// Errors fail the check; warnings only fail it with `--strict`.
+pub fn check(args: &[String]) -> Result<()> {
+    let strict = args.first().map(String::as_str) == Some("--strict");
+    let args = if strict { &args[1..] } else { args };
+    let path = match args {
+        [path] => path,
+        _ => anyhow::bail!("Usage: {}", CHECK_USAGE),
+    };
+
+    let config = Config::from_file(path)?;
+    for endpoint in &config.endpoints {
+        check_build(endpoint)?;
+    }
+
+    let warnings = lint::lint(&config);
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    println!(
+        "{}: {} endpoints, {} warnings",
+        path,
+        config.endpoints.len(),
+        warnings.len()
+    );
+
+    if strict && !warnings.is_empty() {
+        anyhow::bail!("Configuration has warnings (--strict)");
+    }
+    Ok(())
+}
+
+/// Build an endpoint's clients without starting it, catching what only shows
+/// then (headers, TLS files, ...)
+pub fn check_build(endpoint: &Endpoint) -> Result<()> {
+    endpoint
+        .clone()
+        .with_client()?
+        .with_auth_token_file()?
+        .with_auth()?
+        .with_oauth2()?
+        .with_signing()?
+        .with_sigv4()?
+        .with_standby()?
+        .with_key_rewrite()?
+        .with_local_maps()?
+        .with_map_routes()?
+        .with_backend()?
+        .with_cache()?
+        .with_greylist()?
+        .with_geoip()?;
+    Ok(())
+}
+
+/// `lookup` subcommand: run a single lookup and print the raw Postfix response
+///
+/// By default the lookup goes directly through the protocol handlers (without
+/// the cache) and the upstream HTTP exchange is printed as well. With
+/// `--daemon` the request is sent to the running daemon's listener instead.
+pub async fn lookup(args: &[String]) -> Result<()> {
+    let daemon = args.first().map(String::as_str) == Some("--daemon");
+    let args = if daemon { &args[1..] } else { args };
+    let (path, name, key, mapname) = match args {
+        [path, name, key] => (path, name, key, None),
+        [path, name, key, mapname] => (path, name, key, Some(mapname.as_str())),
+        _ => anyhow::bail!("Usage: {}", LOOKUP_USAGE),
+    };
+
+    let config = Config::from_file(path)?;
+    let endpoint = config
+        .endpoints
+        .iter()
+        .find(|endpoint| endpoint.name == *name)
+        .with_context(|| format!("Unknown endpoint '{}'", name))?
+        .clone();
+
+    let (mode, request) = lookup_request(&endpoint, key, mapname)?;
+    println!("Request:  {:?}", request);
+
+    if daemon {
+        let response = query_daemon(&endpoint, &request).await?;
+        println!("Response: {:?}", response);
+        return Ok(());
+    }
+
+    let endpoint = endpoint
+        .with_client()?
+        .with_auth_token_file()?
+        .with_auth()?
+        .with_oauth2()?
+        .with_signing()?
+        .with_sigv4()?
+        .with_key_rewrite()?
+        .with_local_maps()?
+        .with_map_routes()?
+        .with_backend()?
+        .with_targets()
+        .with_maintenance();
+    let (response, traces) = run_lookup(&endpoint, &config.user_agent, mode, &request).await;
+    println!("Response: {:?}", response?);
+
+    if traces.is_empty() {
+        println!("Upstream: no REST request made");
+    }
+    for trace in traces {
+        println!("Upstream: GET {}", trace.url);
+        if let Some(status) = trace.status {
+            println!("  status:   {}", status);
+        }
+        if let Some(error) = trace.error {
+            println!("  error:    {}", error);
+        }
+        println!("  duration: {} ms", trace.duration_ms);
+        if let Some(body) = trace.body {
+            println!("  body:     {}", body);
+        }
+    }
+
+    Ok(())
+}
+
+/// `opensmtpd-table` subcommand: serve an opensmtpd-table endpoint on
+/// stdin/stdout, for `table <name> proc:<command>` in smtpd.conf
+///
+/// smtpd starts one process per table; logs go to stderr.
+pub async fn opensmtpd_table(args: &[String]) -> Result<()> {
+    let (path, name) = match args {
+        [path, name] => (path, name),
+        _ => anyhow::bail!("Usage: {}", OPENSMTPD_TABLE_USAGE),
+    };
+
+    let config = Config::from_file(path)?;
+    let endpoint = config
+        .endpoints
+        .iter()
+        .find(|endpoint| endpoint.name == *name)
+        .with_context(|| format!("Unknown endpoint '{}'", name))?
+        .clone();
+    if endpoint.mode != EndpointMode::OpensmtpdTable {
+        anyhow::bail!("Endpoint '{}' is not an opensmtpd-table endpoint", name);
+    }
+
+    let endpoint = endpoint
+        .with_client()?
+        .with_auth_token_file()?
+        .with_auth()?
+        .with_oauth2()?
+        .with_signing()?
+        .with_sigv4()?
+        .with_standby()?
+        .with_key_rewrite()?
+        .with_local_maps()?
+        .with_map_routes()?
+        .with_backend()?
+        .with_cache()?
+        .with_circuit_breaker()
+        .with_targets()
+        .with_maintenance();
+
+    let mut session = protocol::TableSession::default();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    // smtpd closes stdin when it exits or reloads the table
+    while let Some(line) = lines.next_line().await? {
+        let response =
+            protocol::handle_opensmtpd_table(&endpoint, &line, &mut session, &config.user_agent)
+                .await?;
+        stdout.write_all(response.as_bytes()).await?;
+        stdout.flush().await?;
+    }
+    Ok(())
+}
+
+/// Build the Postfix-format request for a test lookup on an endpoint
+pub fn lookup_request(
+    endpoint: &Endpoint,
+    key: &str,
+    mapname: Option<&str>,
+) -> Result<(EndpointMode, String)> {
+    // Multiplex endpoints speak socketmap when a map name is given
+    let mode = match (&endpoint.mode, mapname) {
+        (EndpointMode::Policy | EndpointMode::Greylist | EndpointMode::Quota, _) => {
+            anyhow::bail!("Endpoint '{}' is a policy endpoint", endpoint.name)
+        }
+        (EndpointMode::Milter, _) => {
+            anyhow::bail!("Endpoint '{}' is a milter endpoint", endpoint.name)
+        }
+        (EndpointMode::AuthPolicy, _) => {
+            anyhow::bail!("Endpoint '{}' is an auth-policy endpoint", endpoint.name)
+        }
+        (EndpointMode::Multiplex, Some(_)) => EndpointMode::SocketmapLookup,
+        (EndpointMode::Multiplex, None) => EndpointMode::TcpLookup,
+        (mode, _) => mode.clone(),
+    };
+    let request = match (&mode, mapname) {
+        (EndpointMode::SocketmapLookup, Some(mapname)) => {
+            encode_netstring(&format!("{} {}", mapname, key))
+        }
+        // sendmail may send the key without a map name
+        (EndpointMode::SocketmapLookup, None)
+            if endpoint.dialect == SocketmapDialect::Sendmail =>
+        {
+            encode_netstring(key)
+        }
+        (EndpointMode::SocketmapLookup, None) => {
+            anyhow::bail!("Socketmap lookups need a map name")
+        }
+        (EndpointMode::Memcache, _) => format!("get {}\r\n", key),
+        (EndpointMode::DovecotDict, mapname) => {
+            format!("H3\t2\t0\t\t{}\nLshared/{}\n", mapname.unwrap_or(""), key)
+        }
+        (EndpointMode::OpensmtpdTable, mapname) => format!(
+            "table|0.1|0|{}|lookup|string|1|{}\n",
+            mapname.unwrap_or(""),
+            key
+        ),
+        _ => format!("get {}\n", key),
+    };
+    Ok((mode, request))
+}
+
+/// Run a request through the protocol handler, collecting the upstream HTTP exchanges
+pub async fn run_lookup(
+    endpoint: &Endpoint,
+    user_agent: &str,
+    mode: EndpointMode,
+    request: &str,
+) -> (Result<String>, Vec<UpstreamTrace>) {
+    protocol::traced(async {
+        match mode {
+            EndpointMode::SocketmapLookup => {
+                protocol::handle_socketmap_lookup(endpoint, request.as_bytes(), user_agent).await
+            }
+            EndpointMode::Memcache => protocol::handle_memcache(endpoint, request, user_agent).await,
+            EndpointMode::DovecotDict => {
+                let mut session = protocol::DictSession::default();
+                protocol::handle_dovecot_dict(endpoint, request, &mut session, user_agent).await
+            }
+            EndpointMode::OpensmtpdTable => {
+                let mut session = protocol::TableSession::default();
+                protocol::handle_opensmtpd_table(endpoint, request, &mut session, user_agent).await
+            }
+            _ => protocol::handle_tcp_lookup(endpoint, request, user_agent).await,
+        }
+    })
+    .await
+}
+
+/// Connection to an endpoint's listener, TCP or Unix socket
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Connect to the running daemon's listener of an endpoint
+pub async fn connect(endpoint: &Endpoint) -> Result<Box<dyn Connection>> {
+    match &endpoint.bind_socket {
+        Some(path) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("Failed to connect to {}", path.display()))?;
+            Ok(Box::new(stream))
+        }
+        None => {
+            // A wildcard bind address is reachable on loopback
+            let host = match endpoint.bind_address.as_str() {
+                "" | "0.0.0.0" => "127.0.0.1",
+                "::" | "[::]" => "[::1]",
+                address => address,
+            };
+            let addr = format!("{}:{}", host, endpoint.bind_port);
+            let stream = TcpStream::connect(&addr)
+                .await
+                .with_context(|| format!("Failed to connect to {}", addr))?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+/// Send one request to the endpoint's listener and return the response
+async fn query_daemon(endpoint: &Endpoint, request: &str) -> Result<String> {
+    // Allow for the daemon's own upstream timeout
+    let timeout = endpoint.timeout() + Duration::from_secs(1);
+
+    let exchange = async { exchange(connect(endpoint).await?, request).await };
+
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .context("Timed out waiting for the daemon")?
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &str,
+) -> Result<String> {
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let n = stream.read(&mut buffer).await?;
+    if n == 0 {
+        anyhow::bail!("Connection closed without a response");
+    }
+    Ok(String::from_utf8_lossy(&buffer[..n]).into_owned())
+}
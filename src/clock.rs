@@ -0,0 +1,31 @@
+//! Monotonic clock for time-based behaviour: cache expiry, circuit breaker,
+//! SLO windows, token and secret refresh, panic and alert rate limits
+//!
+//! This is tokio's clock. In a runtime with paused time (`tokio::time::pause()`,
+//! available with the `test-util` feature) it only advances when the runtime
+//! is idle or `tokio::time::advance()` is called, so all of the above can be
+//! exercised deterministically instead of with real sleeps.
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use tokio::time::Instant;
+
+/// Time since the Unix epoch, for timestamps shared with other processes or
+/// persisted: greylist triplets, shared cache entries, request signatures
+///
+/// Taken from the system clock once and advanced with `Instant` from then
+/// on, so paused time moves it too.
+pub fn unix_time() -> Duration {
+    static BASE: OnceLock<(Instant, Duration)> = OnceLock::new();
+    let (instant, since_epoch) = BASE.get_or_init(|| {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        (Instant::now(), since_epoch)
+    });
+    // Not elapsed(), which saturates: a paused runtime may be behind the base
+    let now = Instant::now();
+    match now.checked_duration_since(*instant) {
+        Some(elapsed) => *since_epoch + elapsed,
+        None => since_epoch.saturating_sub(instant.duration_since(now)),
+    }
+}
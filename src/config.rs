@@ -1,16 +1,104 @@
 use anyhow::{Context, Result};
+use log::{info, warn, LevelFilter};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::Semaphore;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::access_log::AccessLogConfig;
+use crate::action;
+use crate::auth::{AuthConfig, Credentials};
+use crate::authpolicy::AuthPolicyConfig;
+use crate::backend::{Backend, BackendConfig};
+use crate::cache::{CacheConfig, LookupCache};
+use crate::chain::{ChainSource, ChainStep};
+use crate::cidr::Cidr;
+use crate::circuit::{CircuitBreaker, CircuitBreakerConfig};
+use crate::dnsbl::{Dnsbl, DnsblConfig};
+use crate::events::{EventSender, EventsConfig};
+use crate::geoip::{GeoIp, GeoIpConfig};
+use crate::greylist::{Greylist, GreylistConfig};
+use crate::health::HealthConfig;
+use crate::keys::{self, KeyNormalization, KeyRewriteRule, KeyRewriter};
+use crate::localmap::LocalMap;
+use crate::metrics;
+use crate::milter::MilterConfig;
+use crate::oauth::{OAuth2Client, OAuth2Config};
+use crate::policy_cache::{PolicyCache, PolicyCacheConfig};
+use crate::logging::LoggingConfig;
+use crate::privacy::{self, PrivacyConfig};
+use crate::telemetry::TelemetryConfig;
+use crate::proxy::ProxyConfig;
+use crate::qos::{Qos, QosConfig};
+use crate::quota::{Quota, QuotaConfig};
+use crate::ratelimit::{RateLimitConfig, RateLimiter};
+use crate::response::{
+    CoercionConfig, PolicyFallbackConfig, ResponseFormat, SingleValue, SocketmapDialect,
+    SocketmapReplyConfig, StatusMapping,
+};
+use crate::retry::RetryConfig;
+use crate::secret::SecretFile;
+use crate::signing::{RequestSigner, SigningConfig};
+use crate::sigv4::{SigV4Config, SigV4Signer};
+use crate::slo::{SloConfig, SloTracker};
+use crate::sockopt::TcpOptions;
+use crate::targets::{TargetConfig, TargetPool};
+use crate::tls::{self, TlsConfig};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum EndpointMode {
     TcpLookup,
     SocketmapLookup,
     Policy,
+    /// Auto-detect tcp_table, socketmap or policy framing on a single port
+    Multiplex,
+    /// memcache text protocol, as spoken by Postfix memcache_table
+    Memcache,
+    /// Policy delegation answered by the built-in greylisting engine
+    Greylist,
+    /// Policy delegation answered from per-user quota counters, reconciled
+    /// with a REST quota API
+    Quota,
+    /// Dovecot dict proxy protocol (passdb/userdb/quota lookups)
+    DovecotDict,
+    /// Milter protocol, callbacks forwarded to a REST webhook
+    Milter,
+    /// OpenSMTPD table protocol (proc tables)
+    OpensmtpdTable,
+    /// Dovecot auth_policy HTTP JSON exchange, forwarded to a REST API
+    AuthPolicy,
+}
+
+fn default_allowed_modes() -> Vec<EndpointMode> {
+    vec![
+        EndpointMode::TcpLookup,
+        EndpointMode::SocketmapLookup,
+        EndpointMode::Policy,
+    ]
+}
+
+fn default_value_separator() -> String {
+    ",".to_string()
+}
+
+fn default_request_id_header() -> String {
+    "X-Request-ID".to_string()
+}
+
+fn default_idle_timeout() -> u64 {
+    300000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,29 +106,369 @@ pub enum EndpointMode {
 pub struct Endpoint {
     pub name: String,
     pub mode: EndpointMode,
+    #[serde(default)]
     pub target: String,
+    /// Weighted backend replicas, used instead of `target` when present
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    #[serde(default)]
     pub bind_address: String,
+    #[serde(default)]
     pub bind_port: u16,
+    /// Unix domain socket path, used instead of bind-address/bind-port
+    #[serde(default)]
+    pub bind_socket: Option<PathBuf>,
+    /// Octal permissions for bind-socket, e.g. "0660"
+    #[serde(default)]
+    pub socket_mode: Option<String>,
+    /// SO_REUSEPORT, so a new instance can listen on the port before this
+    /// one stops
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// TCP_NODELAY, keepalive, SO_REUSEADDR and listen backlog for bind-port
+    #[serde(default)]
+    pub tcp: TcpOptions,
+    /// Connections served at the same time; further ones wait or are closed
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub connection_overflow: ConnectionOverflow,
+    /// Client networks allowed to connect, e.g. "10.0.0.0/8"; empty allows all
+    #[serde(default)]
+    pub allowed_cidrs: Vec<Cidr>,
+    #[serde(default)]
     pub auth_token: String,
+    /// Read the auth token from this file instead, re-read when it changes
+    #[serde(default)]
+    pub auth_token_file: Option<PathBuf>,
+    /// Bearer, Basic or custom header auth instead of X-Auth-Token
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// OAuth2 client-credentials, sent as a bearer token
+    #[serde(default)]
+    pub oauth2: Option<OAuth2Config>,
+    /// HMAC-SHA256 request signatures with a shared secret
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    /// AWS SigV4 signatures, e.g. for API Gateway with IAM auth (needs the aws feature)
+    #[serde(default)]
+    pub aws_sigv4: Option<SigV4Config>,
+    /// Custom TLS settings for upstream connections
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Extra headers sent with every upstream request, e.g. X-Api-Version
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Names in `headers` whose values are masked in logs, in addition to
+    /// names containing auth, token, key, secret, password or cookie
+    #[serde(default)]
+    pub secret_headers: Vec<String>,
+    /// Header carrying the request ID upstream; empty to not send it
+    #[serde(default = "default_request_id_header")]
+    pub request_id_header: String,
+    /// Append "(request <id>)" to failure replies, so the lines Postfix
+    /// logs for deferred mail carry the ID
+    #[serde(default)]
+    pub request_id_in_replies: bool,
+    /// Level of this endpoint's connection and protocol logging, e.g.
+    /// "debug"; RUST_LOG applies when unset
+    #[serde(default)]
+    pub log_level: Option<LevelFilter>,
+    /// Lookups taking longer are logged as warnings with their upstream time
+    #[serde(default)]
+    pub slow_threshold_ms: Option<u64>,
+    /// Outbound HTTP(S)/SOCKS5 proxy, defaults to the top-level proxy
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
     pub request_timeout: u64, // milliseconds
+    /// Connections without a request for this long are closed; 0 keeps
+    /// them open until the client closes them
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64, // milliseconds
+    /// Protocols accepted when mode is "multiplex"
+    #[serde(default = "default_allowed_modes")]
+    pub allowed_modes: Vec<EndpointMode>,
+    /// Lookup backend (REST unless configured otherwise)
+    #[serde(default)]
+    pub backend: BackendConfig,
+    /// Optional lookup cache (tcp-lookup and socketmap-lookup only)
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// json (array of values) or text (the body is the value)
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+    /// With response-format text: every non-empty line is a value
+    #[serde(default)]
+    pub split_lines: bool,
+    /// JSON pointer (RFC 6901) to the values in object responses, e.g. "/result"
+    #[serde(default)]
+    pub response_pointer: Option<String>,
+    /// JMESPath expression producing the values, e.g. "items[?active].address"
+    #[serde(default)]
+    pub response_expression: Option<String>,
+    /// Rules for non-string values in JSON array responses
+    #[serde(default)]
+    pub value_coercion: CoercionConfig,
+    /// Between multiple values in the reply
+    #[serde(default = "default_value_separator")]
+    pub value_separator: String,
+    /// Maps that take exactly one value, e.g. transport maps
+    #[serde(default)]
+    pub single_value: Option<SingleValue>,
+    /// Socketmap reply code per lookup condition
+    #[serde(default)]
+    pub socketmap_replies: SocketmapReplyConfig,
+    /// Start in maintenance mode: lookups fail temporarily and policy
+    /// requests get policy-fallback's maintenance action, without asking
+    /// the upstream
+    #[serde(default)]
+    pub maintenance: bool,
+    /// Socketmap client flavour, postfix or sendmail
+    #[serde(default)]
+    pub dialect: SocketmapDialect,
+    /// Replies for specific HTTP statuses, overriding the defaults above
+    #[serde(default)]
+    pub status_mapping: StatusMapping,
+    /// Applied in order to lookup keys before the cache and the upstream
+    #[serde(default)]
+    pub key_normalization: Vec<KeyNormalization>,
+    /// Regex rewrites after normalization, first match wins
+    #[serde(default)]
+    pub key_rewrite: Vec<KeyRewriteRule>,
+    /// Local "key value" file answering before the cache and the upstream
+    #[serde(default)]
+    pub static_map: Option<PathBuf>,
+    /// Local "key value" file answering only while the upstream fails
+    #[serde(default)]
+    pub fallback_map: Option<PathBuf>,
+    /// Ordered REST targets and map files instead of target/targets
+    #[serde(default)]
+    pub chain: Vec<ChainStep>,
+    /// Added to every policy request, e.g. {"environment": "prod"}
+    #[serde(default)]
+    pub policy_attributes: BTreeMap<String, String>,
+    /// Reuse verdicts for requests with the same selected attributes
+    #[serde(default)]
+    pub policy_cache: Option<PolicyCacheConfig>,
+    /// Only pass on actions of the access(5) grammar
+    #[serde(default)]
+    pub strict_actions: bool,
+    /// Sent instead of an unrecognized action (default DEFER_IF_PERMIT)
+    #[serde(default)]
+    pub invalid_action: Option<String>,
+    /// Actions for upstream errors, timeouts and invalid responses
+    #[serde(default)]
+    pub policy_fallback: PolicyFallbackConfig,
+    /// Settings of the built-in greylisting engine (greylist mode)
+    #[serde(default)]
+    pub greylist: Option<GreylistConfig>,
+    /// Message rate limits checked before the policy request goes upstream;
+    /// without a target the endpoint only enforces these
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Per-user sending quota (quota mode)
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+    /// Country and ASN of client_address added to policy requests
+    #[serde(default)]
+    pub geoip: Option<GeoIpConfig>,
+    /// DNS block- and allowlists checked before the policy request goes upstream
+    #[serde(default)]
+    pub dnsbl: Option<DnsblConfig>,
+    /// Callbacks forwarded to the webhook and the answer when it fails (milter mode)
+    #[serde(default)]
+    pub milter: MilterConfig,
+    /// Expected api-header and the answer when the REST API fails (auth-policy mode)
+    #[serde(default)]
+    pub auth_policy: AuthPolicyConfig,
+    /// Encoding of the attributes sent to policy backends
+    #[serde(default)]
+    pub policy_body: PolicyBody,
+    /// Pass tcp_table keys on still %XX-encoded, as received from Postfix
+    #[serde(default)]
+    pub raw_tcp_keys: bool,
+    /// Forward tcp_table "put" requests upstream; rejected with 500 if unset
+    #[serde(default)]
+    pub put: Option<PutConfig>,
+    /// Socketmap map name -> upstream; other maps go to target/targets
+    #[serde(default)]
+    pub map_routes: BTreeMap<String, MapRouteConfig>,
+    /// Forward context from socketmap map name suffixes to the REST API
+    #[serde(default)]
+    pub map_context: Option<MapContextConfig>,
+    /// Retries with exponential backoff for upstream requests
+    #[serde(default)]
+    pub retry: Option<RetryConfig>,
+    /// Fail fast while the upstream is failing
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Latency/availability objective tracked as error budget burn
+    #[serde(default)]
+    pub slo: Option<SloConfig>,
+    /// Upstream that takes over while the circuit breaker stays open
+    #[serde(default)]
+    pub standby: Option<StandbyConfig>,
     #[serde(skip)]
     pub http_client: Option<Arc<Client>>,
+    #[serde(skip)]
+    pub lookup_cache: Option<Arc<LookupCache>>,
+    #[serde(skip)]
+    pub key_rewriter: Option<Arc<KeyRewriter>>,
+    #[serde(skip)]
+    pub policy_verdicts: Option<Arc<PolicyCache>>,
+    #[serde(skip)]
+    pub greylist_engine: Option<Arc<Greylist>>,
+    #[serde(skip)]
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    #[serde(skip)]
+    pub quota_counters: Option<Arc<Quota>>,
+    #[serde(skip)]
+    pub geoip_databases: Option<Arc<GeoIp>>,
+    #[serde(skip)]
+    pub dns_lists: Option<Arc<Dnsbl>>,
+    #[serde(skip)]
+    pub static_entries: Option<Arc<LocalMap>>,
+    #[serde(skip)]
+    pub fallback_entries: Option<Arc<LocalMap>>,
+    #[serde(skip)]
+    pub chain_sources: Vec<ChainSource>,
+    #[serde(skip)]
+    pub route_endpoints: BTreeMap<String, Arc<Endpoint>>,
+    #[serde(skip)]
+    pub target_pool: Option<Arc<TargetPool>>,
+    #[serde(skip)]
+    pub qos: Option<Arc<Qos>>,
+    #[serde(skip)]
+    pub events: Option<EventSender>,
+    #[serde(skip)]
+    pub lookup_backend: Option<Arc<Backend>>,
+    #[serde(skip)]
+    pub breaker: Option<Arc<CircuitBreaker>>,
+    #[serde(skip)]
+    pub connection_slots: Option<Arc<Semaphore>>,
+    #[serde(skip)]
+    pub slo_tracker: Option<Arc<SloTracker>>,
+    #[serde(skip)]
+    pub auth_token_source: Option<Arc<SecretFile>>,
+    #[serde(skip)]
+    pub credentials: Option<Arc<Credentials>>,
+    #[serde(skip)]
+    pub oauth2_client: Option<Arc<OAuth2Client>>,
+    #[serde(skip)]
+    pub request_signer: Option<Arc<RequestSigner>>,
+    #[serde(skip)]
+    pub sigv4_signer: Option<Arc<SigV4Signer>>,
+    #[serde(skip)]
+    pub standby_endpoint: Option<Arc<Endpoint>>,
+    #[serde(skip)]
+    pub standby_active: Arc<AtomicBool>,
+    /// Set through the admin API; lookups fail temporarily without asking the upstream
+    #[serde(skip)]
+    pub maintenance_mode: Arc<AtomicBool>,
+    /// Set through the admin API; connections are closed without an answer
+    #[serde(skip)]
+    pub disabled: Arc<AtomicBool>,
 }
 
 impl Endpoint {
     pub fn timeout(&self) -> Duration {
         Duration::from_millis(self.request_timeout)
     }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout > 0).then(|| Duration::from_millis(self.idle_timeout))
+    }
     
+    pub fn bind_socket_display(&self) -> String {
+        self.bind_socket
+            .as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn socket_mode(&self) -> Result<Option<u32>> {
+        self.socket_mode
+            .as_deref()
+            .map(|mode| u32::from_str_radix(mode, 8))
+            .transpose()
+            .with_context(|| format!("Endpoint '{}': invalid socket-mode", self.name))
+    }
+
+    /// Mask the endpoint's inline credentials in all log output
+    fn register_secrets(&self) {
+        const SECRET_NAMES: &[&str] = &["auth", "token", "key", "secret", "password", "cookie"];
+        let is_secret = |name: &str| {
+            let lowercase = name.to_ascii_lowercase();
+            SECRET_NAMES.iter().any(|secret| lowercase.contains(secret))
+        };
+
+        privacy::register_secret(&self.auth_token);
+        if let Some(secret) = self.auth.as_ref().and_then(AuthConfig::inline_secret) {
+            privacy::register_secret(secret);
+        }
+        if let Some(oauth2) = &self.oauth2 {
+            privacy::register_secret(&oauth2.client_secret);
+        }
+        if let Some(signing) = &self.signing {
+            privacy::register_secret(&signing.secret);
+        }
+        for (name, value) in &self.headers {
+            if is_secret(name)
+                || self.secret_headers.iter().any(|secret| secret.eq_ignore_ascii_case(name))
+            {
+                privacy::register_secret(value);
+            }
+        }
+        // Credentials in target URLs, e.g. user:pass@ or ?api_key=...
+        let targets = self.targets.iter().map(|target| &target.url);
+        for target in std::iter::once(&self.target).chain(targets) {
+            let Ok(url) = url::Url::parse(target) else {
+                continue;
+            };
+            if let Some(password) = url.password() {
+                privacy::register_secret(password);
+            }
+            for (name, value) in url.query_pairs() {
+                // Skip the lookup key placeholder
+                if is_secret(&name) && !value.contains('{') {
+                    privacy::register_secret(&value);
+                }
+            }
+        }
+    }
+
     pub fn with_client(mut self) -> Result<Self> {
-        let client = Client::builder()
+        self.register_secrets();
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes()).with_context(|| {
+                format!("Endpoint '{}': invalid header name '{}'", self.name, name)
+            })?;
+            let header_value = HeaderValue::from_str(value).with_context(|| {
+                format!("Endpoint '{}': invalid value for header '{}'", self.name, name)
+            })?;
+            headers.insert(header_name, header_value);
+        }
+
+        let mut builder = Client::builder()
+            .default_headers(headers)
             .timeout(self.timeout())
             .pool_max_idle_per_host(50)
             .pool_idle_timeout(Duration::from_secs(90))
-            .tcp_keepalive(Duration::from_secs(60))
-            // http2_adaptive_window is enabled by default in reqwest 0.12+
-            .build()
-            .context("Failed to create HTTP client")?;
+            .tcp_keepalive(Duration::from_secs(60));
+        // http2_adaptive_window is enabled by default in reqwest 0.12+
+        if let Some(tls_settings) = &self.tls {
+            let tls_config = tls::client_config(tls_settings)
+                .with_context(|| format!("Endpoint '{}': invalid TLS settings", self.name))?;
+            builder = builder.use_preconfigured_tls(tls_config);
+        }
+        if let Some(proxy) = &self.proxy {
+            let proxy = proxy
+                .build()
+                .with_context(|| format!("Endpoint '{}': invalid proxy settings", self.name))?;
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build().context("Failed to create HTTP client")?;
         self.http_client = Some(Arc::new(client));
         Ok(self)
     }
@@ -48,27 +476,1226 @@ impl Endpoint {
     pub fn client(&self) -> &Client {
         self.http_client.as_ref().expect("HTTP client not initialized")
     }
+
+    pub fn with_auth_token_file(mut self) -> Result<Self> {
+        self.auth_token_source = match &self.auth_token_file {
+            Some(path) => Some(Arc::new(
+                SecretFile::open(path)
+                    .with_context(|| format!("Endpoint '{}': invalid auth-token-file", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Token for the X-Auth-Token header
+    pub fn auth_token(&self) -> String {
+        match &self.auth_token_source {
+            Some(source) => source.get(),
+            None => self.auth_token.clone(),
+        }
+    }
+
+    pub fn with_auth(mut self) -> Result<Self> {
+        self.credentials = match &self.auth {
+            Some(config) => Some(Arc::new(
+                Credentials::new(config)
+                    .with_context(|| format!("Endpoint '{}': invalid auth settings", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    pub fn with_oauth2(mut self) -> Result<Self> {
+        self.oauth2_client = match &self.oauth2 {
+            Some(config) => Some(Arc::new(
+                OAuth2Client::new(config)
+                    .with_context(|| format!("Endpoint '{}': invalid oauth2 settings", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Token for the Authorization header, None without oauth2
+    pub async fn bearer_token(&self) -> Result<Option<String>> {
+        match &self.oauth2_client {
+            Some(oauth2) => Ok(Some(oauth2.token(self.client()).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn with_signing(mut self) -> Result<Self> {
+        self.request_signer = match &self.signing {
+            Some(config) => Some(Arc::new(
+                RequestSigner::new(config)
+                    .with_context(|| format!("Endpoint '{}': invalid signing settings", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    pub fn with_sigv4(mut self) -> Result<Self> {
+        self.sigv4_signer = match &self.aws_sigv4 {
+            Some(config) => Some(Arc::new(
+                SigV4Signer::new(config)
+                    .with_context(|| format!("Endpoint '{}': invalid aws-sigv4 settings", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Build the standby upstream: the primary's settings with the standby's
+    /// target, credentials, headers and TLS settings
+    pub fn with_standby(mut self) -> Result<Self> {
+        self.standby_endpoint = match &self.standby {
+            Some(standby) => {
+                let mut endpoint = self.clone();
+                endpoint.name = format!("{}/standby", self.name);
+                endpoint.target = standby.target.clone();
+                endpoint.targets = standby.targets.clone();
+                endpoint.auth_token = standby.auth_token.clone();
+                endpoint.auth_token_file = standby.auth_token_file.clone();
+                endpoint.auth = standby.auth.clone();
+                endpoint.oauth2 = standby.oauth2.clone();
+                endpoint.signing = standby.signing.clone();
+                endpoint.aws_sigv4 = standby.aws_sigv4.clone();
+                endpoint.headers = standby.headers.clone();
+                endpoint.tls = standby.tls.clone();
+                // Failures of the standby are not the primary's circuit's business
+                endpoint.circuit_breaker = None;
+                endpoint.breaker = None;
+                endpoint.standby = None;
+                endpoint.standby_endpoint = None;
+
+                let endpoint = endpoint
+                    .with_client()?
+                    .with_auth_token_file()?
+                    .with_auth()?
+                    .with_oauth2()?
+                    .with_signing()?
+                    .with_sigv4()?
+                    .with_backend()?
+                    .with_targets();
+                Some(Arc::new(endpoint))
+            }
+            None => None,
+        };
+        Ok(self)
+    }
+
+    pub fn with_map_routes(mut self) -> Result<Self> {
+        let mut routes = BTreeMap::new();
+        for (map, route) in &self.map_routes {
+            let mut endpoint = self.clone();
+            endpoint.name = format!("{}/{}", self.name, map);
+            endpoint.target = route.target.clone();
+            endpoint.targets = route.targets.clone();
+            if !route.auth_token.is_empty() || route.auth_token_file.is_some() || route.auth.is_some() {
+                endpoint.auth_token = route.auth_token.clone();
+                endpoint.auth_token_file = route.auth_token_file.clone();
+                endpoint.auth = route.auth.clone();
+            }
+            endpoint.headers.extend(route.headers.clone());
+            // Routed lookups are recorded on the endpoint's own circuit breaker
+            endpoint.circuit_breaker = None;
+            endpoint.breaker = None;
+            endpoint.map_routes = BTreeMap::new();
+
+            let endpoint = endpoint
+                .with_client()?
+                .with_auth_token_file()?
+                .with_auth()?
+                .with_oauth2()?
+                .with_signing()?
+                .with_sigv4()?
+                .with_targets();
+            routes.insert(map.clone(), Arc::new(endpoint));
+        }
+        self.route_endpoints = routes;
+        Ok(self)
+    }
+
+    /// The endpoint serving a socketmap map name: its route, or this
+    /// endpoint's own target; None for unknown maps without a default
+    pub fn route(&self, mapname: Option<&str>) -> Option<&Endpoint> {
+        if self.map_routes.is_empty() {
+            return Some(self);
+        }
+        let base = match (mapname, &self.map_context) {
+            (Some(mapname), Some(map_context)) => Some(map_context.split(mapname).0),
+            (mapname, _) => mapname,
+        };
+        match base.and_then(|base| self.route_endpoints.get(base)) {
+            Some(route) => Some(route),
+            None if !self.target.is_empty() || !self.targets.is_empty() => Some(self),
+            None => None,
+        }
+    }
+
+    /// Where the next upstream request goes: the primary, the standby during
+    /// a takeover, or nowhere while the circuit is open
+    ///
+    /// Half-open probes always go to the primary, so it can recover and take
+    /// the listener back.
+    pub fn upstream(&self) -> Option<&Endpoint> {
+        let allowed = self.circuit_allows();
+        let (standby, config, breaker) = match (&self.standby_endpoint, &self.standby, &self.breaker) {
+            (Some(standby), Some(config), Some(breaker)) => (standby, config, breaker),
+            _ => return allowed.then_some(self),
+        };
+
+        let takeover = matches!(
+            breaker.unhealthy_for(),
+            Some(unhealthy) if unhealthy >= Duration::from_millis(config.takeover_after)
+        );
+        if takeover != self.standby_active.swap(takeover, Ordering::Relaxed) {
+            if takeover {
+                warn!("Endpoint '{}': circuit open too long, standby takes over", self.name);
+            } else {
+                info!("Endpoint '{}': primary recovered, failing back from standby", self.name);
+            }
+            metrics::gauge_set(
+                "connector_standby_active",
+                "Whether the standby upstream currently serves the endpoint",
+                &[("endpoint", &self.name)],
+                if takeover { 1.0 } else { 0.0 },
+            );
+        }
+
+        if allowed {
+            Some(self)
+        } else if takeover {
+            Some(standby)
+        } else {
+            None
+        }
+    }
+
+    /// Forget the bearer token after the upstream answered 401
+    pub async fn bearer_rejected(&self) {
+        if let Some(oauth2) = &self.oauth2_client {
+            oauth2.invalidate().await;
+        }
+    }
+
+    pub fn with_cache(mut self) -> Result<Self> {
+        self.lookup_cache = match &self.cache {
+            Some(config) => Some(Arc::new(LookupCache::new(config, &self.name)?)),
+            None => None,
+        };
+        self.policy_verdicts = self
+            .policy_cache
+            .as_ref()
+            .map(|config| Arc::new(PolicyCache::new(config)));
+        Ok(self)
+    }
+
+    pub fn policy_cache(&self) -> Option<&PolicyCache> {
+        self.policy_verdicts.as_deref()
+    }
+
+    pub fn cache(&self) -> Option<&LookupCache> {
+        self.lookup_cache.as_deref()
+    }
+
+    pub fn with_greylist(mut self) -> Result<Self> {
+        self.greylist_engine = match &self.greylist {
+            Some(config) => Some(Arc::new(
+                Greylist::new(config).with_context(|| format!("Endpoint '{}'", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    pub fn greylist(&self) -> Option<&Greylist> {
+        self.greylist_engine.as_deref()
+    }
+
+    pub fn with_geoip(mut self) -> Result<Self> {
+        self.geoip_databases = match &self.geoip {
+            Some(config) => Some(Arc::new(
+                GeoIp::open(config).with_context(|| format!("Endpoint '{}'", self.name))?,
+            )),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    pub fn geoip(&self) -> Option<&GeoIp> {
+        self.geoip_databases.as_deref()
+    }
+
+    pub fn with_key_rewrite(mut self) -> Result<Self> {
+        self.key_rewriter = if self.key_rewrite.is_empty() {
+            None
+        } else {
+            Some(Arc::new(
+                KeyRewriter::new(&self.key_rewrite)
+                    .with_context(|| format!("Endpoint '{}'", self.name))?,
+            ))
+        };
+        Ok(self)
+    }
+
+    /// The key as sent upstream and cached: normalized, then rewritten
+    pub fn lookup_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let key = keys::normalize(&self.key_normalization, key);
+        match &self.key_rewriter {
+            Some(rewriter) => match rewriter.rewrite(&key) {
+                Some(rewritten) => Cow::Owned(rewritten),
+                None => key,
+            },
+            None => key,
+        }
+    }
+
+    pub fn with_local_maps(mut self) -> Result<Self> {
+        let open = |path: &Path| -> Result<Arc<LocalMap>> {
+            Ok(Arc::new(
+                LocalMap::open(path).with_context(|| format!("Endpoint '{}'", self.name))?,
+            ))
+        };
+        self.static_entries = self.static_map.as_deref().map(&open).transpose()?;
+        self.fallback_entries = self.fallback_map.as_deref().map(&open).transpose()?;
+        self.chain_sources = self
+            .chain
+            .iter()
+            .map(|step| match step {
+                ChainStep::Rest { target } => Ok(ChainSource::Rest(target.clone())),
+                ChainStep::Map { path } => Ok(ChainSource::Map(open(path)?)),
+            })
+            .collect::<Result<_>>()?;
+        Ok(self)
+    }
+
+    pub fn chain_sources(&self) -> &[ChainSource] {
+        &self.chain_sources
+    }
+
+    /// Entry of the static-map overlay for an (already normalized) key
+    pub fn static_entry(&self, key: &str) -> Option<String> {
+        self.static_entries.as_ref()?.get(key)
+    }
+
+    /// Entry of the fallback-map for a key the upstream could not answer
+    pub fn fallback_entry(&self, key: &str) -> Option<String> {
+        self.fallback_entries.as_ref()?.get(key)
+    }
+
+    pub fn with_backend(mut self) -> Result<Self> {
+        self.lookup_backend = Backend::new(&self.backend, &self.name)?.map(Arc::new);
+        Ok(self)
+    }
+
+    /// Non-REST backend, None when lookups go to the REST target
+    pub fn backend(&self) -> Option<&Backend> {
+        self.lookup_backend.as_deref()
+    }
+
+    pub fn with_circuit_breaker(mut self) -> Self {
+        self.breaker = self
+            .circuit_breaker
+            .as_ref()
+            .map(|config| Arc::new(CircuitBreaker::new(&self.name, config)));
+        self
+    }
+
+    pub fn circuit_allows(&self) -> bool {
+        match &self.breaker {
+            Some(breaker) => breaker.allow(),
+            None => true,
+        }
+    }
+
+    /// How long the circuit has been open or half-open, None while closed
+    /// or without a circuit breaker
+    pub fn circuit_unhealthy_for(&self) -> Option<Duration> {
+        self.breaker.as_ref().and_then(|breaker| breaker.unhealthy_for())
+    }
+
+    pub fn circuit_record(&self, success: bool) {
+        if let Some(breaker) = &self.breaker {
+            breaker.record(success);
+        }
+    }
+
+    pub fn with_slo(mut self) -> Self {
+        self.slo_tracker = self
+            .slo
+            .as_ref()
+            .map(|config| Arc::new(SloTracker::new(&self.name, config)));
+        self
+    }
+
+    pub fn with_rate_limit(mut self) -> Self {
+        self.rate_limiter = self
+            .rate_limit
+            .as_ref()
+            .map(|config| Arc::new(RateLimiter::new(config)));
+        self
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_deref()
+    }
+
+    pub fn with_dnsbl(mut self) -> Self {
+        self.dns_lists = self
+            .dnsbl
+            .as_ref()
+            .map(|config| Arc::new(Dnsbl::new(config)));
+        self
+    }
+
+    pub fn dnsbl(&self) -> Option<&Dnsbl> {
+        self.dns_lists.as_deref()
+    }
+
+    pub fn with_quota(mut self) -> Self {
+        self.quota_counters = self
+            .quota
+            .as_ref()
+            .map(|config| Arc::new(Quota::new(config)));
+        self
+    }
+
+    pub fn quota(&self) -> Option<&Quota> {
+        self.quota_counters.as_deref()
+    }
+
+    /// Policy endpoint without upstream, answered by its rate limits and
+    /// DNS lists alone
+    pub fn is_standalone_policy(&self) -> bool {
+        self.mode == EndpointMode::Policy
+            && (self.rate_limit.is_some() || self.dnsbl.is_some())
+            && self.target.is_empty()
+            && self.targets.is_empty()
+    }
+
+    pub fn slo_record(&self, latency: Duration, success: bool) {
+        if let Some(tracker) = &self.slo_tracker {
+            tracker.record(latency, success);
+        }
+    }
+
+    pub fn with_targets(mut self) -> Self {
+        let pool = if self.targets.is_empty() {
+            TargetPool::new(&[TargetConfig {
+                url: self.target.clone(),
+                weight: 1,
+            }])
+        } else {
+            TargetPool::new(&self.targets)
+        };
+        self.target_pool = Some(Arc::new(pool));
+        self
+    }
+
+    pub fn target_pool(&self) -> &TargetPool {
+        self.target_pool.as_ref().expect("Target pool not initialized")
+    }
+
+    pub fn with_qos(mut self, qos: Option<Arc<Qos>>) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn with_events(mut self, events: Option<EventSender>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Create the max-connections semaphore, if configured
+    pub fn with_connection_limit(mut self) -> Self {
+        self.connection_slots = self.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    pub fn connection_slots(&self) -> Option<&Arc<Semaphore>> {
+        self.connection_slots.as_ref()
+    }
+
+    pub fn allows_client(&self, ip: IpAddr) -> bool {
+        self.allowed_cidrs.is_empty() || self.allowed_cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Start in maintenance mode as configured; the admin API can end it
+    pub fn with_maintenance(self) -> Self {
+        self.set_maintenance(self.maintenance);
+        self
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.maintenance_mode.store(maintenance, Ordering::Relaxed);
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_disabled(&self, disabled: bool) {
+        self.disabled.store(disabled, Ordering::Relaxed);
+    }
+
+    /// Select the backend URL for the next request, None if all targets are drained
+    pub fn select_target(&self) -> Option<&str> {
+        self.target_pool().select()
+    }
+}
+
+fn default_drain_timeout() -> u64 {
+    10000
+}
+
+/// Parse a config file, picking JSON, TOML or YAML by its extension
+pub fn parse_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    match extension {
+        "toml" => toml::from_str(&content).map_err(anyhow::Error::from),
+        "yaml" | "yml" => serde_yaml::from_str(&content).map_err(anyhow::Error::from),
+        _ => serde_json::from_str(&content).map_err(anyhow::Error::from),
+    }
+    .with_context(|| format!("Failed to parse config file: {}", path.display()))
+}
+
+/// An include file holds a single endpoint or a list of endpoints
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IncludeFile {
+    Many(Vec<Endpoint>),
+    One(Box<Endpoint>),
+}
+
+/// Load the endpoints of every config file in a directory, in file name order
+fn load_include_dir(dir: &Path) -> Result<Vec<Endpoint>> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read include directory: {}", dir.display()))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        // Skip editor backups, .rpmnew files and the like
+        let included = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("json" | "toml" | "yaml" | "yml")
+        );
+        if included && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut endpoints = Vec::new();
+    for path in paths {
+        match parse_file(&path)? {
+            IncludeFile::Many(included) => endpoints.extend(included),
+            IncludeFile::One(endpoint) => endpoints.push(*endpoint),
+        }
+    }
+    Ok(endpoints)
+}
+
+/// Where the last successfully applied configuration is kept
+pub fn last_good_path(path: &str) -> String {
+    format!("{}.last-good.json", path)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub user_agent: String,
+    #[serde(default)]
     pub endpoints: Vec<Endpoint>,
+    /// Directory of files with additional endpoints, relative to the config file
+    #[serde(default)]
+    pub include_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// HTTP /healthz and /readyz for load balancers and Kubernetes probes
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// Request classification and per-class backend concurrency limits
+    #[serde(default)]
+    pub qos: Option<QosConfig>,
+    /// Publish lookup and verdict events to Kafka or NATS
+    #[serde(default)]
+    pub events: Option<EventsConfig>,
+    /// Abort the process after more than this many connection panics per minute
+    #[serde(default)]
+    pub max_panics_per_minute: Option<u32>,
+    /// How long SIGTERM waits for open connections to finish before exiting
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64, // milliseconds
+    /// Keep lookup keys, addresses and map values out of logs and metrics labels
+    #[serde(default)]
+    pub privacy: Option<PrivacyConfig>,
+    /// Log format and destination, e.g. JSON lines for Loki or Elasticsearch
+    #[serde(default)]
+    pub logging: Option<LoggingConfig>,
+    /// One line per lookup, to a file or the log
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    /// OTLP trace and metric export (requires the otel feature)
+    #[serde(default)]
+    pub telemetry: Option<TelemetryConfig>,
+    /// Default outbound proxy for endpoints without their own
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    /// Switch to this user (name or uid) once all listeners are bound
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    /// Switch to this group (name or gid); run-as-user's primary group by default
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+}
+
+/// Standby upstream for an endpoint; other settings are taken from the endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StandbyConfig {
+    pub target: String,
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    #[serde(default)]
+    pub auth_token: String,
+    #[serde(default)]
+    pub auth_token_file: Option<PathBuf>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub oauth2: Option<OAuth2Config>,
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    #[serde(default)]
+    pub aws_sigv4: Option<SigV4Config>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// How long the primary's circuit must stay open before the standby takes over
+    pub takeover_after: u64, // milliseconds
+}
+
+/// What happens to connections over max-connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionOverflow {
+    /// Not accepted until a connection closes; they wait in the listen backlog
+    #[default]
+    Queue,
+    /// Accepted and closed right away
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyBody {
+    /// name=value&name2=value2
+    #[default]
+    Form,
+    /// {"name": "value", "name2": ["repeated", "values"]}
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PutMethod {
+    #[default]
+    Put,
+    Post,
+}
+
+/// Upstream call for tcp_table "put key value" and memcache set/delete
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PutConfig {
+    #[serde(default)]
+    pub method: PutMethod,
+    /// Defaults to the lookup target; {key} is substituted as for lookups
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+/// Upstream for one socketmap map name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MapRouteConfig {
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    /// Credentials replace the endpoint's if any is given
+    #[serde(default)]
+    pub auth_token: String,
+    #[serde(default)]
+    pub auth_token_file: Option<PathBuf>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Added to the endpoint's headers
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_context_separator() -> String {
+    ".".to_string()
+}
+
+/// Lookup context encoded as suffixes of the socketmap map name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MapContextConfig {
+    #[serde(default = "default_context_separator")]
+    pub separator: String,
+    /// Names of the suffix fields in order, e.g. ["service", "flags"]
+    pub fields: Vec<String>,
+}
+
+impl MapContextConfig {
+    /// Split "virtual.smtpd.fold_fix" into "virtual" and its context fields
+    pub fn split<'a>(&'a self, mapname: &'a str) -> (&'a str, Vec<(&'a str, &'a str)>) {
+        let mut parts = mapname.splitn(self.fields.len() + 1, self.separator.as_str());
+        let base = parts.next().unwrap_or("");
+        let context = self.fields.iter().map(String::as_str).zip(parts).collect();
+        (base, context)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub bind_address: String,
+    #[serde(default)]
+    pub bind_port: u16,
+    /// Unix domain socket path, used instead of bind-address/bind-port
+    #[serde(default)]
+    pub bind_socket: Option<PathBuf>,
+    /// Octal permissions for bind-socket, e.g. "0660"
+    #[serde(default)]
+    pub socket_mode: Option<String>,
+    /// Required in the X-Admin-Token header (except for the UI page and /metrics)
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
 impl Config {
+    /// Stable hash of the effective configuration, for detecting drift across hosts
+    pub fn hash(&self) -> Result<String> {
+        // FNV-1a, so the value does not depend on the Rust version or process
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in serde_json::to_string(self)?.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        Ok(format!("{:016x}", hash))
+    }
+
+    /// Keep a copy of this configuration to fall back to if the file breaks later
+    pub fn save_last_good(&self, path: &str) -> Result<()> {
+        let last_good = last_good_path(path);
+        let tmp = format!("{}.tmp", last_good);
+
+        // Owner-only, the copy contains auth tokens
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("Failed to write {}", tmp))?;
+        // Included endpoints are already merged into the copy
+        let config = Config {
+            include_dir: None,
+            ..self.clone()
+        };
+        file.write_all(serde_json::to_string_pretty(&config)?.as_bytes())?;
+        fs::rename(&tmp, &last_good)
+            .with_context(|| format!("Failed to write {}", last_good))?;
+        Ok(())
+    }
+
+    /// include-dir resolved against the directory of the main config file
+    pub fn include_dir_path(&self, path: &str) -> Option<PathBuf> {
+        let include_dir = self.include_dir.as_ref()?;
+        Some(
+            Path::new(path)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(include_dir),
+        )
+    }
+
     pub fn from_file(path: &str) -> Result<Self> {
-        let content = fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {}", path))?;
+        let mut config: Config = parse_file(Path::new(path))?;
 
-        let config: Config = serde_json::from_str(&content)
-            .with_context(|| format!("Failed to parse config file: {}", path))?;
+        if let Some(include_dir) = config.include_dir_path(path) {
+            let included = load_include_dir(&include_dir)?;
+            config.endpoints.extend(included);
+        }
+
+        if let Some(proxy) = &config.proxy {
+            for endpoint in &mut config.endpoints {
+                endpoint.proxy.get_or_insert_with(|| proxy.clone());
+            }
+        }
 
         // Validate configuration
+        if let Some(logging) = &config.logging {
+            logging.validate().context("logging")?;
+        }
+        if let Some(access_log) = &config.access_log {
+            access_log.validate()?;
+        }
+        if let Some(telemetry) = &config.telemetry {
+            telemetry.validate()?;
+        }
         if config.endpoints.is_empty() {
             anyhow::bail!("Configuration must have at least one endpoint");
         }
+        if config.run_as_user.as_deref() == Some("")
+            || config.run_as_group.as_deref() == Some("")
+        {
+            anyhow::bail!("run-as-user and run-as-group must not be empty");
+        }
+
+        let mut names = HashSet::new();
+        let mut listeners = HashSet::new();
+        for endpoint in &config.endpoints {
+            if !names.insert(endpoint.name.as_str()) {
+                anyhow::bail!("Duplicate endpoint name '{}'", endpoint.name);
+            }
+            let listener = match &endpoint.bind_socket {
+                Some(path) => path.display().to_string(),
+                None => format!("{}:{}", endpoint.bind_address, endpoint.bind_port),
+            };
+            if !listeners.insert(listener.clone()) {
+                anyhow::bail!(
+                    "Endpoint '{}': {} is already used by another endpoint",
+                    endpoint.name,
+                    listener
+                );
+            }
+
+            if endpoint.bind_socket.is_none() && endpoint.bind_port == 0 {
+                anyhow::bail!(
+                    "Endpoint '{}': either bind-port or bind-socket must be set",
+                    endpoint.name
+                );
+            }
+            endpoint.socket_mode()?;
+            endpoint
+                .tcp
+                .validate()
+                .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            if endpoint.max_connections == Some(0) {
+                anyhow::bail!(
+                    "Endpoint '{}': max-connections must be greater than 0",
+                    endpoint.name
+                );
+            }
+            if endpoint.reuse_port && endpoint.bind_socket.is_some() {
+                anyhow::bail!(
+                    "Endpoint '{}': reuse-port only applies to bind-port, unix sockets are \
+                     always taken over",
+                    endpoint.name
+                );
+            }
+            if !endpoint.allowed_cidrs.is_empty() && endpoint.bind_socket.is_some() {
+                anyhow::bail!(
+                    "Endpoint '{}': allowed-cidrs only applies to bind-port, use socket-mode \
+                     to restrict unix sockets",
+                    endpoint.name
+                );
+            }
+            if endpoint.auth_token_file.is_some() && !endpoint.auth_token.is_empty() {
+                anyhow::bail!(
+                    "Endpoint '{}': auth-token and auth-token-file are mutually exclusive",
+                    endpoint.name
+                );
+            }
+            if endpoint.standby.is_some() && endpoint.circuit_breaker.is_none() {
+                anyhow::bail!(
+                    "Endpoint '{}': standby requires a circuit-breaker to detect failures",
+                    endpoint.name
+                );
+            }
+            if let Some(standby) = &endpoint.standby {
+                if standby.auth_token_file.is_some() && !standby.auth_token.is_empty() {
+                    anyhow::bail!(
+                        "Endpoint '{}': standby auth-token and auth-token-file are mutually exclusive",
+                        endpoint.name
+                    );
+                }
+            }
+            if let Some(auth) = &endpoint.auth {
+                if !endpoint.auth_token.is_empty() || endpoint.auth_token_file.is_some() {
+                    anyhow::bail!(
+                        "Endpoint '{}': auth replaces auth-token and auth-token-file, set only one",
+                        endpoint.name
+                    );
+                }
+                if auth.uses_authorization()
+                    && (endpoint.oauth2.is_some() || endpoint.aws_sigv4.is_some())
+                {
+                    anyhow::bail!(
+                        "Endpoint '{}': auth, oauth2 and aws-sigv4 would all set the Authorization header",
+                        endpoint.name
+                    );
+                }
+            }
+            if endpoint.aws_sigv4.is_some() && endpoint.oauth2.is_some() {
+                anyhow::bail!(
+                    "Endpoint '{}': aws-sigv4 and oauth2 both set the Authorization header",
+                    endpoint.name
+                );
+            }
+            if let Some(oauth2) = &endpoint.oauth2 {
+                if oauth2.client_secret_file.is_some() && !oauth2.client_secret.is_empty() {
+                    anyhow::bail!(
+                        "Endpoint '{}': oauth2 client-secret and client-secret-file are mutually exclusive",
+                        endpoint.name
+                    );
+                }
+            }
+            if let Some(map_context) = &endpoint.map_context {
+                if map_context.separator.is_empty() {
+                    anyhow::bail!(
+                        "Endpoint '{}': map-context separator must not be empty",
+                        endpoint.name
+                    );
+                }
+                if map_context
+                    .fields
+                    .iter()
+                    .any(|field| field == "name" || field == "key")
+                {
+                    anyhow::bail!(
+                        "Endpoint '{}': map-context fields cannot be named name or key",
+                        endpoint.name
+                    );
+                }
+            }
+            if endpoint.response_format == ResponseFormat::Text
+                && (endpoint.response_pointer.is_some() || endpoint.response_expression.is_some())
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': response-pointer and response-expression require response-format json",
+                    endpoint.name
+                );
+            }
+            if endpoint.response_pointer.is_some() && endpoint.response_expression.is_some() {
+                anyhow::bail!(
+                    "Endpoint '{}': response-pointer and response-expression are mutually exclusive",
+                    endpoint.name
+                );
+            }
+            if let Some(expression) = &endpoint.response_expression {
+                jmespath::compile(expression).with_context(|| {
+                    format!("Endpoint '{}': invalid response-expression", endpoint.name)
+                })?;
+            }
+            if endpoint.split_lines && endpoint.response_format != ResponseFormat::Text {
+                anyhow::bail!(
+                    "Endpoint '{}': split-lines requires response-format text",
+                    endpoint.name
+                );
+            }
+            if (endpoint.static_map.is_some() || endpoint.fallback_map.is_some())
+                && endpoint.mode == EndpointMode::Policy
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': static-map and fallback-map are not supported in policy mode",
+                    endpoint.name
+                );
+            }
+            if endpoint.value_separator.is_empty() {
+                anyhow::bail!(
+                    "Endpoint '{}': value-separator must not be empty",
+                    endpoint.name
+                );
+            }
+            if let Some(pointer) = &endpoint.response_pointer {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    anyhow::bail!(
+                        "Endpoint '{}': response-pointer must be empty or start with '/'",
+                        endpoint.name
+                    );
+                }
+            }
+            endpoint
+                .status_mapping
+                .validate()
+                .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            if let Some(slo) = &endpoint.slo {
+                slo.validate()
+                    .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            }
+            match (&endpoint.greylist, endpoint.mode == EndpointMode::Greylist) {
+                (Some(greylist), true) => greylist
+                    .validate()
+                    .with_context(|| format!("Endpoint '{}'", endpoint.name))?,
+                (None, true) => anyhow::bail!(
+                    "Endpoint '{}': greylist mode requires greylist settings",
+                    endpoint.name
+                ),
+                (Some(_), false) => anyhow::bail!(
+                    "Endpoint '{}': greylist settings require greylist mode",
+                    endpoint.name
+                ),
+                (None, false) => {}
+            }
+            match (&endpoint.quota, endpoint.mode == EndpointMode::Quota) {
+                (Some(quota), true) => quota
+                    .validate()
+                    .with_context(|| format!("Endpoint '{}'", endpoint.name))?,
+                (None, true) => anyhow::bail!(
+                    "Endpoint '{}': quota mode requires quota settings",
+                    endpoint.name
+                ),
+                (Some(_), false) => anyhow::bail!(
+                    "Endpoint '{}': quota settings require quota mode",
+                    endpoint.name
+                ),
+                (None, false) => {}
+            }
+            if let Some(geoip) = &endpoint.geoip {
+                if endpoint.mode != EndpointMode::Policy {
+                    anyhow::bail!("Endpoint '{}': geoip requires policy mode", endpoint.name);
+                }
+                geoip
+                    .validate()
+                    .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            }
+            if let Some(dnsbl) = &endpoint.dnsbl {
+                if endpoint.mode != EndpointMode::Policy {
+                    anyhow::bail!("Endpoint '{}': dnsbl requires policy mode", endpoint.name);
+                }
+                dnsbl
+                    .validate()
+                    .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            }
+            if let Some(rate_limit) = &endpoint.rate_limit {
+                if endpoint.mode != EndpointMode::Policy {
+                    anyhow::bail!(
+                        "Endpoint '{}': rate-limit requires policy mode",
+                        endpoint.name
+                    );
+                }
+                rate_limit
+                    .validate()
+                    .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            }
+
+            let is_rest = matches!(endpoint.backend, BackendConfig::Rest);
+            if !is_rest && endpoint.mode == EndpointMode::Policy {
+                anyhow::bail!(
+                    "Endpoint '{}': policy mode requires the REST backend",
+                    endpoint.name
+                );
+            }
+
+            let has_target = !endpoint.target.is_empty() || !endpoint.targets.is_empty();
+            let is_local =
+                endpoint.mode == EndpointMode::Greylist || endpoint.is_standalone_policy();
+            if is_rest
+                && !is_local
+                && !has_target
+                && endpoint.chain.is_empty()
+                && endpoint.map_routes.is_empty()
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': either target, targets, chain or map-routes must be set",
+                    endpoint.name
+                );
+            }
+            if let Some(fallback) = &endpoint.invalid_action {
+                if !action::is_valid(fallback) {
+                    anyhow::bail!(
+                        "Endpoint '{}': invalid-action '{}' is not a valid action",
+                        endpoint.name,
+                        fallback
+                    );
+                }
+            }
+            for fallback in endpoint.policy_fallback.actions() {
+                if !action::is_valid(fallback) {
+                    anyhow::bail!(
+                        "Endpoint '{}': policy-fallback action '{}' is not a valid action",
+                        endpoint.name,
+                        fallback
+                    );
+                }
+            }
+            if endpoint.policy_cache.is_some()
+                && !matches!(endpoint.mode, EndpointMode::Policy | EndpointMode::AuthPolicy)
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': policy-cache requires policy or auth-policy mode",
+                    endpoint.name
+                );
+            }
+            endpoint
+                .auth_policy
+                .validate()
+                .with_context(|| format!("Endpoint '{}'", endpoint.name))?;
+            for name in endpoint.policy_attributes.keys() {
+                if name.is_empty() || name.contains(['=', '\n']) {
+                    anyhow::bail!(
+                        "Endpoint '{}': invalid policy attribute name '{}'",
+                        endpoint.name,
+                        name
+                    );
+                }
+            }
+            if endpoint.policy_attributes.values().any(|value| value.contains('\n')) {
+                anyhow::bail!(
+                    "Endpoint '{}': policy attribute values cannot contain newlines",
+                    endpoint.name
+                );
+            }
+            if endpoint.put.is_some()
+                && !matches!(
+                    endpoint.mode,
+                    EndpointMode::TcpLookup | EndpointMode::Multiplex | EndpointMode::Memcache
+                )
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': put requires tcp-lookup, multiplex or memcache mode",
+                    endpoint.name
+                );
+            }
+            if endpoint.put.is_some() && (!is_rest || !endpoint.chain.is_empty()) {
+                anyhow::bail!(
+                    "Endpoint '{}': put requires a REST target",
+                    endpoint.name
+                );
+            }
+            if !endpoint.map_routes.is_empty() {
+                if !matches!(endpoint.mode, EndpointMode::SocketmapLookup | EndpointMode::Multiplex) {
+                    anyhow::bail!(
+                        "Endpoint '{}': map-routes requires socketmap-lookup or multiplex mode",
+                        endpoint.name
+                    );
+                }
+                if !is_rest || endpoint.standby.is_some() || !endpoint.chain.is_empty() {
+                    anyhow::bail!(
+                        "Endpoint '{}': map-routes cannot be combined with a backend, standby or chain",
+                        endpoint.name
+                    );
+                }
+                for (map, route) in &endpoint.map_routes {
+                    if route.target.is_empty() == route.targets.is_empty() {
+                        anyhow::bail!(
+                            "Endpoint '{}': map route '{}' needs either target or targets",
+                            endpoint.name,
+                            map
+                        );
+                    }
+                    if route.auth.is_some() && (!route.auth_token.is_empty() || route.auth_token_file.is_some()) {
+                        anyhow::bail!(
+                            "Endpoint '{}': map route '{}': auth cannot be combined with auth-token",
+                            endpoint.name,
+                            map
+                        );
+                    }
+                }
+            }
+            if !endpoint.chain.is_empty() {
+                if has_target {
+                    anyhow::bail!(
+                        "Endpoint '{}': chain replaces target and targets",
+                        endpoint.name
+                    );
+                }
+                if !is_rest || endpoint.standby.is_some() || endpoint.mode == EndpointMode::Policy {
+                    anyhow::bail!(
+                        "Endpoint '{}': chain cannot be combined with a backend, standby or policy mode",
+                        endpoint.name
+                    );
+                }
+            }
+
+            let has_placeholder = std::iter::once(endpoint.target.as_str())
+                .chain(endpoint.targets.iter().map(|target| target.url.as_str()))
+                .any(|url| url.contains("{key}") || url.contains("{map}"));
+            if has_placeholder && endpoint.mode == EndpointMode::Policy {
+                anyhow::bail!(
+                    "Endpoint '{}': policy targets cannot use {{key}} or {{map}} placeholders",
+                    endpoint.name
+                );
+            }
+
+            if !endpoint.request_id_header.is_empty()
+                && HeaderName::from_bytes(endpoint.request_id_header.as_bytes()).is_err()
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': invalid request-id-header '{}'",
+                    endpoint.name,
+                    endpoint.request_id_header
+                );
+            }
+
+            if endpoint.slow_threshold_ms == Some(0) {
+                anyhow::bail!(
+                    "Endpoint '{}': slow-threshold-ms must be greater than 0",
+                    endpoint.name
+                );
+            }
+
+            if endpoint.dialect == SocketmapDialect::Sendmail
+                && !matches!(
+                    endpoint.mode,
+                    EndpointMode::SocketmapLookup | EndpointMode::Multiplex
+                )
+            {
+                anyhow::bail!(
+                    "Endpoint '{}': dialect sendmail requires socketmap-lookup or multiplex mode",
+                    endpoint.name
+                );
+            }
+
+            if endpoint.mode == EndpointMode::Multiplex {
+                if endpoint.allowed_modes.is_empty() {
+                    anyhow::bail!(
+                        "Endpoint '{}': allowed-modes must not be empty",
+                        endpoint.name
+                    );
+                }
+                if endpoint.allowed_modes.iter().any(|mode| {
+                    matches!(
+                        mode,
+                        EndpointMode::Multiplex
+                            | EndpointMode::Memcache
+                            | EndpointMode::Greylist
+                            | EndpointMode::Quota
+                            | EndpointMode::DovecotDict
+                            | EndpointMode::Milter
+                            | EndpointMode::OpensmtpdTable
+                            | EndpointMode::AuthPolicy
+                    )
+                }) {
+                    anyhow::bail!(
+                        "Endpoint '{}': allowed-modes can only contain tcp-lookup, socketmap-lookup and policy",
+                        endpoint.name
+                    );
+                }
+            }
+        }
 
         Ok(config)
     }
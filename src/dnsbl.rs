@@ -0,0 +1,155 @@
+use anyhow::Result;
+use futures_util::future::join_all;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::action;
+use crate::privacy::sensitive;
+
+fn default_action() -> String {
+    "REJECT Client blocked using {zone}".to_string()
+}
+
+fn default_allow_action() -> String {
+    "DUNNO".to_string()
+}
+
+fn default_timeout() -> u64 {
+    2000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DnsList {
+    /// DNS zone, e.g. zen.spamhaus.org
+    pub zone: String,
+    /// Only these reply addresses count as listed, e.g. ["127.0.0.2"];
+    /// any reply counts when empty
+    #[serde(default)]
+    pub codes: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DnsblConfig {
+    /// Blocklists; a listed client gets `action`
+    #[serde(default)]
+    pub blocklists: Vec<DnsList>,
+    /// Allowlists (DNSWL); a listed client gets `allow-action` without
+    /// checking the blocklists
+    #[serde(default)]
+    pub allowlists: Vec<DnsList>,
+    /// {zone} and {client_address} are replaced
+    #[serde(default = "default_action")]
+    pub action: String,
+    #[serde(default = "default_allow_action")]
+    pub allow_action: String,
+    /// Unanswered queries count as not listed after this time
+    #[serde(default = "default_timeout")]
+    pub timeout: u64, // milliseconds
+}
+
+impl DnsblConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.blocklists.is_empty() && self.allowlists.is_empty() {
+            anyhow::bail!("dnsbl needs blocklists or allowlists");
+        }
+        let sample = self
+            .action
+            .replace("{zone}", "example.org")
+            .replace("{client_address}", "192.0.2.1");
+        if !action::is_valid(&sample) || !action::is_valid(&self.allow_action) {
+            anyhow::bail!("dnsbl action and allow-action must be valid actions");
+        }
+        Ok(())
+    }
+}
+
+/// DNS name of an address in a DNSBL zone: reversed octets for IPv4,
+/// reversed nibbles for IPv6
+fn query_name(ip: IpAddr, zone: &str) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.{}", d, c, b, a, zone)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(64 + zone.len());
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str(zone);
+            name
+        }
+    }
+}
+
+/// DNS-based block- and allowlists, queried through the system resolver
+#[derive(Debug)]
+pub struct Dnsbl {
+    config: DnsblConfig,
+    timeout: Duration,
+}
+
+impl Dnsbl {
+    pub fn new(config: &DnsblConfig) -> Self {
+        Self {
+            config: config.clone(),
+            timeout: Duration::from_millis(config.timeout),
+        }
+    }
+
+    /// Whether an address is listed in a zone; errors and timeouts are not
+    async fn listed(&self, ip: IpAddr, list: &DnsList) -> bool {
+        let name = query_name(ip, &list.zone);
+        let lookup = tokio::net::lookup_host((name.as_str(), 0));
+        match tokio::time::timeout(self.timeout, lookup).await {
+            Ok(Ok(replies)) => {
+                let mut replies = replies.map(|reply| reply.ip());
+                if list.codes.is_empty() {
+                    replies.next().is_some()
+                } else {
+                    replies.any(|reply| list.codes.contains(&reply))
+                }
+            }
+            // NXDOMAIN, the usual answer for unlisted addresses
+            Ok(Err(_)) => false,
+            Err(_) => {
+                warn!("DNSBL query to {} timed out", list.zone);
+                false
+            }
+        }
+    }
+
+    /// First list of `lists`, in configured order, that has the address
+    async fn first_listed<'a>(&self, ip: IpAddr, lists: &'a [DnsList]) -> Option<&'a DnsList> {
+        let listed = join_all(lists.iter().map(|list| self.listed(ip, list))).await;
+        lists
+            .iter()
+            .zip(listed)
+            .find_map(|(list, listed)| listed.then_some(list))
+    }
+
+    /// Action for the client of a policy request, None when it is on no list
+    pub async fn check(&self, request: &str) -> Option<String> {
+        let address = request
+            .lines()
+            .find_map(|line| line.strip_prefix("client_address="))?;
+        let ip: IpAddr = address.parse().ok()?;
+
+        if let Some(list) = self.first_listed(ip, &self.config.allowlists).await {
+            debug!("Client {} allowlisted by {}", sensitive(address), list.zone);
+            return Some(self.config.allow_action.clone());
+        }
+        let list = self.first_listed(ip, &self.config.blocklists).await?;
+        debug!("Client {} listed by {}", sensitive(address), list.zone);
+        Some(
+            self.config
+                .action
+                .replace("{zone}", &list.zone)
+                .replace("{client_address}", address),
+        )
+    }
+}
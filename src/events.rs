@@ -0,0 +1,207 @@
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventSinkKind {
+    Kafka,
+    Nats,
+}
+
+fn default_buffer_size() -> usize {
+    10000
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_flush_interval() -> u64 {
+    1000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventsConfig {
+    pub sink: EventSinkKind,
+    /// NATS server URL or comma-separated Kafka bootstrap servers
+    pub servers: String,
+    /// Kafka topic or NATS subject
+    pub topic: String,
+    /// Events queued beyond this are dropped rather than blocking lookups
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64, // milliseconds
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EventKind {
+    Lookup {
+        map: Option<String>,
+        key: String,
+        result: &'static str,
+        duration_ms: u64,
+    },
+    CacheHit {
+        map: Option<String>,
+        key: String,
+    },
+    Verdict {
+        action: String,
+        duration_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp: u64, // milliseconds since the Unix epoch
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Non-blocking handle used on the lookup path to publish events
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<Event>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSender {
+    pub fn emit(&self, endpoint: &str, kind: EventKind) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let event = Event {
+            timestamp,
+            endpoint: endpoint.to_string(),
+            kind,
+        };
+
+        // Never wait for the publisher: drop the event when the buffer is full
+        if self.tx.try_send(event).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Event buffer full, {} events dropped so far", dropped);
+            }
+        }
+    }
+}
+
+enum EventSink {
+    #[cfg(feature = "kafka")]
+    Kafka(rdkafka::producer::FutureProducer),
+    #[cfg(feature = "nats")]
+    Nats(async_nats::Client),
+}
+
+impl EventSink {
+    async fn connect(config: &EventsConfig) -> Result<Self> {
+        match config.sink {
+            #[cfg(feature = "kafka")]
+            EventSinkKind::Kafka => {
+                let producer = rdkafka::ClientConfig::new()
+                    .set("bootstrap.servers", &config.servers)
+                    .set("linger.ms", config.flush_interval.to_string())
+                    .create()?;
+                Ok(EventSink::Kafka(producer))
+            }
+            #[cfg(feature = "nats")]
+            EventSinkKind::Nats => Ok(EventSink::Nats(async_nats::connect(config.servers.as_str()).await?)),
+            #[allow(unreachable_patterns)]
+            _ => anyhow::bail!("Built without {:?} event support", config.sink),
+        }
+    }
+
+    async fn publish(&self, topic: &str, batch: &[Event]) -> Result<()> {
+        match self {
+            #[cfg(feature = "kafka")]
+            EventSink::Kafka(producer) => {
+                use rdkafka::producer::FutureRecord;
+
+                for event in batch {
+                    let payload = serde_json::to_vec(event)?;
+                    let record: FutureRecord<'_, str, [u8]> = FutureRecord::to(topic)
+                        .key(&event.endpoint)
+                        .payload(&payload);
+                    if let Err((e, _)) = producer.send(record, Duration::from_secs(0)).await {
+                        anyhow::bail!("Kafka publish failed: {}", e);
+                    }
+                }
+                Ok(())
+            }
+            #[cfg(feature = "nats")]
+            EventSink::Nats(client) => {
+                for event in batch {
+                    let payload = serde_json::to_vec(event)?;
+                    client.publish(topic.to_string(), payload.into()).await?;
+                }
+                client.flush().await?;
+                Ok(())
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (topic, batch);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Create the event channel; the receiver is consumed by `run_publisher`
+pub fn channel(config: &EventsConfig) -> (EventSender, mpsc::Receiver<Event>) {
+    let (tx, rx) = mpsc::channel(config.buffer_size);
+    let sender = EventSender {
+        tx,
+        dropped: Arc::new(AtomicU64::new(0)),
+    };
+    (sender, rx)
+}
+
+/// Drain the event channel in batches and publish them to the configured sink
+pub async fn run_publisher(config: EventsConfig, mut rx: mpsc::Receiver<Event>) -> Result<()> {
+    let sink = EventSink::connect(&config).await?;
+    info!("Publishing events to {:?} topic '{}'", config.sink, config.topic);
+
+    let flush_interval = Duration::from_millis(config.flush_interval);
+    let mut batch = Vec::with_capacity(config.batch_size);
+
+    loop {
+        let deadline = tokio::time::sleep(flush_interval);
+        tokio::pin!(deadline);
+
+        // Collect until the batch is full or the flush interval elapses
+        while batch.len() < config.batch_size {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => batch.push(event),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+
+        if !batch.is_empty() {
+            debug!("Publishing {} events", batch.len());
+            if let Err(e) = sink.publish(&config.topic, &batch).await {
+                error!("Failed to publish {} events: {}", batch.len(), e);
+            }
+            batch.clear();
+        }
+
+        if rx.is_closed() && rx.is_empty() {
+            return Ok(());
+        }
+    }
+}
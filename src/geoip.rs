@@ -0,0 +1,115 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GeoIpConfig {
+    /// GeoLite2-Country or GeoIP2-Country (or City) database
+    #[serde(default)]
+    pub country_database: Option<PathBuf>,
+    /// GeoLite2-ASN or GeoIP2-ISP database
+    #[serde(default)]
+    pub asn_database: Option<PathBuf>,
+}
+
+impl GeoIpConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.country_database.is_none() && self.asn_database.is_none() {
+            anyhow::bail!("geoip needs country-database or asn-database");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "geoip")]
+mod maxmind {
+    use anyhow::{Context, Result};
+    use log::debug;
+    use maxminddb::{geoip2, MaxMindDBError, Reader};
+    use std::net::IpAddr;
+    use std::path::Path;
+
+    use super::GeoIpConfig;
+    use crate::secret::WatchedFile;
+
+    fn read_database(path: &Path) -> Result<Reader<Vec<u8>>> {
+        Reader::open_readfile(path)
+            .with_context(|| format!("Failed to read GeoIP database {}", path.display()))
+    }
+
+    /// MaxMind databases, reloaded when geoipupdate replaces them
+    #[derive(Debug)]
+    pub struct GeoIp {
+        country: Option<WatchedFile<Reader<Vec<u8>>>>,
+        asn: Option<WatchedFile<Reader<Vec<u8>>>>,
+    }
+
+    impl GeoIp {
+        pub fn open(config: &GeoIpConfig) -> Result<Self> {
+            let open = |path: &Path| WatchedFile::open(path, "GeoIP database", read_database);
+            Ok(Self {
+                country: config.country_database.as_deref().map(open).transpose()?,
+                asn: config.asn_database.as_deref().map(open).transpose()?,
+            })
+        }
+
+        /// Policy attributes for a client address; addresses not in the
+        /// databases (private networks, "unknown") get none
+        pub fn attributes(&self, address: &str) -> Vec<(&'static str, String)> {
+            let Ok(ip) = address.parse::<IpAddr>() else {
+                return Vec::new();
+            };
+            let mut attributes = Vec::new();
+
+            if let Some(database) = &self.country {
+                let reader = database.get();
+                match reader.lookup::<geoip2::Country>(ip) {
+                    Ok(country) => {
+                        if let Some(code) = country.country.and_then(|country| country.iso_code) {
+                            attributes.push(("geoip_country", code.to_string()));
+                        }
+                    }
+                    Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+                    Err(e) => debug!("GeoIP country lookup failed: {}", e),
+                }
+            }
+
+            if let Some(database) = &self.asn {
+                let reader = database.get();
+                match reader.lookup::<geoip2::Asn>(ip) {
+                    Ok(asn) => {
+                        if let Some(number) = asn.autonomous_system_number {
+                            attributes.push(("geoip_asn", number.to_string()));
+                        }
+                        if let Some(organization) = asn.autonomous_system_organization {
+                            attributes.push(("geoip_as_org", organization.to_string()));
+                        }
+                    }
+                    Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+                    Err(e) => debug!("GeoIP ASN lookup failed: {}", e),
+                }
+            }
+
+            attributes
+        }
+    }
+}
+
+#[cfg(feature = "geoip")]
+pub use maxmind::GeoIp;
+
+#[cfg(not(feature = "geoip"))]
+#[derive(Debug)]
+pub struct GeoIp;
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    pub fn open(_config: &GeoIpConfig) -> Result<Self> {
+        anyhow::bail!("built without geoip support")
+    }
+
+    pub fn attributes(&self, _address: &str) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+}
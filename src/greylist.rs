@@ -0,0 +1,528 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::action;
+use crate::clock::{self, Instant};
+use crate::privacy::sensitive;
+
+// How often expired entries are dropped and the state file is written
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+fn default_delay() -> u64 {
+    300
+}
+
+fn default_retry_window() -> u64 {
+    2 * 24 * 3600
+}
+
+fn default_max_age() -> u64 {
+    35 * 24 * 3600
+}
+
+fn default_auto_whitelist() -> u32 {
+    5
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_action() -> String {
+    "DEFER_IF_PERMIT Greylisted, please try again later".to_string()
+}
+
+fn default_key_prefix() -> String {
+    "postfix-rest-api-connector:greylist:".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GreylistConfig {
+    /// How long a new (client, sender, recipient) triplet is deferred
+    #[serde(default = "default_delay")]
+    pub delay: u64, // seconds
+    /// Unconfirmed triplets are forgotten when no retry comes within this time
+    #[serde(default = "default_retry_window")]
+    pub retry_window: u64, // seconds
+    /// Passed triplets and auto-whitelisted clients expire when unused this long
+    #[serde(default = "default_max_age")]
+    pub max_age: u64, // seconds
+    /// Deliveries after which a client network skips greylisting, 0 disables
+    #[serde(default = "default_auto_whitelist")]
+    pub auto_whitelist: u32,
+    /// Client addresses are greylisted by network, as mail farms retry from
+    /// different hosts
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// Sent while a triplet is greylisted
+    #[serde(default = "default_action")]
+    pub action: String,
+    /// Keep the state in this file across restarts (in-memory store)
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// Keep the state in Redis instead, shared by all connector instances
+    #[serde(default)]
+    pub redis_url: Option<String>,
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+}
+
+impl GreylistConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.ipv4_prefix > 32 || self.ipv6_prefix > 128 {
+            anyhow::bail!("greylist prefix length out of range");
+        }
+        if self.delay >= self.retry_window {
+            anyhow::bail!("greylist retry-window must be longer than delay");
+        }
+        if !action::is_valid(&self.action) {
+            anyhow::bail!("greylist action '{}' is not a valid action", self.action);
+        }
+        if self.state_file.is_some() && self.redis_url.is_some() {
+            anyhow::bail!("greylist state-file and redis-url are mutually exclusive");
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    clock::unix_time().as_secs()
+}
+
+/// Client address reduced to its network, e.g. 192.0.2.0/24
+fn client_network(config: &GreylistConfig, address: &str) -> String {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - config.ipv4_prefix as u32).unwrap_or(0);
+            let network = std::net::Ipv4Addr::from(u32::from(ip) & mask);
+            format!("{}/{}", network, config.ipv4_prefix)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - config.ipv6_prefix as u32).unwrap_or(0);
+            let network = std::net::Ipv6Addr::from(u128::from(ip) & mask);
+            format!("{}/{}", network, config.ipv6_prefix)
+        }
+        // "unknown" and other non-addresses are greylisted as they are
+        Err(_) => address.to_string(),
+    }
+}
+
+/// What is known about one triplet
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Triplet {
+    first_seen: u64,
+    last_seen: u64,
+    /// Retried after the delay, later messages are not deferred
+    passed: bool,
+}
+
+/// Deliveries of an auto-whitelist candidate
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Client {
+    deliveries: u32,
+    last_seen: u64,
+}
+
+/// Contents of the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    triplets: HashMap<String, Triplet>,
+    clients: HashMap<String, Client>,
+}
+
+impl State {
+    fn expire(&mut self, config: &GreylistConfig, now: u64) {
+        self.triplets.retain(|_, triplet| {
+            let ttl = if triplet.passed { config.max_age } else { config.retry_window };
+            now.saturating_sub(triplet.last_seen) < ttl
+        });
+        self.clients
+            .retain(|_, client| now.saturating_sub(client.last_seen) < config.max_age);
+    }
+}
+
+/// In-memory store, optionally saved to a state file
+#[derive(Debug)]
+struct MemoryStore {
+    state: Mutex<State>,
+    state_file: Option<PathBuf>,
+    /// Up to MAINTENANCE_INTERVAL of changes are lost on a restart
+    last_maintenance: Mutex<Instant>,
+}
+
+impl MemoryStore {
+    fn open(config: &GreylistConfig) -> Result<Self> {
+        let mut state = match &config.state_file {
+            Some(path) if path.exists() => {
+                let data = std::fs::read_to_string(path).with_context(|| {
+                    format!("Failed to read greylist state file {}", path.display())
+                })?;
+                serde_json::from_str(&data).with_context(|| {
+                    format!("Invalid greylist state file {}", path.display())
+                })?
+            }
+            _ => State::default(),
+        };
+        state.expire(config, now());
+        if let Some(path) = &config.state_file {
+            info!(
+                "Loaded {} greylist triplets from {}",
+                state.triplets.len(),
+                path.display()
+            );
+        }
+        Ok(Self {
+            state: Mutex::new(state),
+            state_file: config.state_file.clone(),
+            last_maintenance: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn triplet(&self, key: &str) -> Option<Triplet> {
+        self.state.lock().unwrap().triplets.get(key).copied()
+    }
+
+    fn client(&self, network: &str) -> Client {
+        self.state
+            .lock()
+            .unwrap()
+            .clients
+            .get(network)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn update(&self, config: &GreylistConfig, change: impl FnOnce(&mut State)) {
+        {
+            let mut state = self.state.lock().unwrap();
+            change(&mut state);
+        }
+        self.maintain(config);
+    }
+
+    /// Drop expired entries and write the state file every
+    /// MAINTENANCE_INTERVAL; the write happens off the request path
+    fn maintain(&self, config: &GreylistConfig) {
+        {
+            let mut last_maintenance = self.last_maintenance.lock().unwrap();
+            if last_maintenance.elapsed() < MAINTENANCE_INTERVAL {
+                return;
+            }
+            *last_maintenance = Instant::now();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.expire(config, now());
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let data = serde_json::to_string(&*state).expect("greylist state serializes");
+        drop(state);
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = write_atomic(&path, &data) {
+                warn!("Failed to save greylist state to {}: {}", path.display(), e);
+            }
+        });
+    }
+}
+
+/// Replace the file in one step so that a crash never leaves a partial state
+fn write_atomic(path: &Path, data: &str) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, data)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+struct RedisStore {
+    client: redis::Client,
+    connection: tokio::sync::OnceCell<redis::aio::ConnectionManager>,
+    prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    fn new(url: &str, prefix: String) -> Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url).context("invalid greylist redis-url")?,
+            connection: tokio::sync::OnceCell::new(),
+            prefix,
+        })
+    }
+
+    /// Connect lazily so that startup does not depend on Redis availability
+    async fn connection(&self) -> Result<redis::aio::ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| redis::aio::ConnectionManager::new(self.client.clone()))
+            .await?;
+        Ok(connection.clone())
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        use redis::AsyncCommands;
+        let mut connection = self.connection().await?;
+        let data: Option<String> = connection.get(format!("{}{}", self.prefix, key)).await?;
+        Ok(data.and_then(|data| serde_json::from_str(&data).ok()))
+    }
+
+    async fn set<T: Serialize>(&self, key: &str, value: &T, ttl: u64) -> Result<()> {
+        use redis::AsyncCommands;
+        let mut connection = self.connection().await?;
+        let data = serde_json::to_string(value)?;
+        let _: () = connection
+            .set_ex(format!("{}{}", self.prefix, key), data, ttl)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore").field("prefix", &self.prefix).finish()
+    }
+}
+
+#[derive(Debug)]
+enum Store {
+    Memory(MemoryStore),
+    #[cfg(feature = "redis")]
+    Redis(RedisStore),
+}
+
+/// Local greylisting: the first delivery attempt of an unknown (client
+/// network, sender, recipient) triplet is deferred, retries after the delay
+/// pass and are remembered
+#[derive(Debug)]
+pub struct Greylist {
+    config: GreylistConfig,
+    store: Store,
+}
+
+impl Greylist {
+    pub fn new(config: &GreylistConfig) -> Result<Self> {
+        let store = match &config.redis_url {
+            #[cfg(feature = "redis")]
+            Some(url) => Store::Redis(RedisStore::new(url, config.key_prefix.clone())?),
+            #[cfg(not(feature = "redis"))]
+            Some(_) => anyhow::bail!("built without redis support"),
+            None => Store::Memory(MemoryStore::open(config)?),
+        };
+        Ok(Self {
+            config: config.clone(),
+            store,
+        })
+    }
+
+    async fn triplet(&self, key: &str) -> Result<Option<Triplet>> {
+        match &self.store {
+            Store::Memory(store) => Ok(store.triplet(key)),
+            #[cfg(feature = "redis")]
+            Store::Redis(store) => store.get(&format!("triplet:{}", key)).await,
+        }
+    }
+
+    async fn set_triplet(&self, key: &str, triplet: Triplet) -> Result<()> {
+        match &self.store {
+            Store::Memory(store) => {
+                store.update(&self.config, |state| {
+                    state.triplets.insert(key.to_string(), triplet);
+                });
+                Ok(())
+            }
+            #[cfg(feature = "redis")]
+            Store::Redis(store) => {
+                let ttl = if triplet.passed {
+                    self.config.max_age
+                } else {
+                    self.config.retry_window
+                };
+                store.set(&format!("triplet:{}", key), &triplet, ttl).await
+            }
+        }
+    }
+
+    async fn client(&self, network: &str) -> Result<Client> {
+        match &self.store {
+            Store::Memory(store) => Ok(store.client(network)),
+            #[cfg(feature = "redis")]
+            Store::Redis(store) => Ok(store
+                .get(&format!("client:{}", network))
+                .await?
+                .unwrap_or_default()),
+        }
+    }
+
+    async fn set_client(&self, network: &str, client: Client) -> Result<()> {
+        match &self.store {
+            Store::Memory(store) => {
+                store.update(&self.config, |state| {
+                    state.clients.insert(network.to_string(), client);
+                });
+                Ok(())
+            }
+            #[cfg(feature = "redis")]
+            Store::Redis(store) => {
+                store
+                    .set(&format!("client:{}", network), &client, self.config.max_age)
+                    .await
+            }
+        }
+    }
+
+    /// Policy action for a policy delegation request: DUNNO or the
+    /// configured greylist action
+    pub async fn check(&self, request: &str) -> Result<String> {
+        let attributes: HashMap<&str, &str> = request
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let (Some(address), Some(recipient)) =
+            (attributes.get("client_address"), attributes.get("recipient"))
+        else {
+            // Only recipient checks can be greylisted
+            return Ok("DUNNO".to_string());
+        };
+        let sender = match attributes.get("sender") {
+            Some(sender) if !sender.is_empty() => sender.to_lowercase(),
+            _ => "<>".to_string(),
+        };
+        let network = client_network(&self.config, address);
+        let now = now();
+
+        let mut client = self.client(&network).await?;
+        if self.config.auto_whitelist > 0 && client.deliveries >= self.config.auto_whitelist {
+            debug!("Greylist: {} is auto-whitelisted", sensitive(&network));
+            client.last_seen = now;
+            self.set_client(&network, client).await?;
+            return Ok("DUNNO".to_string());
+        }
+
+        let key = format!("{}/{}/{}", network, sender, recipient.to_lowercase());
+        let triplet = self.triplet(&key).await?.filter(|triplet| {
+            let ttl = if triplet.passed {
+                self.config.max_age
+            } else {
+                self.config.retry_window
+            };
+            now.saturating_sub(triplet.last_seen) < ttl
+        });
+
+        match triplet {
+            None => {
+                debug!("Greylist: new triplet {}", sensitive(&key));
+                let triplet = Triplet {
+                    first_seen: now,
+                    last_seen: now,
+                    passed: false,
+                };
+                self.set_triplet(&key, triplet).await?;
+                Ok(self.config.action.clone())
+            }
+            Some(triplet) if !triplet.passed && now.saturating_sub(triplet.first_seen) < self.config.delay => {
+                debug!("Greylist: retry too early for {}", sensitive(&key));
+                Ok(self.config.action.clone())
+            }
+            Some(triplet) => {
+                if !triplet.passed {
+                    debug!(
+                        "Greylist: {} passed after {} seconds",
+                        sensitive(&key),
+                        now.saturating_sub(triplet.first_seen)
+                    );
+                }
+                let triplet = Triplet {
+                    last_seen: now,
+                    passed: true,
+                    ..triplet
+                };
+                self.set_triplet(&key, triplet).await?;
+                client.deliveries = client.deliveries.saturating_add(1);
+                client.last_seen = now;
+                self.set_client(&network, client).await?;
+                Ok("DUNNO".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn greylist(auto_whitelist: u32) -> Greylist {
+        Greylist::new(&GreylistConfig {
+            delay: 300,
+            retry_window: 3600,
+            max_age: default_max_age(),
+            auto_whitelist,
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+            action: default_action(),
+            state_file: None,
+            redis_url: None,
+            key_prefix: default_key_prefix(),
+        })
+        .unwrap()
+    }
+
+    fn request(client: &str, recipient: &str) -> String {
+        format!(
+            "request=smtpd_access_policy\nclient_address={}\nsender=a@example.org\n\
+             recipient={}\n",
+            client, recipient
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_pass_after_the_delay() {
+        let greylist = greylist(0);
+        let request = request("192.0.2.1", "b@example.com");
+        assert_eq!(greylist.check(&request).await.unwrap(), default_action());
+
+        tokio::time::advance(Duration::from_secs(150)).await;
+        assert_eq!(greylist.check(&request).await.unwrap(), default_action());
+
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert_eq!(greylist.check(&request).await.unwrap(), "DUNNO");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unconfirmed_triplets_expire_after_the_retry_window() {
+        let greylist = greylist(0);
+        let request = request("192.0.2.1", "b@example.com");
+        greylist.check(&request).await.unwrap();
+
+        tokio::time::advance(Duration::from_secs(4000)).await;
+        assert_eq!(greylist.check(&request).await.unwrap(), default_action());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_networks_are_auto_whitelisted() {
+        let greylist = greylist(1);
+        let first = request("192.0.2.1", "b@example.com");
+        greylist.check(&first).await.unwrap();
+        tokio::time::advance(Duration::from_secs(400)).await;
+        assert_eq!(greylist.check(&first).await.unwrap(), "DUNNO");
+
+        // Another host of the same /24, another recipient
+        let second = request("192.0.2.2", "c@example.com");
+        assert_eq!(greylist.check(&second).await.unwrap(), "DUNNO");
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::Result;
+use futures_util::future::join_all;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use crate::config::Endpoint;
+use crate::server;
+
+const BUFFER_SIZE: usize = 4096;
+
+fn default_upstream_timeout() -> u64 {
+    2000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthConfig {
+    #[serde(default)]
+    pub bind_address: String,
+    pub bind_port: u16,
+    /// /readyz also requires a TCP connection to every REST target
+    #[serde(default)]
+    pub check_upstream: bool,
+    #[serde(default = "default_upstream_timeout")]
+    pub upstream_timeout: u64, // milliseconds
+}
+
+/// Readiness as seen by the probes
+pub struct HealthState {
+    /// Every listener is bound; cleared again when shutting down
+    ready: AtomicBool,
+    endpoints: RwLock<Vec<Arc<Endpoint>>>,
+}
+
+impl HealthState {
+    pub fn new(endpoints: Vec<Arc<Endpoint>>) -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            endpoints: RwLock::new(endpoints),
+        }
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+
+    /// Replace the endpoint list after a configuration reload
+    pub fn set_endpoints(&self, endpoints: Vec<Arc<Endpoint>>) {
+        *self.endpoints.write().unwrap() = endpoints;
+    }
+
+    /// host:port of every REST target, once each
+    fn upstreams(&self) -> BTreeSet<String> {
+        let endpoints = self.endpoints.read().unwrap();
+        endpoints
+            .iter()
+            .flat_map(|endpoint| {
+                let target = (!endpoint.target.is_empty()).then_some(&endpoint.target);
+                let targets = endpoint.targets.iter().map(|target| &target.url);
+                target.into_iter().chain(targets)
+            })
+            .filter_map(|url| {
+                // {key} and {map} placeholders are not valid in a host
+                let url = Url::parse(&url.replace(['{', '}'], "")).ok()?;
+                Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?))
+            })
+            .collect()
+    }
+}
+
+/// Unreachable upstreams, empty when all accept a TCP connection in time
+async fn unreachable_upstreams(state: &HealthState, timeout: Duration) -> Vec<String> {
+    let upstreams: Vec<String> = state.upstreams().into_iter().collect();
+    let reachable = join_all(upstreams.iter().map(|addr| async move {
+        matches!(
+            tokio::time::timeout(timeout, TcpStream::connect(addr.as_str())).await,
+            Ok(Ok(_))
+        )
+    }))
+    .await;
+    upstreams
+        .into_iter()
+        .zip(reachable)
+        .filter_map(|(addr, reachable)| (!reachable).then_some(addr))
+        .collect()
+}
+
+/// Bind the probe listener, before privileges are dropped
+pub async fn bind_health(config: &HealthConfig) -> Result<TcpListener> {
+    let addr = format!("{}:{}", config.bind_address, config.bind_port);
+    Ok(TcpListener::bind(&addr).await?)
+}
+
+/// Minimal HTTP/1.1 probe server
+///
+/// - `GET /healthz` 200 while the process runs
+/// - `GET /readyz`  200 once all listeners are bound and until shutdown
+///   begins, 503 otherwise (also while a listener is down, and with
+///   `check-upstream` while a REST target is unreachable)
+pub async fn start_health(
+    config: HealthConfig,
+    listener: TcpListener,
+    state: Arc<HealthState>,
+) -> Result<()> {
+    let addr = format!("{}:{}", config.bind_address, config.bind_port);
+    let config = Arc::new(config);
+
+    info!("Health probes listening on {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let state = Arc::clone(&state);
+                let config = Arc::clone(&config);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_health_connection(socket, &state, &config).await {
+                        debug!("Health connection error from {}: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Health accept error: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_health_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    state: &HealthState,
+    config: &HealthConfig,
+) -> Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let n = socket.read(&mut buffer).await?;
+    if n == 0 {
+        return Ok(());
+    }
+
+    let request = String::from_utf8_lossy(&buffer[..n]);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let target = request_line.next().unwrap_or("");
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+
+    let (status, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") => (200, "ok\n".to_string()),
+        ("GET" | "HEAD", "/readyz") if !state.ready.load(Ordering::SeqCst) => {
+            (503, "not ready\n".to_string())
+        }
+        ("GET" | "HEAD", "/readyz") if !server::down_listeners().is_empty() => {
+            let down: Vec<String> =
+                server::down_listeners().into_iter().map(|(name, _)| name).collect();
+            (503, format!("not listening: {}\n", down.join(", ")))
+        }
+        ("GET" | "HEAD", "/readyz") if config.check_upstream => {
+            let timeout = Duration::from_millis(config.upstream_timeout);
+            let unreachable = unreachable_upstreams(state, timeout).await;
+            if unreachable.is_empty() {
+                (200, "ready\n".to_string())
+            } else {
+                warn!("Readiness probe: unreachable upstreams {}", unreachable.join(", "));
+                (503, format!("unreachable: {}\n", unreachable.join(", ")))
+            }
+        }
+        ("GET" | "HEAD", "/readyz") => (200, "ready\n".to_string()),
+        _ => (404, "not found\n".to_string()),
+    };
+
+    let reason = match status {
+        200 => "OK",
+        503 => "Service Unavailable",
+        _ => "Not Found",
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
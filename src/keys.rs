@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// One step of the key normalization pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyNormalization {
+    Lowercase,
+    /// Surrounding whitespace
+    Trim,
+    /// Surrounding angle brackets, as in "<user@example.com>"
+    StripBrackets,
+    /// Part after the last @; keys without @ are kept
+    Domain,
+    /// Part before the last @; keys without @ are kept
+    Localpart,
+}
+
+impl KeyNormalization {
+    fn apply<'a>(&self, key: Cow<'a, str>) -> Cow<'a, str> {
+        match self {
+            KeyNormalization::Lowercase => {
+                if key.chars().any(char::is_uppercase) {
+                    Cow::Owned(key.to_lowercase())
+                } else {
+                    key
+                }
+            }
+            KeyNormalization::Trim => map_str(key, str::trim),
+            KeyNormalization::StripBrackets => map_str(key, |key| {
+                key.strip_prefix('<')
+                    .and_then(|key| key.strip_suffix('>'))
+                    .unwrap_or(key)
+            }),
+            KeyNormalization::Domain => map_str(key, |key| {
+                key.rsplit_once('@').map_or(key, |(_, domain)| domain)
+            }),
+            KeyNormalization::Localpart => map_str(key, |key| {
+                key.rsplit_once('@').map_or(key, |(localpart, _)| localpart)
+            }),
+        }
+    }
+}
+
+/// Apply a slicing step without copying borrowed keys
+fn map_str<'a>(key: Cow<'a, str>, f: impl Fn(&str) -> &str) -> Cow<'a, str> {
+    match key {
+        Cow::Borrowed(key) => Cow::Borrowed(f(key)),
+        Cow::Owned(key) => Cow::Owned(f(&key).to_string()),
+    }
+}
+
+/// Run the steps in order over a lookup key
+pub fn normalize<'a>(steps: &[KeyNormalization], key: &'a str) -> Cow<'a, str> {
+    steps
+        .iter()
+        .fold(Cow::Borrowed(key), |key, step| step.apply(key))
+}
+
+/// Regex rewrite of lookup keys, e.g. "^([^+@]+)\\+[^@]*@" -> "$1@"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct KeyRewriteRule {
+    pub pattern: String,
+    /// May refer to capture groups as $1 or ${name}
+    pub replacement: String,
+}
+
+/// Compiled key-rewrite rules; the first matching rule wins
+#[derive(Debug)]
+pub struct KeyRewriter {
+    rules: Vec<(Regex, String)>,
+}
+
+impl KeyRewriter {
+    pub fn new(rules: &[KeyRewriteRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let pattern = Regex::new(&rule.pattern)
+                    .with_context(|| format!("Invalid key-rewrite pattern '{}'", rule.pattern))?;
+                Ok((pattern, rule.replacement.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The rewritten key, None if no rule matches
+    pub fn rewrite(&self, key: &str) -> Option<String> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(key))
+            .map(|(pattern, replacement)| pattern.replace(key, replacement.as_str()).into_owned())
+    }
+}
@@ -0,0 +1,7 @@
+//! Library API of the connector, for code that embeds or tests against it
+//!
+//! The daemon itself is the `postfix-rest-api-connector` binary; this only
+//! exposes the clock its time-based behaviour runs on, so tests can drive it
+//! with `tokio::time::pause()` and `tokio::time::advance()`.
+
+pub mod clock;
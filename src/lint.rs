@@ -0,0 +1,141 @@
+use crate::auth::AuthConfig;
+use crate::config::{Config, Endpoint, EndpointMode};
+
+// Postfix gives up on most table lookups well before this
+const TYPICAL_LOOKUP_TIMEOUT_MS: u64 = 10000;
+
+fn is_wildcard(address: &str) -> bool {
+    matches!(address, "" | "0.0.0.0" | "::" | "[::]")
+}
+
+/// Worst case time a lookup can take upstream, including retries and backoff
+fn worst_case_ms(endpoint: &Endpoint) -> u64 {
+    let retry = match &endpoint.retry {
+        Some(retry) => retry,
+        None => return endpoint.request_timeout,
+    };
+    let attempts = u64::from(retry.max_attempts.max(1));
+    let backoff: u64 = (0..attempts - 1)
+        .map(|n| retry.backoff_base.saturating_mul(1u64 << n.min(16)) + retry.jitter)
+        .sum();
+    endpoint
+        .request_timeout
+        .saturating_mul(attempts)
+        .saturating_add(backoff)
+}
+
+/// Legal but unwise settings, one message per finding
+pub fn lint(config: &Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for endpoint in &config.endpoints {
+        let name = &endpoint.name;
+
+        let worst_case = worst_case_ms(endpoint);
+        if worst_case > TYPICAL_LOOKUP_TIMEOUT_MS {
+            warnings.push(format!(
+                "Endpoint '{}': a request can take up to {} ms including retries, longer than typical Postfix lookup timeouts ({} ms)",
+                name, worst_case, TYPICAL_LOOKUP_TIMEOUT_MS
+            ));
+        }
+
+        let high_volume = !matches!(
+            endpoint.mode,
+            EndpointMode::Policy
+                | EndpointMode::Greylist
+                | EndpointMode::Quota
+                | EndpointMode::Milter
+                | EndpointMode::AuthPolicy
+        );
+        if high_volume && endpoint.cache.is_none() {
+            warnings.push(format!(
+                "Endpoint '{}': no cache configured, every lookup goes upstream",
+                name
+            ));
+        }
+
+        if !endpoint.auth_token.is_empty() {
+            warnings.push(format!(
+                "Endpoint '{}': auth-token is stored in plaintext in the config, consider auth-token-file",
+                name
+            ));
+        }
+
+        if matches!(&endpoint.auth, Some(auth) if auth.inline_secret().is_some()) {
+            warnings.push(format!(
+                "Endpoint '{}': auth secret is stored in plaintext in the config, use the matching *-file setting",
+                name
+            ));
+        }
+
+        if let Some(oauth2) = &endpoint.oauth2 {
+            if !oauth2.client_secret.is_empty() {
+                warnings.push(format!(
+                    "Endpoint '{}': oauth2 client-secret is stored in plaintext in the config, consider client-secret-file",
+                    name
+                ));
+            }
+            if oauth2.token_url.starts_with("http://") {
+                warnings.push(format!(
+                    "Endpoint '{}': oauth2 client credentials are sent over plain http",
+                    name
+                ));
+            }
+        }
+
+        if matches!(&endpoint.signing, Some(signing) if !signing.secret.is_empty()) {
+            warnings.push(format!(
+                "Endpoint '{}': signing secret is stored in plaintext in the config, consider secret-file",
+                name
+            ));
+        }
+
+        if matches!(&endpoint.proxy, Some(proxy) if !proxy.password.is_empty()) {
+            warnings.push(format!(
+                "Endpoint '{}': proxy password is stored in plaintext in the config, consider password-file",
+                name
+            ));
+        }
+
+        if matches!(&endpoint.tls, Some(tls) if tls.danger_accept_invalid_certs) {
+            warnings.push(format!(
+                "Endpoint '{}': TLS certificate verification is disabled (danger-accept-invalid-certs)",
+                name
+            ));
+        }
+
+        let cleartext = std::iter::once(endpoint.target.as_str())
+            .chain(endpoint.targets.iter().map(|target| target.url.as_str()))
+            .any(|url| url.starts_with("http://"));
+        let has_token = !endpoint.auth_token.is_empty()
+            || endpoint.auth_token_file.is_some()
+            || !matches!(endpoint.auth, None | Some(AuthConfig::None))
+            || endpoint.oauth2.is_some();
+        if cleartext && has_token {
+            warnings.push(format!("Endpoint '{}': auth token is sent over plain http", name));
+        }
+
+        if endpoint.bind_socket.is_none() && is_wildcard(&endpoint.bind_address) {
+            warnings.push(format!(
+                "Endpoint '{}': listens on all interfaces without access control, bind to 127.0.0.1 or a Unix socket",
+                name
+            ));
+        }
+    }
+
+    if let Some(admin) = &config.admin {
+        if admin.bind_socket.is_none() && is_wildcard(&admin.bind_address) {
+            warnings.push(
+                "Admin API listens on all interfaces, bind it to 127.0.0.1 or a Unix socket"
+                    .to_string(),
+            );
+        }
+        if admin.token.is_none() {
+            warnings.push(
+                "Admin API has no token, anyone who can connect can change it".to_string(),
+            );
+        }
+    }
+
+    warnings
+}
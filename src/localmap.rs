@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::secret::WatchedFile;
+
+/// "key value" lines like a Postfix source map; # starts a comment line
+fn parse_map(path: &Path) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read map file {}", path.display()))?;
+
+    let mut entries = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(char::is_whitespace)
+            .map(|(key, value)| (key, value.trim()))
+            .filter(|(_, value)| !value.is_empty())
+            .with_context(|| {
+                format!("{}:{}: expected \"key value\"", path.display(), number + 1)
+            })?;
+        entries.insert(key.to_string(), value.to_string());
+    }
+    Ok(entries)
+}
+
+/// Lookup table from a local file, reloaded when the file changes
+#[derive(Debug)]
+pub struct LocalMap {
+    file: WatchedFile<HashMap<String, String>>,
+}
+
+impl LocalMap {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: WatchedFile::open(path, "Map file", parse_map)?,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.file.get().get(key).cloned()
+    }
+}
@@ -0,0 +1,511 @@
+//! Log output format and destination
+//!
+//! The process logger starts out as plain env_logger text on stderr (see
+//! [`crate::privacy::init_logger`]) and is switched to the configured output
+//! once the configuration is loaded. RUST_LOG still selects the levels,
+//! except where an endpoint sets its own `log-level`.
+//!
+//! Connections, requests and lookups run inside `tracing` spans, whose
+//! fields [`SpanLayer`] adds to the records written inside them. Every
+//! request gets a generated ID, which is added to its log records and sent
+//! upstream as X-Request-ID, so one lookup can be followed from the socket
+//! read through the HTTP call to the response.
+
+use anyhow::{Context, Result};
+use env_logger::fmt::Formatter;
+use log::kv::{self, Key, VisitSource};
+use log::{info, warn, Level, LevelFilter, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record as SpanRecord};
+use tracing::{Instrument, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::access_log;
+use crate::config::{Endpoint, EndpointMode};
+use crate::privacy::{self, sensitive};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// env_logger's human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for Loki, Elasticsearch and similar
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    Stdout,
+    /// Append to `file`
+    File,
+    /// The local syslog daemon, facility mail
+    Syslog,
+}
+
+// syslog(3) LOG_MAIL
+const FACILITY_MAIL: u8 = 2;
+
+fn default_syslog_tag() -> String {
+    "postfix-rest-api-connector".to_string()
+}
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub target: LogTarget,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// Program name in syslog lines, as Postfix's syslog_name
+    #[serde(default = "default_syslog_tag")]
+    pub syslog_tag: String,
+    #[serde(default = "default_syslog_socket")]
+    pub syslog_socket: PathBuf,
+    /// One info record per socketmap/TCP lookup with endpoint, client,
+    /// key, result, upstream status and latency
+    #[serde(default)]
+    pub lookups: bool,
+}
+
+impl LoggingConfig {
+    pub fn validate(&self) -> Result<()> {
+        match (self.target, &self.file) {
+            (LogTarget::File, None) => anyhow::bail!("target 'file' requires file"),
+            (LogTarget::File, Some(_)) => {}
+            (_, Some(_)) => anyhow::bail!("file is only used with target 'file'"),
+            (_, None) => {}
+        }
+        if self.syslog_tag.is_empty()
+            || !self.syslog_tag.chars().all(|c| c.is_ascii_graphic() && c != ':')
+        {
+            anyhow::bail!("syslog-tag must be printable ASCII without spaces or ':'");
+        }
+        Ok(())
+    }
+}
+
+static LOOKUPS: AtomicBool = AtomicBool::new(false);
+
+/// Switch the process logger to the configured output; read at startup
+/// only, so changes require a restart
+pub fn init(config: Option<&LoggingConfig>) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+
+    let mut builder = privacy::output_builder();
+    match config.target {
+        LogTarget::Stderr => builder.target(env_logger::Target::Stderr),
+        LogTarget::Stdout => builder.target(env_logger::Target::Stdout),
+        LogTarget::File => {
+            let path = config.file.as_ref().context("target 'file' requires file")?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            builder.target(env_logger::Target::Pipe(Box::new(file)))
+        }
+        LogTarget::Syslog => {
+            let writer = SyslogWriter::connect(&config.syslog_socket).with_context(|| {
+                format!("Failed to connect to syslog at {}", config.syslog_socket.display())
+            })?;
+            builder.target(env_logger::Target::Pipe(Box::new(writer)))
+        }
+    };
+    match (config.target, config.format) {
+        (LogTarget::Syslog, format) => {
+            // syslogd adds the timestamp and host; one record per datagram
+            let header = format!("{}[{}]: ", config.syslog_tag, std::process::id());
+            builder.format(move |buf, record| {
+                let priority = FACILITY_MAIL * 8 + severity(record.level());
+                write!(buf, "<{}>{}", priority, header)?;
+                match format {
+                    LogFormat::Text => {
+                        write!(buf, "{}: ", record.target())?;
+                        if let Some(id) = request_id() {
+                            write!(buf, "[{}] ", id)?;
+                        }
+                        write!(buf, "{}{}", span_text(), record.args())
+                    }
+                    LogFormat::Json => {
+                        let timestamp = buf.timestamp_millis().to_string();
+                        write!(buf, "{}", json_record(&timestamp, record))
+                    }
+                }
+            });
+        }
+        (_, LogFormat::Json) => {
+            builder.format(|buf, record| {
+                let timestamp = buf.timestamp_millis().to_string();
+                writeln!(buf, "{}", json_record(&timestamp, record))
+            });
+        }
+        (_, LogFormat::Text) => {
+            builder.format(format_text);
+        }
+    }
+
+    privacy::set_logger_output(builder.build());
+    LOOKUPS.store(config.lookups, Ordering::Relaxed);
+    Ok(())
+}
+
+/// syslog(3) severity of a log level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Sends each formatted record as one datagram to the syslog socket
+struct SyslogWriter {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl SyslogWriter {
+    fn connect(path: &Path) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            socket,
+        })
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.socket.send(buf) {
+            Ok(n) => Ok(n),
+            // The socket is recreated when syslogd restarts
+            Err(_) => {
+                *self = Self::connect(&self.path)?;
+                self.socket.send(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Record fields as JSON values
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or(Value::Null);
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// env_logger's default layout, with the request ID and span fields after
+/// the target
+pub fn format_text(buf: &mut Formatter, record: &Record<'_>) -> io::Result<()> {
+    let style = buf.default_level_style(record.level());
+    write!(
+        buf,
+        "[{} {style}{:<5}{style:#} {}] ",
+        buf.timestamp_seconds(),
+        record.level(),
+        record.target()
+    )?;
+    if let Some(id) = request_id() {
+        write!(buf, "[{}] ", id)?;
+    }
+    writeln!(buf, "{}{}", span_text(), record.args())
+}
+
+fn json_record(timestamp: &str, record: &Record<'_>) -> Value {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), json!(timestamp));
+    fields.insert("level".to_string(), json!(record.level().as_str()));
+    fields.insert("target".to_string(), json!(record.target()));
+    fields.insert("message".to_string(), json!(record.args().to_string()));
+    if let Some(id) = request_id() {
+        fields.insert("request_id".to_string(), json!(id));
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    // The record's own fields win over the spans'
+    for (name, value) in span_fields() {
+        fields.entry(name.to_string()).or_insert_with(|| json!(value));
+    }
+    Value::Object(fields)
+}
+
+tokio::task_local! {
+    static CLIENT: String;
+    static LEVEL: LevelFilter;
+    static REQUEST_ID: String;
+}
+
+/// 16 hex digits, unique within the process and unpredictable across runs
+pub fn new_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// ID of the request being handled by the current task
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Fields recorded on a span, as formatted when they were recorded
+struct SpanFields(Vec<(&'static str, String)>);
+
+impl SpanFields {
+    fn set(&mut self, name: &'static str, value: String) {
+        match self.0.iter_mut().find(|(field, _)| *field == name) {
+            Some(field) => field.1 = value,
+            None => self.0.push((name, value)),
+        }
+    }
+}
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), format!("{:?}", value));
+    }
+}
+
+/// Keeps the fields of the connection, request and lookup spans, so the log
+/// output can add them to the records written inside those spans
+pub struct SpanLayer;
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = SpanFields(Vec::new());
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &SpanRecord<'_>, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(fields);
+        }
+    }
+}
+
+/// Install the tracing subscriber for the log output; with OTLP export,
+/// [`crate::telemetry::Telemetry::init`] installs it next to the exporter
+pub fn init_spans() -> Result<()> {
+    tracing_subscriber::registry()
+        .with(SpanLayer)
+        .try_init()
+        .context("Failed to install the tracing subscriber")
+}
+
+/// Fields of the spans the current record is written in, outermost first;
+/// an inner span's field replaces an outer one of the same name
+fn span_fields() -> Vec<(&'static str, String)> {
+    let mut fields = SpanFields(Vec::new());
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let Some(registry) = dispatch.downcast_ref::<Registry>() else {
+            return;
+        };
+        let Some(span) = registry.span(id) else {
+            return;
+        };
+        for span in span.scope().from_root() {
+            if let Some(recorded) = span.extensions().get::<SpanFields>() {
+                for (name, value) in &recorded.0 {
+                    fields.set(*name, value.clone());
+                }
+            }
+        }
+    });
+    fields.0
+}
+
+/// Span fields for a text line, e.g. `{endpoint=virtual key=example.com} `;
+/// the request ID is already in front of them
+fn span_text() -> String {
+    let fields: Vec<String> = span_fields()
+        .into_iter()
+        .filter(|(name, _)| *name != "request_id")
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    if fields.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}} ", fields.join(" "))
+    }
+}
+
+/// Serve a connection in its span, with the (already redacted) client
+/// address available to the lookup records and the endpoint's log-level
+/// applied to its records
+pub async fn connection<F: Future>(
+    endpoint: &Endpoint,
+    client: Option<String>,
+    future: F,
+) -> F::Output {
+    let span = tracing::info_span!(
+        "connection",
+        connection_id = %new_id(),
+        endpoint = endpoint.name.as_str(),
+        client = client.as_deref()
+    );
+    let future = future.instrument(span);
+    match (client, endpoint.log_level) {
+        (Some(client), Some(level)) => CLIENT.scope(client, LEVEL.scope(level, future)).await,
+        (Some(client), None) => CLIENT.scope(client, future).await,
+        (None, Some(level)) => LEVEL.scope(level, future).await,
+        (None, None) => future.await,
+    }
+}
+
+/// Level of this crate's records in the current task, when it serves a
+/// connection of an endpoint with its own log-level
+pub fn endpoint_level(target: &str) -> Option<LevelFilter> {
+    if !target.starts_with(env!("CARGO_CRATE_NAME")) {
+        return None;
+    }
+    LEVEL.try_with(|level| *level).ok()
+}
+
+/// Let records through up to the most verbose endpoint log-level
+pub fn set_endpoint_levels(endpoints: &[Endpoint]) {
+    let level = endpoints.iter().filter_map(|endpoint| endpoint.log_level).max();
+    privacy::set_scoped_max_level(level.unwrap_or(LevelFilter::Off));
+}
+
+/// Handle one request in its span, under a new request ID
+pub async fn request<F: Future>(future: F) -> F::Output {
+    let id = new_id();
+    let span = tracing::info_span!("request", request_id = %id);
+    REQUEST_ID.scope(id, future.instrument(span)).await
+}
+
+/// (Redacted) client address of the connection handled by the current task
+pub fn client() -> Option<String> {
+    CLIENT.try_with(Clone::clone).ok()
+}
+
+/// Whether lookups are recorded, as log records or in the access log
+pub fn lookups_enabled() -> bool {
+    LOOKUPS.load(Ordering::Relaxed) || access_log::enabled()
+}
+
+/// One finished lookup, as logged with `lookups` enabled
+pub struct LookupRecord<'a> {
+    pub endpoint: &'a str,
+    pub mode: &'a EndpointMode,
+    pub map: Option<&'a str>,
+    pub key: &'a str,
+    pub result: &'a str,
+    /// OK, NOTFOUND, TEMP or PERM, as answered to the client
+    pub class: &'static str,
+    /// hit, stale, miss, or none without a cache
+    pub cache: &'static str,
+    /// HTTP status of the last upstream request, if one was made
+    pub upstream_status: Option<u16>,
+    pub duration_ms: u64,
+}
+
+/// Warn about a lookup over the endpoint's slow-threshold-ms, with the time
+/// spent upstream so backend and connector delays can be told apart
+pub fn slow_lookup(
+    record: &LookupRecord<'_>,
+    threshold_ms: u64,
+    requests: usize,
+    upstream_ms: u64,
+) {
+    let client = client();
+    let key_hash = privacy::hash(record.key);
+    let connector_ms = record.duration_ms.saturating_sub(upstream_ms);
+    warn!(
+        endpoint = record.endpoint,
+        client = client.as_deref(),
+        map = record.map,
+        key_hash = key_hash.as_str(),
+        result = record.result,
+        cache = record.cache,
+        upstream_status = record.upstream_status,
+        upstream_requests = requests,
+        upstream_ms = upstream_ms,
+        duration_ms = record.duration_ms;
+        "Endpoint '{}': slow lookup of key {} in map {}: {} ms (threshold {} ms), \
+         {} ms in {} upstream request(s), {} ms in the connector; result {}, cache {}",
+        record.endpoint,
+        key_hash,
+        record.map.unwrap_or("-"),
+        record.duration_ms,
+        threshold_ms,
+        upstream_ms,
+        requests,
+        connector_ms,
+        record.result,
+        record.cache
+    );
+}
+
+pub fn lookup(record: LookupRecord<'_>) {
+    access_log::write(&record);
+    if !LOOKUPS.load(Ordering::Relaxed) {
+        return;
+    }
+    let client = client();
+    info!(
+        target: "lookup",
+        endpoint = record.endpoint,
+        client = client.as_deref(),
+        map = record.map,
+        key = &*privacy::redact(record.key),
+        result = record.result,
+        upstream_status = record.upstream_status,
+        duration_ms = record.duration_ms;
+        "Endpoint '{}': lookup {} from {}: {} (upstream status {}, {} ms)",
+        record.endpoint,
+        sensitive(record.key),
+        client.as_deref().unwrap_or("local"),
+        record.result,
+        record.upstream_status.map_or("-".to_string(), |status| status.to_string()),
+        record.duration_ms
+    );
+}
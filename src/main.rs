@@ -1,72 +1,514 @@
-use anyhow::Result;
-use log::{error, info};
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use sd_notify::NotifyState;
 use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
+use tokio::signal::unix::{signal as unix_signal, Signal, SignalKind};
 use tokio::sync::broadcast;
 
+mod access_log;
+mod action;
+mod admin;
+mod auth;
+mod authpolicy;
+mod backend;
+mod cache;
+mod chain;
+mod cidr;
+mod circuit;
+mod cli;
 mod config;
+mod dnsbl;
+mod events;
+mod geoip;
+mod greylist;
+mod health;
+mod keys;
+mod lint;
+mod localmap;
+mod logging;
+mod metrics;
+mod milter;
+mod mock;
+mod oauth;
+mod policy_cache;
+mod privacy;
+mod privileges;
 mod protocol;
+mod proxy;
+mod qos;
+mod quota;
+mod ratelimit;
+mod response;
+mod retry;
+mod secret;
 mod server;
+mod signing;
+mod simulate;
+mod sigv4;
+mod slo;
+mod sockopt;
+mod targets;
+mod telemetry;
+mod tls;
 
-use config::Config;
-use server::start_endpoint;
+// Shared with the library target rather than compiled twice
+use postfix_rest_api_connector::clock;
+use admin::{bind_admin, start_admin, AdminState, ConfigStatus};
+use config::{Config, Endpoint};
+use events::EventSender;
+use health::{bind_health, start_health, HealthState};
+use qos::Qos;
+use telemetry::Telemetry;
+use server::{active_connections, begin_drain, set_panic_limit, EndpointSupervisor};
+
+// How often the watchdog checks for dead endpoint tasks
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often a graceful shutdown checks for remaining connections
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Export the hash of the loaded configuration as an info metric
+fn export_config_info(config: &Config) -> String {
+    match config.hash() {
+        Ok(hash) => {
+            info!("Configuration hash: {}", hash);
+            metrics::reset("connector_config_info");
+            metrics::gauge_set(
+                "connector_config_info",
+                "Hash of the loaded configuration",
+                &[("hash", &hash)],
+                1.0,
+            );
+            hash
+        }
+        Err(e) => {
+            warn!("Failed to hash configuration: {}", e);
+            "unknown".to_string()
+        }
+    }
+}
+
+/// Export whether the most recent configuration load succeeded
+fn export_config_load(success: bool) {
+    metrics::gauge_set(
+        "connector_config_last_load_success",
+        "Whether the most recent configuration load succeeded",
+        &[],
+        if success { 1.0 } else { 0.0 },
+    );
+}
+
+/// Load the configuration file, falling back to the last good copy if it is invalid
+fn load_config(path: &str) -> Result<(Config, Option<String>)> {
+    let error = match Config::from_file(path) {
+        Ok(config) => return Ok((config, None)),
+        Err(e) => e,
+    };
+
+    let last_good = config::last_good_path(path);
+    match Config::from_file(&last_good) {
+        Ok(config) => {
+            error!(
+                "Invalid configuration ({:#}), starting with last good copy {}",
+                error, last_good
+            );
+            Ok((config, Some(format!("{:#}", error))))
+        }
+        Err(_) => Err(error),
+    }
+}
+
+/// Wait until all connections are closed or the deadline has passed
+async fn drain_connections(timeout: Duration) {
+    begin_drain();
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    while active_connections() > 0 {
+        if tokio::time::Instant::now() >= deadline {
+            warn!(
+                "Drain deadline reached with {} connections still open",
+                active_connections()
+            );
+            return;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    info!("All connections drained");
+}
+
+/// Initialize the runtime state of every endpoint, paired with a fingerprint of
+/// its effective configuration for reload diffing
+fn build_endpoints(
+    config: &Config,
+    qos: &Option<Arc<Qos>>,
+    events: &Option<EventSender>,
+) -> Result<Vec<(Arc<Endpoint>, String)>> {
+    let mut endpoints = Vec::new();
+
+    for endpoint in &config.endpoints {
+        let fingerprint = serde_json::to_string(&(endpoint, &config.qos))?;
+        let endpoint = Arc::new(
+            endpoint
+                .clone()
+                .with_client()?
+                .with_auth_token_file()?
+                .with_auth()?
+                .with_oauth2()?
+                .with_signing()?
+                .with_sigv4()?
+                .with_standby()?
+                .with_key_rewrite()?
+                .with_local_maps()?
+                .with_map_routes()?
+                .with_backend()?
+                .with_cache()?
+                .with_greylist()?
+                .with_geoip()?
+                .with_circuit_breaker()
+                .with_slo()
+                .with_dnsbl()
+                .with_rate_limit()
+                .with_quota()
+                .with_targets()
+                .with_maintenance()
+                .with_connection_limit()
+                .with_qos(qos.clone())
+                .with_events(events.clone()),
+        );
+        endpoints.push((endpoint, fingerprint));
+    }
+
+    Ok(endpoints)
+}
+
+/// Load the configuration again and apply the differences to the running endpoints
+async fn reload_config(
+    path: &str,
+    current: &Config,
+    qos: &Option<Arc<Qos>>,
+    events: &Option<EventSender>,
+    supervisor: &mut EndpointSupervisor,
+) -> Result<(Arc<Config>, Option<Arc<Qos>>)> {
+    let config = Config::from_file(path)?;
+
+    // Keep the QoS state (in-flight permits) unless its configuration changed
+    let qos_changed =
+        serde_json::to_string(&config.qos)? != serde_json::to_string(&current.qos)?;
+    let qos = match &config.qos {
+        Some(qos_config) if qos_changed => Some(Arc::new(Qos::new(qos_config)?)),
+        Some(_) => qos.clone(),
+        None => None,
+    };
+
+    if serde_json::to_string(&config.events)? != serde_json::to_string(&current.events)?
+        || serde_json::to_string(&config.admin)? != serde_json::to_string(&current.admin)?
+        || serde_json::to_string(&config.health)? != serde_json::to_string(&current.health)?
+        || serde_json::to_string(&config.logging)? != serde_json::to_string(&current.logging)?
+        || serde_json::to_string(&config.access_log)?
+            != serde_json::to_string(&current.access_log)?
+        || serde_json::to_string(&config.telemetry)? != serde_json::to_string(&current.telemetry)?
+    {
+        warn!(
+            "Changes to events, admin, health, logging and telemetry settings require a restart"
+        );
+    }
+    if config.run_as_user != current.run_as_user || config.run_as_group != current.run_as_group {
+        warn!("Changes to run-as-user and run-as-group require a restart");
+    }
+
+    let endpoints = build_endpoints(&config, &qos, events)?;
+    logging::set_endpoint_levels(&config.endpoints);
+    supervisor.reload(config.user_agent.clone(), endpoints).await;
+
+    Ok((Arc::new(config), qos))
+}
+
+/// Wait for a configuration reload request: SIGHUP or the admin API
+async fn next_reload(sighup: &mut Signal, admin: &AdminState) -> &'static str {
+    tokio::select! {
+        _ = sighup.recv() => "SIGHUP received",
+        _ = admin.reload_requested() => "Reload requested through the admin API",
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    privacy::init_logger();
 
     let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("check") => return cli::check(&args[2..]),
+        Some("lookup") => return cli::lookup(&args[2..]).await,
+        Some("opensmtpd-table") => return cli::opensmtpd_table(&args[2..]).await,
+        Some("mock") => return mock::run(&args[2..]).await,
+        Some("simulate") => return simulate::run(&args[2..]).await,
+        _ => {}
+    }
     if args.len() != 2 {
         eprintln!("Usage: {} <config-file>", args[0]);
+        eprintln!("       {} {}", args[0], cli::CHECK_USAGE);
+        eprintln!("       {} {}", args[0], cli::LOOKUP_USAGE);
+        eprintln!("       {} {}", args[0], cli::OPENSMTPD_TABLE_USAGE);
+        eprintln!("       {} {}", args[0], mock::MOCK_USAGE);
+        eprintln!("       {} {}", args[0], simulate::SIMULATE_USAGE);
         std::process::exit(1);
     }
 
-    info!("Starting Postfix REST API Connector...");
+    info!(
+        "Starting Postfix REST API Connector {} ({}, rustc {})...",
+        env!("CARGO_PKG_VERSION"),
+        env!("BUILD_GIT_SHA"),
+        env!("BUILD_RUSTC_VERSION")
+    );
+    metrics::gauge_set(
+        "connector_build_info",
+        "Version information of the running binary",
+        &[
+            ("version", env!("CARGO_PKG_VERSION")),
+            ("git_sha", env!("BUILD_GIT_SHA")),
+            ("rustc", env!("BUILD_RUSTC_VERSION")),
+        ],
+        1.0,
+    );
 
     // Load configuration
-    let config = Config::from_file(&args[1])?;
+    let (config, load_error) = load_config(&args[1])?;
+    privacy::init(config.privacy.as_ref());
+    logging::init(config.logging.as_ref())?;
+    logging::set_endpoint_levels(&config.endpoints);
+    access_log::init(config.access_log.as_ref())?;
+    let telemetry = config.telemetry.as_ref().map(Telemetry::init).transpose()?;
+    if telemetry.is_none() {
+        logging::init_spans()?;
+    }
     info!("Configuration loaded: {} endpoints", config.endpoints.len());
+    let config_hash = export_config_info(&config);
+    export_config_load(load_error.is_none());
 
     let config = Arc::new(config);
+    set_panic_limit(config.max_panics_per_minute);
 
     // Create shutdown channel
     let (shutdown_tx, _) = broadcast::channel(1);
 
-    // Start all endpoint servers
+    let qos = match &config.qos {
+        Some(qos) => Some(Arc::new(Qos::new(qos)?)),
+        None => None,
+    };
+
+    // Start the event publisher if configured
     let mut handles = Vec::new();
+    let events = match config.events.clone() {
+        Some(events_config) => {
+            let (sender, rx) = events::channel(&events_config);
+            handles.push(tokio::spawn(async move {
+                if let Err(e) = events::run_publisher(events_config, rx).await {
+                    error!("Event publisher error: {}", e);
+                }
+            }));
+            Some(sender)
+        }
+        None => None,
+    };
 
-    for endpoint in &config.endpoints {
-        let endpoint = Arc::new(endpoint.clone().with_client()?);
-        let user_agent = config.user_agent.clone();
+    // Start all endpoint servers
+    let mut supervisor = EndpointSupervisor::new(config.user_agent.clone());
+    if config.run_as_user.is_some() || config.run_as_group.is_some() {
+        // Restarted and reconfigured listeners reuse the sockets bound as root
+        supervisor.retain_listeners();
+    }
+
+    for (endpoint, fingerprint) in build_endpoints(&config, &qos, &events)? {
+        supervisor.start(endpoint, fingerprint).await?;
+    }
+
+    // Bind the admin and health listeners too, so they may use privileged ports
+    let admin_listener = match &config.admin {
+        Some(admin) => match bind_admin(admin).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Admin API error: {:#}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let health_listener = match &config.health {
+        Some(health) => match bind_health(health).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Health probe listener error: {:#}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Every listener is bound, so root is no longer needed
+    if config.run_as_user.is_some() || config.run_as_group.is_some() {
+        privileges::drop_privileges(config.run_as_user.as_deref(), config.run_as_group.as_deref())
+            .context("Failed to drop privileges")?;
+        supervisor.privileges_dropped();
+    }
+
+    // Start the admin API if configured
+    let admin_state = Arc::new(AdminState::new(
+        supervisor.endpoints(),
+        ConfigStatus {
+            hash: config_hash,
+            fallback: load_error.is_some(),
+            last_error: load_error.clone(),
+        },
+        config.user_agent.clone(),
+        args[1].clone(),
+    ));
+    if let (Some(admin), Some(listener)) = (config.admin.clone(), admin_listener) {
+        let state = Arc::clone(&admin_state);
         let mut shutdown_rx = shutdown_tx.subscribe();
 
-        let handle = tokio::spawn(async move {
+        handles.push(tokio::spawn(async move {
             tokio::select! {
-                result = start_endpoint(endpoint, user_agent) => {
+                result = start_admin(admin, listener, state) => {
                     if let Err(e) = result {
-                        error!("Endpoint error: {}", e);
+                        error!("Admin API error: {}", e);
                     }
                 }
                 _ = shutdown_rx.recv() => {
-                    info!("Endpoint received shutdown signal");
+                    info!("Admin API received shutdown signal");
                 }
             }
-        });
+        }));
+    }
+
+    // Start the health probe listener if configured
+    let health_state = Arc::new(HealthState::new(supervisor.endpoints()));
+    if let (Some(health), Some(listener)) = (config.health.clone(), health_listener) {
+        let state = Arc::clone(&health_state);
+        let mut shutdown_rx = shutdown_tx.subscribe();
 
-        handles.push(handle);
+        handles.push(tokio::spawn(async move {
+            tokio::select! {
+                result = start_health(health, listener, state) => {
+                    if let Err(e) = result {
+                        error!("Health probe listener error: {}", e);
+                    }
+                }
+                _ = shutdown_rx.recv() => {
+                    info!("Health probe listener received shutdown signal");
+                }
+            }
+        }));
     }
 
-    // Wait for shutdown signal
-    info!("All endpoints started. Press Ctrl+C to shutdown.");
-    
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Shutdown signal received, stopping...");
+    // Every endpoint started, so this configuration is good to fall back to
+    if load_error.is_none() {
+        if let Err(e) = config.save_last_good(&args[1]) {
+            warn!("Failed to save last good configuration: {:#}", e);
         }
-        Err(err) => {
-            error!("Unable to listen for shutdown signal: {}", err);
+    }
+
+    // Wait for shutdown signal, restarting dead endpoint tasks meanwhile
+    info!("All endpoints started. Press Ctrl+C to shutdown.");
+
+    // Every listener is bound at this point (Type=notify readiness)
+    health_state.set_ready(true);
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd of readiness: {}", e);
+    }
+
+    // Ping the systemd watchdog at half its interval, if enabled for the unit
+    let mut watchdog_usec = 0;
+    let systemd_watchdog_enabled = sd_notify::watchdog_enabled(false, &mut watchdog_usec);
+    let mut systemd_watchdog = tokio::time::interval(if systemd_watchdog_enabled {
+        Duration::from_micros(watchdog_usec / 2)
+    } else {
+        Duration::from_secs(3600)
+    });
+
+    let mut sighup = unix_signal(SignalKind::hangup())?;
+    let mut sigterm = unix_signal(SignalKind::terminate())?;
+    let mut sigquit = unix_signal(SignalKind::quit())?;
+    let mut sigusr1 = unix_signal(SignalKind::user_defined1())?;
+    let mut config = config;
+    let mut qos = qos;
+
+    let mut watchdog = tokio::time::interval(WATCHDOG_INTERVAL);
+    let graceful = loop {
+        tokio::select! {
+            _ = watchdog.tick() => supervisor.check().await,
+            trigger = next_reload(&mut sighup, &admin_state) => {
+                info!("{}, reloading configuration from {}", trigger, args[1]);
+                match reload_config(&args[1], &config, &qos, &events, &mut supervisor).await {
+                    Ok((new_config, new_qos)) => {
+                        config = new_config;
+                        qos = new_qos;
+                        info!("Configuration reloaded: {} endpoints", config.endpoints.len());
+                        export_config_load(true);
+                        admin_state.set_user_agent(config.user_agent.clone());
+                        admin_state.set_config_status(ConfigStatus {
+                            hash: export_config_info(&config),
+                            fallback: false,
+                            last_error: None,
+                        });
+                        if let Err(e) = config.save_last_good(&args[1]) {
+                            warn!("Failed to save last good configuration: {:#}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Reload failed, keeping current configuration: {:#}", e);
+                        export_config_load(false);
+                        admin_state.set_config_status(ConfigStatus {
+                            last_error: Some(format!("{:#}", e)),
+                            ..admin_state.config_status()
+                        });
+                    }
+                }
+                admin_state.set_endpoints(supervisor.endpoints());
+                health_state.set_endpoints(supervisor.endpoints());
+            }
+            _ = systemd_watchdog.tick(), if systemd_watchdog_enabled => {
+                let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            }
+            _ = sigterm.recv() => {
+                info!("SIGTERM received, draining connections...");
+                break true;
+            }
+            _ = sigusr1.recv() => {
+                info!("SIGUSR1 received, runtime statistics:\n{}", supervisor.dump_stats());
+            }
+            _ = sigquit.recv() => {
+                error!(
+                    "SIGQUIT received, aborting. State at exit:\n{}{}",
+                    supervisor.dump_state(),
+                    metrics::render()
+                );
+                std::process::abort();
+            }
+            result = signal::ctrl_c() => {
+                match result {
+                    Ok(()) => {
+                        info!("Shutdown signal received, stopping...");
+                    }
+                    Err(err) => {
+                        error!("Unable to listen for shutdown signal: {}", err);
+                    }
+                }
+                break false;
+            }
         }
+    };
+
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+    // Load balancers stop sending new connections while this one drains
+    health_state.set_ready(false);
+    supervisor.shutdown().await;
+
+    // Stop accepting first, then let in-flight requests finish
+    if graceful {
+        drain_connections(Duration::from_millis(config.drain_timeout)).await;
     }
 
     // Send shutdown signal to all tasks
@@ -80,6 +522,10 @@ async fn main() -> Result<()> {
         handle.abort();
     }
 
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown().await;
+    }
+
     info!("Shutdown complete");
     Ok(())
 }
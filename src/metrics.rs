@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+
+use crate::privacy;
+
+// Upper bounds in seconds, from a cache hit to a lookup that makes smtpd wait
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+struct Family {
+    kind: &'static str,
+    help: &'static str,
+    series: BTreeMap<String, f64>,
+    histograms: BTreeMap<String, Histogram>,
+}
+
+/// Observations per bucket of LATENCY_BUCKETS, the last one for +Inf
+#[derive(Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; LATENCY_BUCKETS.len() + 1];
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Estimated quantile, interpolated within its bucket like Prometheus'
+    /// histogram_quantile(); the highest bound when it falls into +Inf
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = q * self.count() as f64;
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            if (seen + count) as f64 >= rank && *count > 0 {
+                let Some(upper) = LATENCY_BUCKETS.get(i) else {
+                    break;
+                };
+                let lower = if i == 0 { 0.0 } else { LATENCY_BUCKETS[i - 1] };
+                return lower + (upper - lower) * (rank - seen as f64) / *count as f64;
+            }
+            seen += count;
+        }
+        LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1]
+    }
+}
+
+type Registry = Mutex<BTreeMap<&'static str, Family>>;
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn series_key(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = privacy::label_value(name, value);
+            format!("{}=\"{}\"", name, escape_label(&value))
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// A series key with one more label, e.g. a histogram bucket's le
+fn with_label(key: &str, name: &str, value: &str) -> String {
+    match key.strip_suffix('}') {
+        Some(labels) => format!("{},{}=\"{}\"}}", labels, name, value),
+        None => format!("{{{}=\"{}\"}}", name, value),
+    }
+}
+
+fn family<'a>(
+    registry: &'a mut BTreeMap<&'static str, Family>,
+    kind: &'static str,
+    name: &'static str,
+    help: &'static str,
+) -> &'a mut Family {
+    registry.entry(name).or_insert_with(|| Family {
+        kind,
+        help,
+        series: BTreeMap::new(),
+        histograms: BTreeMap::new(),
+    })
+}
+
+fn update(
+    kind: &'static str,
+    name: &'static str,
+    help: &'static str,
+    labels: &[(&str, &str)],
+    apply: impl FnOnce(&mut f64),
+) {
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let family = family(&mut registry, kind, name, help);
+    apply(family.series.entry(series_key(labels)).or_insert(0.0));
+}
+
+/// Increment a counter by one
+pub fn counter_inc(name: &'static str, help: &'static str, labels: &[(&str, &str)]) {
+    update("counter", name, help, labels, |value| *value += 1.0);
+}
+
+/// Set a gauge to an absolute value
+pub fn gauge_set(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+    update("gauge", name, help, labels, |current| *current = value);
+}
+
+/// Add to a gauge, e.g. +1/-1 for something currently in progress
+pub fn gauge_add(name: &'static str, help: &'static str, labels: &[(&str, &str)], delta: f64) {
+    update("gauge", name, help, labels, |value| *value += delta);
+}
+
+/// Record a latency in seconds in a histogram
+pub fn histogram_observe(
+    name: &'static str,
+    help: &'static str,
+    labels: &[(&str, &str)],
+    seconds: f64,
+) {
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let family = family(&mut registry, "histogram", name, help);
+    family.histograms.entry(series_key(labels)).or_default().observe(seconds);
+}
+
+/// Drop one series of a metric, e.g. of a removed endpoint
+pub fn remove(name: &'static str, labels: &[(&str, &str)]) {
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(family) = registry.get_mut(name) {
+        let key = series_key(labels);
+        family.series.remove(&key);
+        family.histograms.remove(&key);
+    }
+}
+
+/// Drop all series of a metric, e.g. before re-setting an info metric's labels
+pub fn reset(name: &'static str) {
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(family) = registry.get_mut(name) {
+        family.series.clear();
+        family.histograms.clear();
+    }
+}
+
+/// Counter series with a given label value, e.g. everything of one
+/// endpoint, keyed by name and labels as rendered
+pub fn counters_with(label: &str, value: &str) -> BTreeMap<String, f64> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let value = privacy::label_value(label, value);
+    let pair = format!("{}=\"{}\"", label, escape_label(&value));
+    let (first, other) = (format!("{{{}", pair), format!(",{}", pair));
+
+    registry
+        .iter()
+        .filter(|(_, family)| family.kind == "counter")
+        .flat_map(|(name, family)| {
+            family
+                .series
+                .iter()
+                .filter(|(labels, _)| labels.contains(&first) || labels.contains(&other))
+                .map(move |(labels, value)| (format!("{}{}", name, labels), *value))
+        })
+        .collect()
+}
+
+/// Estimated p50, p90 and p99 of the histogram series with a given label
+/// value, keyed by name and labels as rendered
+pub fn quantiles_with(label: &str, value: &str) -> BTreeMap<String, BTreeMap<&'static str, f64>> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let value = privacy::label_value(label, value);
+    let pair = format!("{}=\"{}\"", label, escape_label(&value));
+    let (first, other) = (format!("{{{}", pair), format!(",{}", pair));
+
+    registry
+        .iter()
+        .flat_map(|(name, family)| {
+            family
+                .histograms
+                .iter()
+                .filter(|(labels, _)| labels.contains(&first) || labels.contains(&other))
+                .map(move |(labels, histogram)| {
+                    let quantiles = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)]
+                        .into_iter()
+                        .map(|(name, q)| (name, histogram.quantile(q)))
+                        .collect();
+                    (format!("{}{}", name, labels), quantiles)
+                })
+        })
+        .collect()
+}
+
+/// Series of one metric with a given label value, summed up by the value of
+/// another label, e.g. lookups of an endpoint by result; observation counts
+/// for histograms
+pub fn breakdown(name: &str, label: &str, value: &str, by: &str) -> BTreeMap<String, f64> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let Some(family) = registry.get(name) else {
+        return BTreeMap::new();
+    };
+    let value = privacy::label_value(label, value);
+    let histograms = family
+        .histograms
+        .iter()
+        .map(|(labels, histogram)| (labels, histogram.count() as f64));
+    let series = family.series.iter().map(|(labels, value)| (labels, *value));
+
+    let mut totals = BTreeMap::new();
+    for (labels, count) in series.chain(histograms) {
+        let labels = parse_series_key(labels);
+        if !labels.iter().any(|(name, v)| name == label && *v == value) {
+            continue;
+        }
+        if let Some((_, group)) = labels.iter().find(|(name, _)| name == by) {
+            *totals.entry(group.clone()).or_insert(0.0) += count;
+        }
+    }
+    totals
+}
+
+/// One series of a metric, as exported over OTLP
+#[cfg(feature = "otel")]
+pub struct Sample {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub help: &'static str,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// Label pairs of a series key as built by series_key()
+fn parse_series_key(key: &str) -> Vec<(String, String)> {
+    let mut labels = Vec::new();
+    let mut rest = key.trim_start_matches('{');
+    while let Some((name, value)) = rest.split_once("=\"") {
+        let mut unescaped = String::new();
+        let mut chars = value.char_indices();
+        let mut end = value.len();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => unescaped.push('\n'),
+                    Some((_, c)) => unescaped.push(c),
+                    None => {}
+                },
+                '"' => {
+                    end = i + 1;
+                    break;
+                }
+                c => unescaped.push(c),
+            }
+        }
+        labels.push((name.to_string(), unescaped));
+        rest = value[end..].trim_start_matches([',', '}']);
+    }
+    labels
+}
+
+/// Every counter and gauge series; histograms are only exposed to Prometheus
+#[cfg(feature = "otel")]
+pub fn snapshot() -> Vec<Sample> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    registry
+        .iter()
+        .flat_map(|(name, family)| {
+            family.series.iter().map(move |(labels, value)| Sample {
+                name: *name,
+                kind: family.kind,
+                help: family.help,
+                labels: parse_series_key(labels),
+                value: *value,
+            })
+        })
+        .collect()
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let mut output = String::new();
+
+    for (name, family) in registry.iter() {
+        let _ = writeln!(output, "# HELP {} {}", name, family.help);
+        let _ = writeln!(output, "# TYPE {} {}", name, family.kind);
+        for (labels, value) in &family.series {
+            let _ = writeln!(output, "{}{} {}", name, labels, value);
+        }
+        for (labels, histogram) in &family.histograms {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.counts) {
+                cumulative += count;
+                let bucket = with_label(labels, "le", &bound.to_string());
+                let _ = writeln!(output, "{}_bucket{} {}", name, bucket, cumulative);
+            }
+            let bucket = with_label(labels, "le", "+Inf");
+            let _ = writeln!(output, "{}_bucket{} {}", name, bucket, histogram.count());
+            let _ = writeln!(output, "{}_sum{} {}", name, labels, histogram.sum);
+            let _ = writeln!(output, "{}_count{} {}", name, labels, histogram.count());
+        }
+    }
+
+    output
+}
@@ -0,0 +1,358 @@
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::Endpoint;
+use crate::logging;
+use crate::privacy::sensitive;
+use crate::protocol::post_json;
+
+// Larger packets are a protocol error (body chunks are at most 64 KiB)
+const MAX_PACKET_LENGTH: usize = 1 << 20;
+
+const MILTER_VERSION: u32 = 6;
+
+// Actions requested in option negotiation: add headers
+const SMFIF_ADDHDRS: u32 = 0x01;
+
+// Protocol steps the MTA can leave out
+const SMFIP_NOBODY: u32 = 0x10;
+const SMFIP_NOHDRS: u32 = 0x20;
+const SMFIP_NOEOH: u32 = 0x40;
+const SMFIP_NOUNKNOWN: u32 = 0x100;
+const SMFIP_NODATA: u32 = 0x200;
+
+/// Milter callbacks that can be forwarded to the webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MilterEvent {
+    Connect,
+    Helo,
+    Mail,
+    Rcpt,
+    Header,
+    Eom,
+}
+
+impl MilterEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            MilterEvent::Connect => "connect",
+            MilterEvent::Helo => "helo",
+            MilterEvent::Mail => "mail",
+            MilterEvent::Rcpt => "rcpt",
+            MilterEvent::Header => "header",
+            MilterEvent::Eom => "eom",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MilterAction {
+    #[default]
+    Continue,
+    Accept,
+    Reject,
+    Tempfail,
+    Discard,
+}
+
+fn default_events() -> Vec<MilterEvent> {
+    vec![
+        MilterEvent::Connect,
+        MilterEvent::Helo,
+        MilterEvent::Mail,
+        MilterEvent::Rcpt,
+        MilterEvent::Eom,
+    ]
+}
+
+fn default_on_error() -> MilterAction {
+    MilterAction::Tempfail
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MilterConfig {
+    /// Callbacks sent to the webhook; others are continued locally
+    #[serde(default = "default_events")]
+    pub events: Vec<MilterEvent>,
+    /// Answer when the webhook fails, like Postfix milter_default_action
+    #[serde(default = "default_on_error")]
+    pub on_error: MilterAction,
+}
+
+impl Default for MilterConfig {
+    fn default() -> Self {
+        Self {
+            events: default_events(),
+            on_error: default_on_error(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HeaderField {
+    name: String,
+    value: String,
+}
+
+/// Webhook answer
+#[derive(Debug, Default, Deserialize)]
+struct Verdict {
+    #[serde(default)]
+    action: MilterAction,
+    /// SMTP reply for reject and tempfail, e.g. "550 5.7.1 Spam"
+    #[serde(default)]
+    reply: Option<String>,
+    /// Headers to add, only at eom
+    #[serde(default)]
+    headers: Vec<HeaderField>,
+}
+
+/// What is known about the SMTP session so far, sent with every event
+#[derive(Debug, Default)]
+struct Session {
+    macros: BTreeMap<String, String>,
+    client_name: String,
+    client_address: String,
+    helo: String,
+    sender: String,
+    recipients: Vec<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl Session {
+    /// Forget the current message after an abort
+    fn reset_message(&mut self) {
+        self.sender.clear();
+        self.recipients.clear();
+        self.headers.clear();
+    }
+
+    fn event(&self, event: MilterEvent) -> Value {
+        let headers: Vec<Value> = self
+            .headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        json!({
+            "event": event.name(),
+            "macros": self.macros,
+            "client_name": self.client_name,
+            "client_address": self.client_address,
+            "helo": self.helo,
+            "sender": self.sender,
+            "recipients": self.recipients,
+            "headers": headers,
+        })
+    }
+}
+
+/// NUL-terminated strings of a packet
+fn strings(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(&[0]).unwrap_or(data);
+    data.split(|&b| b == 0)
+        .map(|part| String::from_utf8_lossy(part).into_owned())
+        .collect()
+}
+
+/// Envelope address without angle brackets
+fn address(argument: Option<&String>) -> String {
+    argument
+        .map(|address| address.trim_start_matches('<').trim_end_matches('>'))
+        .unwrap_or("")
+        .to_string()
+}
+
+async fn write_packet<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    command: u8,
+    data: &[u8],
+) -> Result<()> {
+    let mut packet = Vec::with_capacity(data.len() + 5);
+    packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+    packet.push(command);
+    packet.extend_from_slice(data);
+    socket.write_all(&packet).await?;
+    Ok(())
+}
+
+/// Reply to a callback: continue, accept, discard, or reject/tempfail with
+/// the default or the given SMTP reply
+async fn write_verdict<S: AsyncWrite + Unpin>(socket: &mut S, verdict: &Verdict) -> Result<()> {
+    let expected = match verdict.action {
+        MilterAction::Reject => Some('5'),
+        MilterAction::Tempfail => Some('4'),
+        _ => None,
+    };
+    match (&verdict.reply, expected) {
+        (Some(reply), Some(class)) if reply.starts_with(class) => {
+            let mut data = reply.replace('\0', "").into_bytes();
+            data.push(0);
+            write_packet(socket, b'y', &data).await
+        }
+        _ => {
+            let command = match verdict.action {
+                MilterAction::Continue => b'c',
+                MilterAction::Accept => b'a',
+                MilterAction::Reject => b'r',
+                MilterAction::Tempfail => b't',
+                MilterAction::Discard => b'd',
+            };
+            write_packet(socket, command, &[]).await
+        }
+    }
+}
+
+/// Forward an event to the webhook, or continue when it is not configured
+async fn ask(
+    endpoint: &Endpoint,
+    session: &Session,
+    event: MilterEvent,
+    user_agent: &str,
+) -> Verdict {
+    if !endpoint.milter.events.contains(&event) {
+        return Verdict::default();
+    }
+    let request = post_json::<Verdict>(endpoint, &session.event(event), user_agent);
+    match logging::request(request).await {
+        Ok(verdict) => {
+            debug!("Milter {} verdict: {:?}", event.name(), verdict.action);
+            verdict
+        }
+        Err(e) => {
+            warn!("Endpoint '{}': milter webhook failed: {:#}", endpoint.name, e);
+            Verdict {
+                action: endpoint.milter.on_error,
+                ..Verdict::default()
+            }
+        }
+    }
+}
+
+/// Serve one MTA connection speaking the milter protocol (version 6)
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    endpoint: &Endpoint,
+    user_agent: &str,
+) -> Result<()> {
+    let mut session = Session::default();
+
+    loop {
+        let length = match socket.read_u32().await {
+            Ok(length) => length as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                debug!("Client closed connection");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if length == 0 || length > MAX_PACKET_LENGTH {
+            anyhow::bail!("Invalid milter packet length {}", length);
+        }
+        let mut packet = vec![0u8; length];
+        socket.read_exact(&mut packet).await?;
+        let (command, data) = (packet[0], &packet[1..]);
+
+        match command {
+            // Option negotiation: version, actions, protocol steps
+            b'O' => {
+                let field = |index: usize| -> u32 {
+                    data.get(index * 4..index * 4 + 4)
+                        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+                        .unwrap_or(0)
+                };
+                let mut skip = SMFIP_NOBODY | SMFIP_NOEOH | SMFIP_NOUNKNOWN | SMFIP_NODATA;
+                let events = &endpoint.milter.events;
+                if !events.contains(&MilterEvent::Header) && !events.contains(&MilterEvent::Eom) {
+                    skip |= SMFIP_NOHDRS;
+                }
+                let mut reply = Vec::with_capacity(12);
+                reply.extend_from_slice(&MILTER_VERSION.min(field(0)).to_be_bytes());
+                reply.extend_from_slice(&(SMFIF_ADDHDRS & field(1)).to_be_bytes());
+                reply.extend_from_slice(&(skip & field(2)).to_be_bytes());
+                write_packet(socket, b'O', &reply).await?;
+            }
+            // Macros for the next command: command byte, then name/value pairs
+            b'D' => {
+                let values = strings(data.get(1..).unwrap_or(&[]));
+                for pair in values.chunks_exact(2) {
+                    session.macros.insert(pair[0].clone(), pair[1].clone());
+                }
+            }
+            // Hostname, family, port, address
+            b'C' => {
+                let name_end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                session.client_name = String::from_utf8_lossy(&data[..name_end]).into_owned();
+                let rest = data.get(name_end + 1..).unwrap_or(&[]);
+                session.client_address = match rest.first() {
+                    Some(b'4' | b'6' | b'L') if rest.len() > 3 => {
+                        strings(&rest[3..]).into_iter().next().unwrap_or_default()
+                    }
+                    _ => String::new(),
+                };
+                debug!("Milter connect from {}", sensitive(&session.client_address));
+                let verdict = ask(endpoint, &session, MilterEvent::Connect, user_agent).await;
+                write_verdict(socket, &verdict).await?;
+            }
+            b'H' => {
+                session.helo = strings(data).into_iter().next().unwrap_or_default();
+                let verdict = ask(endpoint, &session, MilterEvent::Helo, user_agent).await;
+                write_verdict(socket, &verdict).await?;
+            }
+            b'M' => {
+                session.reset_message();
+                session.sender = address(strings(data).first());
+                let verdict = ask(endpoint, &session, MilterEvent::Mail, user_agent).await;
+                write_verdict(socket, &verdict).await?;
+            }
+            b'R' => {
+                session.recipients.push(address(strings(data).first()));
+                let verdict = ask(endpoint, &session, MilterEvent::Rcpt, user_agent).await;
+                if matches!(verdict.action, MilterAction::Reject | MilterAction::Tempfail) {
+                    // A refused recipient is not part of the message
+                    session.recipients.pop();
+                }
+                write_verdict(socket, &verdict).await?;
+            }
+            b'L' => {
+                let fields = strings(data);
+                let name = fields.first().cloned().unwrap_or_default();
+                let value = fields.get(1).cloned().unwrap_or_default();
+                session.headers.push((name, value));
+                let verdict = ask(endpoint, &session, MilterEvent::Header, user_agent).await;
+                write_verdict(socket, &verdict).await?;
+            }
+            // End of message: header changes, then the final verdict
+            b'E' => {
+                let verdict = ask(endpoint, &session, MilterEvent::Eom, user_agent).await;
+                for header in &verdict.headers {
+                    let mut data = Vec::new();
+                    for field in [&header.name, &header.value] {
+                        data.extend_from_slice(field.replace('\0', "").as_bytes());
+                        data.push(0);
+                    }
+                    write_packet(socket, b'h', &data).await?;
+                }
+                write_verdict(socket, &verdict).await?;
+                session.reset_message();
+            }
+            // Steps negotiated away or not used; answered if the MTA sends them anyway
+            b'B' | b'N' | b'T' | b'U' => write_packet(socket, b'c', &[]).await?,
+            b'A' => session.reset_message(),
+            // Quit, or quit and start over with a new SMTP connection
+            b'Q' => return Ok(()),
+            b'K' => session = Session::default(),
+            other => {
+                warn!("Unknown milter command {:?}", other as char);
+                return Ok(());
+            }
+        }
+        socket.flush().await?;
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use crate::config::parse_file;
+use crate::privacy::sensitive;
+
+const BUFFER_SIZE: usize = 8192;
+
+pub const MOCK_USAGE: &str = "mock <mock-config-file>";
+
+fn default_action() -> String {
+    "DUNNO".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MockConfig {
+    #[serde(default)]
+    pub bind_address: String,
+    pub bind_port: u16,
+    /// Required X-Auth-Token, any token is accepted when unset
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    pub routes: Vec<MockRoute>,
+}
+
+/// One fake REST API endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MockRoute {
+    pub path: String,
+    /// key -> values for tcp_table and memcache lookups; unknown keys are 404
+    #[serde(default)]
+    pub values: BTreeMap<String, Vec<String>>,
+    /// map name -> key -> values for socketmap lookups
+    #[serde(default)]
+    pub maps: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    /// Policy verdict returned for POST requests
+    #[serde(default = "default_action")]
+    pub action: String,
+    /// Delay before every response
+    #[serde(default)]
+    pub latency: u64, // milliseconds
+    /// Answer every request with this status code and an empty body
+    #[serde(default)]
+    pub status: Option<u16>,
+}
+
+/// `mock` subcommand: serve a fake REST API for testing Postfix and connector setups
+pub async fn run(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => anyhow::bail!("Usage: {}", MOCK_USAGE),
+    };
+    let config: MockConfig = parse_file(Path::new(path))?;
+
+    let addr = format!("{}:{}", config.bind_address, config.bind_port);
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", addr))?;
+    info!("Mock REST API listening on {} ({} routes)", addr, config.routes.len());
+
+    let config = Arc::new(config);
+    loop {
+        match listener.accept().await {
+            Ok((mut socket, addr)) => {
+                let config = Arc::clone(&config);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_mock_connection(&mut socket, &config).await {
+                        error!("Mock connection error from {}: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Mock accept error: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_mock_connection(socket: &mut TcpStream, config: &MockConfig) -> Result<()> {
+    // Read the head, then as much body as Content-Length announces
+    let mut request = Vec::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let head_end = loop {
+        let n = socket.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+        if let Some(pos) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let target = request_line.next().unwrap_or("");
+
+    let mut headers = BTreeMap::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    while request.len() < head_end + content_length {
+        let n = socket.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    let body = String::from_utf8_lossy(&request[head_end..]).into_owned();
+
+    debug!("Mock request: {} {}", method, sensitive(target));
+
+    let (status, content_type, body) = respond(config, method, target, &headers, &body).await;
+    write_response(socket, status, content_type, &body).await
+}
+
+async fn respond(
+    config: &MockConfig,
+    method: &str,
+    target: &str,
+    headers: &BTreeMap<String, String>,
+    body: &str,
+) -> (u16, &'static str, String) {
+    let url = match Url::parse("http://mock").and_then(|base| base.join(target)) {
+        Ok(url) => url,
+        Err(_) => return (400, "text/plain", String::new()),
+    };
+
+    let route = match config.routes.iter().find(|route| route.path == url.path()) {
+        Some(route) => route,
+        None => return (404, "text/plain", String::new()),
+    };
+
+    if route.latency > 0 {
+        tokio::time::sleep(Duration::from_millis(route.latency)).await;
+    }
+
+    if let Some(token) = &config.auth_token {
+        if headers.get("x-auth-token") != Some(token) {
+            return (401, "text/plain", String::new());
+        }
+    }
+
+    if let Some(status) = route.status {
+        return (status, "text/plain", String::new());
+    }
+
+    match method {
+        "GET" => {
+            let query: BTreeMap<String, String> = url.query_pairs().into_owned().collect();
+            let key = query.get("key").map(String::as_str).unwrap_or("");
+            let values = match query.get("name") {
+                Some(name) => route.maps.get(name).and_then(|map| map.get(key)),
+                None => route.values.get(key),
+            };
+            match values {
+                Some(values) => (200, "application/json", json!(values).to_string()),
+                None => (404, "application/json", "[]".to_string()),
+            }
+        }
+        "POST" => {
+            debug!("Mock policy request: {}", sensitive(body));
+            (200, "text/plain", format!("action={}", route.action))
+        }
+        _ => (405, "text/plain", String::new()),
+    }
+}
+
+async fn write_response(
+    socket: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
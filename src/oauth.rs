@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::clock::Instant;
+use crate::secret::SecretFile;
+
+fn default_refresh_margin() -> u64 {
+    30000
+}
+
+// Used when the token response has no expires-in
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientAuthMethod {
+    /// Client id and secret as HTTP Basic credentials
+    #[default]
+    Basic,
+    /// Client id and secret in the form body
+    Post,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OAuth2Config {
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: String,
+    /// Read the client secret from this file instead, re-read when it changes
+    #[serde(default)]
+    pub client_secret_file: Option<PathBuf>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Some providers (e.g. Auth0) require the API identifier
+    #[serde(default)]
+    pub audience: Option<String>,
+    #[serde(default)]
+    pub auth_method: ClientAuthMethod,
+    /// Fetch a new token this long before the current one expires
+    #[serde(default = "default_refresh_margin")]
+    pub refresh_margin: u64, // milliseconds
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>, // seconds
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    value: String,
+    refresh_at: Instant,
+}
+
+/// Client-credentials token source with caching and refresh before expiry
+#[derive(Debug)]
+pub struct OAuth2Client {
+    config: OAuth2Config,
+    secret_source: Option<SecretFile>,
+    // Held across the refresh so concurrent lookups wait for a single token request
+    token: Mutex<Option<CachedToken>>,
+}
+
+impl OAuth2Client {
+    pub fn new(config: &OAuth2Config) -> Result<Self> {
+        url::Url::parse(&config.token_url).context("Invalid oauth2 token-url")?;
+        let secret_source = match &config.client_secret_file {
+            Some(path) => Some(SecretFile::open(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            secret_source,
+            token: Mutex::new(None),
+        })
+    }
+
+    fn client_secret(&self) -> String {
+        match &self.secret_source {
+            Some(source) => source.get(),
+            None => self.config.client_secret.clone(),
+        }
+    }
+
+    /// Current access token, fetched first if missing or about to expire
+    pub async fn token(&self, client: &Client) -> Result<String> {
+        let mut cached = self.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if Instant::now() < token.refresh_at {
+                return Ok(token.value.clone());
+            }
+        }
+
+        let token = self.fetch(client).await?;
+        let value = token.value.clone();
+        *cached = Some(token);
+        Ok(value)
+    }
+
+    /// Drop the cached token, e.g. after the upstream rejected it
+    pub async fn invalidate(&self) {
+        *self.token.lock().await = None;
+    }
+
+    async fn fetch(&self, client: &Client) -> Result<CachedToken> {
+        let config = &self.config;
+        let secret = self.client_secret();
+
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        if !config.scopes.is_empty() {
+            form.push(("scope", config.scopes.join(" ")));
+        }
+        if let Some(audience) = &config.audience {
+            form.push(("audience", audience.clone()));
+        }
+
+        let mut request = client.post(&config.token_url);
+        match config.auth_method {
+            ClientAuthMethod::Basic => {
+                request = request.basic_auth(&config.client_id, Some(&secret));
+            }
+            ClientAuthMethod::Post => {
+                form.push(("client_id", config.client_id.clone()));
+                form.push(("client_secret", secret));
+            }
+        }
+
+        let started = Instant::now();
+        let response = request
+            .form(&form)
+            .send()
+            .await
+            .context("OAuth2 token request failed")?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("OAuth2 token request failed with HTTP {}", status);
+        }
+        let token: TokenResponse = response
+            .json()
+            .await
+            .context("Invalid OAuth2 token response")?;
+
+        let lifetime = token
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+        // Never refresh on every request, even if the margin exceeds the lifetime
+        let refresh_in = lifetime
+            .saturating_sub(Duration::from_millis(config.refresh_margin))
+            .max(lifetime / 2);
+        info!("OAuth2 token for client '{}' obtained, valid for {:?}", config.client_id, lifetime);
+        debug!("OAuth2 token request took {:?}", started.elapsed());
+
+        Ok(CachedToken {
+            value: token.access_token,
+            refresh_at: started + refresh_in,
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::Instant;
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+/// Opt-in cache of policy verdicts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyCacheConfig {
+    /// Request attributes in {name} placeholders, e.g. "{sender}/{recipient}/{client_address}"
+    pub key: String,
+    pub ttl: u64, // milliseconds
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+/// Verdicts by cache key; a verdict is reused for requests whose selected
+/// attributes are equal, whatever the other attributes say
+#[derive(Debug)]
+pub struct PolicyCache {
+    key: String,
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl PolicyCache {
+    pub fn new(config: &PolicyCacheConfig) -> Self {
+        Self {
+            key: config.key.clone(),
+            ttl: Duration::from_millis(config.ttl),
+            max_entries: config.max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cache key of a request; missing attributes are empty
+    pub fn key(&self, request: &str) -> String {
+        let attributes: HashMap<&str, &str> = request
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+
+        let mut key = String::new();
+        let mut rest = self.key.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            key.push_str(&rest[..start]);
+            let name = &rest[start + 1..start + len];
+            key.push_str(attributes.get(name).copied().unwrap_or(""));
+            rest = &rest[start + len + 1..];
+        }
+        key.push_str(rest);
+        key
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        let (response, inserted) = entries.get(key)?;
+        (inserted.elapsed() < self.ttl).then(|| response.clone())
+    }
+
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    pub fn insert(&self, key: String, response: String) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries {
+            let ttl = self.ttl;
+            entries.retain(|_, (_, inserted)| inserted.elapsed() < ttl);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(key, (response, Instant::now()));
+    }
+}
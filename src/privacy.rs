@@ -0,0 +1,294 @@
+//! Keeps lookup keys, addresses and map values out of logs and metrics labels
+//!
+//! Call sites mark request data with [`sensitive`]; the logger installed by
+//! [`init_logger`] additionally rewrites anything that looks like an email
+//! address, so a forgotten marker does not leak the common case.
+//!
+//! Independently of privacy mode, the logger masks every secret registered
+//! with [`register_secret`] and passwords in URLs.
+
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{OnceLock, RwLock};
+
+// Shorter secrets would mask ordinary words and numbers in log messages
+const MIN_SECRET_LENGTH: usize = 6;
+
+// Metrics label names that may carry request data
+const SENSITIVE_LABELS: &[&str] = &["key", "map", "value", "address", "sender", "recipient", "client"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrivacyMode {
+    /// Replace values with a salted hash, so equal values still correlate
+    #[default]
+    Hash,
+    /// Replace values with a fixed placeholder
+    Omit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PrivacyConfig {
+    #[serde(default)]
+    pub mode: PrivacyMode,
+    /// Hash salt; random per process start if unset, so hashes only
+    /// correlate within one run
+    #[serde(default)]
+    pub salt: Option<String>,
+}
+
+struct Settings {
+    mode: PrivacyMode,
+    salt: Vec<u8>,
+}
+
+static SETTINGS: OnceLock<Option<Settings>> = OnceLock::new();
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = Vec::with_capacity(16);
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u8(0);
+        salt.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    salt
+}
+
+/// Enable privacy mode for the lifetime of the process (first call wins)
+pub fn init(config: Option<&PrivacyConfig>) {
+    let settings = config.map(|config| Settings {
+        mode: config.mode,
+        salt: match &config.salt {
+            Some(salt) => salt.as_bytes().to_vec(),
+            None => random_salt(),
+        },
+    });
+    let _ = SETTINGS.set(settings);
+}
+
+fn settings() -> Option<&'static Settings> {
+    SETTINGS.get().and_then(Option::as_ref)
+}
+
+pub fn enabled() -> bool {
+    settings().is_some()
+}
+
+fn salted_hash(salt: &[u8], value: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(value.as_bytes())
+        .finalize();
+    let hex: String = digest[..6].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("#{}", hex)
+}
+
+/// The value itself, or its replacement in privacy mode
+pub fn redact(value: &str) -> Cow<'_, str> {
+    match settings() {
+        None => Cow::Borrowed(value),
+        Some(Settings { mode: PrivacyMode::Omit, .. }) => Cow::Borrowed("[redacted]"),
+        Some(Settings { mode: PrivacyMode::Hash, salt }) => Cow::Owned(salted_hash(salt, value)),
+    }
+}
+
+/// A salted hash of the value even outside privacy mode, to correlate
+/// log lines without writing the value; "[redacted]" in omit mode
+pub fn hash(value: &str) -> String {
+    static SALT: OnceLock<Vec<u8>> = OnceLock::new();
+    match settings() {
+        None => salted_hash(SALT.get_or_init(random_salt), value),
+        Some(Settings { mode: PrivacyMode::Omit, .. }) => "[redacted]".to_string(),
+        Some(Settings { mode: PrivacyMode::Hash, salt }) => salted_hash(salt, value),
+    }
+}
+
+/// Request data in a log message, formatted through [`redact`]
+pub struct Sensitive<'a, T: ?Sized>(&'a T);
+
+pub fn sensitive<T: ?Sized>(value: &T) -> Sensitive<'_, T> {
+    Sensitive(value)
+}
+
+impl<T: fmt::Display + ?Sized> fmt::Display for Sensitive<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if enabled() {
+            f.write_str(&redact(&self.0.to_string()))
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for Sensitive<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if enabled() {
+            f.write_str(&redact(&format!("{:?}", self.0)))
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+/// Metrics label value, redacted if the label can carry request data
+pub fn label_value<'a>(name: &str, value: &'a str) -> Cow<'a, str> {
+    if SENSITIVE_LABELS.contains(&name) {
+        redact(value)
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// reqwest errors include the request URL, which contains the lookup key
+pub fn strip_url(error: reqwest::Error) -> reqwest::Error {
+    if enabled() {
+        error.without_url()
+    } else {
+        error
+    }
+}
+
+static SECRETS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+/// Mask a token, password or similar in all log output from now on
+pub fn register_secret(secret: &str) {
+    if secret.len() < MIN_SECRET_LENGTH {
+        return;
+    }
+    let known = SECRETS.read().unwrap().contains(secret);
+    if !known {
+        SECRETS.write().unwrap().insert(secret.to_string());
+    }
+}
+
+fn url_password_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"(://[^/\s:@]*:)[^/\s@]+@").expect("valid URL password pattern")
+    })
+}
+
+/// The message with registered secrets and URL passwords replaced
+fn mask_secrets(message: &str) -> Cow<'_, str> {
+    let mut masked = url_password_pattern().replace_all(message, "${1}[secret]@");
+    for secret in SECRETS.read().unwrap().iter() {
+        if masked.contains(secret.as_str()) {
+            masked = Cow::Owned(masked.replace(secret.as_str(), "[secret]"));
+        }
+    }
+    masked
+}
+
+fn email_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r#"[^\s@<>"'`,;:()\[\]{}=&]+@[A-Za-z0-9](?:[A-Za-z0-9.-]*[A-Za-z0-9])?"#)
+            .expect("valid email pattern")
+    })
+}
+
+/// env_logger with secrets masked in every record, and email addresses
+/// scrubbed in privacy mode
+struct PrivacyLogger {
+    /// RUST_LOG levels, unless an endpoint log-level applies
+    filter: Filter,
+    /// Formats and writes every record it is given
+    inner: RwLock<env_logger::Logger>,
+}
+
+static LOGGER: OnceLock<PrivacyLogger> = OnceLock::new();
+
+impl Log for PrivacyLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match crate::logging::endpoint_level(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let passes = match crate::logging::endpoint_level(record.target()) {
+            Some(level) => record.level() <= level,
+            None => self.filter.matches(record),
+        };
+        if !passes {
+            return;
+        }
+        let inner = self.inner.read().unwrap();
+
+        let message = record.args().to_string();
+        let masked = mask_secrets(&message);
+        let scrubbed = if enabled() {
+            email_pattern().replace_all(&masked, |caps: &regex::Captures<'_>| {
+                redact(&caps[0]).into_owned()
+            })
+        } else {
+            Cow::Borrowed(masked.as_ref())
+        };
+        inner.log(
+            &Record::builder()
+                .args(format_args!("{}", scrubbed))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .key_values(record.key_values())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+/// env_logger builder for the installed logger's output: RUST_LOG_STYLE
+/// applies, levels are filtered before records reach it
+pub fn output_builder() -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Trace);
+    if let Ok(style) = std::env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&style);
+    }
+    builder
+}
+
+/// Install the process logger, configured from RUST_LOG like env_logger::init()
+pub fn init_logger() {
+    let filter = FilterBuilder::from_env("RUST_LOG").build();
+    let max_level = filter.filter();
+    let logger = LOGGER.get_or_init(|| PrivacyLogger {
+        filter,
+        inner: RwLock::new(output_builder().format(crate::logging::format_text).build()),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Replace the output of the installed logger, e.g. with another format
+/// or destination; filtering and scrubbing stay in place
+pub fn set_logger_output(inner: env_logger::Logger) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    *logger.inner.write().unwrap() = inner;
+}
+
+/// Let records through up to `level` for endpoints with a log-level more
+/// verbose than RUST_LOG
+pub fn set_scoped_max_level(level: LevelFilter) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    log::set_max_level(level.max(logger.filter.filter()));
+}
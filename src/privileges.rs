@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use log::info;
+use nix::unistd::{self, Gid, Group, Uid, User};
+use std::ffi::CString;
+
+/// User to switch to: a passwd entry, or a bare uid without one
+struct RunAs {
+    uid: Uid,
+    name: Option<CString>,
+    gid: Option<Gid>,
+}
+
+fn lookup_user(user: &str) -> Result<RunAs> {
+    let entry = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(user)?,
+    };
+    match entry {
+        Some(entry) => Ok(RunAs {
+            uid: entry.uid,
+            name: Some(CString::new(entry.name)?),
+            gid: Some(entry.gid),
+        }),
+        // e.g. an arbitrary uid in a container
+        None => match user.parse::<u32>() {
+            Ok(uid) => Ok(RunAs {
+                uid: Uid::from_raw(uid),
+                name: None,
+                gid: None,
+            }),
+            Err(_) => anyhow::bail!("run-as-user: no such user '{}'", user),
+        },
+    }
+}
+
+fn lookup_group(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(group)? {
+        Some(entry) => Ok(entry.gid),
+        None => anyhow::bail!("run-as-group: no such group '{}'", group),
+    }
+}
+
+/// Switch to run-as-user/run-as-group, once every listener is bound
+///
+/// Any failure is an error: running on as root because a drop silently
+/// did not happen is worse than not running.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => match user.as_ref().and_then(|user| user.gid) {
+            Some(gid) => gid,
+            None => anyhow::bail!("run-as-user has no passwd entry, set run-as-group too"),
+        },
+    };
+    let uid = user.as_ref().map_or_else(unistd::getuid, |user| user.uid);
+
+    if unistd::geteuid().is_root() {
+        // Supplementary groups first, root's would otherwise be kept
+        match user.as_ref().and_then(|user| user.name.as_ref()) {
+            Some(name) => unistd::initgroups(name, gid),
+            None => unistd::setgroups(&[gid]),
+        }
+        .context("Failed to set supplementary groups")?;
+        // Sets the real, effective and saved ids as root; group before user,
+        // as the user can no longer change its group
+        unistd::setgid(gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+        unistd::setuid(uid).with_context(|| format!("Failed to switch to user {}", uid))?;
+    } else if unistd::getuid() != uid || unistd::getgid() != gid {
+        anyhow::bail!(
+            "run-as-user/run-as-group need the connector to start as root (running as uid {})",
+            unistd::getuid()
+        );
+    }
+
+    if unistd::getuid() != uid
+        || unistd::geteuid() != uid
+        || unistd::getgid() != gid
+        || unistd::getegid() != gid
+    {
+        anyhow::bail!("Still running as uid {}, gid {}", unistd::geteuid(), unistd::getegid());
+    }
+    if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        anyhow::bail!("Regained root after switching to uid {}", uid);
+    }
+
+    info!("Running as uid {}, gid {}", uid, gid);
+    Ok(())
+}
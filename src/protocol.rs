@@ -1,9 +1,30 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::{debug, error, warn};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::Instrument;
 use url::Url;
 
-use crate::config::Endpoint;
+use crate::action;
+use crate::chain::ChainSource;
+use crate::clock::Instant;
+use crate::config::{Endpoint, EndpointMode, PolicyBody, PutMethod};
+use crate::events::EventKind;
+use crate::logging::{self, LookupRecord};
+use crate::metrics;
+use crate::privacy::sensitive;
+use crate::qos::RequestInfo;
+use crate::quota::{QuotaStatus, QuotaVerdict};
+use crate::response::{ResponseFormat, SingleValue, SocketmapDialect, SocketmapReply};
+use crate::retry::send_with_retry;
+use crate::telemetry;
 
 // Postfix protocol constants
 const TCP_MAXIMUM_RESPONSE_LENGTH: usize = 4096;
@@ -29,6 +50,62 @@ fn encode_response(data: &str) -> String {
     utf8_percent_encode(data, ALLOWED).to_string()
 }
 
+// Characters left as-is in substituted path segments (RFC 3986 unreserved + @)
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'@');
+
+/// Substitute the `{key}` and `{map}` placeholders of a resource-style target
+///
+/// Values are percent-encoded as a single path segment, so a key containing
+/// `/` or `?` cannot change the request path.
+fn expand_target(target: &str, mapname: Option<&str>, key: &str) -> String {
+    use percent_encoding::utf8_percent_encode;
+
+    let mut url = target.replace("{key}", &utf8_percent_encode(key, PATH_SEGMENT).to_string());
+    if let Some(mapname) = mapname {
+        url = url.replace("{map}", &utf8_percent_encode(mapname, PATH_SEGMENT).to_string());
+    }
+    url
+}
+
+/// Join lookup values with value-separator, encoding values and separator
+///
+/// Commas in the separator stay literal, as in the default ",". None if
+/// single-value is "error" and there are several values.
+fn join_values(endpoint: &Endpoint, values: &[String], encode: fn(&str) -> String) -> Option<String> {
+    let values = match (endpoint.single_value, values) {
+        (Some(SingleValue::First), [first, ..]) => std::slice::from_ref(first),
+        (Some(SingleValue::Error), [_, _, ..]) => {
+            warn!(
+                "Endpoint '{}': backend returned {} values, single-value allows one",
+                endpoint.name,
+                values.len()
+            );
+            return None;
+        }
+        _ => values,
+    };
+    let separator = endpoint
+        .value_separator
+        .split(',')
+        .map(encode)
+        .collect::<Vec<_>>()
+        .join(",");
+    Some(values.iter().map(|v| encode(v)).collect::<Vec<_>>().join(&separator))
+}
+
+/// Failure reply text, with the request ID if request-id-in-replies is set
+fn reply_reason<'a>(endpoint: &Endpoint, reason: &'a str) -> Cow<'a, str> {
+    match logging::request_id().filter(|_| endpoint.request_id_in_replies) {
+        Some(id) => Cow::Owned(format!("{} (request {})", reason, id)),
+        None => Cow::Borrowed(reason),
+    }
+}
+
 /// Format Postfix TCP response - ALL text is encoded per spec
 fn format_tcp_response(code: u16, data: &str) -> Result<String> {
     let encoded = encode_response(data);
@@ -47,13 +124,13 @@ fn format_tcp_response(code: u16, data: &str) -> Result<String> {
 
 /// Encode response as netstring for socketmap protocol
 /// Format: <length>:<data>,
-fn encode_netstring(data: &str) -> String {
+pub fn encode_netstring(data: &str) -> String {
     format!("{}:{},", data.len(), data)
 }
 
 /// Decode netstring from socketmap request
 /// Format: <length>:<data>,
-fn decode_netstring(input: &[u8]) -> Option<String> {
+fn decode_netstring(input: &[u8]) -> Option<&[u8]> {
     // Find the colon separator
     let colon_pos = input.iter().position(|&b| b == b':')?;
     
@@ -76,192 +153,1416 @@ fn decode_netstring(input: &[u8]) -> Option<String> {
     
     if input[data_end] != b',' {
         warn!("Netstring: expected comma at position {}, found: {:?}", 
-              data_end, input[data_end] as char);
+              data_end, sensitive(&(input[data_end] as char)));
         return None;
     }
     
-    // Extract data
-    let data = std::str::from_utf8(&input[data_start..data_end]).ok()?;
-    debug!("Netstring decoded successfully: '{}'", data);
-    Some(data.to_string())
+    // Extract data; it may contain 8-bit bytes that are not UTF-8
+    let data = &input[data_start..data_end];
+    debug!("Netstring decoded successfully: '{}'", sensitive(&String::from_utf8_lossy(data)));
+    Some(data)
 }
 
-/// Handle TCP lookup protocol
-pub async fn handle_tcp_lookup(
+/// A socketmap key as text for the upstream
+///
+/// Invalid UTF-8 bytes (and then `%` itself) are percent-encoded instead of
+/// being replaced, so distinct keys stay distinct and can be restored.
+fn key_text(key: &[u8]) -> Cow<'_, str> {
+    if let Ok(text) = std::str::from_utf8(key) {
+        return Cow::Borrowed(text);
+    }
+    let mut text = String::with_capacity(key.len() + 8);
+    for chunk in key.utf8_chunks() {
+        text.push_str(&chunk.valid().replace('%', "%25"));
+        for byte in chunk.invalid() {
+            text.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    Cow::Owned(text)
+}
+
+/// Skip what some clients send to keep a connection alive: blank lines,
+/// stray whitespace and zero-length netstrings. An empty result means the
+/// whole read was a no-op and needs no reply.
+pub fn strip_keepalive(input: &[u8]) -> &[u8] {
+    let mut input = input;
+    loop {
+        let trimmed = input.trim_ascii_start();
+        match trimmed.strip_prefix(b"0:,") {
+            Some(rest) => input = rest,
+            None => return trimmed,
+        }
+    }
+}
+
+/// Detect the Postfix protocol from the framing of the first request
+/// - socketmap: netstring, starts with "<digits>:"
+/// - tcp_table: "get SPACE key" or "put SPACE key SPACE value"
+/// - policy: "name=value" attribute lines
+pub fn detect_mode(input: &[u8]) -> Option<EndpointMode> {
+    let digits = input.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits > 0 && input.get(digits) == Some(&b':') {
+        return Some(EndpointMode::SocketmapLookup);
+    }
+
+    if input.starts_with(b"get ") || input.starts_with(b"put ") {
+        return Some(EndpointMode::TcpLookup);
+    }
+
+    let first_line = input.split(|&b| b == b'\n').next()?;
+    if first_line.contains(&b'=') {
+        return Some(EndpointMode::Policy);
+    }
+
+    None
+}
+
+/// Outcome of a REST lookup, independent of the Postfix wire format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LookupResult {
+    Found(Vec<String>),
+    Empty,
+    NotFound,
+    InvalidJson,
+    ClientError,
+    ServerError,
+    UnknownError,
+    ConnectionFailed,
+    /// The backend did not answer within request-timeout
+    Timeout,
+    Overloaded,
+    CircuitOpen,
+    Maintenance,
+    /// HTTP status answered as configured in status-mapping
+    Mapped {
+        status: u16,
+        tcp: u16,
+        socketmap: SocketmapReply,
+        reason: String,
+    },
+}
+
+impl LookupResult {
+    /// Short name used in events and logs
+    pub fn label(&self) -> &'static str {
+        match self {
+            LookupResult::Found(_) => "found",
+            LookupResult::Empty => "empty",
+            LookupResult::NotFound => "not-found",
+            LookupResult::InvalidJson => "invalid-json",
+            LookupResult::ClientError => "client-error",
+            LookupResult::ServerError => "server-error",
+            LookupResult::UnknownError => "unknown-error",
+            LookupResult::ConnectionFailed => "connection-failed",
+            LookupResult::Timeout => "timeout",
+            LookupResult::Overloaded => "overloaded",
+            LookupResult::CircuitOpen => "circuit-open",
+            LookupResult::Maintenance => "maintenance",
+            LookupResult::Mapped { .. } => "status-mapped",
+        }
+    }
+
+    /// The upstream could not give an answer (as opposed to a definitive one)
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(
+            self,
+            LookupResult::InvalidJson
+                | LookupResult::ServerError
+                | LookupResult::UnknownError
+                | LookupResult::ConnectionFailed
+                | LookupResult::Timeout
+                | LookupResult::CircuitOpen
+                | LookupResult::Maintenance
+        ) || matches!(self, LookupResult::Mapped { status, .. } if *status >= 500)
+    }
+
+    /// Only definitive answers from the upstream are worth caching
+    pub fn is_cacheable(&self) -> bool {
+        matches!(
+            self,
+            LookupResult::Found(_) | LookupResult::Empty | LookupResult::NotFound
+        )
+    }
+
+    /// OK, NOTFOUND, TEMP or PERM, as answered to the client
+    pub fn class(&self, endpoint: &Endpoint) -> &'static str {
+        if let LookupResult::Found(_) = self {
+            return "OK";
+        }
+        if endpoint.mode == EndpointMode::TcpLookup {
+            return match self {
+                LookupResult::Empty | LookupResult::NotFound => "NOTFOUND",
+                LookupResult::InvalidJson | LookupResult::UnknownError => "PERM",
+                LookupResult::Mapped { tcp, .. } if *tcp >= 500 => "PERM",
+                _ => "TEMP",
+            };
+        }
+        match self.failure_reply(endpoint).0 {
+            SocketmapReply::NotFound => "NOTFOUND",
+            SocketmapReply::Temp | SocketmapReply::Timeout => "TEMP",
+            SocketmapReply::Perm => "PERM",
+        }
+    }
+
+    fn to_tcp_response(&self, endpoint: &Endpoint) -> Result<String> {
+        // 400 is a temporary failure, worth correlating with the upstream
+        let temp = |reason: &str| format_tcp_response(400, &reply_reason(endpoint, reason));
+        match self {
+            LookupResult::Found(values) => {
+                let Some(joined) = join_values(endpoint, values, encode_response) else {
+                    return temp("Multiple values");
+                };
+                let response = format!("200 {}{}", joined, END_CHAR);
+
+                if response.len() > TCP_MAXIMUM_RESPONSE_LENGTH {
+                    warn!("Response exceeds maximum length: {} > {}",
+                          response.len(), TCP_MAXIMUM_RESPONSE_LENGTH);
+                    Ok(format!("500 Response%20too%20long{}", END_CHAR))
+                } else {
+                    Ok(response)
+                }
+            }
+            LookupResult::Empty => format_tcp_response(500, "Empty result"),
+            LookupResult::NotFound => format_tcp_response(500, "Not found"),
+            LookupResult::InvalidJson => format_tcp_response(500, "Invalid JSON"),
+            LookupResult::ClientError => temp("Client error"),
+            LookupResult::ServerError => temp("Server error"),
+            LookupResult::UnknownError => format_tcp_response(500, "Unknown error"),
+            LookupResult::ConnectionFailed => temp("Connection failed"),
+            LookupResult::Timeout => temp("Timeout"),
+            LookupResult::Overloaded => temp("Overloaded"),
+            LookupResult::CircuitOpen => temp("Circuit open"),
+            LookupResult::Maintenance => temp("Maintenance"),
+            LookupResult::Mapped { tcp: 400, reason, .. } => temp(reason),
+            LookupResult::Mapped { tcp, reason, .. } => format_tcp_response(*tcp, reason),
+        }
+    }
+
+    fn to_socketmap_response(&self, endpoint: &Endpoint) -> String {
+        let replies = &endpoint.socketmap_replies;
+        let (reply, reason) = match self {
+            LookupResult::Found(values) => {
+                let Some(joined) = join_values(endpoint, values, encode_response) else {
+                    return encode_netstring(&format!(
+                        "{} Multiple values",
+                        replies.multiple_values.code()
+                    ));
+                };
+                let response_text = match endpoint.dialect {
+                    SocketmapDialect::Sendmail if joined.is_empty() => "OK".to_string(),
+                    _ => format!("OK {}", joined),
+                };
+
+                if response_text.len() <= SOCKETMAP_MAXIMUM_RESPONSE_LENGTH {
+                    return encode_netstring(&response_text);
+                }
+                warn!("Socketmap response too long: {} bytes", response_text.len());
+                (replies.too_long, "Response too long")
+            }
+            _ => self.failure_reply(endpoint),
+        };
+
+        // NOTFOUND carries no reason text
+        match (reply, endpoint.dialect) {
+            (SocketmapReply::NotFound, SocketmapDialect::Sendmail) => encode_netstring("NOTFOUND"),
+            (SocketmapReply::NotFound, SocketmapDialect::Postfix) => encode_netstring("NOTFOUND "),
+            _ => {
+                let reason = reply_reason(endpoint, reason);
+                encode_netstring(&format!("{} {}", reply.code(), reason))
+            }
+        }
+    }
+
+    /// Socketmap reply code and reason for anything but a value
+    fn failure_reply(&self, endpoint: &Endpoint) -> (SocketmapReply, &str) {
+        let replies = &endpoint.socketmap_replies;
+        match self {
+            LookupResult::Found(_) => (replies.too_long, "Response too long"),
+            LookupResult::Empty => (replies.empty, "Empty result"),
+            LookupResult::NotFound => (replies.not_found, "Not found"),
+            LookupResult::InvalidJson => (replies.invalid_json, "Invalid JSON"),
+            LookupResult::ClientError => (replies.client_error, "Configuration error"),
+            LookupResult::ServerError => (replies.server_error, "Server error"),
+            LookupResult::UnknownError => (replies.unknown_error, "Unknown error"),
+            LookupResult::ConnectionFailed => (replies.connection_failed, "Connection failed"),
+            LookupResult::Timeout => (replies.timeout, "Backend deadline exceeded"),
+            LookupResult::Overloaded => (replies.overloaded, "Overloaded"),
+            LookupResult::CircuitOpen => (replies.circuit_open, "Circuit open"),
+            LookupResult::Maintenance => (replies.maintenance, "Maintenance"),
+            LookupResult::Mapped { socketmap, reason, .. } => (*socketmap, reason.as_str()),
+        }
+    }
+}
+
+/// Evaluate response-expression against the response JSON
+fn evaluate_expression(expression: &str, json: &Value) -> Result<Value> {
+    // Compiled per lookup: jmespath expressions are not Send
+    let expression = jmespath::compile(expression)?;
+    let result = expression.search(json.clone())?;
+    Ok(serde_json::to_value(&*result)?)
+}
+
+/// Lookup values from a JSON response body
+///
+/// The body is a JSON array unless response-pointer or response-expression
+/// selects the array (or a single value) inside it.
+fn response_values(endpoint: &Endpoint, json: &Value) -> LookupResult {
+    let evaluated;
+    let selected = match (&endpoint.response_pointer, &endpoint.response_expression) {
+        (Some(pointer), _) => match json.pointer(pointer) {
+            Some(value) => value,
+            None => return LookupResult::Empty,
+        },
+        (None, Some(expression)) => match evaluate_expression(expression, json) {
+            Ok(value) => {
+                evaluated = value;
+                &evaluated
+            }
+            Err(e) => {
+                error!("Endpoint '{}': response-expression failed: {:#}", endpoint.name, e);
+                return LookupResult::InvalidJson;
+            }
+        },
+        (None, None) => json,
+    };
+    let selecting = endpoint.response_pointer.is_some() || endpoint.response_expression.is_some();
+    let elements = match selected {
+        Value::Null if selecting => return LookupResult::Empty,
+        Value::Array(arr) => arr.as_slice(),
+        _ if selecting => std::slice::from_ref(selected),
+        _ => return LookupResult::Empty,
+    };
+
+    let values: Vec<String> = elements
+        .iter()
+        .filter_map(|v| endpoint.value_coercion.coerce(v))
+        .collect();
+
+    if values.len() < elements.len() {
+        warn!(
+            "Endpoint '{}': skipped {} non-string response values (see value-coercion)",
+            endpoint.name,
+            elements.len() - values.len()
+        );
+    }
+
+    if values.is_empty() {
+        LookupResult::Empty
+    } else {
+        LookupResult::Found(values)
+    }
+}
+
+/// Lookup values from a plain-text response body
+fn text_values(endpoint: &Endpoint, body: &[u8]) -> LookupResult {
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text,
+        Err(e) => {
+            error!("Endpoint '{}': response is not valid UTF-8: {}", endpoint.name, e);
+            return LookupResult::InvalidJson;
+        }
+    };
+
+    let values: Vec<String> = if endpoint.split_lines {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    } else {
+        Some(text.trim())
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .into_iter()
+            .collect()
+    };
+
+    if values.is_empty() {
+        LookupResult::Empty
+    } else {
+        LookupResult::Found(values)
+    }
+}
+
+/// Result for an unsuccessful HTTP status, as configured in status-mapping
+fn status_result(endpoint: &Endpoint, status: StatusCode) -> LookupResult {
+    let replies = &endpoint.socketmap_replies;
+    let (result, tcp, socketmap, reason) = if status.as_u16() == 404 {
+        (LookupResult::NotFound, 500, replies.not_found, "Not found")
+    } else if status.is_client_error() {
+        (LookupResult::ClientError, 400, replies.client_error, "Client error")
+    } else if status.is_server_error() {
+        (LookupResult::ServerError, 400, replies.server_error, "Server error")
+    } else {
+        (LookupResult::UnknownError, 500, replies.unknown_error, "Unknown error")
+    };
+
+    match endpoint.status_mapping.rule(status.as_u16()) {
+        Some(rule) => LookupResult::Mapped {
+            status: status.as_u16(),
+            tcp: rule.tcp.unwrap_or(tcp),
+            socketmap: rule.socketmap.unwrap_or(socketmap),
+            reason: rule.reason.clone().unwrap_or_else(|| reason.to_string()),
+        },
+        None => result,
+    }
+}
+
+/// Perform the lookup for a map/key pair, logging it when lookup records
+/// are enabled
+async fn lookup(
     endpoint: &Endpoint,
-    request: &str,
+    mapname: Option<&str>,
+    key: &str,
     user_agent: &str,
-) -> Result<String> {
-    // Parse: "get SPACE key NEWLINE"
-    // split_whitespace() already trims, so no need to call trim() first
-    let parts: Vec<&str> = request.split_whitespace().collect();
-    if parts.len() < 2 || parts[0] != "get" {
-        return format_tcp_response(500, "Invalid request");
+) -> Result<LookupResult> {
+    let span = tracing::info_span!(
+        "lookup",
+        endpoint = %endpoint.name,
+        map = mapname,
+        key = %sensitive(key)
+    );
+    let started = Instant::now();
+    let mut cache_status = CacheStatus::None;
+    if !logging::lookups_enabled() && endpoint.slow_threshold_ms.is_none() {
+        let result = resolve(endpoint, mapname, key, user_agent, &mut cache_status)
+            .instrument(span)
+            .await;
+        observe_lookup(endpoint, &result, cache_status, started.elapsed());
+        return result;
     }
 
-    let key = parts[1];
-    debug!("TCP lookup for key: {}", key);
+    let resolving = resolve(endpoint, mapname, key, user_agent, &mut cache_status);
+    let (result, traces) = traced(resolving).instrument(span).await;
+    let upstream_status = traces.last().and_then(|trace| trace.status);
+    let upstream_requests = traces.len();
+    let upstream_ms: u64 = traces.iter().map(|trace| trace.duration_ms).sum();
+    // Pass the exchanges on to an enclosing traced(), e.g. a test lookup
+    for trace in traces {
+        record_trace(trace);
+    }
+    let elapsed = started.elapsed();
+    observe_lookup(endpoint, &result, cache_status, elapsed);
+    let record = LookupRecord {
+        endpoint: &endpoint.name,
+        mode: &endpoint.mode,
+        map: mapname,
+        key,
+        result: result.as_ref().map_or("error", LookupResult::label),
+        class: result.as_ref().map_or("TEMP", |result| result.class(endpoint)),
+        cache: cache_status.label(),
+        upstream_status,
+        duration_ms: elapsed.as_millis() as u64,
+    };
+    if let Some(threshold) = endpoint.slow_threshold_ms {
+        if record.duration_ms > threshold {
+            logging::slow_lookup(&record, threshold, upstream_requests, upstream_ms);
+        }
+    }
+    logging::lookup(record);
+    result
+}
 
-    // Build URL
-    let mut url = Url::parse(&endpoint.target)?;
-    url.query_pairs_mut().append_pair("key", key);
+/// Add a finished lookup to the end-to-end latency histogram and the cache
+/// counters
+fn observe_lookup(
+    endpoint: &Endpoint,
+    result: &Result<LookupResult>,
+    cache_status: CacheStatus,
+    elapsed: Duration,
+) {
+    let class = result.as_ref().map_or("TEMP", |result| result.class(endpoint));
+    metrics::histogram_observe(
+        "connector_lookup_duration_seconds",
+        "Lookup latency as seen by the client, including cache and upstream",
+        &[("endpoint", &endpoint.name), ("result", class)],
+        elapsed.as_secs_f64(),
+    );
+    if !matches!(cache_status, CacheStatus::None) {
+        metrics::counter_inc(
+            "connector_cache_lookups_total",
+            "Lookups answered from the cache (hit, stale) or passed on (miss)",
+            &[("endpoint", &endpoint.name), ("status", cache_status.label())],
+        );
+    }
+}
+
+/// How the endpoint cache took part in a lookup
+#[derive(Debug, Clone, Copy)]
+enum CacheStatus {
+    /// No cache, or a static entry answered first
+    None,
+    Hit,
+    /// Expired entry served because the upstream failed or is in maintenance
+    Stale,
+    Miss,
+}
+
+impl CacheStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            CacheStatus::None => "none",
+            CacheStatus::Hit => "hit",
+            CacheStatus::Stale => "stale",
+            CacheStatus::Miss => "miss",
+        }
+    }
+}
 
+/// Resolve a map/key pair, consulting the endpoint cache first
+async fn resolve(
+    endpoint: &Endpoint,
+    mapname: Option<&str>,
+    key: &str,
+    user_agent: &str,
+    cache_status: &mut CacheStatus,
+) -> Result<LookupResult> {
+    let lookup_key = endpoint.lookup_key(key);
+    let key = lookup_key.as_ref();
+
+    if let Some(value) = endpoint.static_entry(key) {
+        debug!("Static map entry for key: {}", sensitive(key));
+        return Ok(LookupResult::Found(vec![value]));
+    }
+    let cache_map = mapname.unwrap_or("");
+    if let Some(cache) = endpoint.cache() {
+        *cache_status = CacheStatus::Miss;
+        if let Some(result) = cache.get(cache_map, key).await {
+            *cache_status = CacheStatus::Hit;
+            debug!("Cache hit for map: {:?}, key: {}", sensitive(&mapname), sensitive(key));
+            if let Some(events) = &endpoint.events {
+                events.emit(
+                    &endpoint.name,
+                    EventKind::CacheHit {
+                        map: mapname.map(str::to_string),
+                        key: key.to_string(),
+                    },
+                );
+            }
+            return Ok(result);
+        }
+    }
+
+    // Maintenance mode: stale answers where available, temporary failure otherwise
+    if endpoint.in_maintenance() {
+        if let Some(cache) = endpoint.cache() {
+            if let Some(stale) = cache.get_stale(cache_map, key).await {
+                *cache_status = CacheStatus::Stale;
+                return Ok(stale);
+            }
+        }
+        return Ok(LookupResult::Maintenance);
+    }
+
+    // Wait for a backend slot in the request's QoS class
+    let _permit = match &endpoint.qos {
+        Some(qos) => {
+            let info = RequestInfo {
+                endpoint: &endpoint.name,
+                map: mapname,
+                key: Some(key),
+                attributes: &[],
+            };
+            match qos.acquire(&info).await {
+                Ok(permit) => permit,
+                Err(_) => return Ok(LookupResult::Overloaded),
+            }
+        }
+        None => None,
+    };
+
+    let started = Instant::now();
+    let result = match endpoint.upstream() {
+        None => LookupResult::CircuitOpen,
+        Some(upstream) => {
+            let result = match upstream.backend() {
+                Some(backend) => backend.lookup(mapname, key).await,
+                None if !upstream.chain.is_empty() => {
+                    chain_lookup(upstream, mapname, key, user_agent).await?
+                }
+                None => match upstream.route(mapname) {
+                    Some(route) => rest_lookup(route, mapname, key, user_agent).await?,
+                    None => LookupResult::NotFound,
+                },
+            };
+            // No-op for the standby, which has no circuit breaker
+            upstream.circuit_record(!result.is_upstream_failure());
+            result
+        }
+    };
+    endpoint.slo_record(started.elapsed(), result.is_cacheable());
+
+    if let Some(events) = &endpoint.events {
+        events.emit(
+            &endpoint.name,
+            EventKind::Lookup {
+                map: mapname.map(str::to_string),
+                key: key.to_string(),
+                result: result.label(),
+                duration_ms: started.elapsed().as_millis() as u64,
+            },
+        );
+    }
+
+    if let Some(cache) = endpoint.cache() {
+        if result.is_cacheable() {
+            cache.insert(cache_map, key, result.clone()).await;
+        } else if result.is_upstream_failure() {
+            // Stale-while-error: prefer a recent answer over deferring mail
+            if let Some(stale) = cache.get_stale(cache_map, key).await {
+                warn!(
+                    "Endpoint '{}': upstream failed ({}), serving stale cache entry for key: {}",
+                    endpoint.name,
+                    result.label(),
+                    sensitive(key)
+                );
+                *cache_status = CacheStatus::Stale;
+                return Ok(stale);
+            }
+        }
+    }
+
+    if result.is_upstream_failure() {
+        if let Some(value) = endpoint.fallback_entry(key) {
+            warn!(
+                "Endpoint '{}': upstream failed ({}), answering from fallback-map for key: {}",
+                endpoint.name,
+                result.label(),
+                sensitive(key)
+            );
+            return Ok(LookupResult::Found(vec![value]));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Look up a map/key pair through the REST API
+async fn rest_lookup(
+    endpoint: &Endpoint,
+    mapname: Option<&str>,
+    key: &str,
+    user_agent: &str,
+) -> Result<LookupResult> {
+    let target = match endpoint.select_target() {
+        Some(target) => target,
+        None => {
+            warn!("Endpoint '{}': all targets drained", endpoint.name);
+            return Ok(LookupResult::ConnectionFailed);
+        }
+    };
+    rest_lookup_target(endpoint, target, mapname, key, user_agent).await
+}
+
+/// Consult the chain sources in order; the first hit wins
+///
+/// A miss (not found or empty) moves on to the next source. Any other
+/// result, e.g. an unreachable REST target, ends the chain so a key is
+/// never answered by a lower-priority source only because a higher one failed.
+async fn chain_lookup(
+    endpoint: &Endpoint,
+    mapname: Option<&str>,
+    key: &str,
+    user_agent: &str,
+) -> Result<LookupResult> {
+    for source in endpoint.chain_sources() {
+        let result = match source {
+            ChainSource::Map(map) => match map.get(key) {
+                Some(value) => LookupResult::Found(vec![value]),
+                None => LookupResult::NotFound,
+            },
+            ChainSource::Rest(target) => {
+                rest_lookup_target(endpoint, target, mapname, key, user_agent).await?
+            }
+        };
+        if !matches!(result, LookupResult::NotFound | LookupResult::Empty) {
+            return Ok(result);
+        }
+    }
+    Ok(LookupResult::NotFound)
+}
+
+async fn rest_lookup_target(
+    endpoint: &Endpoint,
+    target: &str,
+    mapname: Option<&str>,
+    key: &str,
+    user_agent: &str,
+) -> Result<LookupResult> {
+    let (name, context) = match (mapname, &endpoint.map_context) {
+        (Some(mapname), Some(map_context)) => {
+            let (name, context) = map_context.split(mapname);
+            (Some(name), context)
+        }
+        (mapname, _) => (mapname, Vec::new()),
+    };
+
+    // Build URL; {key} and {map} in the target replace the query parameters
+    let key_in_path = target.contains("{key}");
+    let map_in_path = name.is_some() && target.contains("{map}");
+    let mut url = Url::parse(&expand_target(target, name, key))?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let (Some(name), false) = (name, map_in_path) {
+            query.append_pair("name", name);
+        }
+        for (field, value) in context {
+            query.append_pair(field, value);
+        }
+        if !key_in_path {
+            query.append_pair("key", key);
+        }
+    }
+
+    Ok(fetch_lookup(endpoint, url, user_agent).await)
+}
+
+/// Upstream HTTP exchange of a lookup, captured for test lookups
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpstreamTrace {
+    pub url: String,
+    pub status: Option<u16>,
+    pub body: Option<String>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+tokio::task_local! {
+    static UPSTREAM_TRACES: RefCell<Vec<UpstreamTrace>>;
+}
+
+/// Run a future, collecting the upstream HTTP exchanges of its lookups
+pub async fn traced<F: Future>(future: F) -> (F::Output, Vec<UpstreamTrace>) {
+    UPSTREAM_TRACES
+        .scope(RefCell::new(Vec::new()), async move {
+            let output = future.await;
+            (output, UPSTREAM_TRACES.with(|traces| traces.take()))
+        })
+        .await
+}
+
+fn record_trace(trace: UpstreamTrace) {
+    // Outside of traced() there is nothing to record into
+    let _ = UPSTREAM_TRACES.try_with(|traces| traces.borrow_mut().push(trace));
+}
+
+/// Attach the request ID, trace context and the endpoint's credentials to an
+/// upstream request
+async fn authorize(endpoint: &Endpoint, request: RequestBuilder) -> Result<RequestBuilder> {
+    let request = match logging::request_id() {
+        Some(id) if !endpoint.request_id_header.is_empty() => {
+            request.header(endpoint.request_id_header.as_str(), id)
+        }
+        _ => request,
+    };
+    let request = telemetry::inject(request);
+    let mut request = match &endpoint.credentials {
+        Some(credentials) => credentials.apply(request),
+        None => {
+            let token = endpoint.auth_token();
+            // With oauth2 or a signature the static token is optional
+            let other_auth = endpoint.oauth2.is_some()
+                || endpoint.signing.is_some()
+                || endpoint.aws_sigv4.is_some();
+            if !token.is_empty() || !other_auth {
+                request.header("X-Auth-Token", token)
+            } else {
+                request
+            }
+        }
+    };
+    if let Some(bearer) = endpoint.bearer_token().await? {
+        request = request.bearer_auth(bearer);
+    }
+    // Signed last, over the request exactly as it is sent
+    if endpoint.request_signer.is_some() || endpoint.sigv4_signer.is_some() {
+        let (client, built) = request.build_split();
+        let mut built = built?;
+        if let Some(signer) = &endpoint.request_signer {
+            signer.sign(&mut built)?;
+        }
+        if let Some(signer) = &endpoint.sigv4_signer {
+            signer.sign(&mut built).await?;
+        }
+        request = RequestBuilder::from_parts(client, built);
+    }
+    Ok(request)
+}
+
+/// Send an upstream request, recording its latency (including retries) by
+/// status class
+async fn send_upstream(
+    upstream: &Endpoint,
+    request: RequestBuilder,
+    idempotent: bool,
+) -> reqwest::Result<Response> {
+    let started = Instant::now();
+    let response = send_with_retry(upstream.retry.as_ref(), request, idempotent).await;
+    let status = match &response {
+        Ok(response) => match response.status().as_u16() / 100 {
+            1 => "1xx",
+            2 => "2xx",
+            3 => "3xx",
+            4 => "4xx",
+            _ => "5xx",
+        },
+        Err(_) => "error",
+    };
+    metrics::histogram_observe(
+        "connector_upstream_request_duration_seconds",
+        "Upstream HTTP request latency, including retries",
+        &[("endpoint", &upstream.name), ("status", status)],
+        started.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+/// Issue the HTTP request and classify the upstream response
+async fn fetch_lookup(endpoint: &Endpoint, url: Url, user_agent: &str) -> LookupResult {
+    let mut trace = UpstreamTrace {
+        url: url.to_string(),
+        status: None,
+        body: None,
+        error: None,
+        duration_ms: 0,
+    };
+    let started = Instant::now();
+
+    let result = fetch_lookup_traced(endpoint, url, user_agent, &mut trace).await;
+    trace.duration_ms = started.elapsed().as_millis() as u64;
+    record_trace(trace);
+    result
+}
+
+async fn fetch_lookup_traced(
+    endpoint: &Endpoint,
+    url: Url,
+    user_agent: &str,
+    trace: &mut UpstreamTrace,
+) -> LookupResult {
     // Use the pre-created HTTP client (connection pooling!)
-    let response = endpoint.client()
+    let request = endpoint.client()
         .get(url)
-        .header("X-Auth-Token", &endpoint.auth_token)
-        .header("User-Agent", user_agent)
-        .send()
-        .await;
+        .header("User-Agent", user_agent);
+    let request = match authorize(endpoint, request).await {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Endpoint '{}': {:#}", endpoint.name, e);
+            trace.error = Some(format!("{:#}", e));
+            return LookupResult::ConnectionFailed;
+        }
+    };
+    let response = send_upstream(endpoint, request, true).await;
 
     match response {
         Ok(resp) => {
             let status = resp.status();
             debug!("HTTP response code: {}", status);
+            trace.status = Some(status.as_u16());
 
             if status.is_success() {
                 // Parse JSON array response
-                match resp.json::<Value>().await {
-                    Ok(Value::Array(arr)) if !arr.is_empty() => {
-                        // Encode each value and join with commas
-                        let encoded_values: Vec<String> = arr
-                            .iter()
-                            .filter_map(|v| v.as_str())
-                            .map(encode_response)
-                            .collect();
-                        
-                        if encoded_values.is_empty() {
-                            format_tcp_response(500, "Empty result")
-                        } else {
-                            // Join encoded values with literal commas
-                            let joined = encoded_values.join(",");
-                            let response = format!("200 {}{}", joined, END_CHAR);
-                            
-                            if response.len() > TCP_MAXIMUM_RESPONSE_LENGTH {
-                                warn!("Response exceeds maximum length: {} > {}", 
-                                      response.len(), TCP_MAXIMUM_RESPONSE_LENGTH);
-                                Ok(format!("500 Response%20too%20long{}", END_CHAR))
-                            } else {
-                                Ok(response)
-                            }
-                        }
+                let body = match resp.bytes().await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        error!("Failed to read response: {}", e);
+                        trace.error = Some(e.to_string());
+                        return LookupResult::InvalidJson;
                     }
-                    Ok(_) => format_tcp_response(500, "Empty result"),
+                };
+                trace.body = Some(String::from_utf8_lossy(&body).into_owned());
+
+                if endpoint.response_format == ResponseFormat::Text {
+                    return text_values(endpoint, &body);
+                }
+                match serde_json::from_slice::<Value>(&body) {
+                    Ok(json) => response_values(endpoint, &json),
                     Err(e) => {
                         error!("JSON parse error: {}", e);
-                        format_tcp_response(500, "Invalid JSON")
+                        LookupResult::InvalidJson
                     }
                 }
-            } else if status.as_u16() == 404 {
-                format_tcp_response(500, "Not found")
-            } else if status.is_client_error() {
-                format_tcp_response(400, "Client error")
-            } else if status.is_server_error() {
-                format_tcp_response(400, "Server error")
             } else {
-                format_tcp_response(500, "Unknown error")
+                if status.as_u16() == 401 {
+                    endpoint.bearer_rejected().await;
+                }
+                status_result(endpoint, status)
             }
         }
+        Err(e) if e.is_timeout() => {
+            error!("HTTP request timed out: {}", e);
+            trace.error = Some(e.to_string());
+            LookupResult::Timeout
+        }
         Err(e) => {
             error!("HTTP request failed: {}", e);
-            format_tcp_response(400, "Connection failed")
+            trace.error = Some(e.to_string());
+            LookupResult::ConnectionFailed
         }
     }
 }
 
+/// Handle TCP lookup protocol
+pub async fn handle_tcp_lookup(
+    endpoint: &Endpoint,
+    request: &str,
+    user_agent: &str,
+) -> Result<String> {
+    // Parse: "get SPACE key NEWLINE"
+    // split_whitespace() already trims, so no need to call trim() first
+    let parts: Vec<&str> = request.split_whitespace().collect();
+    match parts.as_slice() {
+        ["put", key, value] => {
+            let key = decode_tcp_data(endpoint, key);
+            let value = decode_tcp_data(endpoint, value);
+            handle_tcp_put(endpoint, &key, &value, user_agent).await
+        }
+        ["get", key, ..] => {
+            let key = decode_tcp_data(endpoint, key);
+            debug!("TCP lookup for key: {}", sensitive(&key));
+            lookup(endpoint, None, &key, user_agent).await?.to_tcp_response(endpoint)
+        }
+        _ => format_tcp_response(500, "Invalid request"),
+    }
+}
+
+/// Undo the tcp_table %XX encoding of keys and values, unless raw-tcp-keys
+fn decode_tcp_data<'a>(endpoint: &Endpoint, data: &'a str) -> Cow<'a, str> {
+    if endpoint.raw_tcp_keys {
+        Cow::Borrowed(data)
+    } else {
+        percent_encoding::percent_decode_str(data).decode_utf8_lossy()
+    }
+}
+
+/// Handle "put SPACE key SPACE value" by sending both upstream
+///
+/// Replies 200 once the upstream accepted the update, 400 when it may work
+/// later and 500 when it will not (including endpoints without put).
+async fn handle_tcp_put(
+    endpoint: &Endpoint,
+    key: &str,
+    value: &str,
+    user_agent: &str,
+) -> Result<String> {
+    if endpoint.put.is_none() {
+        return format_tcp_response(500, "Put not supported");
+    }
+    match store_value(endpoint, key, Some(value), user_agent).await? {
+        StoreOutcome::Stored => format_tcp_response(200, "Stored"),
+        StoreOutcome::Rejected => format_tcp_response(500, "Put rejected"),
+        StoreOutcome::Failed(reason) => format_tcp_response(400, reason),
+    }
+}
+
+/// Result of a map update sent upstream
+enum StoreOutcome {
+    Stored,
+    /// 4xx, e.g. the key is not writable or (on delete) does not exist
+    Rejected,
+    Failed(&'static str),
+}
+
+/// Send a map update through the put upstream: `{key, value}` with the put
+/// method, or a DELETE of the key when there is no value
+async fn store_value(
+    endpoint: &Endpoint,
+    key: &str,
+    value: Option<&str>,
+    user_agent: &str,
+) -> Result<StoreOutcome> {
+    let Some(put) = &endpoint.put else {
+        return Ok(StoreOutcome::Rejected);
+    };
+    let lookup_key = endpoint.lookup_key(key);
+    let key = lookup_key.as_ref();
+    debug!("Map update for key: {}", sensitive(key));
+
+    let Some(upstream) = endpoint.upstream() else {
+        return Ok(StoreOutcome::Failed("Circuit open"));
+    };
+    let target = match &put.target {
+        Some(target) => target.as_str(),
+        None => match upstream.select_target() {
+            Some(target) => target,
+            None => return Ok(StoreOutcome::Failed("Connection failed")),
+        },
+    };
+    let url = Url::parse(&expand_target(target, None, key))?;
+
+    let (request, idempotent) = match (value, put.method) {
+        (Some(_), PutMethod::Put) => (upstream.client().put(url), true),
+        (Some(_), PutMethod::Post) => (upstream.client().post(url), false),
+        (None, _) => (upstream.client().delete(url), true),
+    };
+    let body = match value {
+        Some(value) => serde_json::json!({ "key": key, "value": value }),
+        None => serde_json::json!({ "key": key }),
+    };
+    let request = request.header("User-Agent", user_agent).json(&body);
+    let request = match authorize(upstream, request).await {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Endpoint '{}': {:#}", upstream.name, e);
+            return Ok(StoreOutcome::Failed("Connection failed"));
+        }
+    };
+    let response = send_upstream(upstream, request, idempotent).await;
+    upstream.circuit_record(matches!(&response, Ok(resp) if !resp.status().is_server_error()));
+
+    Ok(match response {
+        Ok(resp) if resp.status().is_success() => {
+            // Later lookups must not see the old value
+            if let Some(cache) = endpoint.cache() {
+                cache.remove("", key).await;
+            }
+            StoreOutcome::Stored
+        }
+        Ok(resp) if resp.status().is_client_error() => {
+            if resp.status().as_u16() == 401 {
+                upstream.bearer_rejected().await;
+            }
+            warn!("Endpoint '{}': update rejected with HTTP {}", endpoint.name, resp.status());
+            StoreOutcome::Rejected
+        }
+        Ok(resp) => {
+            warn!("Endpoint '{}': update failed with HTTP {}", endpoint.name, resp.status());
+            StoreOutcome::Failed("Server error")
+        }
+        Err(e) => {
+            error!("HTTP request failed: {}", e);
+            StoreOutcome::Failed("Connection failed")
+        }
+    })
+}
+
+/// Handle memcache text protocol (the subset used by Postfix memcache_table)
+///
+/// Lookups go through the backend. `set` and `delete` go to the put upstream
+/// when one is configured and are answered NOT_STORED/NOT_FOUND otherwise.
+pub async fn handle_memcache(
+    endpoint: &Endpoint,
+    request: &str,
+    user_agent: &str,
+) -> Result<String> {
+    let mut response = String::new();
+    let mut lines = request.split('\n').map(|line| line.trim_end_matches('\r'));
+
+    while let Some(line) = lines.next() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let Some(&command) = parts.first() else {
+            continue;
+        };
+        let noreply = parts.last() == Some(&"noreply");
+
+        match command {
+            "get" | "gets" if parts.len() >= 2 => {
+                for key in &parts[1..] {
+                    debug!("Memcache lookup for key: {}", sensitive(*key));
+                    match lookup(endpoint, None, key, user_agent).await? {
+                        LookupResult::Found(values) => {
+                            let Some(data) = join_values(endpoint, &values, str::to_string) else {
+                                response.push_str("SERVER_ERROR multiple-values\r\n");
+                                return Ok(response);
+                            };
+                            response.push_str(&format!(
+                                "VALUE {} 0 {}\r\n{}\r\n",
+                                key,
+                                data.len(),
+                                data
+                            ));
+                        }
+                        result if result.is_cacheable() => {}
+                        LookupResult::Mapped {
+                            socketmap: SocketmapReply::NotFound,
+                            ..
+                        } => {}
+                        result => {
+                            response.push_str(&format!("SERVER_ERROR {}\r\n", result.label()));
+                            return Ok(response);
+                        }
+                    }
+                }
+                response.push_str("END\r\n");
+            }
+            "set" if parts.len() >= 5 && endpoint.put.is_some() => {
+                let data = lines.next().unwrap_or("");
+                let reply = match store_value(endpoint, parts[1], Some(data), user_agent).await? {
+                    StoreOutcome::Stored => "STORED\r\n".to_string(),
+                    StoreOutcome::Rejected => "NOT_STORED\r\n".to_string(),
+                    StoreOutcome::Failed(reason) => format!("SERVER_ERROR {}\r\n", reason),
+                };
+                if !noreply {
+                    response.push_str(&reply);
+                }
+            }
+            "set" | "add" | "replace" | "append" | "prepend" if parts.len() >= 5 => {
+                // Skip the data block
+                lines.next();
+                if !noreply {
+                    response.push_str("NOT_STORED\r\n");
+                }
+            }
+            "delete" if parts.len() >= 2 && endpoint.put.is_some() => {
+                let reply = match store_value(endpoint, parts[1], None, user_agent).await? {
+                    StoreOutcome::Stored => "DELETED\r\n".to_string(),
+                    StoreOutcome::Rejected => "NOT_FOUND\r\n".to_string(),
+                    StoreOutcome::Failed(reason) => format!("SERVER_ERROR {}\r\n", reason),
+                };
+                if !noreply {
+                    response.push_str(&reply);
+                }
+            }
+            "delete" if parts.len() >= 2 => {
+                if !noreply {
+                    response.push_str("NOT_FOUND\r\n");
+                }
+            }
+            "version" => response.push_str(concat!("VERSION ", env!("CARGO_PKG_VERSION"), "\r\n")),
+            "quit" => break,
+            _ => response.push_str("ERROR\r\n"),
+        }
+    }
+
+    Ok(response)
+}
+
 /// Handle socketmap lookup protocol (uses netstring format!)
 pub async fn handle_socketmap_lookup(
     endpoint: &Endpoint,
-    request: &str,
+    request: &[u8],
     user_agent: &str,
 ) -> Result<String> {
     // Socketmap uses netstring protocol
     debug!("Received socketmap request: {} bytes", request.len());
     
     // Decode the netstring request
-    let decoded = match decode_netstring(request.as_bytes()) {
+    let decoded = match decode_netstring(request) {
         Some(data) => data,
         None => {
             warn!("Invalid netstring format. Received: {:?}", 
-                  String::from_utf8_lossy(request.as_bytes()));
+                  sensitive(&String::from_utf8_lossy(request)));
             return Ok(encode_netstring("TEMP Invalid netstring format"));
         }
     };
     
-    // Parse: "name SPACE key"
-    let parts: Vec<&str> = decoded.splitn(2, ' ').collect();
+    // Parse: "name SPACE key"; only the key may be binary. Some sendmail
+    // versions send the key alone.
+    let (mapname, key) = match decoded.iter().position(|&b| b == b' ') {
+        Some(space) => (Some(&decoded[..space]), &decoded[space + 1..]),
+        None if endpoint.dialect == SocketmapDialect::Sendmail => (None, decoded),
+        None => return Ok(encode_netstring("TEMP Invalid request")),
+    };
+    let Ok(mapname) = mapname.map(std::str::from_utf8).transpose() else {
+        return Ok(encode_netstring("TEMP Invalid map name"));
+    };
+    let key = key_text(key);
+    let key = key.as_ref();
     
-    if parts.len() != 2 {
-        return Ok(encode_netstring("TEMP Invalid request"));
+    debug!("Socketmap lookup - map: {:?}, key: {}", sensitive(&mapname), sensitive(key));
+
+    if endpoint.route(mapname).is_none() {
+        warn!("Endpoint '{}': no route for map {:?}", endpoint.name, mapname);
+        return Ok(encode_netstring(&format!(
+            "{} Unknown map",
+            endpoint.socketmap_replies.unknown_map.code()
+        )));
     }
 
-    let mapname = parts[0];
-    let key = parts[1];
-    
-    debug!("Socketmap lookup - map: {}, key: {}", mapname, key);
+    Ok(lookup(endpoint, mapname, key, user_agent)
+        .await?
+        .to_socketmap_response(endpoint))
+}
 
-    // Build URL
-    let mut url = Url::parse(&endpoint.target)?;
-    url.query_pairs_mut()
-        .append_pair("name", mapname)
-        .append_pair("key", key);
+/// Connection state of the Dovecot dict protocol, set by the hello command
+#[derive(Debug, Default)]
+pub struct DictSession {
+    user: String,
+    dict: String,
+}
 
-    // Use the pre-created HTTP client
-    let response = endpoint.client()
-        .get(url)
-        .header("X-Auth-Token", &endpoint.auth_token)
-        .header("User-Agent", user_agent)
-        .send()
-        .await;
+/// Undo Dovecot's tab escaping: \x01 followed by 1, t, r or n
+fn dict_unescape(data: &str) -> Cow<'_, str> {
+    if !data.contains('\x01') {
+        return Cow::Borrowed(data);
+    }
+    let mut unescaped = String::with_capacity(data.len());
+    let mut chars = data.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x01' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('1') => unescaped.push('\x01'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    Cow::Owned(unescaped)
+}
 
-    match response {
-        Ok(resp) => {
-            let status = resp.status();
-            debug!("HTTP response code: {}", status);
+fn dict_escape(data: &str) -> Cow<'_, str> {
+    if !data.contains(['\x01', '\t', '\r', '\n']) {
+        return Cow::Borrowed(data);
+    }
+    let mut escaped = String::with_capacity(data.len() + 8);
+    for c in data.chars() {
+        match c {
+            '\x01' => escaped.push_str("\x011"),
+            '\t' => escaped.push_str("\x01t"),
+            '\r' => escaped.push_str("\x01r"),
+            '\n' => escaped.push_str("\x01n"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
 
-            if status.is_success() {
-                match resp.json::<Value>().await {
-                    Ok(Value::Array(arr)) if !arr.is_empty() => {
-                        // Encode each value and join with commas
-                        let encoded_values: Vec<String> = arr
-                            .iter()
-                            .filter_map(|v| v.as_str())
-                            .map(encode_response)
-                            .collect();
-                        
-                        if encoded_values.is_empty() {
-                            Ok(encode_netstring("NOTFOUND "))
-                        } else {
-                            let joined = encoded_values.join(",");
-                            let response_text = format!("OK {}", joined);
-                            
-                            if response_text.len() > SOCKETMAP_MAXIMUM_RESPONSE_LENGTH {
-                                warn!("Socketmap response too long: {} bytes", response_text.len());
-                                Ok(encode_netstring("TEMP Response too long"))
-                            } else {
-                                Ok(encode_netstring(&response_text))
-                            }
-                        }
-                    }
-                    Ok(_) => Ok(encode_netstring("NOTFOUND ")),
-                    Err(e) => {
-                        error!("JSON parse error: {}", e);
-                        Ok(encode_netstring("TEMP Invalid JSON"))
-                    }
+/// Handle the Dovecot dict proxy protocol (read-only)
+///
+/// The dict name of the hello command is the map name; "shared/<path>" is
+/// looked up as <path> and "priv/<path>" as <user>/<path>.
+pub async fn handle_dovecot_dict(
+    endpoint: &Endpoint,
+    request: &str,
+    session: &mut DictSession,
+    user_agent: &str,
+) -> Result<String> {
+    let mut response = String::new();
+
+    for line in request.split('\n').map(|line| line.trim_end_matches('\r')) {
+        let Some(command) = line.chars().next() else {
+            continue;
+        };
+        let args: Vec<Cow<str>> = line[command.len_utf8()..]
+            .split('\t')
+            .map(dict_unescape)
+            .collect();
+
+        match command {
+            // H<major>\t<minor>\t<value type>\t<user>\t<dict name>, no reply
+            'H' => {
+                session.user = args.get(3).map(|user| user.to_string()).unwrap_or_default();
+                session.dict = args.get(4).map(|dict| dict.to_string()).unwrap_or_default();
+                debug!("Dovecot dict hello for dict '{}'", session.dict);
+            }
+            'L' => {
+                let reply = dict_lookup(endpoint, session, &args[0], user_agent).await?;
+                response.push_str(&reply);
+            }
+            // REST lookups cannot be enumerated, iterations finish empty
+            'I' => response.push('\n'),
+            // Writes are discarded and their transaction fails on commit
+            'C' | 'D' => {
+                response.push_str(&format!("F{}\tRead-only dictionary\n", args[0]));
+            }
+            'B' | 'R' | 'S' | 'U' | 'A' | 'T' => {}
+            _ => warn!("Unknown Dovecot dict command: {:?}", command),
+        }
+    }
+
+    Ok(response)
+}
+
+async fn dict_lookup(
+    endpoint: &Endpoint,
+    session: &DictSession,
+    key: &str,
+    user_agent: &str,
+) -> Result<String> {
+    let key = match (key.strip_prefix("shared/"), key.strip_prefix("priv/")) {
+        (Some(path), _) => path.to_string(),
+        (None, Some(path)) => format!("{}/{}", session.user, path),
+        (None, None) => return Ok("FInvalid key namespace\n".to_string()),
+    };
+    let mapname = (!session.dict.is_empty()).then_some(session.dict.as_str());
+    debug!("Dovecot dict lookup - dict: {:?}, key: {}", mapname, sensitive(&key));
+
+    if endpoint.route(mapname).is_none() {
+        warn!("Endpoint '{}': no route for dict {:?}", endpoint.name, mapname);
+        return Ok("FUnknown dictionary\n".to_string());
+    }
+
+    Ok(match lookup(endpoint, mapname, &key, user_agent).await? {
+        LookupResult::Found(values) => match join_values(endpoint, &values, str::to_string) {
+            Some(value) => format!("O{}\n", dict_escape(&value)),
+            None => "FMultiple values\n".to_string(),
+        },
+        result if result.is_cacheable() => "N\n".to_string(),
+        LookupResult::Mapped {
+            socketmap: SocketmapReply::NotFound,
+            ..
+        } => "N\n".to_string(),
+        result => format!("F{}\n", result.label()),
+    })
+}
+
+// Lookup services announced to smtpd; "source" needs fetch, which REST
+// lookups cannot serve
+const OPENSMTPD_SERVICES: &[&str] = &[
+    "alias",
+    "domain",
+    "credentials",
+    "netaddr",
+    "userinfo",
+    "mailaddr",
+    "addrname",
+    "mailaddrmap",
+    "relayhost",
+    "string",
+];
+
+#[derive(Debug, Default)]
+pub struct TableSession {
+    table: String,
+}
+
+/// Handle the OpenSMTPD table protocol (proc tables, version 0.1)
+///
+/// The table name is the map name. After smtpd's configuration lines the
+/// lookup services are registered; check and lookup queries go to the REST
+/// API, fetch is not supported and updates succeed without doing anything.
+pub async fn handle_opensmtpd_table(
+    endpoint: &Endpoint,
+    request: &str,
+    session: &mut TableSession,
+    user_agent: &str,
+) -> Result<String> {
+    let mut response = String::new();
+
+    for line in request.split('\n').map(|line| line.trim_end_matches('\r')) {
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(8, '|').collect();
+
+        match fields.as_slice() {
+            ["config", "tablename", name] => session.table = name.to_string(),
+            ["config", "ready"] => {
+                for service in OPENSMTPD_SERVICES {
+                    response.push_str(&format!("register|{}\n", service));
                 }
-            } else if status.as_u16() == 404 {
-                Ok(encode_netstring("NOTFOUND "))
-            } else if status.is_client_error() {
-                Ok(encode_netstring("PERM Configuration error"))
-            } else if status.is_server_error() {
-                Ok(encode_netstring("TEMP Server error"))
-            } else {
-                Ok(encode_netstring("TEMP Unknown error"))
+                response.push_str("register|ready\n");
+            }
+            ["config", ..] => {}
+            ["table", _version, _timestamp, table, "update", id] => {
+                debug!("OpenSMTPD table update for table '{}'", table);
+                response.push_str(&format!("update-result|{}|ok\n", id));
             }
+            [
+                "table",
+                _version,
+                _timestamp,
+                table,
+                op @ ("check" | "lookup" | "fetch"),
+                service,
+                id,
+                key @ ..,
+            ] => {
+                if !table.is_empty() {
+                    session.table = table.to_string();
+                }
+                let key = key.first().copied().unwrap_or("");
+                let result = match *op {
+                    "fetch" => "error".to_string(),
+                    op => table_lookup(endpoint, session, op, service, key, user_agent).await?,
+                };
+                response.push_str(&format!("{}-result|{}|{}\n", op, id, result));
+            }
+            _ => warn!("Unknown OpenSMTPD table request: {:?}", sensitive(line)),
         }
-        Err(e) => {
-            error!("HTTP request failed: {}", e);
-            Ok(encode_netstring("TEMP Connection failed"))
+    }
+
+    Ok(response)
+}
+
+async fn table_lookup(
+    endpoint: &Endpoint,
+    session: &TableSession,
+    op: &str,
+    service: &str,
+    key: &str,
+    user_agent: &str,
+) -> Result<String> {
+    let mapname = (!session.table.is_empty()).then_some(session.table.as_str());
+    debug!(
+        "OpenSMTPD table {} - table: {:?}, service: {}, key: {}",
+        op,
+        mapname,
+        service,
+        sensitive(key)
+    );
+
+    if endpoint.route(mapname).is_none() {
+        warn!("Endpoint '{}': no route for table {:?}", endpoint.name, mapname);
+        return Ok("error".to_string());
+    }
+
+    Ok(match lookup(endpoint, mapname, key, user_agent).await? {
+        LookupResult::Found(_) if op == "check" => "found".to_string(),
+        // Multiple values are a list, e.g. alias expansions
+        LookupResult::Found(values) => match join_values(endpoint, &values, str::to_string) {
+            Some(value) => format!("found|{}", value.replace(['\r', '\n'], " ")),
+            None => "error".to_string(),
+        },
+        result if result.is_cacheable() => "not-found".to_string(),
+        LookupResult::Mapped {
+            socketmap: SocketmapReply::NotFound,
+            ..
+        } => "not-found".to_string(),
+        _ => "error".to_string(),
+    })
+}
+
+/// Why a policy query gave no verdict, selecting the policy-fallback action
+#[derive(Debug, Clone, Copy)]
+enum PolicyFailure {
+    /// Upstream unreachable, failing or not asked (overload)
+    Unavailable,
+    Timeout,
+    /// Not asked, the endpoint is in maintenance mode
+    Maintenance,
+    /// Upstream answered with something that is not a usable action
+    InvalidResponse,
+}
+
+/// Outcome of a policy query
+enum PolicyReply {
+    /// Verdict from the policy service
+    Upstream(String),
+    /// The service gave no usable verdict; the policy-fallback action, or
+    /// DEFER_IF_PERMIT with this reason
+    Deferred(PolicyFailure, &'static str),
+    /// Action configured in status-mapping for the HTTP status
+    Mapped(String),
+}
+
+impl PolicyReply {
+    fn into_response(self, endpoint: &Endpoint) -> String {
+        match self {
+            PolicyReply::Upstream(response) => response,
+            PolicyReply::Deferred(failure, reason) => {
+                let fallback = &endpoint.policy_fallback;
+                let specific = match failure {
+                    PolicyFailure::Unavailable => None,
+                    PolicyFailure::Timeout => fallback.timeout.as_ref(),
+                    PolicyFailure::InvalidResponse => fallback.invalid_response.as_ref(),
+                    PolicyFailure::Maintenance => fallback.maintenance.as_ref(),
+                };
+                match specific.or(fallback.default.as_ref()) {
+                    Some(action) => format!("action={}\n\n", action),
+                    None => format!(
+                        "action=DEFER_IF_PERMIT {}\n\n",
+                        reply_reason(endpoint, reason)
+                    ),
+                }
+            }
+            PolicyReply::Mapped(action) => format!("action={}\n\n", action),
         }
     }
 }
@@ -272,28 +1573,310 @@ pub async fn handle_policy_check(
     request: &str,
     user_agent: &str,
 ) -> Result<String> {
+    let started = Instant::now();
+    // Local checks answer before the backend is asked, standalone
+    // endpoints have nothing else
+    let mut local_action = None;
+    if let Some(dnsbl) = endpoint.dnsbl() {
+        local_action = dnsbl.check(request).await;
+    }
+    if let (None, Some(limiter)) = (&local_action, endpoint.rate_limiter()) {
+        local_action = limiter.check(request).map(str::to_string);
+    }
+    if local_action.is_some() || endpoint.is_standalone_policy() {
+        let action = local_action.as_deref().unwrap_or("DUNNO");
+        let response = format!("action={}\n\n", action);
+        emit_verdict(endpoint, &response, started);
+        return Ok(response);
+    }
+
+    let cache = endpoint.policy_cache();
+    let cache_key = cache.map(|cache| cache.key(request));
+    let cached = match (cache, &cache_key) {
+        (Some(cache), Some(key)) => cache.get(key),
+        _ => None,
+    };
+
+    let response = match cached {
+        Some(response) => {
+            debug!("Policy cache hit for key: {:?}", sensitive(&cache_key));
+            response
+        }
+        None => {
+            let reply = query_policy(endpoint, request, user_agent).await?;
+            endpoint.slo_record(started.elapsed(), matches!(reply, PolicyReply::Upstream(_)));
+            // Only verdicts of the backend, never fallbacks, are reused
+            if let (PolicyReply::Upstream(response), Some(cache), Some(key)) =
+                (&reply, cache, cache_key)
+            {
+                cache.insert(key, response.clone());
+            }
+            reply.into_response(endpoint)
+        }
+    };
+
+    emit_verdict(endpoint, &response, started);
+    Ok(response)
+}
+
+/// Answer a policy request with the built-in greylisting engine
+pub async fn handle_greylist(endpoint: &Endpoint, request: &str) -> Result<String> {
+    let started = Instant::now();
+    let greylist = endpoint
+        .greylist()
+        .context("Greylist engine not initialized")?;
+
+    let action = match greylist.check(request).await {
+        Ok(action) => action,
+        Err(e) => {
+            // Greylisting fails open unless policy-fallback says otherwise
+            error!("Endpoint '{}': greylist check failed: {}", endpoint.name, e);
+            endpoint
+                .policy_fallback
+                .default
+                .clone()
+                .unwrap_or_else(|| "DUNNO".to_string())
+        }
+    };
+    let response = format!("action={}\n\n", action);
+
+    emit_verdict(endpoint, &response, started);
+    Ok(response)
+}
+
+/// Answer a policy request from the local per-user quota counters
+pub async fn handle_quota(endpoint: &Endpoint, request: &str, user_agent: &str) -> Result<String> {
+    let started = Instant::now();
+    let quota = endpoint.quota().context("Quota counters not initialized")?;
+    let attributes: HashMap<&str, &str> = request
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+
+    // Only authenticated submissions have a quota
+    let action = match attributes.get("sasl_username") {
+        Some(user) if !user.is_empty() => {
+            if let Some(reported) = quota.begin_sync(user) {
+                let status = sync_quota(endpoint, user, reported, user_agent).await;
+                if let Err(e) = &status {
+                    warn!("Endpoint '{}': quota sync failed: {:#}", endpoint.name, e);
+                }
+                quota.end_sync(user, reported, status.ok());
+            }
+            let instance = attributes.get("instance").copied().unwrap_or("");
+            match quota.count(user, instance) {
+                QuotaVerdict::Allowed => "DUNNO",
+                QuotaVerdict::Exceeded => {
+                    debug!("Quota exceeded for {}", sensitive(user));
+                    quota.action()
+                }
+            }
+        }
+        _ => "DUNNO",
+    };
+    let response = format!("action={}\n\n", action);
+
+    emit_verdict(endpoint, &response, started);
+    Ok(response)
+}
+
+/// Report a user's count since the last sync to the quota API and return
+/// the user's limit and usage
+async fn sync_quota(
+    endpoint: &Endpoint,
+    user: &str,
+    count: u64,
+    user_agent: &str,
+) -> Result<QuotaStatus> {
+    let body = serde_json::json!({ "user": user, "count": count });
+    post_json(endpoint, &body, user_agent).await
+}
+
+/// POST a JSON document to the endpoint's target and parse the JSON answer
+pub async fn post_json<T: DeserializeOwned>(
+    endpoint: &Endpoint,
+    body: &Value,
+    user_agent: &str,
+) -> Result<T> {
+    let upstream = endpoint.upstream().context("Circuit open")?;
+    let target = upstream.select_target().context("All targets drained")?;
+    let request = upstream
+        .client()
+        .post(target)
+        .header("User-Agent", user_agent)
+        .json(body);
+    let request = authorize(upstream, request).await?;
+    // POSTs are only retried when the endpoint opts in (retry-policy)
+    let response = send_upstream(upstream, request, false).await;
+    upstream.circuit_record(matches!(&response, Ok(resp) if !resp.status().is_server_error()));
+
+    let response = response?;
+    if !response.status().is_success() {
+        anyhow::bail!("HTTP {}", response.status());
+    }
+    Ok(response.json().await?)
+}
+
+fn emit_verdict(endpoint: &Endpoint, response: &str, started: Instant) {
+    if let Some(events) = &endpoint.events {
+        let action = response
+            .trim()
+            .strip_prefix("action=")
+            .unwrap_or("")
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_string();
+        events.emit(
+            &endpoint.name,
+            EventKind::Verdict {
+                action,
+                duration_ms: started.elapsed().as_millis() as u64,
+            },
+        );
+    }
+}
+
+/// Policy attributes as a form body, names and values percent-encoded
+fn policy_form(request: &str) -> String {
+    let mut form = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in request.lines().filter_map(|line| line.split_once('=')) {
+        form.append_pair(name, value);
+    }
+    form.finish()
+}
+
+/// Policy attributes as a JSON object; repeated attributes become arrays
+fn policy_json(request: &str) -> Value {
+    let mut object = serde_json::Map::new();
+    for (name, value) in request.lines().filter_map(|line| line.split_once('=')) {
+        let value = Value::String(value.to_string());
+        match object.get_mut(name) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(first) => *first = Value::Array(vec![first.take(), value]),
+            None => {
+                object.insert(name.to_string(), value);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// Forward a policy request to the backend and return the Postfix response
+async fn query_policy(
+    endpoint: &Endpoint,
+    request: &str,
+    user_agent: &str,
+) -> Result<PolicyReply> {
     debug!("Policy check request");
 
+    // Static attributes tell a shared backend which connector is asking,
+    // GeoIP attributes where the client is
+    let mut extra: Vec<String> = endpoint
+        .policy_attributes
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    if let Some(geoip) = endpoint.geoip() {
+        let address = request
+            .lines()
+            .find_map(|line| line.strip_prefix("client_address="));
+        if let Some(address) = address {
+            extra.extend(
+                geoip
+                    .attributes(address)
+                    .into_iter()
+                    .map(|(name, value)| format!("{}={}", name, value)),
+            );
+        }
+    }
+    let enriched;
+    let request = if extra.is_empty() {
+        request
+    } else {
+        let mut lines: Vec<String> = request
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+        lines.extend(extra);
+        enriched = lines.join("\n");
+        enriched.as_str()
+    };
+
     // Convert Postfix policy format (newline-separated) to URL-encoded format
     // Postfix sends: "name=value\nname2=value2\n\n"
-    // REST API expects: "name=value&name2=value2"
-    let body = request
-        .lines()
-        .filter(|line| !line.is_empty())  // Remove empty lines
-        .collect::<Vec<&str>>()
-        .join("&");  // Join with & instead of newlines
+    // REST API expects: "name=value&name2=value2" (or a JSON object)
+    let (content_type, body) = match endpoint.policy_body {
+        PolicyBody::Form => ("application/x-www-form-urlencoded", policy_form(request)),
+        PolicyBody::Json => ("application/json", policy_json(request).to_string()),
+    };
 
-    debug!("Converted policy request body: {}", body);
+    debug!("Converted policy request body: {}", sensitive(&body));
+
+    if endpoint.in_maintenance() {
+        return Ok(PolicyReply::Deferred(PolicyFailure::Maintenance, "Maintenance"));
+    }
+
+    // Wait for a backend slot in the request's QoS class
+    let _permit = match &endpoint.qos {
+        Some(qos) => {
+            let attributes: Vec<(&str, &str)> = request
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .collect();
+            let info = RequestInfo {
+                endpoint: &endpoint.name,
+                map: None,
+                key: None,
+                attributes: &attributes,
+            };
+            match qos.acquire(&info).await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    return Ok(PolicyReply::Deferred(
+                        PolicyFailure::Unavailable,
+                        "Service overloaded",
+                    ));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let upstream = match endpoint.upstream() {
+        Some(upstream) => upstream,
+        None => {
+            debug!("Circuit open, failing fast");
+            return Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Service unavailable"));
+        }
+    };
+
+    let target = match upstream.select_target() {
+        Some(target) => target,
+        None => {
+            warn!("Endpoint '{}': all targets drained", upstream.name);
+            return Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Service unavailable"));
+        }
+    };
 
     // Use the pre-created HTTP client
-    let response = endpoint.client()
-        .post(&endpoint.target)
-        .header("X-Auth-Token", &endpoint.auth_token)
+    let request = upstream.client()
+        .post(target)
         .header("User-Agent", user_agent)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()
-        .await;
+        .header("Content-Type", content_type)
+        .body(body);
+    let request = match authorize(upstream, request).await {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Endpoint '{}': {:#}", upstream.name, e);
+            return Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Service unavailable"));
+        }
+    };
+    // POSTs are only retried when the endpoint opts in (retry-policy)
+    let response = send_upstream(upstream, request, false).await;
+
+    upstream.circuit_record(matches!(&response, Ok(resp) if !resp.status().is_server_error()));
 
     match response {
         Ok(resp) => {
@@ -305,10 +1888,30 @@ pub async fn handle_policy_check(
                     Ok(text) => {
                         let trimmed = text.trim();
                         
-                        // Validate response format (should start with "action=")
-                        if !trimmed.starts_with("action=") {
-                            warn!("Invalid policy response format: {}", trimmed);
-                            return Ok("action=DEFER_IF_PERMIT Invalid response format\n\n".to_string());
+                        // Validate response format (should be a single "action=" line)
+                        let action = match trimmed.strip_prefix("action=") {
+                            Some(action) if !action.contains('\n') => action,
+                            _ => {
+                                warn!("Invalid policy response format: {}", sensitive(trimmed));
+                                return Ok(PolicyReply::Deferred(
+                                    PolicyFailure::InvalidResponse,
+                                    "Invalid response format",
+                                ));
+                            }
+                        };
+                        if upstream.strict_actions && !action::is_valid(action) {
+                            warn!(
+                                "Endpoint '{}': unrecognized policy action: {}",
+                                upstream.name,
+                                sensitive(action)
+                            );
+                            return Ok(match &upstream.invalid_action {
+                                Some(fallback) => PolicyReply::Mapped(fallback.clone()),
+                                None => PolicyReply::Deferred(
+                                    PolicyFailure::InvalidResponse,
+                                    "Invalid policy action",
+                                ),
+                            });
                         }
                         
                         // Policy response format: "action=DUNNO\n\n" (double newline required)
@@ -316,27 +1919,46 @@ pub async fn handle_policy_check(
                         
                         if response.len() > TCP_MAXIMUM_RESPONSE_LENGTH {
                             warn!("Policy response too long: {} bytes", response.len());
-                            Ok("action=DEFER_IF_PERMIT Response too long\n\n".to_string())
+                            Ok(PolicyReply::Deferred(
+                                PolicyFailure::InvalidResponse,
+                                "Response too long",
+                            ))
                         } else {
-                            Ok(response)
+                            Ok(PolicyReply::Upstream(response))
                         }
                     }
                     Err(e) => {
                         error!("Failed to read response: {}", e);
-                        Ok("action=DEFER_IF_PERMIT Service error\n\n".to_string())
+                        Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Service error"))
                     }
                 }
+            } else if let Some(action) = upstream
+                .status_mapping
+                .rule(status.as_u16())
+                .and_then(|rule| rule.policy.clone())
+            {
+                if status.as_u16() == 401 {
+                    upstream.bearer_rejected().await;
+                }
+                Ok(PolicyReply::Mapped(action))
             } else if status.is_client_error() {
-                Ok("action=DEFER_IF_PERMIT Configuration error\n\n".to_string())
+                if status.as_u16() == 401 {
+                    upstream.bearer_rejected().await;
+                }
+                Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Configuration error"))
             } else if status.is_server_error() {
-                Ok("action=DEFER_IF_PERMIT Server error\n\n".to_string())
+                Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Server error"))
             } else {
-                Ok("action=DEFER_IF_PERMIT Unknown error\n\n".to_string())
+                Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Unknown error"))
             }
         }
+        Err(e) if e.is_timeout() => {
+            error!("HTTP request timed out: {}", e);
+            Ok(PolicyReply::Deferred(PolicyFailure::Timeout, "Service timeout"))
+        }
         Err(e) => {
             error!("HTTP request failed: {}", e);
-            Ok("action=DEFER_IF_PERMIT Service unavailable\n\n".to_string())
+            Ok(PolicyReply::Deferred(PolicyFailure::Unavailable, "Service unavailable"))
         }
     }
 }
@@ -0,0 +1,43 @@
+use anyhow::{Context, Result};
+use reqwest::{NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::secret::SecretFile;
+
+/// Outbound proxy for upstream requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxyConfig {
+    /// http://, https://, socks5:// or socks5h:// (DNS resolved by the proxy)
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    /// Read the password from this file instead (read when the client is built)
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// Hosts, domains or networks reached directly, e.g. ["localhost", ".internal", "10.0.0.0/8"]
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    pub fn build(&self) -> Result<Proxy> {
+        let mut proxy = Proxy::all(&self.url)
+            .with_context(|| format!("Invalid proxy url '{}'", self.url))?;
+
+        if let Some(username) = &self.username {
+            let password = match &self.password_file {
+                Some(path) => SecretFile::open(path)?.get(),
+                None => self.password.clone(),
+            };
+            proxy = proxy.basic_auth(username, &password);
+        }
+        if !self.no_proxy.is_empty() {
+            proxy = proxy.no_proxy(NoProxy::from_string(&self.no_proxy.join(",")));
+        }
+        Ok(proxy)
+    }
+}
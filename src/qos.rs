@@ -0,0 +1,171 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QosConfig {
+    pub classes: Vec<QosClassConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QosClassConfig {
+    pub name: String,
+    pub max_concurrent: usize,
+    /// Maximum requests waiting for a slot; further requests are rejected
+    #[serde(default)]
+    pub max_queue: Option<usize>,
+    #[serde(default, rename = "match")]
+    pub matcher: QosMatchConfig,
+}
+
+/// All configured criteria must match; an empty matcher matches everything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QosMatchConfig {
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub map: Option<String>,
+    #[serde(default)]
+    pub key_pattern: Option<String>,
+    /// Policy attribute name -> value regex
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// What is known about a request when it is classified
+pub struct RequestInfo<'a> {
+    pub endpoint: &'a str,
+    pub map: Option<&'a str>,
+    pub key: Option<&'a str>,
+    pub attributes: &'a [(&'a str, &'a str)],
+}
+
+#[derive(Debug)]
+struct QosClass {
+    name: String,
+    endpoint: Option<String>,
+    map: Option<String>,
+    key_pattern: Option<Regex>,
+    attributes: Vec<(String, Regex)>,
+    max_queue: Option<usize>,
+    waiting: AtomicUsize,
+    semaphore: Arc<Semaphore>,
+}
+
+impl QosClass {
+    fn matches(&self, info: &RequestInfo) -> bool {
+        if let Some(endpoint) = &self.endpoint {
+            if endpoint != info.endpoint {
+                return false;
+            }
+        }
+
+        if let Some(map) = &self.map {
+            if info.map != Some(map.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(pattern) = &self.key_pattern {
+            match info.key {
+                Some(key) if pattern.is_match(key) => {}
+                _ => return false,
+            }
+        }
+
+        self.attributes.iter().all(|(name, pattern)| {
+            info.attributes
+                .iter()
+                .any(|(attr, value)| attr == name && pattern.is_match(value))
+        })
+    }
+}
+
+/// Backend request classified into a priority class
+pub struct QosPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// The class queue was full
+#[derive(Debug)]
+pub struct QosRejected;
+
+/// Request classification with per-class concurrency limits toward the backend
+#[derive(Debug)]
+pub struct Qos {
+    classes: Vec<QosClass>,
+}
+
+impl Qos {
+    pub fn new(config: &QosConfig) -> Result<Self> {
+        let mut classes = Vec::new();
+
+        for class in &config.classes {
+            let key_pattern = class
+                .matcher
+                .key_pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("QoS class '{}': invalid key-pattern", class.name))?;
+
+            let mut attributes = Vec::new();
+            for (name, pattern) in &class.matcher.attributes {
+                let pattern = Regex::new(pattern).with_context(|| {
+                    format!("QoS class '{}': invalid pattern for attribute {}", class.name, name)
+                })?;
+                attributes.push((name.clone(), pattern));
+            }
+
+            classes.push(QosClass {
+                name: class.name.clone(),
+                endpoint: class.matcher.endpoint.clone(),
+                map: class.matcher.map.clone(),
+                key_pattern,
+                attributes,
+                max_queue: class.max_queue,
+                waiting: AtomicUsize::new(0),
+                semaphore: Arc::new(Semaphore::new(class.max_concurrent)),
+            });
+        }
+
+        Ok(Self { classes })
+    }
+
+    /// Wait for a slot in the first matching class
+    ///
+    /// Returns Ok(None) for unclassified requests.
+    pub async fn acquire(&self, info: &RequestInfo<'_>) -> Result<Option<QosPermit>, QosRejected> {
+        let class = match self.classes.iter().find(|class| class.matches(info)) {
+            Some(class) => class,
+            None => return Ok(None),
+        };
+
+        if let Some(max_queue) = class.max_queue {
+            if class.semaphore.available_permits() == 0
+                && class.waiting.load(Ordering::Relaxed) >= max_queue
+            {
+                warn!("QoS class '{}' queue full, rejecting request", class.name);
+                return Err(QosRejected);
+            }
+        }
+
+        debug!("Request classified as '{}'", class.name);
+
+        class.waiting.fetch_add(1, Ordering::Relaxed);
+        let permit = Arc::clone(&class.semaphore).acquire_owned().await;
+        class.waiting.fetch_sub(1, Ordering::Relaxed);
+
+        // The semaphore is never closed
+        let permit = permit.map_err(|_| QosRejected)?;
+        Ok(Some(QosPermit { _permit: permit }))
+    }
+}
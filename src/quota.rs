@@ -0,0 +1,149 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::action;
+use crate::clock::Instant;
+
+fn default_sync_interval() -> u64 {
+    60000
+}
+
+fn default_action() -> String {
+    "DEFER Sending quota exceeded".to_string()
+}
+
+/// What a quota limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaCount {
+    /// Every recipient (policy request) counts
+    #[default]
+    Recipients,
+    /// Recipients of the same message (instance) count once
+    Messages,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub count: QuotaCount,
+    /// How often local counts are reported to, and usage reloaded from, the quota API
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval: u64, // milliseconds
+    /// Limit used until the quota API has answered for a user; unset lets
+    /// those messages pass
+    #[serde(default)]
+    pub default_limit: Option<u64>,
+    /// Sent when a user is over quota
+    #[serde(default = "default_action")]
+    pub action: String,
+}
+
+impl QuotaConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !action::is_valid(&self.action) {
+            anyhow::bail!("quota action '{}' is not a valid action", self.action);
+        }
+        Ok(())
+    }
+}
+
+/// Answer of the quota API: the user's limit (none for unlimited) and usage
+/// including the reported count
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct QuotaStatus {
+    #[serde(default)]
+    pub limit: Option<u64>,
+    pub used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Account {
+    /// Known to the quota API at the last sync
+    status: Option<QuotaStatus>,
+    /// Counted locally since the last sync
+    pending: u64,
+    synced: Option<Instant>,
+    /// Last counted message, for QuotaCount::Messages
+    instance: String,
+}
+
+/// Result of counting one policy request
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuotaVerdict {
+    Allowed,
+    Exceeded,
+}
+
+/// Per-SASL-user usage counters, reconciled with the quota API
+#[derive(Debug)]
+pub struct Quota {
+    config: QuotaConfig,
+    sync_interval: Duration,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl Quota {
+    pub fn new(config: &QuotaConfig) -> Self {
+        Self {
+            config: config.clone(),
+            sync_interval: Duration::from_millis(config.sync_interval),
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn action(&self) -> &str {
+        &self.config.action
+    }
+
+    /// Take the pending count of a user that is due for a sync; None if the
+    /// last sync is recent enough
+    pub fn begin_sync(&self, user: &str) -> Option<u64> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(user.to_string()).or_default();
+        if matches!(account.synced, Some(synced) if synced.elapsed() < self.sync_interval) {
+            return None;
+        }
+        // Concurrent requests wait for the next interval instead of syncing too
+        account.synced = Some(Instant::now());
+        Some(std::mem::take(&mut account.pending))
+    }
+
+    pub fn end_sync(&self, user: &str, reported: u64, status: Option<QuotaStatus>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(user.to_string()).or_default();
+        match status {
+            Some(status) => account.status = Some(status),
+            // Report the count again next time
+            None => account.pending += reported,
+        }
+    }
+
+    /// Count a request of a user unless that would exceed the quota
+    pub fn count(&self, user: &str, instance: &str) -> QuotaVerdict {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(user.to_string()).or_default();
+
+        if self.config.count == QuotaCount::Messages
+            && !instance.is_empty()
+            && account.instance == instance
+        {
+            return QuotaVerdict::Allowed;
+        }
+
+        let (limit, used) = match account.status {
+            Some(status) => (status.limit, status.used),
+            None => (self.config.default_limit, 0),
+        };
+        if matches!(limit, Some(limit) if used + account.pending >= limit) {
+            return QuotaVerdict::Exceeded;
+        }
+        account.pending += 1;
+        account.instance = instance.to_string();
+        QuotaVerdict::Allowed
+    }
+}
@@ -0,0 +1,250 @@
+use anyhow::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::action;
+use crate::clock::Instant;
+use crate::privacy::sensitive;
+
+fn default_action() -> String {
+    "DEFER Rate limit exceeded, please try again later".to_string()
+}
+
+fn default_max_entries() -> usize {
+    100000
+}
+
+/// Policy request attribute a limit is counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKey {
+    Sender,
+    SaslUsername,
+    ClientAddress,
+}
+
+impl RateLimitKey {
+    fn attribute(&self) -> &'static str {
+        match self {
+            RateLimitKey::Sender => "sender",
+            RateLimitKey::SaslUsername => "sasl_username",
+            RateLimitKey::ClientAddress => "client_address",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimitRule {
+    pub by: RateLimitKey,
+    /// Messages allowed per window, also the burst size
+    pub messages: u32,
+    pub window: u64, // seconds
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimitConfig {
+    pub limits: Vec<RateLimitRule>,
+    /// Sent when a limit is exceeded
+    #[serde(default = "default_action")]
+    pub action: String,
+    /// Senders, users and clients tracked at most; beyond that new ones are
+    /// not limited until idle buckets are dropped
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.limits.is_empty() {
+            anyhow::bail!("rate-limit limits must not be empty");
+        }
+        if self
+            .limits
+            .iter()
+            .any(|rule| rule.messages == 0 || rule.window == 0)
+        {
+            anyhow::bail!("rate-limit messages and window must be greater than 0");
+        }
+        if !action::is_valid(&self.action) {
+            anyhow::bail!("rate-limit action '{}' is not a valid action", self.action);
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket of one sender, user or client under one rule
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Message the last token was taken for; further recipients of the same
+    /// message are not counted again
+    instance: String,
+}
+
+impl Bucket {
+    fn refill(&mut self, rule: &RateLimitRule) {
+        let rate = rule.messages as f64 / rule.window as f64;
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rule.messages as f64);
+        self.updated = Instant::now();
+    }
+
+    fn is_full(&self, rule: &RateLimitRule) -> bool {
+        let rate = rule.messages as f64 / rule.window as f64;
+        self.tokens + self.updated.elapsed().as_secs_f64() * rate >= rule.messages as f64
+    }
+}
+
+/// Per-sender, per-SASL-user and per-client message rate limits
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(usize, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count the message of a policy request against every applicable limit;
+    /// returns the configured action when one of them is exceeded
+    pub fn check(&self, request: &str) -> Option<&str> {
+        let attributes: HashMap<&str, &str> = request
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let instance = attributes.get("instance").copied().unwrap_or("");
+
+        // Requests without the attribute (null sender, no SASL login) are
+        // not limited by that rule
+        let keys: Vec<(usize, String)> = self
+            .config
+            .limits
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rule)| {
+                let value = attributes.get(rule.by.attribute())?;
+                (!value.is_empty()).then(|| (index, value.to_lowercase()))
+            })
+            .collect();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in &keys {
+            let rule = &self.config.limits[key.0];
+            let Some(bucket) = buckets.get_mut(key) else {
+                continue;
+            };
+            bucket.refill(rule);
+            let counted = !instance.is_empty() && bucket.instance == instance;
+            if !counted && bucket.tokens < 1.0 {
+                debug!(
+                    "Rate limit exceeded for {} {}",
+                    rule.by.attribute(),
+                    sensitive(&key.1)
+                );
+                return Some(&self.config.action);
+            }
+        }
+
+        // All limits allow the message, take one token from each
+        for key in keys {
+            let rule = &self.config.limits[key.0];
+            if !buckets.contains_key(&key) && !self.make_room(&mut buckets) {
+                continue;
+            }
+            let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+                tokens: rule.messages as f64,
+                updated: Instant::now(),
+                instance: String::new(),
+            });
+            if instance.is_empty() || bucket.instance != instance {
+                bucket.tokens -= 1.0;
+                bucket.instance = instance.to_string();
+            }
+        }
+        None
+    }
+
+    /// Drop buckets that have refilled completely when the table is full
+    fn make_room(&self, buckets: &mut HashMap<(usize, String), Bucket>) -> bool {
+        if buckets.len() < self.config.max_entries {
+            return true;
+        }
+        let limits = &self.config.limits;
+        buckets.retain(|(index, _), bucket| !bucket.is_full(&limits[*index]));
+        buckets.len() < self.config.max_entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            limits: vec![RateLimitRule {
+                by: RateLimitKey::Sender,
+                messages: 2,
+                window: 10,
+            }],
+            action: default_action(),
+            max_entries: default_max_entries(),
+        })
+    }
+
+    fn request(instance: &str) -> String {
+        format!("request=smtpd_access_policy\nsender=user@example.com\ninstance={}\n", instance)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_over_the_window() {
+        let limiter = limiter();
+        assert!(limiter.check(&request("a")).is_none());
+        assert!(limiter.check(&request("b")).is_none());
+        assert!(limiter.check(&request("c")).is_some());
+
+        // One message per 5 seconds
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(limiter.check(&request("c")).is_some());
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(limiter.check(&request("c")).is_none());
+        assert!(limiter.check(&request("d")).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recipients_of_one_message_count_once() {
+        let limiter = limiter();
+        assert!(limiter.check(&request("a")).is_none());
+        assert!(limiter.check(&request("a")).is_none());
+        assert!(limiter.check(&request("a")).is_none());
+        assert!(limiter.check(&request("b")).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_buckets_make_room() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            max_entries: 1,
+            ..limiter().config
+        });
+        let other = "request=smtpd_access_policy\nsender=other@example.com\ninstance=x\n";
+        assert!(limiter.check(&request("a")).is_none());
+        // Table full and the first bucket not refilled: not tracked
+        assert!(limiter.check(other).is_none());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(limiter.check(other).is_none());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.keys().all(|(_, key)| key == "other@example.com"));
+    }
+}
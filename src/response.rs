@@ -0,0 +1,262 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Body format of successful upstream lookup responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseFormat {
+    /// JSON array of values (see response-pointer)
+    #[default]
+    Json,
+    /// The body itself is the value (text/plain)
+    Text,
+}
+
+/// What to do when a single-value map gets several values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SingleValue {
+    /// Use the first value
+    First,
+    /// Fail the lookup temporarily
+    Error,
+}
+
+/// How non-string elements of a JSON array response become lookup values
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CoercionConfig {
+    /// 42 -> "42", 1.5 -> "1.5"
+    #[serde(default)]
+    pub stringify_numbers: bool,
+    /// true -> "true"
+    #[serde(default)]
+    pub stringify_booleans: bool,
+    /// Take this field of object elements (which is itself coerced)
+    #[serde(default)]
+    pub object_field: Option<String>,
+}
+
+impl CoercionConfig {
+    /// Convert one array element; None means it is skipped
+    pub fn coerce(&self, value: &Value) -> Option<String> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) if self.stringify_numbers => Some(n.to_string()),
+            Value::Bool(b) if self.stringify_booleans => Some(b.to_string()),
+            Value::Object(map) => {
+                let field = self.object_field.as_ref()?;
+                match map.get(field)? {
+                    Value::Object(_) => None,
+                    inner => self.coerce(inner),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Socketmap reply codes for conditions without a lookup result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SocketmapReply {
+    NotFound,
+    Temp,
+    Timeout,
+    Perm,
+}
+
+impl SocketmapReply {
+    pub fn from_code(code: &str) -> Option<Self> {
+        Some(match code {
+            "NOTFOUND" => SocketmapReply::NotFound,
+            "TEMP" => SocketmapReply::Temp,
+            "TIMEOUT" => SocketmapReply::Timeout,
+            "PERM" => SocketmapReply::Perm,
+            _ => return None,
+        })
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            SocketmapReply::NotFound => "NOTFOUND",
+            SocketmapReply::Temp => "TEMP",
+            SocketmapReply::Timeout => "TIMEOUT",
+            SocketmapReply::Perm => "PERM",
+        }
+    }
+}
+
+/// Socketmap client flavour
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SocketmapDialect {
+    #[default]
+    Postfix,
+    /// sendmail: bare NOTFOUND and OK replies without payload, requests
+    /// without a map name go to the default target
+    Sendmail,
+}
+
+fn reply_not_found() -> SocketmapReply {
+    SocketmapReply::NotFound
+}
+
+fn reply_temp() -> SocketmapReply {
+    SocketmapReply::Temp
+}
+
+fn reply_timeout() -> SocketmapReply {
+    SocketmapReply::Timeout
+}
+
+fn reply_perm() -> SocketmapReply {
+    SocketmapReply::Perm
+}
+
+/// Which socketmap reply code each lookup condition is answered with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SocketmapReplyConfig {
+    #[serde(default = "reply_not_found")]
+    pub not_found: SocketmapReply,
+    /// Upstream answered with an empty array
+    #[serde(default = "reply_not_found")]
+    pub empty: SocketmapReply,
+    #[serde(default = "reply_temp")]
+    pub invalid_json: SocketmapReply,
+    /// 4xx other than 404, usually a configuration problem
+    #[serde(default = "reply_perm")]
+    pub client_error: SocketmapReply,
+    #[serde(default = "reply_temp")]
+    pub server_error: SocketmapReply,
+    #[serde(default = "reply_temp")]
+    pub unknown_error: SocketmapReply,
+    #[serde(default = "reply_temp")]
+    pub connection_failed: SocketmapReply,
+    /// The backend did not answer within request-timeout
+    #[serde(default = "reply_timeout")]
+    pub timeout: SocketmapReply,
+    #[serde(default = "reply_temp")]
+    pub overloaded: SocketmapReply,
+    #[serde(default = "reply_temp")]
+    pub circuit_open: SocketmapReply,
+    /// The endpoint is in maintenance mode
+    #[serde(default = "reply_temp")]
+    pub maintenance: SocketmapReply,
+    /// The result does not fit into a socketmap reply
+    #[serde(default = "reply_temp")]
+    pub too_long: SocketmapReply,
+    /// Several values while single-value is "error"
+    #[serde(default = "reply_temp")]
+    pub multiple_values: SocketmapReply,
+    /// Map name without a map-routes entry or default target
+    #[serde(default = "reply_perm")]
+    pub unknown_map: SocketmapReply,
+}
+
+impl Default for SocketmapReplyConfig {
+    fn default() -> Self {
+        Self {
+            not_found: reply_not_found(),
+            empty: reply_not_found(),
+            invalid_json: reply_temp(),
+            client_error: reply_perm(),
+            server_error: reply_temp(),
+            unknown_error: reply_temp(),
+            connection_failed: reply_temp(),
+            timeout: reply_timeout(),
+            overloaded: reply_temp(),
+            circuit_open: reply_temp(),
+            maintenance: reply_temp(),
+            too_long: reply_temp(),
+            multiple_values: reply_temp(),
+            unknown_map: reply_perm(),
+        }
+    }
+}
+
+/// Policy actions sent when the policy service gives no usable verdict;
+/// unset entries fall back to `default`, then to DEFER_IF_PERMIT
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyFallbackConfig {
+    /// Upstream unreachable, failing or overloaded
+    #[serde(default)]
+    pub default: Option<String>,
+    /// The backend did not answer within request-timeout
+    #[serde(default)]
+    pub timeout: Option<String>,
+    /// Unparsable, oversized or (with strict-actions) invalid responses
+    #[serde(default)]
+    pub invalid_response: Option<String>,
+    /// The endpoint is in maintenance mode
+    #[serde(default)]
+    pub maintenance: Option<String>,
+}
+
+impl PolicyFallbackConfig {
+    pub fn actions(&self) -> impl Iterator<Item = &String> {
+        [
+            &self.default,
+            &self.timeout,
+            &self.invalid_response,
+            &self.maintenance,
+        ]
+            .into_iter()
+            .flatten()
+    }
+}
+
+/// How one HTTP status (or status class) is answered instead of the default
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StatusRule {
+    /// tcp_table code, 400 (temporary) or 500 (not found / permanent)
+    #[serde(default)]
+    pub tcp: Option<u16>,
+    #[serde(default)]
+    pub socketmap: Option<SocketmapReply>,
+    /// Policy action sent instead of DEFER_IF_PERMIT, e.g. "REJECT Gone"
+    #[serde(default)]
+    pub policy: Option<String>,
+    /// Reason text in tcp_table and socketmap replies
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Rules keyed by status code ("410") or class ("4xx"); exact codes win
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StatusMapping(HashMap<String, StatusRule>);
+
+impl StatusMapping {
+    pub fn rule(&self, status: u16) -> Option<&StatusRule> {
+        self.0
+            .get(&status.to_string())
+            .or_else(|| self.0.get(&format!("{}xx", status / 100)))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (pattern, rule) in &self.0 {
+            let valid = match pattern.strip_suffix("xx") {
+                Some(class) => matches!(class, "1" | "2" | "3" | "4" | "5"),
+                None => matches!(pattern.parse::<u16>(), Ok(100..=599)),
+            };
+            if !valid {
+                anyhow::bail!("invalid status-mapping key '{}'", pattern);
+            }
+            if pattern.starts_with('2') {
+                anyhow::bail!("status-mapping cannot override successful responses ({})", pattern);
+            }
+            if let Some(code) = rule.tcp {
+                if code != 400 && code != 500 {
+                    anyhow::bail!("status-mapping '{}': tcp must be 400 or 500", pattern);
+                }
+            }
+        }
+        Ok(())
+    }
+}
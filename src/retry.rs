@@ -0,0 +1,93 @@
+use log::{debug, warn};
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::privacy;
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_backoff_base() -> u64 {
+    50
+}
+
+fn default_retryable_status() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetryConfig {
+    /// Total attempts including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry
+    #[serde(default = "default_backoff_base")]
+    pub backoff_base: u64, // milliseconds
+    /// Random extra delay of up to this many milliseconds per retry
+    #[serde(default)]
+    pub jitter: u64, // milliseconds
+    #[serde(default = "default_retryable_status")]
+    pub retryable_status: Vec<u16>,
+    /// Policy POSTs are not idempotent and are only retried when enabled
+    #[serde(default)]
+    pub retry_policy: bool,
+}
+
+impl RetryConfig {
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff_base.saturating_mul(1u64 << retry.min(16));
+        let jitter = if self.jitter > 0 {
+            // RandomState is randomly seeded, which is plenty for jitter
+            RandomState::new().build_hasher().finish() % (self.jitter + 1)
+        } else {
+            0
+        };
+        Duration::from_millis(backoff.saturating_add(jitter))
+    }
+}
+
+/// Send a request, retrying connection errors and retryable status codes
+pub async fn send_with_retry(
+    config: Option<&RetryConfig>,
+    request: RequestBuilder,
+    idempotent: bool,
+) -> reqwest::Result<Response> {
+    let config = match config {
+        Some(config) if idempotent || config.retry_policy => config,
+        _ => return request.send().await.map_err(privacy::strip_url),
+    };
+
+    let mut retry = 0;
+    loop {
+        // Bodies are always buffered, so cloning only fails for streams
+        let attempt = match request.try_clone() {
+            Some(attempt) => attempt,
+            None => return request.send().await.map_err(privacy::strip_url),
+        };
+
+        let result = attempt.send().await.map_err(privacy::strip_url);
+        let retryable = match &result {
+            Ok(resp) => config.retryable_status.contains(&resp.status().as_u16()),
+            Err(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        };
+
+        if !retryable || retry + 1 >= config.max_attempts {
+            return result;
+        }
+
+        let delay = config.delay(retry);
+        match &result {
+            Ok(resp) => warn!("Upstream returned {}, retrying in {:?}", resp.status(), delay),
+            Err(e) => warn!("Upstream request failed ({}), retrying in {:?}", e, delay),
+        }
+
+        retry += 1;
+        debug!("Retry attempt {} of {}", retry, config.max_attempts - 1);
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::Instant;
+use crate::privacy;
+
+// How often the file is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Identifies a version of the file; rotation by rename changes the inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileVersion {
+    inode: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self {
+            inode: metadata.ino(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CheckState {
+    version: FileVersion,
+    checked: Instant,
+}
+
+/// Parsed content of a file, parsed again when the file changes
+#[derive(Debug)]
+pub struct WatchedFile<T> {
+    path: PathBuf,
+    /// What the file is, for log messages ("Secret file")
+    kind: &'static str,
+    parse: fn(&Path) -> Result<T>,
+    value: RwLock<Arc<T>>,
+    state: Mutex<CheckState>,
+}
+
+impl<T> WatchedFile<T> {
+    pub fn open(path: &Path, kind: &'static str, parse: fn(&Path) -> Result<T>) -> Result<Self> {
+        let version = FileVersion::of(path)
+            .with_context(|| format!("Failed to read {} {}", kind.to_lowercase(), path.display()))?;
+        let value = parse(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            kind,
+            parse,
+            value: RwLock::new(Arc::new(value)),
+            state: Mutex::new(CheckState {
+                version,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// Current content, re-read first if the file changed since the last check
+    pub fn get(&self) -> Arc<T> {
+        self.refresh();
+        Arc::clone(&self.value.read().unwrap())
+    }
+
+    fn refresh(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.checked.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        state.checked = Instant::now();
+
+        // Keep the previous value while the file is missing or unreadable mid-rotation
+        let version = match FileVersion::of(&self.path) {
+            Ok(version) => version,
+            Err(e) => {
+                warn!("{} {} not accessible: {}", self.kind, self.path.display(), e);
+                return;
+            }
+        };
+        if version == state.version {
+            return;
+        }
+
+        match (self.parse)(&self.path) {
+            Ok(value) => {
+                info!("{} {} changed, reloaded", self.kind, self.path.display());
+                *self.value.write().unwrap() = Arc::new(value);
+                state.version = version;
+            }
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+}
+
+fn read_secret(path: &Path) -> Result<String> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret file {}", path.display()))?;
+    let secret = content.trim_end_matches(['\r', '\n']);
+    privacy::register_secret(secret);
+    Ok(secret.to_string())
+}
+
+/// A secret kept in its own file (e.g. written by Vault Agent), re-read when it changes
+#[derive(Debug)]
+pub struct SecretFile {
+    file: WatchedFile<String>,
+}
+
+impl SecretFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: WatchedFile::open(path, "Secret file", read_secret)?,
+        })
+    }
+
+    /// Current value, re-read first if the file changed since the last check
+    pub fn get(&self) -> String {
+        self.file.get().as_ref().clone()
+    }
+}
@@ -1,57 +1,1025 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures_util::FutureExt;
 use log::{debug, error, info, warn};
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::future::Future;
+use std::os::fd::AsFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::panic::AssertUnwindSafe;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{Notify, OwnedSemaphorePermit};
+use tokio::task::JoinHandle;
 
-use crate::config::{Endpoint, EndpointMode};
-use crate::protocol::{handle_policy_check, handle_socketmap_lookup, handle_tcp_lookup};
+use crate::authpolicy;
+use crate::clock::Instant;
+use crate::config::{ConnectionOverflow, Endpoint, EndpointMode};
+use crate::logging;
+use crate::metrics;
+use crate::milter;
+use crate::privacy::sensitive;
+use crate::protocol::{
+    detect_mode, handle_dovecot_dict, handle_greylist, handle_memcache, handle_opensmtpd_table,
+    handle_policy_check, handle_quota, handle_socketmap_lookup, handle_tcp_lookup,
+    strip_keepalive, DictSession, TableSession,
+};
+use crate::sockopt::TcpOptions;
 
 const BUFFER_SIZE: usize = 8192;
 
+// How long listeners get on shutdown to hand their queued connections over
+const LISTENER_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Alerts about a repeatedly failing endpoint are logged at most this often
+const ALERT_INTERVAL: Duration = Duration::from_secs(60);
+
+// A failed bind is retried for this long, e.g. while the previous owner of
+// the port is still closing it, before starting the endpoint fails
+const BIND_RETRY_DEADLINE: Duration = Duration::from_secs(10);
+const BIND_RETRY_INITIAL: Duration = Duration::from_millis(100);
+
+// Restarts of a listener that keeps dying are delayed by the watchdog
+// interval, doubled with every further failure up to this
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+const RESTART_BASE_DELAY: Duration = Duration::from_secs(5);
+
+// A listener that ran this long counts as recovered
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+struct EndpointTask {
+    endpoint: Arc<Endpoint>,
+    /// Serialized effective configuration, compared on reload
+    fingerprint: String,
+    handle: JoinHandle<()>,
+    restarts: u64,
+    last_alert: Option<Instant>,
+    /// When the current listener task was started
+    started: Instant,
+    /// Restarts since the listener last ran for STABLE_AFTER
+    failures: u32,
+}
+
+/// Tracks endpoint listener tasks by name and restarts the ones that died
+pub struct EndpointSupervisor {
+    user_agent: String,
+    tasks: HashMap<String, EndpointTask>,
+    /// Copies of listeners bound before privileges were dropped, by address,
+    /// reused when a listener is restarted or reconfigured
+    retained: Option<HashMap<String, RetainedListener>>,
+    /// Set once root privileges are dropped; new binds may then fail
+    unprivileged: bool,
+}
+
+impl EndpointSupervisor {
+    pub fn new(user_agent: String) -> Self {
+        Self {
+            user_agent,
+            tasks: HashMap::new(),
+            retained: None,
+            unprivileged: false,
+        }
+    }
+
+    /// Keep a copy of every listener bound from now on, so it can be served
+    /// again without binding once root privileges are gone
+    pub fn retain_listeners(&mut self) {
+        self.retained.get_or_insert_with(HashMap::new);
+    }
+
+    /// Listeners bound from now on run without root privileges
+    pub fn privileges_dropped(&mut self) {
+        self.unprivileged = true;
+    }
+
+    /// The retained listener for the endpoint's address, if any
+    fn retained_listener(&self, endpoint: &Endpoint) -> Option<EndpointListener> {
+        let retained = self.retained.as_ref()?.get(&listener_address(endpoint))?;
+        match retained.listener() {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                warn!("Endpoint '{}': failed to reuse its listener: {}", endpoint.name, e);
+                None
+            }
+        }
+    }
+
+    /// Bind the endpoint, or reuse the listener retained for its address
+    async fn bind(&mut self, endpoint: &Endpoint) -> Result<EndpointListener> {
+        if let Some(listener) = self.retained_listener(endpoint) {
+            return Ok(listener);
+        }
+        let listener = match bind_endpoint(endpoint).await {
+            Ok(listener) => listener,
+            Err(e) if self.unprivileged => {
+                return Err(e.context(
+                    "not bound before privileges were dropped; restart the connector to bind \
+                     it as root",
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(retained) = &mut self.retained {
+            retained.insert(listener_address(endpoint), RetainedListener::new(&listener)?);
+        }
+        Ok(listener)
+    }
+
+    /// Serve a bound listener, or bind and serve after `delay`
+    fn spawn(
+        &self,
+        endpoint: Arc<Endpoint>,
+        listener: Option<EndpointListener>,
+        delay: Duration,
+    ) -> JoinHandle<()> {
+        let user_agent = self.user_agent.clone();
+        tokio::spawn(async move {
+            let name = endpoint.name.clone();
+            if !delay.is_zero() {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = wait_for_close() => return,
+                }
+            }
+            let result = match listener {
+                Some(listener) => serve_endpoint(endpoint, user_agent, listener).await,
+                None => start_endpoint(endpoint, user_agent).await,
+            };
+            if let Err(e) = result {
+                error!("Endpoint '{}' error: {}", name, e);
+            }
+        })
+    }
+
+    /// Bind the endpoint and start serving it; fails if the bind still
+    /// fails after retrying for BIND_RETRY_DEADLINE
+    pub async fn start(&mut self, endpoint: Arc<Endpoint>, fingerprint: String) -> Result<()> {
+        let deadline = Instant::now() + BIND_RETRY_DEADLINE;
+        let mut delay = BIND_RETRY_INITIAL;
+        let listener = loop {
+            match self.bind(&endpoint).await {
+                Ok(listener) => break listener,
+                Err(e) if Instant::now() + delay < deadline => {
+                    warn!(
+                        "Endpoint '{}': {:#}, retrying in {} ms",
+                        endpoint.name,
+                        e,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => return Err(e.context(format!("Endpoint '{}'", endpoint.name))),
+            }
+        };
+        let handle = self.spawn(Arc::clone(&endpoint), Some(listener), Duration::ZERO);
+        self.tasks.insert(
+            endpoint.name.clone(),
+            EndpointTask {
+                endpoint,
+                fingerprint,
+                handle,
+                restarts: 0,
+                last_alert: None,
+                started: Instant::now(),
+                failures: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Watchdog pass: restart listener tasks that exited or panicked
+    pub async fn check(&mut self) {
+        let dead: Vec<String> = self
+            .tasks
+            .iter()
+            .filter(|(_, task)| task.handle.is_finished())
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in dead {
+            let Some(task) = self.tasks.get_mut(&name) else {
+                continue;
+            };
+
+            let reason = match (&mut task.handle).await {
+                Err(e) if e.is_panic() => "panicked",
+                _ => "stopped",
+            };
+
+            set_listening(&name, false);
+            task.restarts += 1;
+            metrics::counter_inc(
+                "connector_endpoint_restarts_total",
+                "Endpoint listener tasks restarted by the watchdog",
+                &[("endpoint", &name), ("reason", reason)],
+            );
+
+            // The first restart is immediate, repeated ones back off
+            if task.started.elapsed() >= STABLE_AFTER {
+                task.failures = 0;
+            }
+            let delay = match task.failures {
+                0 => Duration::ZERO,
+                failures => RESTART_BASE_DELAY
+                    .saturating_mul(1 << (failures - 1).min(16))
+                    .min(MAX_RESTART_DELAY),
+            };
+            task.failures += 1;
+
+            let alert_due = match task.last_alert {
+                Some(last) => last.elapsed() >= ALERT_INTERVAL,
+                None => true,
+            };
+            if alert_due {
+                error!(
+                    "Endpoint '{}' listener {}, restarting in {} s (restart #{})",
+                    name,
+                    reason,
+                    delay.as_secs(),
+                    task.restarts
+                );
+                task.last_alert = Some(Instant::now());
+            } else {
+                debug!("Endpoint '{}' listener {}, restarting", name, reason);
+            }
+
+            let endpoint = Arc::clone(&task.endpoint);
+            let listener = self.retained_listener(&endpoint);
+            let handle = self.spawn(endpoint, listener, delay);
+            if let Some(task) = self.tasks.get_mut(&name) {
+                task.handle = handle;
+                task.started = Instant::now() + delay;
+            }
+        }
+    }
+
+    /// Close an endpoint's listener; connections in progress are left to finish
+    async fn stop(&mut self, name: &str) -> Option<EndpointTask> {
+        let mut task = self.tasks.remove(name)?;
+        task.handle.abort();
+        // Wait until the listener is actually dropped so its address can be reused
+        let _ = (&mut task.handle).await;
+        forget_listener(name);
+        Some(task)
+    }
+
+    /// Apply a reloaded configuration, leaving unchanged endpoints untouched
+    pub async fn reload(&mut self, user_agent: String, endpoints: Vec<(Arc<Endpoint>, String)>) {
+        let user_agent_changed = user_agent != self.user_agent;
+        self.user_agent = user_agent;
+
+        // Close removed endpoints first so that their addresses can be reused
+        let removed: Vec<String> = self
+            .tasks
+            .keys()
+            .filter(|name| !endpoints.iter().any(|(endpoint, _)| &endpoint.name == *name))
+            .cloned()
+            .collect();
+        for name in removed {
+            self.stop(&name).await;
+            info!("Endpoint '{}' removed", name);
+        }
+
+        for (endpoint, fingerprint) in endpoints {
+            let name = endpoint.name.clone();
+            let previous = match self.tasks.get(&name) {
+                Some(task) if task.fingerprint == fingerprint && !user_agent_changed => {
+                    debug!("Endpoint '{}' unchanged", name);
+                    continue;
+                }
+                Some(_) => self.stop(&name).await,
+                None => None,
+            };
+
+            match self.start(endpoint, fingerprint).await {
+                Ok(()) if previous.is_some() => info!("Endpoint '{}' reconfigured", name),
+                Ok(()) => info!("Endpoint '{}' added", name),
+                Err(e) => {
+                    error!("Failed to start endpoint '{}': {:#}", name, e);
+                    // Fall back to the previous configuration of this endpoint
+                    if let Some(previous) = previous {
+                        if let Err(e) = self.start(previous.endpoint, previous.fingerprint).await {
+                            error!("Failed to restore endpoint '{}': {:#}", name, e);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Addresses no endpoint uses any more stop accepting connections
+        if let Some(retained) = &mut self.retained {
+            let addresses: HashSet<String> = self
+                .tasks
+                .values()
+                .map(|task| listener_address(&task.endpoint))
+                .collect();
+            retained.retain(|address, _| addresses.contains(address));
+        }
+    }
+
+    /// Endpoints currently being served
+    pub fn endpoints(&self) -> Vec<Arc<Endpoint>> {
+        self.tasks
+            .values()
+            .map(|task| Arc::clone(&task.endpoint))
+            .collect()
+    }
+
+    /// Human-readable summary of the supervised endpoints
+    pub fn dump_state(&self) -> String {
+        let mut names: Vec<&String> = self.tasks.keys().collect();
+        names.sort();
+
+        let mut dump = format!("active connections: {}\n", active_connections());
+        for name in names {
+            let task = &self.tasks[name];
+            let listener = if task.handle.is_finished() {
+                "dead"
+            } else if !is_listening(name) {
+                "waiting to restart"
+            } else {
+                "running"
+            };
+            dump.push_str(&format!(
+                "endpoint '{}': mode {:?}, listener {}, restarts {}\n",
+                name, task.endpoint.mode, listener, task.restarts
+            ));
+        }
+        dump
+    }
+
+    /// Per-endpoint counters for the log, for hosts without a metrics stack
+    pub fn dump_stats(&self) -> String {
+        let mut names: Vec<&String> = self.tasks.keys().collect();
+        names.sort();
+
+        let mut dump = format!("active connections: {}\n", active_connections());
+        for name in names {
+            let endpoint = &self.tasks[name].endpoint;
+            let connections: f64 = metrics::breakdown(
+                "connector_active_connections",
+                "endpoint",
+                name,
+                "endpoint",
+            )
+            .into_values()
+            .sum();
+            let circuit = match endpoint.circuit_unhealthy_for() {
+                Some(unhealthy) => format!("open for {} s", unhealthy.as_secs()),
+                None => "closed".to_string(),
+            };
+            let targets: Vec<String> = endpoint
+                .target_pool()
+                .status()
+                .iter()
+                .map(|target| {
+                    let draining = if target.draining { ", draining" } else { "" };
+                    format!("{} (weight {}{})", target.url, target.weight, draining)
+                })
+                .collect();
+            let by = |metric: &str, label: &str| {
+                stats_line(&metrics::breakdown(metric, "endpoint", name, label))
+            };
+
+            dump.push_str(&format!(
+                "endpoint '{}': mode {:?}, {} active connections\n",
+                name,
+                endpoint.mode,
+                connections
+            ));
+            dump.push_str(&format!(
+                "  lookups: {}\n",
+                by("connector_lookup_duration_seconds", "result")
+            ));
+            dump.push_str(&format!(
+                "  cache: {}\n",
+                by("connector_cache_lookups_total", "status")
+            ));
+            dump.push_str(&format!(
+                "  upstream requests: {}; circuit {}\n",
+                by("connector_upstream_request_duration_seconds", "status"),
+                circuit
+            ));
+            if !targets.is_empty() {
+                dump.push_str(&format!("  targets: {}\n", targets.join(", ")));
+            }
+        }
+        dump
+    }
+
+    /// Stop accepting on all listeners (sockets are cleaned up as the tasks
+    /// end)
+    ///
+    /// Connections the kernel already queued on a listener would be reset
+    /// when it is closed, so the listener tasks accept and serve them first;
+    /// tasks that take too long are aborted.
+    pub async fn shutdown(self) {
+        CLOSING.store(true, Ordering::SeqCst);
+        CLOSE_NOTIFY.notify_waiters();
+        for mut task in self.tasks.into_values() {
+            if tokio::time::timeout(LISTENER_CLOSE_TIMEOUT, &mut task.handle)
+                .await
+                .is_err()
+            {
+                task.handle.abort();
+            }
+        }
+    }
+}
+
+/// "OK 120, TEMP 2" from counts by label value
+fn stats_line(counts: &BTreeMap<String, f64>) -> String {
+    if counts.is_empty() {
+        return "none".to_string();
+    }
+    counts
+        .iter()
+        .map(|(value, count)| format!("{} {}", value, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A bound endpoint listener
+pub enum EndpointListener {
+    Tcp(TcpListener, String),
+    Unix(UnixListener, Arc<SocketCleanup>),
+}
+
+/// Listener address, unique between endpoints
+fn listener_address(endpoint: &Endpoint) -> String {
+    match &endpoint.bind_socket {
+        Some(path) => format!("unix:{}", path.display()),
+        None => format!("{}:{}", endpoint.bind_address, endpoint.bind_port),
+    }
+}
+
+/// A duplicate of a bound listener's socket, kept open so the listener can
+/// be served again without binding; the socket file is removed once both
+/// it and the listeners made from it are gone
+enum RetainedListener {
+    Tcp(std::net::TcpListener, String),
+    Unix(std::os::unix::net::UnixListener, Arc<SocketCleanup>),
+}
+
+impl RetainedListener {
+    fn new(listener: &EndpointListener) -> std::io::Result<Self> {
+        Ok(match listener {
+            EndpointListener::Tcp(listener, addr) => {
+                let fd = listener.as_fd().try_clone_to_owned()?;
+                RetainedListener::Tcp(fd.into(), addr.clone())
+            }
+            EndpointListener::Unix(listener, cleanup) => {
+                let fd = listener.as_fd().try_clone_to_owned()?;
+                RetainedListener::Unix(fd.into(), Arc::clone(cleanup))
+            }
+        })
+    }
+
+    fn listener(&self) -> std::io::Result<EndpointListener> {
+        Ok(match self {
+            RetainedListener::Tcp(listener, addr) => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                EndpointListener::Tcp(TcpListener::from_std(listener)?, addr.clone())
+            }
+            RetainedListener::Unix(listener, cleanup) => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                EndpointListener::Unix(UnixListener::from_std(listener)?, Arc::clone(cleanup))
+            }
+        })
+    }
+}
+
+/// Bind the endpoint's TCP port or Unix socket
+pub async fn bind_endpoint(endpoint: &Endpoint) -> Result<EndpointListener> {
+    let listener = match &endpoint.bind_socket {
+        Some(path) => {
+            // Bound next to the final path and renamed over it, which replaces
+            // a stale socket or takes over from a still running instance
+            // without a moment where connecting fails
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let staging = path.with_file_name(format!(".{}.{}", file_name, std::process::id()));
+            if staging.exists() {
+                fs::remove_file(&staging).with_context(|| {
+                    format!("Failed to remove stale socket {}", staging.display())
+                })?;
+            }
+
+            let listener = UnixListener::bind(&staging)
+                .with_context(|| format!("Failed to bind socket {}", path.display()))?;
+            if let Some(mode) = endpoint.socket_mode()? {
+                fs::set_permissions(&staging, fs::Permissions::from_mode(mode))
+                    .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+            }
+            fs::rename(&staging, path)
+                .with_context(|| format!("Failed to move socket to {}", path.display()))?;
+            let cleanup = Arc::new(SocketCleanup::new(path)?);
+
+            EndpointListener::Unix(listener, cleanup)
+        }
+        None => {
+            let addr = format!("{}:{}", endpoint.bind_address, endpoint.bind_port);
+            let listener = bind_tcp(&addr, &endpoint.tcp, endpoint.reuse_port)
+                .await
+                .with_context(|| format!("Failed to bind {}", addr))?;
+            EndpointListener::Tcp(listener, addr)
+        }
+    };
+
+    Ok(listener)
+}
+
+/// With reuse_port, other processes may listen on the same address (the
+/// kernel spreads connections between them), e.g. the next version during
+/// an upgrade
+async fn bind_tcp(
+    addr: &str,
+    options: &TcpOptions,
+    reuse_port: bool,
+) -> std::io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "no address to bind")
+    })?;
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(options.reuse_address)?;
+    socket.set_reuseport(reuse_port)?;
+    socket.bind(addr)?;
+    socket.listen(options.backlog)
+}
+
 pub async fn start_endpoint(endpoint: Arc<Endpoint>, user_agent: String) -> Result<()> {
-    let addr = format!("{}:{}", endpoint.bind_address, endpoint.bind_port);
-    let listener = TcpListener::bind(&addr).await?;
+    let listener = bind_endpoint(&endpoint).await?;
+    serve_endpoint(endpoint, user_agent, listener).await
+}
+
+/// Listener tasks that died, with the time since, until they listen again
+static DOWN: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+fn set_listening(name: &str, listening: bool) {
+    let mut down = DOWN.lock().unwrap_or_else(|e| e.into_inner());
+    if listening {
+        down.remove(name);
+    } else {
+        down.entry(name.to_string()).or_insert_with(Instant::now);
+    }
+    metrics::gauge_set(
+        "connector_endpoint_listening",
+        "Whether the endpoint listener is bound and accepting",
+        &[("endpoint", name)],
+        if listening { 1.0 } else { 0.0 },
+    );
+}
+
+/// Drop the state of a removed endpoint
+fn forget_listener(name: &str) {
+    DOWN.lock().unwrap_or_else(|e| e.into_inner()).remove(name);
+    metrics::remove("connector_endpoint_listening", &[("endpoint", name)]);
+}
+
+pub fn is_listening(name: &str) -> bool {
+    !DOWN.lock().unwrap_or_else(|e| e.into_inner()).contains_key(name)
+}
+
+/// Endpoints whose listener is not running, with how long it has been down
+pub fn down_listeners() -> Vec<(String, Duration)> {
+    DOWN.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(name, since)| (name.clone(), since.elapsed()))
+        .collect()
+}
+
+/// Accept connections on a bound listener until the task is aborted
+pub async fn serve_endpoint(
+    endpoint: Arc<Endpoint>,
+    user_agent: String,
+    listener: EndpointListener,
+) -> Result<()> {
+    set_listening(&endpoint.name, true);
+    match listener {
+        EndpointListener::Unix(listener, _cleanup) => {
+            serve_unix_endpoint(endpoint, user_agent, listener).await
+        }
+        EndpointListener::Tcp(listener, addr) => {
+            serve_tcp_endpoint(endpoint, user_agent, listener, &addr).await
+        }
+    }
+}
+
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static DRAIN_NOTIFY: Notify = Notify::const_new();
+static CLOSING: AtomicBool = AtomicBool::new(false);
+static CLOSE_NOTIFY: Notify = Notify::const_new();
+
+/// Counts a connection as active for as long as it is alive
+struct ActiveConnection {
+    endpoint: String,
+}
+
+impl ActiveConnection {
+    fn new(endpoint: &str) -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        Self::count(endpoint, 1.0);
+        ActiveConnection {
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    fn count(endpoint: &str, delta: f64) {
+        metrics::gauge_add(
+            "connector_active_connections",
+            "Client connections currently open",
+            &[("endpoint", endpoint)],
+            delta,
+        );
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        Self::count(&self.endpoint, -1.0);
+    }
+}
+
+/// Number of client connections currently open
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+/// Ask all connections to close once their current request is answered
+pub fn begin_drain() {
+    DRAINING.store(true, Ordering::SeqCst);
+    DRAIN_NOTIFY.notify_waiters();
+}
+
+async fn wait_for(flag: &AtomicBool, notify: &Notify) {
+    let notified = notify.notified();
+    tokio::pin!(notified);
+    // Register before checking the flag so a concurrent notification is not missed
+    notified.as_mut().enable();
+    if flag.load(Ordering::SeqCst) {
+        return;
+    }
+    notified.await;
+}
+
+async fn wait_for_drain() {
+    wait_for(&DRAINING, &DRAIN_NOTIFY).await
+}
+
+/// Resolves after the endpoint's idle-timeout, never without one
+async fn idle(endpoint: &Endpoint) {
+    match endpoint.idle_timeout() {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the listeners are asked to stop accepting
+async fn wait_for_close() {
+    wait_for(&CLOSING, &CLOSE_NOTIFY).await
+}
+
+/// Set the process-wide limit of connection panics per minute before aborting
+pub fn set_panic_limit(limit: Option<u32>) {
+    let _ = PANIC_LIMIT.set(limit);
+}
+
+static PANIC_LIMIT: OnceLock<Option<u32>> = OnceLock::new();
+static RECENT_PANICS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+/// Run a connection, turning a panic into a logged, counted event
+///
+/// Returns None if the connection panicked. Aborts the process when more than
+/// the configured number of panics happened within the last minute.
+async fn isolate_panics<F: Future>(
+    connection: F,
+    endpoint: &Endpoint,
+    peer: &str,
+) -> Option<F::Output> {
+    match AssertUnwindSafe(connection).catch_unwind().await {
+        Ok(output) => Some(output),
+        Err(payload) => {
+            error!(
+                "Connection from {} on endpoint '{}' (mode: {:?}) panicked: {}",
+                peer,
+                endpoint.name,
+                endpoint.mode,
+                panic_message(payload.as_ref())
+            );
+            metrics::counter_inc(
+                "connector_connection_panics_total",
+                "Connection handlers that panicked",
+                &[("endpoint", &endpoint.name)],
+            );
+
+            if let Some(limit) = PANIC_LIMIT.get().copied().flatten() {
+                let mut recent = RECENT_PANICS.lock().unwrap_or_else(|e| e.into_inner());
+                let now = Instant::now();
+                recent.push_back(now);
+                while recent
+                    .front()
+                    .is_some_and(|t| now.duration_since(*t) > Duration::from_secs(60))
+                {
+                    recent.pop_front();
+                }
+                if recent.len() > limit as usize {
+                    error!(
+                        "{} connection panics within a minute (limit {}), aborting",
+                        recent.len(),
+                        limit
+                    );
+                    std::process::abort();
+                }
+            }
+
+            None
+        }
+    }
+}
+
+/// Accept the next connection within the endpoint's max-connections, with
+/// the slot it holds while open
+///
+/// With overflow "queue", nothing is accepted until a slot is free, so new
+/// connections wait in the listen backlog. With "reject", connections over
+/// the limit are closed right after accepting; None then.
+async fn accept_within_limit<S, A>(
+    endpoint: &Endpoint,
+    accept: impl Future<Output = std::io::Result<(S, A)>>,
+) -> std::io::Result<Option<(S, A, Option<OwnedSemaphorePermit>)>> {
+    let Some(slots) = endpoint.connection_slots() else {
+        let (socket, addr) = accept.await?;
+        return Ok(Some((socket, addr, None)));
+    };
+
+    if endpoint.connection_overflow == ConnectionOverflow::Queue {
+        let slot = Arc::clone(slots)
+            .acquire_owned()
+            .await
+            .expect("connection slots are never closed");
+        let (socket, addr) = accept.await?;
+        return Ok(Some((socket, addr, Some(slot))));
+    }
+
+    let (socket, addr) = accept.await?;
+    match Arc::clone(slots).try_acquire_owned() {
+        Ok(slot) => Ok(Some((socket, addr, Some(slot)))),
+        Err(_) => {
+            debug!("Endpoint '{}' at max-connections, closing connection", endpoint.name);
+            metrics::counter_inc(
+                "connector_connections_rejected_total",
+                "Connections closed because the endpoint was at max-connections",
+                &[("endpoint", &endpoint.name)],
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Removes the socket file when the listener task ends or is aborted,
+/// unless another instance has put its own socket there in the meantime
+pub struct SocketCleanup {
+    path: PathBuf,
+    inode: (u64, u64),
+}
+
+impl SocketCleanup {
+    fn new(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("Failed to stat socket {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            inode: (metadata.dev(), metadata.ino()),
+        })
+    }
+}
+
+impl Drop for SocketCleanup {
+    fn drop(&mut self) {
+        match fs::metadata(&self.path) {
+            Ok(metadata) if (metadata.dev(), metadata.ino()) == self.inode => {}
+            Ok(_) => {
+                info!("Socket {} was taken over, leaving it in place", self.path.display());
+                return;
+            }
+            Err(_) => return,
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+async fn serve_unix_endpoint(
+    endpoint: Arc<Endpoint>,
+    user_agent: String,
+    listener: UnixListener,
+) -> Result<()> {
+    let path = endpoint.bind_socket_display();
 
     info!(
-        "Endpoint '{}' listening on {} (mode: {:?})",
-        endpoint.name, addr, endpoint.mode
+        "Endpoint '{}' listening on unix:{} (mode: {:?})",
+        endpoint.name, path, endpoint.mode
     );
 
     loop {
-        match listener.accept().await {
-            Ok((mut socket, addr)) => {
-                debug!("New connection from {}", addr);
+        let accepted = tokio::select! {
+            accepted = accept_within_limit(&endpoint, listener.accept()) => accepted,
+            _ = wait_for_close() => break,
+        };
+        match accepted {
+            Ok(Some((socket, _, slot))) => {
+                debug!("New connection on {}", path);
+                spawn_unix_connection(&endpoint, &user_agent, socket, slot);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Accept error: {}", e);
+            }
+        }
+    }
 
-                let endpoint = Arc::clone(&endpoint);
-                let user_agent = user_agent.clone();
+    while let Some(Ok((socket, _))) = listener.accept().now_or_never() {
+        debug!("Serving connection queued on {} before closing", path);
+        spawn_unix_connection(&endpoint, &user_agent, socket, None);
+    }
+    Ok(())
+}
 
-                tokio::spawn(async move {
-                    if let Err(e) = handle_connection(&mut socket, &endpoint, &user_agent).await {
-                        error!("Connection error from {}: {}", addr, e);
-                    }
-                    debug!("Connection closed from {}", addr);
-                });
+fn spawn_unix_connection(
+    endpoint: &Arc<Endpoint>,
+    user_agent: &str,
+    mut socket: UnixStream,
+    slot: Option<OwnedSemaphorePermit>,
+) {
+    let endpoint = Arc::clone(endpoint);
+    let user_agent = user_agent.to_string();
+
+    tokio::spawn(async move {
+        let _slot = slot;
+        let peer = format!("unix:{}", endpoint.bind_socket_display());
+        let connection = logging::connection(
+            &endpoint,
+            None,
+            handle_connection(&mut socket, &endpoint, &user_agent),
+        );
+        match isolate_panics(connection, &endpoint, &peer).await {
+            Some(Err(e)) => {
+                error!("Connection error on endpoint '{}': {}", endpoint.name, e)
+            }
+            _ => debug!("Unix connection closed"),
+        }
+    });
+}
+
+async fn serve_tcp_endpoint(
+    endpoint: Arc<Endpoint>,
+    user_agent: String,
+    listener: TcpListener,
+    addr: &str,
+) -> Result<()> {
+    info!(
+        "Endpoint '{}' listening on {} (mode: {:?})",
+        endpoint.name, addr, endpoint.mode
+    );
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept_within_limit(&endpoint, listener.accept()) => accepted,
+            _ = wait_for_close() => break,
+        };
+        match accepted {
+            Ok(Some((socket, addr, slot))) => {
+                debug!("New connection from {}", sensitive(&addr));
+                spawn_tcp_connection(&endpoint, &user_agent, socket, addr, slot);
             }
+            Ok(None) => {}
             Err(e) => {
                 error!("Accept error: {}", e);
             }
         }
     }
+
+    while let Some(Ok((socket, addr))) = listener.accept().now_or_never() {
+        debug!("Serving connection from {} queued before closing", sensitive(&addr));
+        spawn_tcp_connection(&endpoint, &user_agent, socket, addr, None);
+    }
+    Ok(())
+}
+
+fn spawn_tcp_connection(
+    endpoint: &Arc<Endpoint>,
+    user_agent: &str,
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    slot: Option<OwnedSemaphorePermit>,
+) {
+    if !endpoint.allows_client(addr.ip()) {
+        warn!(
+            "Endpoint '{}' refused connection from {}: not in allowed-cidrs",
+            endpoint.name,
+            sensitive(&addr)
+        );
+        metrics::counter_inc(
+            "connector_connections_denied_total",
+            "Connections closed because the client was not in allowed-cidrs",
+            &[("endpoint", &endpoint.name)],
+        );
+        return;
+    }
+    if let Err(e) = endpoint.tcp.apply(&socket) {
+        debug!("Failed to set socket options for {}: {}", sensitive(&addr), e);
+    }
+
+    let endpoint = Arc::clone(endpoint);
+    let user_agent = user_agent.to_string();
+
+    tokio::spawn(async move {
+        let _slot = slot;
+        let peer = sensitive(&addr).to_string();
+        let connection = logging::connection(
+            &endpoint,
+            Some(peer.clone()),
+            handle_connection(&mut socket, &endpoint, &user_agent),
+        );
+        if let Some(Err(e)) = isolate_panics(connection, &endpoint, &peer).await {
+            error!("Connection error from {}: {}", peer, e);
+        }
+        debug!("Connection closed from {}", peer);
+    });
 }
 
-async fn handle_connection(
-    socket: &mut tokio::net::TcpStream,
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
     endpoint: &Endpoint,
     user_agent: &str,
 ) -> Result<()> {
+    let _active = ActiveConnection::new(&endpoint.name);
+    if endpoint.is_disabled() {
+        debug!("Endpoint '{}' disabled, closing connection", endpoint.name);
+        return Ok(());
+    }
+    // Milter packets are length-prefixed and need their own read loop
+    if endpoint.mode == EndpointMode::Milter {
+        return milter::serve(socket, endpoint, user_agent).await;
+    }
+    // Dovecot's auth policy requests are HTTP
+    if endpoint.mode == EndpointMode::AuthPolicy {
+        return authpolicy::serve(socket, endpoint, user_agent).await;
+    }
     let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut dict_session = DictSession::default();
+    let mut table_session = TableSession::default();
 
     // CRITICAL FIX: Loop to handle multiple requests on the same connection
     // Postfix reuses TCP connections for multiple lookups
     loop {
-        // Read request from Postfix
-        let n = match socket.read(&mut buffer).await {
+        // Read request from Postfix; idle connections are closed when draining
+        let read = tokio::select! {
+            // A request that already arrived is answered before closing
+            biased;
+            read = socket.read(&mut buffer) => read,
+            _ = wait_for_drain() => {
+                debug!("Draining, closing idle connection");
+                return Ok(());
+            }
+            _ = idle(endpoint) => {
+                debug!("No request for {} ms, closing idle connection", endpoint.idle_timeout);
+                metrics::counter_inc(
+                    "connector_idle_connections_closed_total",
+                    "Connections closed after idle-timeout without a request",
+                    &[("endpoint", &endpoint.name)],
+                );
+                socket.shutdown().await.ok();
+                return Ok(());
+            }
+        };
+        let n = match read {
             Ok(0) => {
                 // Connection closed by client (normal)
                 debug!("Client closed connection");
@@ -64,43 +1032,120 @@ async fn handle_connection(
             }
         };
 
-        let request = String::from_utf8_lossy(&buffer[..n]);
-        debug!("Received {} bytes: {:?}", n, &request[..n.min(100)]);
+        // Keepalives from monitoring and older Postfix versions get no reply
+        let input = strip_keepalive(&buffer[..n]);
+        if input.is_empty() {
+            debug!("Ignoring {} bytes of keepalive no-op", n);
+            continue;
+        }
+        // Persistent connections end with their next request once disabled
+        if endpoint.is_disabled() {
+            debug!("Endpoint '{}' disabled, closing connection", endpoint.name);
+            return Ok(());
+        }
 
-        // Process based on mode
-        let response = match endpoint.mode {
-            EndpointMode::TcpLookup => {
-                handle_tcp_lookup(endpoint, &request, user_agent).await?
-            }
-            EndpointMode::SocketmapLookup => {
-                handle_socketmap_lookup(endpoint, &request, user_agent).await?
+        let request = handle_request(
+            socket,
+            endpoint,
+            input,
+            &mut dict_session,
+            &mut table_session,
+            user_agent,
+        );
+        if !logging::request(request).await? {
+            return Ok(());
+        }
+
+        // Continue loop to handle next request on same connection
+    }
+}
+
+/// Answer one request; Ok(false) when the connection is to be closed
+async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    endpoint: &Endpoint,
+    input: &[u8],
+    dict_session: &mut DictSession,
+    table_session: &mut TableSession,
+    user_agent: &str,
+) -> Result<bool> {
+    let request = String::from_utf8_lossy(input);
+    debug!(
+        "Received {} bytes: {:?}",
+        input.len(),
+        sensitive(&request[..request.len().min(100)])
+    );
+
+    // Resolve the protocol for this request (multiplex endpoints sniff the framing)
+    let mode = match endpoint.mode {
+        EndpointMode::Multiplex => match detect_mode(input) {
+            Some(mode) if endpoint.allowed_modes.contains(&mode) => mode,
+            Some(mode) => {
+                warn!("Protocol {:?} not allowed on endpoint '{}'", mode, endpoint.name);
+                return Ok(false);
             }
-            EndpointMode::Policy => {
-                handle_policy_check(endpoint, &request, user_agent).await?
+            None => {
+                warn!("Unable to detect protocol on endpoint '{}'", endpoint.name);
+                return Ok(false);
             }
-        };
+        },
+        ref mode => mode.clone(),
+    };
 
-        // Send response back to Postfix
-        if let Err(e) = socket.write_all(response.as_bytes()).await {
-            warn!("Write error: {}", e);
-            return Err(e.into());
+    // Process based on mode
+    let response = match mode {
+        EndpointMode::TcpLookup => {
+            handle_tcp_lookup(endpoint, &request, user_agent).await?
         }
-        
-        // CRITICAL: Flush the socket to ensure data is sent immediately
-        if let Err(e) = socket.flush().await {
-            warn!("Flush error: {}", e);
-            return Err(e.into());
+        EndpointMode::SocketmapLookup => {
+            handle_socketmap_lookup(endpoint, input, user_agent).await?
         }
-        
-        debug!("Sent response: {}", response.trim());
-
-        // For Policy delegation, connection is typically closed after response
-        // as per Postfix policy protocol specification
-        if matches!(endpoint.mode, EndpointMode::Policy) {
-            debug!("Policy check complete, closing connection");
-            return Ok(());
+        EndpointMode::Policy => {
+            handle_policy_check(endpoint, &request, user_agent).await?
+        }
+        EndpointMode::Memcache => {
+            handle_memcache(endpoint, &request, user_agent).await?
+        }
+        EndpointMode::Greylist => {
+            handle_greylist(endpoint, &request).await?
         }
+        EndpointMode::Quota => {
+            handle_quota(endpoint, &request, user_agent).await?
+        }
+        EndpointMode::DovecotDict => {
+            handle_dovecot_dict(endpoint, &request, dict_session, user_agent).await?
+        }
+        EndpointMode::OpensmtpdTable => {
+            handle_opensmtpd_table(endpoint, &request, table_session, user_agent).await?
+        }
+        EndpointMode::Multiplex => unreachable!("multiplex is resolved above"),
+        EndpointMode::Milter => unreachable!("milter connections are served above"),
+        EndpointMode::AuthPolicy => unreachable!("auth policy connections are served above"),
+    };
 
-        // Continue loop to handle next request on same connection
+    // Send response back to Postfix
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        warn!("Write error: {}", e);
+        return Err(e.into());
     }
+    
+    // CRITICAL: Flush the socket to ensure data is sent immediately
+    if let Err(e) = socket.flush().await {
+        warn!("Flush error: {}", e);
+        return Err(e.into());
+    }
+    
+    debug!("Sent response: {}", sensitive(response.trim()));
+
+    // For Policy delegation, connection is typically closed after response
+    // as per Postfix policy protocol specification
+    if matches!(
+        mode,
+        EndpointMode::Policy | EndpointMode::Greylist | EndpointMode::Quota
+    ) {
+        debug!("Policy check complete, closing connection");
+        return Ok(false);
+    }
+
+    Ok(true)
 }
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Request;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::PathBuf;
+
+use crate::clock;
+use crate::secret::SecretFile;
+
+fn default_signature_header() -> String {
+    "X-Signature".to_string()
+}
+
+fn default_timestamp_header() -> String {
+    "X-Timestamp".to_string()
+}
+
+/// HMAC-SHA256 signature over each upstream request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SigningConfig {
+    #[serde(default)]
+    pub secret: String,
+    /// Read the shared secret from this file instead, re-read when it changes
+    #[serde(default)]
+    pub secret_file: Option<PathBuf>,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    /// Unix time in seconds, for the backend to reject old (replayed) requests
+    #[serde(default = "default_timestamp_header")]
+    pub timestamp_header: String,
+}
+
+#[derive(Debug)]
+pub struct RequestSigner {
+    secret: String,
+    secret_source: Option<SecretFile>,
+    signature_header: HeaderName,
+    timestamp_header: HeaderName,
+}
+
+impl RequestSigner {
+    pub fn new(config: &SigningConfig) -> Result<Self> {
+        if config.secret.is_empty() && config.secret_file.is_none() {
+            anyhow::bail!("signing requires secret or secret-file");
+        }
+        if !config.secret.is_empty() && config.secret_file.is_some() {
+            anyhow::bail!("signing secret and secret-file are mutually exclusive");
+        }
+        let secret_source = match &config.secret_file {
+            Some(path) => Some(SecretFile::open(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            secret: config.secret.clone(),
+            secret_source,
+            signature_header: HeaderName::from_bytes(config.signature_header.as_bytes())
+                .context("Invalid signature-header")?,
+            timestamp_header: HeaderName::from_bytes(config.timestamp_header.as_bytes())
+                .context("Invalid timestamp-header")?,
+        })
+    }
+
+    fn secret(&self) -> String {
+        match &self.secret_source {
+            Some(source) => source.get(),
+            None => self.secret.clone(),
+        }
+    }
+
+    /// Add the timestamp and signature headers
+    ///
+    /// The signed message is `METHOD\nPATH?QUERY\nTIMESTAMP\nBODY`, the
+    /// signature is the lowercase hex HMAC-SHA256 of it.
+    pub fn sign(&self, request: &mut Request) -> Result<()> {
+        let timestamp = clock::unix_time().as_secs().to_string();
+
+        let url = request.url();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let body = request
+            .body()
+            .map(|body| body.as_bytes().context("Streaming bodies cannot be signed"))
+            .transpose()?
+            .unwrap_or_default();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(request.method().as_str().as_bytes());
+        mac.update(b"\n");
+        mac.update(path.as_bytes());
+        mac.update(b"\n");
+        mac.update(timestamp.as_bytes());
+        mac.update(b"\n");
+        mac.update(body);
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let headers = request.headers_mut();
+        headers.insert(self.timestamp_header.clone(), HeaderValue::from_str(&timestamp)?);
+        headers.insert(self.signature_header.clone(), HeaderValue::from_str(&signature)?);
+        Ok(())
+    }
+}
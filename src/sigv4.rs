@@ -0,0 +1,60 @@
+use anyhow::Result;
+use reqwest::Request;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "aws")]
+mod aws;
+
+fn default_service() -> String {
+    "execute-api".to_string()
+}
+
+/// AWS Signature Version 4 with credentials from the default provider chain
+/// (environment, profile, web identity, ECS, EC2 instance metadata)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SigV4Config {
+    /// Defaults to the region from the environment or profile
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default = "default_service")]
+    pub service: String,
+    /// Named profile from ~/.aws/config instead of the default one
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct SigV4Signer {
+    #[cfg(feature = "aws")]
+    inner: aws::AwsSigner,
+}
+
+impl SigV4Signer {
+    pub fn new(config: &SigV4Config) -> Result<Self> {
+        #[cfg(feature = "aws")]
+        {
+            Ok(Self {
+                inner: aws::AwsSigner::new(config),
+            })
+        }
+        #[cfg(not(feature = "aws"))]
+        {
+            let _ = config;
+            anyhow::bail!("built without AWS SigV4 support")
+        }
+    }
+
+    /// Add the SigV4 Authorization and X-Amz-* headers
+    pub async fn sign(&self, request: &mut Request) -> Result<()> {
+        #[cfg(feature = "aws")]
+        {
+            self.inner.sign(request).await
+        }
+        #[cfg(not(feature = "aws"))]
+        {
+            let _ = request;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_credential_types::provider::ProvideCredentials;
+use aws_credential_types::Credentials;
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use aws_smithy_runtime_api::client::identity::Identity;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Request;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, OnceCell};
+
+use super::SigV4Config;
+
+// Temporary credentials are replaced this long before they expire
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct AwsSigner {
+    config: SigV4Config,
+    // Loaded on first use, the provider chain may need network access (IMDS)
+    sdk_config: OnceCell<SdkConfig>,
+    credentials: Mutex<Option<Credentials>>,
+}
+
+impl AwsSigner {
+    pub fn new(config: &SigV4Config) -> Self {
+        Self {
+            config: config.clone(),
+            sdk_config: OnceCell::new(),
+            credentials: Mutex::new(None),
+        }
+    }
+
+    async fn sdk_config(&self) -> &SdkConfig {
+        self.sdk_config
+            .get_or_init(|| async {
+                let mut loader = aws_config::defaults(BehaviorVersion::latest());
+                if let Some(profile) = &self.config.profile {
+                    loader = loader.profile_name(profile);
+                }
+                if let Some(region) = &self.config.region {
+                    loader = loader.region(Region::new(region.clone()));
+                }
+                loader.load().await
+            })
+            .await
+    }
+
+    async fn credentials(&self) -> Result<Credentials> {
+        let mut cached = self.credentials.lock().await;
+        if let Some(credentials) = cached.as_ref() {
+            let fresh = match credentials.expiry() {
+                Some(expiry) => SystemTime::now() + REFRESH_BEFORE_EXPIRY < expiry,
+                None => true,
+            };
+            if fresh {
+                return Ok(credentials.clone());
+            }
+        }
+
+        let provider = self
+            .sdk_config()
+            .await
+            .credentials_provider()
+            .context("No AWS credentials provider available")?;
+        let credentials = provider
+            .provide_credentials()
+            .await
+            .context("Failed to load AWS credentials")?;
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+
+    pub async fn sign(&self, request: &mut Request) -> Result<()> {
+        let identity: Identity = self.credentials().await?.into();
+        let region = self
+            .sdk_config()
+            .await
+            .region()
+            .map(|region| region.to_string())
+            .context("No AWS region configured")?;
+
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&region)
+            .name(&self.config.service)
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()?
+            .into();
+
+        let body = request
+            .body()
+            .map(|body| body.as_bytes().context("Streaming bodies cannot be signed"))
+            .transpose()?
+            .unwrap_or_default();
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+        let signable = SignableRequest::new(
+            request.method().as_str(),
+            request.url().as_str(),
+            headers,
+            SignableBody::Bytes(body),
+        )?;
+        let (instructions, _signature) = sign(signable, &params)?.into_parts();
+
+        for (name, value) in instructions.headers() {
+            request
+                .headers_mut()
+                .insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+        }
+        Ok(())
+    }
+}
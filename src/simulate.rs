@@ -0,0 +1,379 @@
+use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::cli::{self, Connection};
+use crate::config::{Config, Endpoint, EndpointMode};
+
+pub const SIMULATE_USAGE: &str = "simulate <config-file> <endpoint> \
+    [--scenario persistent|pipelining|disconnect|storm] [--clients N] \
+    [--duration SECONDS] [--key TEMPLATE] [--map NAME]";
+
+const BUFFER_SIZE: usize = 65536;
+
+// Requests written at once in the pipelining scenario
+const PIPELINE_DEPTH: usize = 8;
+
+/// Client behaviour to emulate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scenario {
+    /// One request at a time on long-lived connections, like proxymap
+    Persistent,
+    /// Several requests per write before reading the replies
+    Pipelining,
+    /// Connections dropped mid-request or before the reply is read
+    Disconnect,
+    /// Connect, one request, close, immediately again (e.g. after postfix reload)
+    Storm,
+}
+
+impl Scenario {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "persistent" => Scenario::Persistent,
+            "pipelining" => Scenario::Pipelining,
+            "disconnect" => Scenario::Disconnect,
+            "storm" => Scenario::Storm,
+            _ => anyhow::bail!("Unknown scenario '{}'", name),
+        })
+    }
+}
+
+struct Options {
+    scenario: Scenario,
+    clients: usize,
+    duration: Duration,
+    key: String,
+    map: Option<String>,
+}
+
+#[derive(Default)]
+struct Stats {
+    requests: AtomicU64,
+    replies: AtomicU64,
+    errors: AtomicU64,
+    disconnects: AtomicU64,
+    connections: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl Stats {
+    fn error(&self, error: &anyhow::Error) {
+        // The first few show what is going on without flooding the terminal
+        if self.errors.fetch_add(1, Ordering::Relaxed) < 5 {
+            eprintln!("error: {:#}", error);
+        }
+    }
+}
+
+/// Builds the Postfix-format requests for one endpoint
+struct Requests {
+    mode: EndpointMode,
+    key: String,
+    map: Option<String>,
+}
+
+impl Requests {
+    fn new(endpoint: &Endpoint, key: &str, map: Option<&str>) -> Result<Self> {
+        let mode = match &endpoint.mode {
+            EndpointMode::Policy | EndpointMode::Greylist | EndpointMode::Quota => {
+                EndpointMode::Policy
+            }
+            _ => cli::lookup_request(endpoint, key, map)?.0,
+        };
+        Ok(Self {
+            mode,
+            key: key.to_string(),
+            map: map.map(str::to_string),
+        })
+    }
+
+    /// Request number `n`; `{n}` in the key template makes keys unique
+    fn build(&self, n: u64) -> String {
+        let key = self.key.replace("{n}", &n.to_string());
+        match (&self.mode, &self.map) {
+            (EndpointMode::Policy, _) => format!(
+                "request=smtpd_access_policy\nprotocol_state=RCPT\n\
+                 client_address=192.0.2.{}\nsender=sender@example.org\nrecipient={}\n\n",
+                n % 254 + 1,
+                key
+            ),
+            (EndpointMode::SocketmapLookup, Some(map)) => {
+                let data = format!("{} {}", map, key);
+                format!("{}:{},", data.len(), data)
+            }
+            (EndpointMode::Memcache, _) => format!("get {}\r\n", key),
+            (EndpointMode::DovecotDict, map) => format!(
+                "H3\t2\t0\t\t{}\nLshared/{}\n",
+                map.as_deref().unwrap_or(""),
+                key
+            ),
+            (EndpointMode::OpensmtpdTable, map) => format!(
+                "table|0.1|0|{}|lookup|string|{}|{}\n",
+                map.as_deref().unwrap_or(""),
+                n,
+                key
+            ),
+            _ => format!("get {}\n", key),
+        }
+    }
+
+    /// Length of the first complete reply in `buffer`
+    fn reply_len(&self, buffer: &[u8]) -> Option<usize> {
+        match self.mode {
+            EndpointMode::SocketmapLookup => {
+                let colon = buffer.iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&buffer[..colon]).ok()?.parse().ok()?;
+                let end = colon + 1 + len + 1;
+                (buffer.len() >= end).then_some(end)
+            }
+            EndpointMode::Policy => find(buffer, b"\n\n").map(|pos| pos + 2),
+            EndpointMode::Memcache => find(buffer, b"END\r\n").map(|pos| pos + 5),
+            _ => buffer.iter().position(|&b| b == b'\n').map(|pos| pos + 1),
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// One client connection with buffered reply framing
+struct Client {
+    stream: Box<dyn Connection>,
+    buffer: Vec<u8>,
+    timeout: Duration,
+}
+
+impl Client {
+    async fn connect(endpoint: &Endpoint, stats: &Stats) -> Result<Self> {
+        let stream = cli::connect(endpoint).await?;
+        stats.connections.fetch_add(1, Ordering::Relaxed);
+        Ok(Self {
+            stream,
+            buffer: Vec::new(),
+            // Allow for the daemon's own upstream timeout
+            timeout: endpoint.timeout() + Duration::from_secs(1),
+        })
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn reply(&mut self, requests: &Requests) -> Result<()> {
+        let mut chunk = vec![0u8; BUFFER_SIZE];
+        loop {
+            if let Some(len) = requests.reply_len(&self.buffer) {
+                self.buffer.drain(..len);
+                return Ok(());
+            }
+            let n = tokio::time::timeout(self.timeout, self.stream.read(&mut chunk))
+                .await
+                .context("Timed out waiting for a reply")??;
+            if n == 0 {
+                anyhow::bail!("Connection closed without a reply");
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// Send one request and wait for its reply, recording the latency
+async fn round_trip(client: &mut Client, requests: &Requests, n: u64, stats: &Stats) -> Result<()> {
+    let started = Instant::now();
+    stats.requests.fetch_add(1, Ordering::Relaxed);
+    client.send(requests.build(n).as_bytes()).await?;
+    client.reply(requests).await?;
+    stats.replies.fetch_add(1, Ordering::Relaxed);
+    stats.latencies.lock().unwrap().push(started.elapsed());
+    Ok(())
+}
+
+async fn run_client(
+    endpoint: Arc<Endpoint>,
+    requests: Arc<Requests>,
+    options: Arc<Options>,
+    stats: Arc<Stats>,
+    id: u64,
+    deadline: Instant,
+) {
+    let mut client: Option<Client> = None;
+    let mut n = id << 32;
+
+    while Instant::now() < deadline {
+        n += 1;
+        let result = async {
+            match options.scenario {
+                Scenario::Persistent => {
+                    if client.is_none() {
+                        client = Some(Client::connect(&endpoint, &stats).await?);
+                    }
+                    let connection = client.as_mut().expect("connected above");
+                    round_trip(connection, &requests, n, &stats).await
+                }
+                Scenario::Pipelining => {
+                    if client.is_none() {
+                        client = Some(Client::connect(&endpoint, &stats).await?);
+                    }
+                    let connection = client.as_mut().expect("connected above");
+                    let started = Instant::now();
+                    let batch: String = (0..PIPELINE_DEPTH as u64)
+                        .map(|i| requests.build(n * PIPELINE_DEPTH as u64 + i))
+                        .collect();
+                    stats.requests.fetch_add(PIPELINE_DEPTH as u64, Ordering::Relaxed);
+                    connection.send(batch.as_bytes()).await?;
+                    for _ in 0..PIPELINE_DEPTH {
+                        connection.reply(&requests).await?;
+                        stats.replies.fetch_add(1, Ordering::Relaxed);
+                    }
+                    stats.latencies.lock().unwrap().push(started.elapsed());
+                    Ok(())
+                }
+                Scenario::Disconnect => {
+                    let mut connection = Client::connect(&endpoint, &stats).await?;
+                    let request = requests.build(n);
+                    match n % 3 {
+                        // Half a request, then gone
+                        0 => connection.send(&request.as_bytes()[..request.len() / 2]).await?,
+                        // Whole request, gone before the reply
+                        1 => connection.send(request.as_bytes()).await?,
+                        // A normal exchange, to check the daemon still answers
+                        _ => return round_trip(&mut connection, &requests, n, &stats).await,
+                    }
+                    stats.disconnects.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+                Scenario::Storm => {
+                    let mut connection = Client::connect(&endpoint, &stats).await?;
+                    round_trip(&mut connection, &requests, n, &stats).await
+                }
+            }
+        }
+        .await;
+
+        if let Err(e) = result {
+            stats.error(&e);
+            // Start over on a fresh connection
+            client = None;
+        }
+        // Policy endpoints close the connection after every reply
+        if matches!(requests.mode, EndpointMode::Policy) {
+            client = None;
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+fn parse_options(args: &[String]) -> Result<(String, String, Options)> {
+    let mut positional = Vec::new();
+    let mut options = Options {
+        scenario: Scenario::Persistent,
+        clients: 10,
+        duration: Duration::from_secs(30),
+        key: "user{n}@example.com".to_string(),
+        map: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", arg))
+                .map(String::as_str)
+        };
+        match arg.as_str() {
+            "--scenario" => options.scenario = Scenario::parse(value()?)?,
+            "--clients" => options.clients = value()?.parse().context("Invalid --clients")?,
+            "--duration" => {
+                options.duration =
+                    Duration::from_secs(value()?.parse().context("Invalid --duration")?)
+            }
+            "--key" => options.key = value()?.to_string(),
+            "--map" => options.map = Some(value()?.to_string()),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    match <[String; 2]>::try_from(positional) {
+        Ok([path, name]) => Ok((path, name, options)),
+        Err(_) => anyhow::bail!("Usage: {}", SIMULATE_USAGE),
+    }
+}
+
+/// `simulate` subcommand: emulate Postfix client patterns against a running
+/// connector for soak testing
+///
+/// Fails if any request got no valid reply.
+pub async fn run(args: &[String]) -> Result<()> {
+    let (path, name, options) = parse_options(args)?;
+
+    let config = Config::from_file(&path)?;
+    let endpoint = config
+        .endpoints
+        .iter()
+        .find(|endpoint| endpoint.name == name)
+        .with_context(|| format!("Unknown endpoint '{}'", name))?
+        .clone();
+    let requests = Arc::new(Requests::new(&endpoint, &options.key, options.map.as_deref())?);
+
+    println!(
+        "Simulating {:?} with {} clients for {:?} against endpoint '{}'",
+        options.scenario, options.clients, options.duration, name
+    );
+
+    let endpoint = Arc::new(endpoint);
+    let options = Arc::new(options);
+    let stats = Arc::new(Stats::default());
+    let started = Instant::now();
+    let deadline = started + options.duration;
+
+    let handles: Vec<_> = (0..options.clients as u64)
+        .map(|id| {
+            tokio::spawn(run_client(
+                Arc::clone(&endpoint),
+                Arc::clone(&requests),
+                Arc::clone(&options),
+                Arc::clone(&stats),
+                id,
+                deadline,
+            ))
+        })
+        .collect();
+    for handle in handles {
+        handle.await?;
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let requests = stats.requests.load(Ordering::Relaxed);
+    let errors = stats.errors.load(Ordering::Relaxed);
+    let mut latencies = std::mem::take(&mut *stats.latencies.lock().unwrap());
+    latencies.sort();
+
+    println!("Connections: {}", stats.connections.load(Ordering::Relaxed));
+    println!("Requests:    {} ({:.1}/s)", requests, requests as f64 / elapsed);
+    println!("Replies:     {}", stats.replies.load(Ordering::Relaxed));
+    println!("Disconnects: {}", stats.disconnects.load(Ordering::Relaxed));
+    println!("Errors:      {}", errors);
+    println!(
+        "Latency:     p50 {:?}, p99 {:?}, max {:?}",
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default()
+    );
+
+    if errors > 0 {
+        anyhow::bail!("Simulation finished with {} errors", errors);
+    }
+    Ok(())
+}
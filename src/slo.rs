@@ -0,0 +1,161 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::Instant;
+use crate::metrics;
+
+// Longest supported window (30 days), one bucket per minute
+const MAX_WINDOW_MINUTES: u64 = 30 * 24 * 60;
+
+fn default_windows() -> Vec<u64> {
+    vec![5, 60]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SloConfig {
+    /// Requests slower than this count against the error budget
+    pub latency_target: u64, // milliseconds
+    /// Fraction of requests that must succeed within latency-target, e.g. 0.999
+    pub objective: f64,
+    /// Rolling windows the burn rate is computed over
+    #[serde(default = "default_windows")]
+    pub windows: Vec<u64>, // minutes
+}
+
+impl SloConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.objective > 0.0 && self.objective < 1.0) {
+            anyhow::bail!("slo objective must be between 0 and 1");
+        }
+        if self.windows.is_empty() {
+            anyhow::bail!("slo windows must not be empty");
+        }
+        if self
+            .windows
+            .iter()
+            .any(|window| *window == 0 || *window > MAX_WINDOW_MINUTES)
+        {
+            anyhow::bail!(
+                "slo windows must be between 1 and {} minutes",
+                MAX_WINDOW_MINUTES
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    minute: u64,
+    total: u64,
+    bad: u64,
+}
+
+#[derive(Debug)]
+struct SloState {
+    buckets: VecDeque<Bucket>,
+    last_export: Option<u64>, // seconds since start
+}
+
+/// Error budget burn of an endpoint over rolling windows
+#[derive(Debug)]
+pub struct SloTracker {
+    name: String,
+    latency_target: Duration,
+    budget: f64,
+    windows: Vec<u64>,
+    started: Instant,
+    state: Mutex<SloState>,
+}
+
+impl SloTracker {
+    pub fn new(name: &str, config: &SloConfig) -> Self {
+        let mut windows = config.windows.clone();
+        windows.sort_unstable();
+        windows.dedup();
+
+        Self {
+            name: name.to_string(),
+            latency_target: Duration::from_millis(config.latency_target),
+            budget: 1.0 - config.objective,
+            windows,
+            started: Instant::now(),
+            state: Mutex::new(SloState {
+                buckets: VecDeque::new(),
+                last_export: None,
+            }),
+        }
+    }
+
+    /// Record a request; it is good if it succeeded within the latency target
+    pub fn record(&self, latency: Duration, success: bool) {
+        let good = success && latency <= self.latency_target;
+        metrics::counter_inc(
+            "connector_slo_requests_total",
+            "Requests counted against the endpoint SLO",
+            &[
+                ("endpoint", &self.name),
+                ("outcome", if good { "good" } else { "bad" }),
+            ],
+        );
+
+        let elapsed = self.started.elapsed().as_secs();
+        let minute = elapsed / 60;
+        let mut state = self.state.lock().unwrap();
+
+        match state.buckets.back_mut() {
+            Some(bucket) if bucket.minute == minute => {
+                bucket.total += 1;
+                bucket.bad += u64::from(!good);
+            }
+            _ => state.buckets.push_back(Bucket {
+                minute,
+                total: 1,
+                bad: u64::from(!good),
+            }),
+        }
+
+        let longest = self.windows.last().copied().unwrap_or(1);
+        while let Some(front) = state.buckets.front() {
+            if front.minute + longest > minute {
+                break;
+            }
+            state.buckets.pop_front();
+        }
+
+        // Summing long windows on every request is wasteful, once a second is plenty
+        if state.last_export == Some(elapsed) {
+            return;
+        }
+        state.last_export = Some(elapsed);
+        self.export(&state.buckets, minute);
+    }
+
+    fn export(&self, buckets: &VecDeque<Bucket>, minute: u64) {
+        for window in &self.windows {
+            let (total, bad) = buckets
+                .iter()
+                .filter(|bucket| bucket.minute + window > minute)
+                .fold((0, 0), |(total, bad), bucket| {
+                    (total + bucket.total, bad + bucket.bad)
+                });
+
+            // 1.0 burns the budget exactly over the window, above that faster
+            let burn_rate = if total == 0 {
+                0.0
+            } else {
+                (bad as f64 / total as f64) / self.budget
+            };
+            metrics::gauge_set(
+                "connector_slo_burn_rate",
+                "Error budget burn rate over a rolling window",
+                &[("endpoint", &self.name), ("window", &format!("{}m", window))],
+                burn_rate,
+            );
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+fn default_nodelay() -> bool {
+    true
+}
+
+fn default_reuse_address() -> bool {
+    true
+}
+
+fn default_backlog() -> u32 {
+    // As std's TcpListener::bind
+    128
+}
+
+fn default_keepalive_idle() -> u64 {
+    60
+}
+
+fn default_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_retries() -> u32 {
+    5
+}
+
+/// Socket options for a bind-port endpoint's listener and its client
+/// connections, set explicitly since OS defaults differ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TcpOptions {
+    /// TCP_NODELAY, so small responses aren't held back by Nagle's algorithm
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    /// SO_KEEPALIVE probes to notice clients that went away silently
+    #[serde(default)]
+    pub keepalive: Option<KeepaliveConfig>,
+    /// SO_REUSEADDR, so a restart can bind while old connections are in TIME_WAIT
+    #[serde(default = "default_reuse_address")]
+    pub reuse_address: bool,
+    /// Connections the kernel queues before they are accepted
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: default_nodelay(),
+            keepalive: None,
+            reuse_address: default_reuse_address(),
+            backlog: default_backlog(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct KeepaliveConfig {
+    /// Seconds without traffic before the first probe
+    #[serde(default = "default_keepalive_idle")]
+    pub idle: u64,
+    /// Seconds between unanswered probes
+    #[serde(default = "default_keepalive_interval")]
+    pub interval: u64,
+    /// Unanswered probes before the connection is dropped
+    #[serde(default = "default_keepalive_retries")]
+    pub retries: u32,
+}
+
+impl TcpOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.backlog == 0 {
+            anyhow::bail!("tcp backlog must be greater than 0");
+        }
+        if let Some(keepalive) = &self.keepalive {
+            if keepalive.idle == 0 || keepalive.interval == 0 || keepalive.retries == 0 {
+                anyhow::bail!("tcp keepalive idle, interval and retries must be greater than 0");
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the per-connection options on an accepted client connection
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        match &self.keepalive {
+            Some(keepalive) => socket.set_tcp_keepalive(
+                &TcpKeepalive::new()
+                    .with_time(Duration::from_secs(keepalive.idle))
+                    .with_interval(Duration::from_secs(keepalive.interval))
+                    .with_retries(keepalive.retries),
+            ),
+            None => socket.set_keepalive(false),
+        }
+    }
+}
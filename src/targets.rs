@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TargetConfig {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+#[derive(Debug)]
+struct Target {
+    url: String,
+    weight: AtomicU32,
+    draining: AtomicBool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TargetStatus {
+    pub url: String,
+    pub weight: u32,
+    pub draining: bool,
+}
+
+/// Backend replicas of an endpoint with runtime-adjustable weights
+#[derive(Debug)]
+pub struct TargetPool {
+    targets: Vec<Target>,
+    counter: AtomicU64,
+}
+
+impl TargetPool {
+    pub fn new(configs: &[TargetConfig]) -> Self {
+        let targets = configs
+            .iter()
+            .map(|config| Target {
+                url: config.url.clone(),
+                weight: AtomicU32::new(config.weight),
+                draining: AtomicBool::new(false),
+            })
+            .collect();
+
+        Self {
+            targets,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Pick a target by weighted round-robin, skipping drained and zero-weight targets
+    pub fn select(&self) -> Option<&str> {
+        let weights: Vec<u64> = self
+            .targets
+            .iter()
+            .map(|target| {
+                if target.draining.load(Ordering::Relaxed) {
+                    0
+                } else {
+                    target.weight.load(Ordering::Relaxed) as u64
+                }
+            })
+            .collect();
+
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut slot = self.counter.fetch_add(1, Ordering::Relaxed) % total;
+        for (target, weight) in self.targets.iter().zip(weights) {
+            if slot < weight {
+                return Some(&target.url);
+            }
+            slot -= weight;
+        }
+
+        None
+    }
+
+    pub fn set_weight(&self, index: usize, weight: u32) -> bool {
+        match self.targets.get(index) {
+            Some(target) => {
+                target.weight.store(weight, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_draining(&self, index: usize, draining: bool) -> bool {
+        match self.targets.get(index) {
+            Some(target) => {
+                target.draining.store(draining, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> Vec<TargetStatus> {
+        self.targets
+            .iter()
+            .map(|target| TargetStatus {
+                url: target.url.clone(),
+                weight: target.weight.load(Ordering::Relaxed),
+                draining: target.draining.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::Result;
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    HttpProtobuf,
+}
+
+fn default_service_name() -> String {
+    "postfix-rest-api-connector".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_metrics_interval() -> u64 {
+    60000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TelemetryConfig {
+    /// OTLP collector, e.g. http://127.0.0.1:4317 (gRPC) or
+    /// http://127.0.0.1:4318/v1/traces (HTTP)
+    pub endpoint: String,
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Share of connections traced, 0.0 to 1.0
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    /// Also export the Prometheus metrics over OTLP
+    #[serde(default)]
+    pub metrics: bool,
+    /// Required for metrics over HTTP, e.g. http://127.0.0.1:4318/v1/metrics
+    #[serde(default)]
+    pub metrics_endpoint: Option<String>,
+    #[serde(default = "default_metrics_interval")]
+    pub metrics_interval: u64, // milliseconds
+}
+
+impl TelemetryConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.endpoint.is_empty() {
+            anyhow::bail!("telemetry endpoint must not be empty");
+        }
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            anyhow::bail!("telemetry sample-ratio must be between 0.0 and 1.0");
+        }
+        if self.metrics && self.metrics_interval == 0 {
+            anyhow::bail!("telemetry metrics-interval must be greater than 0");
+        }
+        if self.metrics
+            && self.protocol == OtlpProtocol::HttpProtobuf
+            && self.metrics_endpoint.is_none()
+        {
+            anyhow::bail!("telemetry metrics over http-protobuf need metrics-endpoint");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otlp {
+    use anyhow::{Context, Result};
+    use log::{info, warn};
+    use opentelemetry::metrics::{Counter, Gauge, Meter, MeterProvider as _};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+    use opentelemetry_sdk::Resource;
+    use reqwest::RequestBuilder;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    use super::{OtlpProtocol, TelemetryConfig};
+    use crate::logging::SpanLayer;
+    use crate::metrics;
+
+    const SCOPE: &str = "postfix-rest-api-connector";
+
+    /// Installed exporters, flushed on shutdown
+    pub struct Telemetry {
+        tracer_provider: SdkTracerProvider,
+        meter_provider: Option<SdkMeterProvider>,
+    }
+
+    fn span_exporter(config: &TelemetryConfig) -> Result<SpanExporter> {
+        let exporter = match config.protocol {
+            OtlpProtocol::Grpc => SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(&config.endpoint)
+                .build()?,
+            OtlpProtocol::HttpProtobuf => SpanExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpBinary)
+                .with_endpoint(&config.endpoint)
+                .build()?,
+        };
+        Ok(exporter)
+    }
+
+    fn metric_exporter(config: &TelemetryConfig) -> Result<MetricExporter> {
+        let endpoint = config.metrics_endpoint.as_deref().unwrap_or(&config.endpoint);
+        let exporter = match config.protocol {
+            OtlpProtocol::Grpc => MetricExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?,
+            OtlpProtocol::HttpProtobuf => MetricExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpBinary)
+                .with_endpoint(endpoint)
+                .build()?,
+        };
+        Ok(exporter)
+    }
+
+    impl Telemetry {
+        pub fn init(config: &TelemetryConfig) -> Result<Self> {
+            let resource = Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+                .build();
+
+            let tracer_provider = SdkTracerProvider::builder()
+                .with_batch_exporter(span_exporter(config)?)
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    config.sample_ratio,
+                ))))
+                .with_resource(resource.clone())
+                .build();
+            tracing_subscriber::registry()
+                .with(SpanLayer)
+                .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(SCOPE)))
+                .try_init()
+                .context("Failed to install the tracing subscriber")?;
+            opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+            let meter_provider = if config.metrics {
+                let interval = Duration::from_millis(config.metrics_interval);
+                let reader = PeriodicReader::builder(metric_exporter(config)?)
+                    .with_interval(interval)
+                    .build();
+                let provider = SdkMeterProvider::builder()
+                    .with_reader(reader)
+                    .with_resource(resource)
+                    .build();
+                tokio::spawn(mirror_metrics(provider.meter(SCOPE), interval));
+                Some(provider)
+            } else {
+                None
+            };
+
+            info!("Exporting traces over OTLP to {}", config.endpoint);
+            Ok(Self {
+                tracer_provider,
+                meter_provider,
+            })
+        }
+
+        /// Export what is still buffered
+        pub async fn shutdown(self) {
+            let result = tokio::task::spawn_blocking(move || {
+                if let Some(provider) = self.meter_provider {
+                    if let Err(e) = provider.shutdown() {
+                        warn!("OTLP metrics shutdown failed: {}", e);
+                    }
+                }
+                self.tracer_provider.shutdown()
+            })
+            .await;
+            if let Ok(Err(e)) = result {
+                warn!("OTLP trace shutdown failed: {}", e);
+            }
+        }
+    }
+
+    /// Copy the Prometheus registry into OTLP instruments at every export
+    /// interval; counters are added as the increase since the last copy
+    async fn mirror_metrics(meter: Meter, interval: Duration) {
+        let mut counters: HashMap<&'static str, Counter<f64>> = HashMap::new();
+        let mut gauges: HashMap<&'static str, Gauge<f64>> = HashMap::new();
+        let mut totals: HashMap<(&'static str, Vec<(String, String)>), f64> = HashMap::new();
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+            for sample in metrics::snapshot() {
+                let attributes: Vec<KeyValue> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| KeyValue::new(name.clone(), value.clone()))
+                    .collect();
+                if sample.kind == "counter" {
+                    let counter = counters.entry(sample.name).or_insert_with(|| {
+                        meter.f64_counter(sample.name).with_description(sample.help).build()
+                    });
+                    let previous = totals.insert((sample.name, sample.labels), sample.value);
+                    // A series that went down was reset; count it from zero
+                    let increase = match previous {
+                        Some(previous) if previous <= sample.value => sample.value - previous,
+                        _ => sample.value,
+                    };
+                    if increase > 0.0 {
+                        counter.add(increase, &attributes);
+                    }
+                } else {
+                    let gauge = gauges.entry(sample.name).or_insert_with(|| {
+                        meter.f64_gauge(sample.name).with_description(sample.help).build()
+                    });
+                    gauge.record(sample.value, &attributes);
+                }
+            }
+        }
+    }
+
+    /// Add the current span's W3C trace context (traceparent) to an
+    /// upstream request, so the REST API's spans join the lookup's trace
+    pub fn inject(request: RequestBuilder) -> RequestBuilder {
+        let context = tracing::Span::current().context();
+        let mut headers = HashMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut headers)
+        });
+        headers
+            .into_iter()
+            .fold(request, |request, (name, value)| request.header(name, value))
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use otlp::{inject, Telemetry};
+
+#[cfg(not(feature = "otel"))]
+pub struct Telemetry;
+
+#[cfg(not(feature = "otel"))]
+impl Telemetry {
+    pub fn init(_config: &TelemetryConfig) -> Result<Self> {
+        anyhow::bail!("built without OTLP support (build with --features otel)")
+    }
+
+    pub async fn shutdown(self) {}
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn inject(request: RequestBuilder) -> RequestBuilder {
+    request
+}
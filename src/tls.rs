@@ -0,0 +1,289 @@
+use anyhow::{Context, Result};
+use log::warn;
+use p12_keystore::KeyStore;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{
+    version, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    SupportedProtocolVersion,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn default_alpn() -> Vec<String> {
+    vec!["h2".to_string(), "http/1.1".to_string()]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// PEM bundle or DER file with additional trusted root certificates
+    #[serde(default, alias = "ca-cert")]
+    pub ca_file: Option<PathBuf>,
+    /// Trust only ca-file, not the built-in public roots
+    #[serde(default)]
+    pub ca_only: bool,
+    /// PEM client certificate chain and key for mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// Client certificate chain and key as PKCS#12 (.p12/.pfx) instead of PEM
+    #[serde(default)]
+    pub client_pkcs12: Option<PathBuf>,
+    #[serde(default)]
+    pub client_pkcs12_password: String,
+    #[serde(default = "default_alpn")]
+    pub alpn: Vec<String>,
+    /// Hex SHA-256 fingerprints of accepted server certificates, checked on
+    /// top of the normal chain verification
+    #[serde(default)]
+    pub pinned_certificates: Vec<String>,
+    /// Key exchange groups in order of preference, e.g. ["X25519MLKEM768", "X25519"]
+    #[serde(default)]
+    pub key_exchange_groups: Vec<String>,
+    /// "1.2" (default) or "1.3"
+    #[serde(default)]
+    pub min_version: Option<String>,
+    /// Verify the server certificate against this name instead of the URL host
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Skip certificate verification entirely (pins are still checked); lab use only
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+}
+
+fn crypto_provider() -> CryptoProvider {
+    // ML-KEM key exchange is only available with aws-lc-rs
+    #[cfg(feature = "post-quantum")]
+    {
+        rustls::crypto::aws_lc_rs::default_provider()
+    }
+    #[cfg(not(feature = "post-quantum"))]
+    {
+        rustls::crypto::ring::default_provider()
+    }
+}
+
+/// Build the rustls client configuration for an endpoint's HTTP client
+pub fn client_config(config: &TlsConfig) -> Result<ClientConfig> {
+    let mut provider = crypto_provider();
+    if !config.key_exchange_groups.is_empty() {
+        let mut groups = Vec::new();
+        for name in &config.key_exchange_groups {
+            let group = provider
+                .kx_groups
+                .iter()
+                .find(|group| format!("{:?}", group.name()).eq_ignore_ascii_case(name))
+                .with_context(|| format!("Unsupported key exchange group '{}'", name))?;
+            groups.push(*group);
+        }
+        provider.kx_groups = groups;
+    }
+    let provider = Arc::new(provider);
+
+    let mut roots = RootCertStore::empty();
+    if !config.ca_only {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    if let Some(ca_file) = &config.ca_file {
+        for cert in load_ca_file(ca_file)? {
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid CA certificate in {}", ca_file.display()))?;
+        }
+    }
+    if roots.is_empty() {
+        anyhow::bail!("No trusted root certificates (ca-only without ca-file)");
+    }
+
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()?;
+    let pins = config
+        .pinned_certificates
+        .iter()
+        .map(|pin| parse_pin(pin))
+        .collect::<Result<Vec<_>>>()?;
+
+    let server_name = config
+        .server_name
+        .as_ref()
+        .map(|name| ServerName::try_from(name.clone()))
+        .transpose()
+        .context("Invalid server-name")?;
+    if config.danger_accept_invalid_certs {
+        warn!("TLS certificate verification is disabled (danger-accept-invalid-certs)");
+    }
+
+    let versions: &[&SupportedProtocolVersion] = match config.min_version.as_deref() {
+        None | Some("1.2") => &[&version::TLS13, &version::TLS12],
+        Some("1.3") => &[&version::TLS13],
+        Some(other) => anyhow::bail!("Unsupported min-version '{}', expected 1.2 or 1.3", other),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+            inner: verifier,
+            pins,
+            server_name,
+            insecure: config.danger_accept_invalid_certs,
+        }));
+
+    let identity = match (&config.client_cert, &config.client_key, &config.client_pkcs12) {
+        (Some(cert), Some(key), None) => Some(load_pem_identity(cert, key)?),
+        (None, None, Some(pkcs12)) => {
+            Some(load_pkcs12_identity(pkcs12, &config.client_pkcs12_password)?)
+        }
+        (None, None, None) => None,
+        (_, _, Some(_)) => anyhow::bail!("client-pkcs12 replaces client-cert and client-key"),
+        _ => anyhow::bail!("client-cert and client-key must be set together"),
+    };
+    let mut client_config = match identity {
+        Some((chain, key)) => builder
+            .with_client_auth_cert(chain, key)
+            .context("Client certificate does not match its private key")?,
+        None => builder.with_no_client_auth(),
+    };
+    client_config.alpn_protocols = config
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(client_config)
+}
+
+fn load_ca_file(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let data = fs::read(path)
+        .with_context(|| format!("Failed to read CA file {}", path.display()))?;
+    let certs = CertificateDer::pem_slice_iter(&data)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid PEM in CA file {}", path.display()))?;
+    if !certs.is_empty() {
+        return Ok(certs);
+    }
+    // Not PEM; a single DER certificate starts with a SEQUENCE tag
+    if data.first() == Some(&0x30) {
+        return Ok(vec![CertificateDer::from(data)]);
+    }
+    anyhow::bail!("No certificates found in CA file {}", path.display())
+}
+
+type ClientIdentity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+fn load_pem_identity(cert: &Path, key: &Path) -> Result<ClientIdentity> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .with_context(|| format!("Failed to read client certificate {}", cert.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid PEM in client certificate {}", cert.display()))?;
+    if chain.is_empty() {
+        anyhow::bail!("No certificate found in {}", cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read a private key from {}", key.display()))?;
+    Ok((chain, key))
+}
+
+fn load_pkcs12_identity(path: &Path, password: &str) -> Result<ClientIdentity> {
+    let data = fs::read(path)
+        .with_context(|| format!("Failed to read client certificate {}", path.display()))?;
+    let keystore = KeyStore::from_pkcs12(&data, password).map_err(|e| {
+        anyhow::anyhow!("Failed to open PKCS#12 file {} (wrong password?): {}", path.display(), e)
+    })?;
+    let (_, key_chain) = keystore
+        .private_key_chain()
+        .with_context(|| format!("No private key found in {}", path.display()))?;
+
+    let chain: Vec<_> = key_chain
+        .chain()
+        .iter()
+        .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+        .collect();
+    if chain.is_empty() {
+        anyhow::bail!("No certificate found in {}", path.display());
+    }
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_chain.key().to_vec()));
+    Ok((chain, key))
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 {
+        anyhow::bail!("Invalid certificate pin '{}': expected a SHA-256 hex digest", pin);
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("Invalid certificate pin '{}'", pin))?;
+    }
+    Ok(digest)
+}
+
+/// Chain verification plus an optional allow-list of server certificates
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+    server_name: Option<ServerName<'static>>,
+    insecure: bool,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.insecure {
+            let server_name = match &self.server_name {
+                Some(name) => name,
+                None => server_name,
+            };
+            self.inner
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
+        if !self.pins.is_empty() {
+            let digest: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+            if !self.pins.contains(&digest) {
+                return Err(rustls::Error::General(
+                    "server certificate does not match any pin".to_string(),
+                ));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}